@@ -0,0 +1,104 @@
+use std::collections::HashMap;
+
+use schemars::schema::RootSchema;
+use schemars::{schema_for, JsonSchema};
+use schemars_zod::merge_schemas;
+use serde::{Deserialize, Serialize};
+
+use crate::{BucketKey, Events, Timestamp};
+
+pub mod buckets {
+  use crate::audio_device::{HostAudioDeviceSpecs, HostAudioDevices};
+  use crate::BucketName;
+
+  pub const HOST_AUDIO_DEVICES: BucketName<HostAudioDevices> = BucketName::new("audiocloud_host_audio_devices");
+  pub const HOST_AUDIO_DEVICE_SPEC: BucketName<HostAudioDeviceSpecs> = BucketName::new("audiocloud_host_audio_device_spec");
+}
+
+/// An audio device the host has available, as reported by its driver
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct AudioDeviceDescription {
+  /// Driver type, e.g. CoreAudio, ASIO or ALSA
+  pub device_type:  String,
+  /// Device name, unique within the driver type
+  pub name:         String,
+  pub num_inputs:   usize,
+  pub num_outputs:  usize,
+  pub sample_rates: Vec<u32>,
+  pub buffer_sizes: Vec<u32>,
+}
+
+/// The audio devices available on a host
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct HostAudioDevices {
+  pub devices:    Vec<AudioDeviceDescription>,
+  pub updated_at: Timestamp,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase", tag = "type")]
+pub enum AudioDeviceEvent {
+  /// A device was plugged in, or its capabilities changed
+  Appeared { device: AudioDeviceDescription },
+  /// A device was unplugged, or its capabilities changed
+  Disappeared { device: AudioDeviceDescription },
+}
+
+/// The audio devices a host should open, by device id
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct HostAudioDeviceSpecs {
+  pub devices:      HashMap<String, AudioDeviceSpec>,
+  /// Device clocking the graphs, if not set the host picks one
+  #[serde(default)]
+  pub clock_device: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct AudioDeviceSpec {
+  pub driver:      AudioDeviceDriverSpec,
+  pub sample_rate: u32,
+  pub buffer_size: u32,
+  pub num_inputs:  usize,
+  pub num_outputs: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema, PartialEq)]
+#[serde(rename_all = "camelCase", tag = "type")]
+pub enum AudioDeviceDriverSpec {
+  /// A device opened through JUCE, as listed in [HostAudioDevices]
+  Juce(JuceAudioDeviceSpec),
+  /// A simulated device with silent inputs, which discards its outputs
+  Simulator,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct JuceAudioDeviceSpec {
+  /// See [AudioDeviceDescription::device_type]
+  pub device_type: String,
+  /// See [AudioDeviceDescription::name]
+  pub name:        String,
+}
+
+pub fn host_audio_devices_key<T: ToString>(host: &T) -> BucketKey<String, HostAudioDevices> {
+  host.to_string().into()
+}
+
+pub fn host_audio_device_specs_key<T: ToString>(host: &T) -> BucketKey<String, HostAudioDeviceSpecs> {
+  host.to_string().into()
+}
+
+pub fn host_audio_device_events(host: impl AsRef<str>) -> Events<AudioDeviceEvent> {
+  Events::new(format!("audiocloud_host.{}.audio_devices.events", host.as_ref()))
+}
+
+pub fn schema() -> RootSchema {
+  merge_schemas([schema_for!(AudioDeviceDescription),
+                 schema_for!(HostAudioDevices),
+                 schema_for!(AudioDeviceEvent),
+                 schema_for!(HostAudioDeviceSpecs)].into_iter())
+}
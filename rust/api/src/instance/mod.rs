@@ -19,7 +19,7 @@ pub mod state;
 pub mod buckets {
   use crate::instance::control::{InstancePlayControl, InstancePowerControl};
   use crate::instance::spec::InstanceSpec;
-  use crate::instance::{InstanceConnectionState, InstancePlayState, InstancePowerState};
+  use crate::instance::{InstanceConnectionState, InstanceLatencyCalibration, InstancePlayState, InstancePowerState};
   use crate::BucketName;
 
   pub const INSTANCE_POWER_CONTROL: BucketName<InstancePowerControl> = BucketName::new("audiocloud_instance_power_control");
@@ -28,6 +28,8 @@ pub mod buckets {
   pub const INSTANCE_POWER_STATE: BucketName<InstancePowerState> = BucketName::new("audiocloud_instance_power_state");
   pub const INSTANCE_PLAY_STATE: BucketName<InstancePlayState> = BucketName::new("audiocloud_instance_play_state");
   pub const INSTANCE_SPEC: BucketName<InstanceSpec> = BucketName::new("audiocloud_instance_spec");
+  pub const INSTANCE_LATENCY_CALIBRATION: BucketName<InstanceLatencyCalibration> =
+    BucketName::new("audiocloud_instance_latency_calibration");
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, JsonSchema, Eq, PartialEq, Ord, PartialOrd, Hash)]
@@ -112,6 +114,22 @@ impl Display for InstanceConnectionState {
   }
 }
 
+/// Round trip latency of an instance attachment, measured by sending a test signal through the instance
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct InstanceLatencyCalibration {
+  /// The audio device the instance was attached to when measured, the calibration does not apply to other devices
+  pub device:             String,
+  pub sample_rate:        u32,
+  /// Samples between writing to the sends and reading the signal back from the returns
+  pub round_trip:         usize,
+  /// The part of the round trip not already reported as latency by the audio device
+  pub additional_latency: usize,
+  /// Round trip measured on each return, `None` where the signal did not come back
+  pub returns:            Vec<Option<usize>>,
+  pub measured_at:        Timestamp,
+}
+
 impl InstancePowerState {
   pub fn is_in_progress(&self) -> bool {
     matches!(self, Self::CoolingDown | Self::WarmingUp)
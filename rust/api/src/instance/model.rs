@@ -56,6 +56,10 @@ pub fn unit_hz() -> Option<String> {
   Some("Hz".to_owned())
 }
 
+pub fn unit_ms() -> Option<String> {
+  Some("ms".to_owned())
+}
+
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "camelCase", tag = "type")]
 pub enum ValueRange {
@@ -5,6 +5,7 @@ use serde::{Deserialize, Serialize};
 
 use crate::instance::driver::config::InstanceDriverConfig;
 use crate::instance::spec::{InstanceMediaSpec, InstancePowerSpec};
+use crate::instance::InstanceLatencyCalibration;
 use crate::Request;
 
 #[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
@@ -28,7 +29,31 @@ pub fn register_or_update_instance_request() -> Request<RegisterOrUpdateInstance
   Request::new("audiocloud_instance_register_or_update")
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CalibrateInstanceLatencyRequest {
+  pub instance_id: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum CalibrateInstanceLatencyResponse {
+  /// The calibration was measured and stored
+  Success(InstanceLatencyCalibration),
+  Failed {
+    error: String,
+  },
+}
+
+/// Served by the host the instance is attached to, see [crate::instance::spec::InstanceSpec::host]
+pub fn calibrate_instance_latency_request(host: impl AsRef<str>)
+                                          -> Request<CalibrateInstanceLatencyRequest, CalibrateInstanceLatencyResponse> {
+  Request::new(format!("audiocloud_host.{}.calibrate_instance_latency", host.as_ref()))
+}
+
 pub fn schema() -> RootSchema {
   merge_schemas([schema_for!(RegisterOrUpdateInstanceRequest),
-                 schema_for!(RegisterOrUpdateInstanceResponse)].into_iter())
+                 schema_for!(RegisterOrUpdateInstanceResponse),
+                 schema_for!(CalibrateInstanceLatencyRequest),
+                 schema_for!(CalibrateInstanceLatencyResponse)].into_iter())
 }
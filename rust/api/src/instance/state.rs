@@ -2,7 +2,7 @@ use schemars::schema::RootSchema;
 use schemars::schema_for;
 use schemars_zod::merge_schemas;
 
-use crate::instance::{InstanceConnectionState, InstancePlayState, InstancePowerState};
+use crate::instance::{InstanceConnectionState, InstanceLatencyCalibration, InstancePlayState, InstancePowerState};
 use crate::BucketKey;
 
 pub fn instance_power_state_key<T: ToString>(instance_id: &T) -> BucketKey<String, InstancePowerState> {
@@ -17,6 +17,12 @@ pub fn instance_play_state_key<T: ToString>(instance_id: &T) -> BucketKey<String
   instance_id.to_string().into()
 }
 
+pub fn instance_latency_calibration_key<T: ToString>(instance_id: &T) -> BucketKey<String, InstanceLatencyCalibration> {
+  instance_id.to_string().into()
+}
+
 pub fn schema() -> RootSchema {
-  merge_schemas([schema_for!(InstancePowerState), schema_for!(InstancePlayState)].into_iter())
+  merge_schemas([schema_for!(InstancePowerState),
+                 schema_for!(InstancePlayState),
+                 schema_for!(InstanceLatencyCalibration)].into_iter())
 }
@@ -4,6 +4,7 @@ pub use chrono::{DateTime, Utc};
 use schemars::schema::RootSchema;
 use schemars_zod::merge_schemas;
 
+pub mod audio_device;
 pub mod instance;
 pub mod media;
 pub mod rt;
@@ -91,5 +92,5 @@ impl<Content> BucketName<Content> {
 }
 
 pub fn schema() -> RootSchema {
-  merge_schemas([instance::schema(), media::schema(), rt::schema(), task::schema(), user::schema()].into_iter())
+  merge_schemas([audio_device::schema(), instance::schema(), media::schema(), rt::schema(), task::schema(), user::schema()].into_iter())
 }
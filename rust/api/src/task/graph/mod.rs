@@ -8,6 +8,7 @@ use serde::{Deserialize, Serialize};
 use crate::media::spec::MediaId;
 
 pub mod modify;
+pub mod validate;
 
 pub type SourceId = u64;
 pub type InsertId = u64;
@@ -63,6 +64,8 @@ pub enum InputId {
   DeviceSink(SinkId, usize),
   #[display(fmt = "streaming sink {_0}, input channel {_1}")]
   StreamingSink(SinkId, usize),
+  #[display(fmt = "recording sink {_0}, input channel {_1}")]
+  RecordingSink(SinkId, usize),
 }
 
 impl InputId {
@@ -73,6 +76,7 @@ impl InputId {
       | InputId::Bus(_, channel) => *channel,
       | InputId::DeviceSink(_, channel) => *channel,
       | InputId::StreamingSink(_, channel) => *channel,
+      | InputId::RecordingSink(_, channel) => *channel,
     }
   }
 }
@@ -96,6 +100,7 @@ impl Into<NodeId> for InputId {
       | InputId::Bus(id, _) => NodeId::Bus(id),
       | InputId::DeviceSink(id, _) => NodeId::DeviceSink(id),
       | InputId::StreamingSink(id, _) => NodeId::StreamingSink(id),
+      | InputId::RecordingSink(id, _) => NodeId::RecordingSink(id),
     }
   }
 }
@@ -116,6 +121,8 @@ pub enum NodeId {
   DeviceSink(SinkId),
   #[display(fmt = "streaming sink {_0}")]
   StreamingSink(SinkId),
+  #[display(fmt = "recording sink {_0}")]
+  RecordingSink(SinkId),
 }
 
 impl NodeId {
@@ -126,6 +133,7 @@ impl NodeId {
       | NodeId::Bus(id) => InputId::Bus(*id, index),
       | NodeId::DeviceSink(id) => InputId::DeviceSink(*id, index),
       | NodeId::StreamingSink(id) => InputId::StreamingSink(*id, index),
+      | NodeId::RecordingSink(id) => InputId::RecordingSink(*id, index),
       | _ => bail!("Node {self} does not have inputs"),
     })
   }
@@ -141,12 +149,69 @@ impl NodeId {
   }
 }
 
+/// Specification of a media clip played from the timeline
+///
+/// Positions and lengths are in samples at the graph sample rate. The clip plays the media from `media_in` to
+/// `media_out` starting at timeline position `start_at`, and is silent elsewhere on the timeline.
+///
+/// Only WAV and FLAC media can be placed as clips, other formats play whole from the start of the timeline and a
+/// source using them with a trim, offset, fade or gain is rejected.
 #[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct SourceSpec {
   pub media_id:     MediaId,
+  /// Timeline position of the first sample of the clip
   pub start_at:     u64,
   pub num_channels: usize,
+  /// Position in the media the clip starts playing from
+  #[serde(default)]
+  pub media_in:     u64,
+  /// Position in the media the clip stops playing at, or the end of the media
+  #[serde(default)]
+  pub media_out:    Option<u64>,
+  #[serde(default)]
+  pub fade_in:      SourceFade,
+  #[serde(default)]
+  pub fade_out:     SourceFade,
+  /// Gain applied to the whole clip
+  #[serde(default)]
+  pub gain_db:      f64,
+}
+
+impl SourceSpec {
+  /// Length of the clip on the timeline, if the media out point is known
+  pub fn length(&self) -> Option<u64> {
+    self.media_out.map(|media_out| media_out.saturating_sub(self.media_in))
+  }
+}
+
+/// Fade at the start or the end of a source clip
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize, JsonSchema, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct SourceFade {
+  /// Length of the fade in samples, zero for no fade
+  pub length: u64,
+  pub curve:  FadeCurve,
+}
+
+/// Shape of the gain of a fade, going from silence to full level
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum FadeCurve {
+  /// Gain rises linearly, overlapping linear fades of correlated material keep a constant level
+  Linear,
+  /// Gain follows a quarter sine, overlapping equal-power fades of uncorrelated material keep a constant level
+  EqualPower,
+  /// Gain follows a raised cosine, starting and ending gently
+  SCurve,
+  /// Gain rises slowly at first and quickly near the end, which sounds close to linear in loudness
+  Exponential,
+}
+
+impl Default for FadeCurve {
+  fn default() -> Self {
+    Self::Linear
+  }
 }
 
 /// Specification of a software summing bus
@@ -155,6 +220,25 @@ pub struct SourceSpec {
 pub struct BusSpec {
   pub inputs:      Vec<Vec<OutputId>>,
   pub num_outputs: usize,
+  #[serde(default)]
+  pub mode:        BusMode,
+}
+
+/// How a bus combines its inputs into its outputs
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum BusMode {
+  /// Input channels pass to the output channel with the same index, or are split or collapsed between mono and stereo
+  Summing,
+  /// Inputs are mixed into the outputs through a routing matrix, with per-input gain, pan, mute and solo. The matrix
+  /// starts out passing each input to the output with the same index.
+  Matrix,
+}
+
+impl Default for BusMode {
+  fn default() -> Self {
+    Self::Summing
+  }
 }
 
 /// Specification of an insert instance within the graph (e.g. an external hardware, or VST plugin) that can be connected to the graph
@@ -177,4 +261,52 @@ pub struct VirtualInsertSpec {
 pub struct SinkSpec {
   pub inputs:      Vec<Vec<OutputId>>,
   pub sample_rate: u32,
+  #[serde(default)]
+  pub codec:       SinkCodec,
+}
+
+/// Specification of a sink recording its inputs to new media
+///
+/// The media is written under the media root at the graph sample rate, with a fresh media id in the `app_id` app.
+#[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RecordingSpec {
+  pub inputs:      Vec<Vec<OutputId>>,
+  pub app_id:      String,
+  /// Sample rate of the graph, used when the devices driving the graph do not report one
+  pub sample_rate: u32,
+  #[serde(default)]
+  pub format:      RecordingFormat,
+}
+
+/// File format of a recording
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum RecordingFormat {
+  /// 32 bit float WAV
+  Wav,
+  /// 24 bit FLAC
+  Flac,
+}
+
+impl Default for RecordingFormat {
+  fn default() -> Self {
+    Self::Flac
+  }
+}
+
+/// Encoding of the stream captured by a streaming sink
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase", tag = "type")]
+pub enum SinkCodec {
+  /// Lossless FLAC at the sink sample rate
+  Flac,
+  /// Ogg/Opus at the given bitrate in bits per second, always encoded at 48 kHz regardless of the sink sample rate
+  Opus { bitrate: u32 },
+}
+
+impl Default for SinkCodec {
+  fn default() -> Self {
+    Self::Flac
+  }
 }
@@ -5,7 +5,7 @@ use thiserror::Error;
 
 use crate::task::graph::VirtualInsertSpec;
 
-use super::{BusId, BusSpec, DeviceInsertSpec, InsertId, NodeId, OutputId, SourceId, SourceSpec};
+use super::{AudioGraphSpec, BusId, BusSpec, DeviceInsertSpec, InsertId, NodeId, OutputId, SinkId, SourceId, SourceSpec};
 
 #[derive(Debug, PartialEq, Display, Serialize, Deserialize, JsonSchema, Clone)]
 #[serde(rename_all = "camelCase", tag = "type")]
@@ -47,7 +47,7 @@ pub enum AudioGraphModification {
 }
 
 /// Validation errors for the graph to be created or modified
-#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone, Error)]
+#[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema, Clone, Error)]
 #[serde(rename_all = "camelCase", tag = "type")]
 pub enum GraphModificationError {
   /// The output to be linked does not exist
@@ -64,4 +64,104 @@ pub enum GraphModificationError {
     input:     usize,
     output:    OutputId,
   },
+  /// The component to be connected or disconnected does not exist
+  #[error("component {component} does not exist")]
+  ComponentNotFound { component: NodeId },
+  /// The component does not have the input channel
+  #[error("component {component} has {num_inputs} inputs, input {input} is out of range")]
+  InputChannelOutOfRange {
+    component:  NodeId,
+    input:      usize,
+    num_inputs: usize,
+  },
+  /// The output exists, but does not have the referenced channel
+  #[error("component {component} input {input} references output {output}, but the component only has {num_outputs} outputs")]
+  OutputChannelOutOfRange {
+    component:   NodeId,
+    input:       usize,
+    output:      OutputId,
+    num_outputs: usize,
+  },
+  /// The sink would not receive any audio
+  #[error("sink {sink_id} has no inputs")]
+  SinkWithoutInputs { sink_id: SinkId },
+  /// The source clip ends before it starts
+  #[error("source {source_id} media out point is not after its media in point")]
+  EmptySourceClip { source_id: SourceId },
+}
+
+/// All errors found while validating a graph or a batch of modifications
+#[derive(Debug, PartialEq, Clone, Error)]
+#[error("graph validation failed: {}", .0.iter().map(ToString::to_string).collect::<Vec<_>>().join(", "))]
+pub struct GraphValidationFailed(pub Vec<GraphModificationError>);
+
+impl AudioGraphSpec {
+  /// Apply a modification to the specification, without validating the connections
+  ///
+  /// Removing components that do not exist is not an error, but connecting to or disconnecting from them is.
+  pub fn apply(&mut self, modification: &AudioGraphModification) -> Result<(), GraphModificationError> {
+    match modification {
+      | AudioGraphModification::AddOrReplaceSource { source_id, source_spec } => {
+        self.sources.insert(*source_id, source_spec.clone());
+      }
+      | AudioGraphModification::AddOrReplaceDeviceInsert { insert_id, insert_spec } => {
+        self.device_inserts.insert(*insert_id, insert_spec.clone());
+      }
+      | AudioGraphModification::AddOrReplaceVirtualInsert { insert_id, insert_spec } => {
+        self.virtual_inserts.insert(*insert_id, insert_spec.clone());
+      }
+      | AudioGraphModification::AddOrReplaceBus { bus_id, bus_spec } => {
+        self.busses.insert(*bus_id, bus_spec.clone());
+      }
+      | AudioGraphModification::RemoveSource { source_id } => {
+        self.sources.remove(source_id);
+      }
+      | AudioGraphModification::RemoveDeviceInsert { insert_id } => {
+        self.device_inserts.remove(insert_id);
+      }
+      | AudioGraphModification::RemoveVirtualInsert { insert_id } => {
+        self.virtual_inserts.remove(insert_id);
+      }
+      | AudioGraphModification::RemoveBus { bus_id } => {
+        self.busses.remove(bus_id);
+      }
+      | AudioGraphModification::Connect { component,
+                                          input_channel,
+                                          output, } => {
+        let input = self.input_mut(*component, *input_channel)?;
+        if !input.contains(output) {
+          input.push(*output);
+        }
+      }
+      | AudioGraphModification::Disconnect { component,
+                                             input_channel,
+                                             output, } => {
+        self.input_mut(*component, *input_channel)?.retain(|connected| connected != output);
+      }
+    }
+
+    Ok(())
+  }
+
+  fn input_mut(&mut self, component: NodeId, input: usize) -> Result<&mut Vec<OutputId>, GraphModificationError> {
+    let inputs = match component {
+      | NodeId::DeviceInsert(id) => self.device_inserts.get_mut(&id).map(|spec| &mut spec.inputs),
+      | NodeId::VirtualInsert(id) => self.virtual_inserts.get_mut(&id).map(|spec| &mut spec.inputs),
+      | NodeId::Bus(id) => self.busses.get_mut(&id).map(|spec| &mut spec.inputs),
+      | NodeId::Source(id) if self.sources.contains_key(&id) => {
+        return Err(GraphModificationError::InputChannelOutOfRange { component,
+                                                                    input,
+                                                                    num_inputs: 0 });
+      }
+      | _ => None,
+    };
+
+    let Some(inputs) = inputs else { return Err(GraphModificationError::ComponentNotFound { component }); };
+    let num_inputs = inputs.len();
+
+    inputs.get_mut(input)
+          .ok_or(GraphModificationError::InputChannelOutOfRange { component,
+                                                                  input,
+                                                                  num_inputs })
+  }
 }
@@ -0,0 +1,307 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use super::modify::{AudioGraphModification, GraphModificationError};
+use super::{AudioGraphSpec, NodeId, OutputId, RecordingSpec, SinkId, SinkSpec};
+
+type ComponentInputs<'a> = BTreeMap<NodeId, &'a Vec<Vec<OutputId>>>;
+
+/// Checks graph specifications, modifications and sinks before they are applied
+///
+/// The number of device insert outputs depends on the instance the insert is attached to, so device insert output channels
+/// are only range checked for instances registered with [GraphValidator::with_device_instance_outputs].
+#[derive(Debug, Clone, Default)]
+pub struct GraphValidator {
+  device_instance_outputs: HashMap<String, usize>,
+}
+
+impl GraphValidator {
+  pub fn with_device_instance_outputs(mut self, instance_id: impl ToString, num_outputs: usize) -> Self {
+    self.device_instance_outputs.insert(instance_id.to_string(), num_outputs);
+    self
+  }
+
+  /// Check a complete graph for dangling outputs, out of range output channels and loops
+  pub fn validate(&self, spec: &AudioGraphSpec) -> Result<(), Vec<GraphModificationError>> {
+    let components = Self::component_inputs(spec);
+    let mut errors = vec![];
+
+    for (component, inputs) in &components {
+      for (input, outputs) in inputs.iter().enumerate() {
+        errors.extend(outputs.iter()
+                             .filter_map(|output| self.check_output(spec, *component, input, *output)));
+      }
+    }
+
+    errors.extend(Self::find_loops(&components));
+
+    errors.extend(spec.sources
+                      .iter()
+                      .collect::<BTreeMap<_, _>>()
+                      .into_iter()
+                      .filter(|(_, source)| source.length() == Some(0))
+                      .map(|(source_id, _)| GraphModificationError::EmptySourceClip { source_id: *source_id }));
+
+    if errors.is_empty() {
+      Ok(())
+    } else {
+      Err(errors)
+    }
+  }
+
+  /// Apply the modifications to a copy of the graph and check the result, returning the modified graph
+  pub fn validate_modifications(&self,
+                                spec: &AudioGraphSpec,
+                                modifications: &[AudioGraphModification])
+                                -> Result<AudioGraphSpec, Vec<GraphModificationError>> {
+    let mut modified = spec.clone();
+    let mut errors = modifications.iter()
+                                  .filter_map(|modification| modified.apply(modification).err())
+                                  .collect::<Vec<_>>();
+
+    if let Err(graph_errors) = self.validate(&modified) {
+      errors.extend(graph_errors);
+    }
+
+    if errors.is_empty() {
+      Ok(modified)
+    } else {
+      Err(errors)
+    }
+  }
+
+  /// Check that every sink has at least one input and that all of its inputs exist in the graph
+  pub fn validate_sinks(&self, spec: &AudioGraphSpec, sinks: &HashMap<SinkId, SinkSpec>) -> Result<(), Vec<GraphModificationError>> {
+    self.validate_sink_inputs(spec,
+                              sinks.iter()
+                                   .map(|(sink_id, sink)| (*sink_id, NodeId::StreamingSink(*sink_id), &sink.inputs)))
+  }
+
+  /// Check that every recording has at least one input and that all of its inputs exist in the graph
+  pub fn validate_recordings(&self,
+                             spec: &AudioGraphSpec,
+                             recordings: &HashMap<SinkId, RecordingSpec>)
+                             -> Result<(), Vec<GraphModificationError>> {
+    self.validate_sink_inputs(spec,
+                              recordings.iter()
+                                        .map(|(sink_id, recording)| (*sink_id, NodeId::RecordingSink(*sink_id), &recording.inputs)))
+  }
+
+  fn validate_sink_inputs<'a>(&self,
+                              spec: &AudioGraphSpec,
+                              sinks: impl Iterator<Item = (SinkId, NodeId, &'a Vec<Vec<OutputId>>)>)
+                              -> Result<(), Vec<GraphModificationError>> {
+    let mut sinks = sinks.collect::<Vec<_>>();
+    sinks.sort_by_key(|(sink_id, _, _)| *sink_id);
+
+    let mut errors = vec![];
+
+    for (sink_id, component, inputs) in sinks {
+      if inputs.iter().all(Vec::is_empty) {
+        errors.push(GraphModificationError::SinkWithoutInputs { sink_id });
+      }
+
+      for (input, outputs) in inputs.iter().enumerate() {
+        errors.extend(outputs.iter()
+                             .filter_map(|output| self.check_output(spec, component, input, *output)));
+      }
+    }
+
+    if errors.is_empty() {
+      Ok(())
+    } else {
+      Err(errors)
+    }
+  }
+
+  fn check_output(&self, spec: &AudioGraphSpec, component: NodeId, input: usize, output: OutputId) -> Option<GraphModificationError> {
+    match self.num_outputs(spec, output.into()) {
+      | None => Some(GraphModificationError::InputSourceNotFound { component, input, output }),
+      | Some(Some(num_outputs)) if output.channel_index() >= num_outputs => {
+        Some(GraphModificationError::OutputChannelOutOfRange { component,
+                                                               input,
+                                                               output,
+                                                               num_outputs })
+      }
+      | _ => None,
+    }
+  }
+
+  /// `None` if the component does not exist, `Some(None)` if it exists but the number of outputs is not known
+  fn num_outputs(&self, spec: &AudioGraphSpec, component: NodeId) -> Option<Option<usize>> {
+    match component {
+      | NodeId::Source(id) => spec.sources.get(&id).map(|source| Some(source.num_channels)),
+      | NodeId::DeviceInsert(id) => spec.device_inserts
+                                        .get(&id)
+                                        .map(|insert| self.device_instance_outputs.get(&insert.instance_id).copied()),
+      // virtual inserts are created with as many outputs as inputs
+      | NodeId::VirtualInsert(id) => spec.virtual_inserts.get(&id).map(|insert| Some(insert.inputs.len())),
+      | NodeId::Bus(id) => spec.busses.get(&id).map(|bus| Some(bus.num_outputs)),
+      | NodeId::DeviceSink(_) | NodeId::StreamingSink(_) | NodeId::RecordingSink(_) => None,
+    }
+  }
+
+  fn component_inputs(spec: &AudioGraphSpec) -> ComponentInputs<'_> {
+    let device_inserts = spec.device_inserts
+                             .iter()
+                             .map(|(id, insert)| (NodeId::DeviceInsert(*id), &insert.inputs));
+    let virtual_inserts = spec.virtual_inserts
+                              .iter()
+                              .map(|(id, insert)| (NodeId::VirtualInsert(*id), &insert.inputs));
+    let busses = spec.busses.iter().map(|(id, bus)| (NodeId::Bus(*id), &bus.inputs));
+
+    device_inserts.chain(virtual_inserts).chain(busses).collect()
+  }
+
+  fn find_loops(components: &ComponentInputs) -> Vec<GraphModificationError> {
+    let mut visited = HashSet::new();
+    let mut on_path = HashSet::new();
+    let mut errors = vec![];
+
+    for component in components.keys() {
+      if !visited.contains(component) {
+        Self::visit(*component, components, &mut visited, &mut on_path, &mut errors);
+      }
+    }
+
+    errors
+  }
+
+  // depth first, walking from every component to the components feeding its inputs
+  fn visit(component: NodeId,
+           components: &ComponentInputs,
+           visited: &mut HashSet<NodeId>,
+           on_path: &mut HashSet<NodeId>,
+           errors: &mut Vec<GraphModificationError>) {
+    on_path.insert(component);
+
+    for (input, outputs) in components.get(&component).into_iter().flat_map(|inputs| inputs.iter().enumerate()) {
+      for output in outputs {
+        let feeding: NodeId = (*output).into();
+
+        if on_path.contains(&feeding) {
+          errors.push(GraphModificationError::LoopDetected { component,
+                                                             input,
+                                                             output: *output });
+        } else if !visited.contains(&feeding) {
+          Self::visit(feeding, components, visited, on_path, errors);
+        }
+      }
+    }
+
+    on_path.remove(&component);
+    visited.insert(component);
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use crate::media::spec::MediaId;
+  use crate::task::graph::{BusSpec, SourceSpec};
+
+  use super::*;
+
+  fn spec() -> AudioGraphSpec {
+    let media_id = MediaId { app_id:   "app".to_owned(),
+                             media_id: "media".to_owned(), };
+
+    AudioGraphSpec { sources: HashMap::from([(1, SourceSpec { media_id,
+                                                               start_at: 0,
+                                                               num_channels: 2,
+                                                               media_in: 0,
+                                                               media_out: None,
+                                                               fade_in: Default::default(),
+                                                               fade_out: Default::default(),
+                                                               gain_db: 0.0 })]),
+                     busses: HashMap::from([(2, BusSpec { inputs:      vec![vec![OutputId::Source(1, 0)], vec![OutputId::Source(1, 1)]],
+                                                          num_outputs: 2,
+                                                          mode:        Default::default(), })]),
+                     ..Default::default() }
+  }
+
+  #[test]
+  fn test_valid_graph() {
+    let validator = GraphValidator::default();
+    assert_eq!(validator.validate(&spec()), Ok(()));
+
+    let sinks = HashMap::from([(3, SinkSpec { inputs:      vec![vec![OutputId::Bus(2, 0)], vec![OutputId::Bus(2, 1)]],
+                                              sample_rate: 48_000,
+                                              codec:       Default::default(), })]);
+    assert_eq!(validator.validate_sinks(&spec(), &sinks), Ok(()));
+  }
+
+  #[test]
+  fn test_invalid_modifications() {
+    let modifications = vec![AudioGraphModification::Connect { component:     NodeId::Bus(2),
+                                                               input_channel: 0,
+                                                               output:        OutputId::Source(1, 2), },
+                             AudioGraphModification::Connect { component:     NodeId::Bus(2),
+                                                               input_channel: 1,
+                                                               output:        OutputId::Source(9, 0), },
+                             AudioGraphModification::Connect { component:     NodeId::Bus(2),
+                                                               input_channel: 2,
+                                                               output:        OutputId::Source(1, 0), },
+                             AudioGraphModification::Connect { component:     NodeId::Bus(7),
+                                                               input_channel: 0,
+                                                               output:        OutputId::Source(1, 0), },];
+
+    let errors = GraphValidator::default().validate_modifications(&spec(), &modifications)
+                                          .expect_err("modifications should be invalid");
+
+    assert_eq!(errors,
+               vec![GraphModificationError::InputChannelOutOfRange { component:  NodeId::Bus(2),
+                                                                     input:      2,
+                                                                     num_inputs: 2, },
+                    GraphModificationError::ComponentNotFound { component: NodeId::Bus(7) },
+                    GraphModificationError::OutputChannelOutOfRange { component:   NodeId::Bus(2),
+                                                                      input:       0,
+                                                                      output:      OutputId::Source(1, 2),
+                                                                      num_outputs: 2, },
+                    GraphModificationError::InputSourceNotFound { component: NodeId::Bus(2),
+                                                                  input:     1,
+                                                                  output:    OutputId::Source(9, 0), },]);
+  }
+
+  #[test]
+  fn test_loops_and_sinks() {
+    let modifications = vec![AudioGraphModification::AddOrReplaceBus { bus_id:   3,
+                                                                       bus_spec: BusSpec { inputs:      vec![vec![OutputId::Bus(2, 0)]],
+                                                                                           num_outputs: 1,
+                                                                                           mode:        Default::default(), }, },
+                             AudioGraphModification::Connect { component:     NodeId::Bus(2),
+                                                               input_channel: 0,
+                                                               output:        OutputId::Bus(3, 0), },];
+
+    let errors = GraphValidator::default().validate_modifications(&spec(), &modifications)
+                                          .expect_err("modifications should create a loop");
+
+    assert_eq!(errors.len(), 1);
+    assert!(matches!(errors[0], GraphModificationError::LoopDetected { .. }));
+
+    let sinks = HashMap::from([(4, SinkSpec { inputs:      vec![vec![], vec![]],
+                                              sample_rate: 48_000,
+                                              codec:       Default::default(), })]);
+    assert_eq!(GraphValidator::default().validate_sinks(&spec(), &sinks),
+               Err(vec![GraphModificationError::SinkWithoutInputs { sink_id: 4 }]));
+
+    let recordings = HashMap::from([(5, RecordingSpec { inputs:      vec![vec![OutputId::Bus(2, 0)], vec![OutputId::Bus(2, 5)]],
+                                                        app_id:      "test".to_owned(),
+                                                        sample_rate: 48_000,
+                                                        format:      Default::default(), })]);
+    assert_eq!(GraphValidator::default().validate_recordings(&spec(), &recordings),
+               Err(vec![GraphModificationError::OutputChannelOutOfRange { component:   NodeId::RecordingSink(5),
+                                                                          input:       1,
+                                                                          output:      OutputId::Bus(2, 5),
+                                                                          num_outputs: 2, }]));
+  }
+
+  #[test]
+  fn test_empty_source_clip() {
+    let mut spec = spec();
+    let source = spec.sources.get_mut(&1).unwrap();
+    source.media_in = 1_000;
+    source.media_out = Some(1_000);
+
+    assert_eq!(GraphValidator::default().validate(&spec),
+               Err(vec![GraphModificationError::EmptySourceClip { source_id: 1 }]));
+  }
+}
@@ -5,11 +5,12 @@ use schemars::{schema_for, JsonSchema};
 use schemars_zod::merge_schemas;
 use serde::{Deserialize, Serialize};
 
-use graph::{modify::AudioGraphModification, AudioGraphSpec};
+use graph::modify::{AudioGraphModification, GraphModificationError};
+use graph::AudioGraphSpec;
 use player::{GraphPlayerEvent, PlayId};
 
 use crate::instance::driver::events::InstanceDriverEvent;
-use crate::task::graph::{SinkId, SinkSpec};
+use crate::task::graph::{RecordingSpec, SinkId, SinkSpec};
 use crate::task::player::PlayerParameterCommand;
 use crate::task::spec::TaskSpec;
 use crate::Timestamp;
@@ -50,6 +51,7 @@ pub type SetTaskGraphRequest = AudioGraphSpec;
 pub enum SetTaskGraphResponse {
   Success,
   NotFound,
+  InvalidGraph { errors: Vec<GraphModificationError> },
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
@@ -75,6 +77,7 @@ pub enum ModifyTaskGraphResponse {
   Success,
   NotFound,
   Failure,
+  InvalidGraph { errors: Vec<GraphModificationError> },
 }
 
 pub type SetTaskInstancesRequest = HashMap<String, InstanceAllocationRequest>;
@@ -154,6 +157,12 @@ pub struct PlayRequest {
   pub start_from: u64,
   pub looping:    bool,
   pub sinks:      HashMap<SinkId, SinkSpec>,
+  /// Sinks recording to new media, registered when the play stops
+  #[serde(default)]
+  pub recordings: HashMap<SinkId, RecordingSpec>,
+  /// See [player::PlayRegion::crossfade]
+  #[serde(default = "player::default_crossfade")]
+  pub crossfade:  u32,
 }
 
 impl Default for DesiredTaskPlayState {
@@ -201,8 +210,9 @@ pub mod buckets {
 }
 
 pub mod subjects {
+  use crate::task::player::GraphPlayerEvent;
   use crate::task::{GetTaskListRequest, GetTaskListResponse, SetTaskGraphRequest, SetTaskGraphResponse};
-  use crate::Request;
+  use crate::{Events, Request};
 
   pub fn get_task_list_req() -> Request<GetTaskListRequest, GetTaskListResponse> {
     Request::new("audiocloud_get_task_list")
@@ -210,6 +220,11 @@ pub mod subjects {
   pub fn set_task_graph_req() -> Request<SetTaskGraphRequest, SetTaskGraphResponse> {
     Request::new("audiocloud_set_task_graph")
   }
+
+  /// Everything the player of a task reports, including the encoded output of its streaming sinks
+  pub fn task_player_events(task_id: impl AsRef<str>) -> Events<GraphPlayerEvent> {
+    Events::new(format!("audiocloud_task.{}.player.events", task_id.as_ref()))
+  }
 }
 
 pub fn schema() -> RootSchema {
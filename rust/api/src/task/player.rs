@@ -8,16 +8,27 @@ use thiserror::Error;
 use crate::instance::model::{ParameterModel, ReportModel};
 use crate::instance::spec::SetParameterCommand;
 use crate::task::graph::modify::AudioGraphModification;
-use crate::task::graph::{NodeId, SinkId, SinkSpec};
+use crate::media::spec::MediaSpec;
+use crate::task::graph::{NodeId, RecordingSpec, SinkId, SinkSpec};
 
 pub type PlayId = u64;
 
+/// Crossfade length used when a play request does not specify one, in samples
+pub const DEFAULT_CROSSFADE: u32 = 256;
+
+pub fn default_crossfade() -> u32 {
+  DEFAULT_CROSSFADE
+}
+
 #[derive(Debug, PartialEq, Serialize, Deserialize, JsonSchema, Clone, Copy, Default)]
 #[serde(rename_all = "camelCase")]
 pub struct PlayRegion {
-  pub start:   u64,
-  pub end:     u64,
-  pub looping: bool,
+  pub start:     u64,
+  pub end:       u64,
+  pub looping:   bool,
+  /// Length of the equal-power crossfade at loop wraps, seeks and graph changes, in samples. Zero cuts without fading.
+  #[serde(default = "default_crossfade")]
+  pub crossfade: u32,
 }
 
 #[derive(Debug, Serialize, Deserialize, JsonSchema, Clone)]
@@ -58,10 +69,14 @@ pub enum GraphPlaybackError {
   /// The graph is already playing by another player. E_NO_EXCLUSIVE_ACCESS
   #[error("graph is already playing by player {play_id}")]
   GraphAlreadyPlaying { play_id: PlayId },
+
+  /// A recording could not be written or finalized, the media was not registered
+  #[error("recording sink {sink} failed: {error}")]
+  RecordingFailed { sink: SinkId, error: String },
 }
 
 /// State of the graph playback
-#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone)]
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone, PartialEq)]
 #[serde(rename_all = "camelCase", tag = "type", content = "position")]
 pub enum GraphPlaybackState {
   /// The graph is not playing, it is buffering from the inputs
@@ -92,6 +107,9 @@ pub enum GraphPlayerEvent {
     play_id: PlayId,
     nodes:   HashMap<NodeId, NodeInfo>,
   },
+  GraphLatencyChanged {
+    latency: usize,
+  },
   GraphSinkCaptured {
     play_id:   PlayId,
     play_head: PlayHead,
@@ -103,6 +121,116 @@ pub enum GraphPlayerEvent {
     node_id: NodeId,
     events:  Vec<NodeEvent>,
   },
+  /// A play reached the end of its region, with the loudness of everything the measuring nodes processed during the play
+  GraphLoudnessSummary {
+    play_id:   PlayId,
+    summaries: Vec<(NodeId, LoudnessSummary)>,
+  },
+  /// Nodes did not finish processing a device cycle before its deadline and were silenced for that cycle
+  GraphXRun {
+    play_id:    PlayId,
+    play_head:  PlayHead,
+    /// The nodes that were late in this cycle
+    late_nodes: Vec<NodeId>,
+    stats:      XRunStats,
+  },
+  /// Time the nodes spent processing, reported about once per second
+  GraphProcessingStats {
+    play_id: PlayId,
+    stats:   ProcessingStats,
+  },
+  /// A recording sink finished writing its media after the play stopped
+  GraphRecordingFinished {
+    play_id:    PlayId,
+    sink_id:    SinkId,
+    media:      MediaSpec,
+    /// Recorded length in samples
+    num_frames: u64,
+  },
+  /// Devices running on clocks of their own, bridged to the device clocking the graph, reported about once per second
+  GraphClockDomains {
+    play_id:      PlayId,
+    clock_device: String,
+    domains:      Vec<ClockDomainStats>,
+  },
+}
+
+/// EBU R128 loudness summary of a completed play
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct LoudnessSummary {
+  /// Integrated loudness in LUFS
+  pub integrated:     f64,
+  /// Loudness range in LU
+  pub range:          f64,
+  /// Maximum true peak over all channels in dBTP
+  pub max_true_peak:  f64,
+  /// Maximum short-term loudness in LUFS
+  pub max_short_term: f64,
+}
+
+/// Deadline misses counted since the play started
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct XRunStats {
+  /// Device cycles processed
+  pub cycles:        u64,
+  /// Device cycles in which at least one node missed the deadline
+  pub missed_cycles: u64,
+  /// Missed deadlines per node
+  pub nodes:         Vec<(NodeId, u64)>,
+}
+
+impl XRunStats {
+  pub fn add_missed(&mut self, late_nodes: &[NodeId]) {
+    self.missed_cycles += 1;
+
+    for node_id in late_nodes {
+      match self.nodes.iter_mut().find(|(id, _)| id == node_id) {
+        | Some((_, count)) => *count += 1,
+        | None => self.nodes.push((*node_id, 1)),
+      }
+    }
+  }
+}
+
+/// A device bridged to the clock of the graph by asynchronous sample rate conversion
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ClockDomainStats {
+  pub device_id: String,
+  /// Latency the bridge adds to a round trip through the device, in samples at the graph sample rate
+  pub latency:   usize,
+  /// How much faster the device clock runs than the graph clock, in parts per million, as corrected by the bridge
+  pub drift_ppm: f64,
+  /// Times the bridge ran out of samples in either direction and started over
+  pub underruns: u64,
+  /// Times the bridge dropped samples because the other side stalled
+  pub overruns:  u64,
+}
+
+/// Wall clock time the nodes spent processing device cycles during a reporting period
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ProcessingStats {
+  /// Device cycles processed during the period
+  pub cycles:    u64,
+  /// Average duration of a device cycle, the time all nodes share to process it, in microseconds
+  pub budget_us: f64,
+  pub nodes:     Vec<(NodeId, NodeProcessingStats)>,
+}
+
+/// Time a node spent processing device cycles during a reporting period
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct NodeProcessingStats {
+  pub min_us:   f64,
+  pub avg_us:   f64,
+  pub max_us:   f64,
+  /// Average processing time, in percent of the cycle budget
+  pub avg_load: f64,
+  /// Longest processing time, in percent of the cycle budget
+  pub max_load: f64,
 }
 
 // Information about a playhead
@@ -182,6 +310,8 @@ pub enum PlayerControlCommand {
   Play {
     play_id:    PlayId,
     sinks:      HashMap<SinkId, SinkSpec>,
+    #[serde(default)]
+    recordings: HashMap<SinkId, RecordingSpec>,
     region:     PlayRegion,
     start_from: u64,
   },
@@ -198,9 +328,44 @@ pub enum PlayerControlCommand {
   },
 }
 
+/// Parameter changes for a node in the graph
+///
+/// `changes` apply at the start of the next buffer the node processes. `automation`, if present, replaces all scheduled
+/// changes of the node, which are applied at their exact sample every time the play head passes their position.
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct PlayerParameterCommand {
-  pub node:    NodeId,
-  pub changes: Vec<SetParameterCommand>,
+  pub node:       NodeId,
+  pub changes:    Vec<SetParameterCommand>,
+  #[serde(default)]
+  pub automation: Option<Vec<ParameterEvent>>,
+}
+
+/// A parameter change scheduled at a play position
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ParameterEvent {
+  /// Play position the change starts at
+  pub position: u64,
+  pub change:   SetParameterCommand,
+  #[serde(default)]
+  pub ramp:     ParameterRamp,
+}
+
+/// How a node moves from the current value of a parameter to a new one
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase", tag = "type")]
+pub enum ParameterRamp {
+  /// Jump to the new value
+  Step,
+  /// Move by equal steps over `length` samples
+  Linear { length: u64 },
+  /// Move by equal ratios over `length` samples, which is linear in decibels for gains
+  Exponential { length: u64 },
+}
+
+impl Default for ParameterRamp {
+  fn default() -> Self {
+    Self::Step
+  }
 }
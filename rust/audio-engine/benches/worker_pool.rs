@@ -0,0 +1,235 @@
+//! Cycle latency of running a graph of nodes on the worker pool, against spawning a tokio task per node that locks the
+//! node for processing, as the player did before the worker pool.
+//!
+//! `cycle_latency` measures the scheduling alone, `player_cycle` renders a chain of gain inserts through the player with
+//! the offline renderer, which includes writing the WAV file.
+//!
+//! Measured in a single core x86_64 Linux container, without realtime priority for the workers (`cargo bench --bench
+//! worker_pool`), times per cycle of 16 nodes at 32 samples and 192 kHz, a budget of 167µs:
+//!
+//! | benchmark                           | time     |
+//! |-------------------------------------|----------|
+//! | cycle_latency/worker_pool/16        | 221.3 µs |
+//! | cycle_latency/tokio_spawn_rwlock/16 | 207.4 µs |
+//! | player_cycle/offline_gain_chain/16  | 268.1 µs |
+//!
+//! With a single core the one worker can not poll for jobs and every node waits for a thread wake up, so the pool is no
+//! faster than tokio there. The difference needs spare cores, measure on the target machine before relying on it.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::bail;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use tokio::runtime::Runtime;
+use tokio::sync::{mpsc, RwLock};
+use tokio::task::block_in_place;
+
+use api::media::spec::MediaId;
+use api::task::graph::{AudioGraphSpec, NodeId, OutputId, VirtualInsertSpec};
+use api::task::player::{NodeEvent, NodeInfo, PlayHead};
+use audio_engine::buffer::{DevicesBuffers, NodeBuffers};
+use audio_engine::player::offline::{OfflineRenderRequest, OfflineRenderer};
+use audio_engine::player::worker_pool::{completion_queue, CompletionReceiver, CompletionSender, NodeJob, WorkerPool};
+use audio_engine::player::{InternalTaskEvent, MediaResolver};
+use audio_engine::virtual_inserts::GAIN_MODEL_ID;
+use audio_engine::wav::WavSampleFormat;
+use audio_engine::{BoxedNode, Node, Result};
+
+const BUFFER_SIZE: usize = 32;
+const NUM_NODES: usize = 16;
+const SAMPLE_RATE: u32 = 192_000;
+/// Number of cycles rendered per iteration of the player benchmark
+const PLAYER_CYCLES: u64 = 64;
+
+/// A node doing a little work on its buffer, so the measurement is dominated by scheduling
+struct GainNode;
+
+impl Node for GainNode {
+  fn process(&mut self, _play: PlayHead, _devices: DevicesBuffers, io: NodeBuffers, _deadline: Instant, _events: &mut Vec<NodeEvent>) -> Result {
+    for (output, input) in io.outputs().zip(io.inputs()) {
+      output.iter_mut().zip(input.iter()).for_each(|(output, input)| *output = *input * 0.5);
+    }
+
+    Ok(())
+  }
+}
+
+fn node_buffers() -> NodeBuffers {
+  NodeBuffers::allocate(&NodeInfo { num_inputs: 1,
+                                    num_outputs: 1,
+                                    ..Default::default() },
+                        BUFFER_SIZE)
+}
+
+fn play_head() -> PlayHead {
+  PlayHead { buffer_size: BUFFER_SIZE as u32,
+             sample_rate: SAMPLE_RATE,
+             ..Default::default() }
+}
+
+/// Process `nodes` in a chain, each node starting when the previous one completed
+async fn chain_on_workers(workers: &WorkerPool,
+                          nodes: &mut [Option<BoxedNode>],
+                          buffers: &NodeBuffers,
+                          tx_completed: &CompletionSender,
+                          rx_completed: &mut CompletionReceiver) {
+  for (index, slot) in nodes.iter_mut().enumerate() {
+    let job = NodeJob { node_id:      NodeId::Bus(index as u64),
+                        node:         slot.take().expect("Node not returned"),
+                        devices:      DevicesBuffers::default(),
+                        buffers:      buffers.clone(),
+                        play_head:    play_head(),
+                        deadline:     Instant::now() + Duration::from_millis(1),
+                        parameters:   vec![],
+                        events:       vec![],
+                        tx_completed: tx_completed.clone(), };
+
+    if workers.submit(job).is_err() {
+      panic!("Worker queue full");
+    }
+
+    let Some(InternalTaskEvent::Completed { node, .. }) = rx_completed.recv().await else { panic!("Workers stopped") };
+    *slot = Some(node);
+  }
+}
+
+/// Process `nodes` in a chain the way the player did before the worker pool
+async fn chain_on_tokio(nodes: &[Arc<RwLock<BoxedNode>>], buffers: &NodeBuffers, tx_completed: &mpsc::Sender<usize>, rx_completed: &mut mpsc::Receiver<usize>) {
+  for (index, node) in nodes.iter().enumerate() {
+    let node = node.clone();
+    let buffers = buffers.clone();
+    let tx_completed = tx_completed.clone();
+
+    tokio::spawn(async move {
+      let mut node = node.write().await;
+      let mut events = vec![];
+      let _ = block_in_place(|| {
+        node.process(play_head(),
+                     DevicesBuffers::default(),
+                     buffers,
+                     Instant::now() + Duration::from_millis(1),
+                     &mut events)
+      });
+
+      let _ = tx_completed.send(index).await;
+    });
+
+    rx_completed.recv().await.expect("Task stopped");
+  }
+}
+
+fn cycle_latency(c: &mut Criterion) {
+  let runtime = Runtime::new().expect("Failed to create runtime");
+  let workers = WorkerPool::shared();
+  let buffers = node_buffers();
+
+  let mut group = c.benchmark_group("cycle_latency");
+
+  group.bench_with_input(BenchmarkId::new("worker_pool", NUM_NODES), &NUM_NODES, |b, num_nodes| {
+         let mut nodes = (0..*num_nodes).map(|_| Some(Box::new(GainNode) as BoxedNode)).collect::<Vec<_>>();
+         let (tx_completed, mut rx_completed) = completion_queue();
+
+         b.iter_custom(|iters| {
+            runtime.block_on(async {
+                     let started = Instant::now();
+                     for _ in 0..iters {
+                       chain_on_workers(&workers, &mut nodes, &buffers, &tx_completed, &mut rx_completed).await;
+                     }
+                     started.elapsed()
+                   })
+          });
+       });
+
+  group.bench_with_input(BenchmarkId::new("tokio_spawn_rwlock", NUM_NODES), &NUM_NODES, |b, num_nodes| {
+         let nodes = (0..*num_nodes).map(|_| Arc::new(RwLock::new(Box::new(GainNode) as BoxedNode)))
+                                    .collect::<Vec<_>>();
+         let (tx_completed, mut rx_completed) = mpsc::channel(NUM_NODES);
+
+         b.iter_custom(|iters| {
+            runtime.block_on(async {
+                     let started = Instant::now();
+                     for _ in 0..iters {
+                       chain_on_tokio(&nodes, &buffers, &tx_completed, &mut rx_completed).await;
+                     }
+                     started.elapsed()
+                   })
+          });
+       });
+
+  group.finish();
+}
+
+struct NoMedia;
+
+impl MediaResolver for NoMedia {
+  fn resolve(&self, media_id: &MediaId) -> Result<String> {
+    bail!("No media {media_id}")
+  }
+
+  fn create(&self, media_id: &MediaId) -> Result<String> {
+    bail!("No media {media_id}")
+  }
+}
+
+/// A chain of `num_nodes` gain inserts, the first one without inputs
+fn gain_chain(num_nodes: usize) -> AudioGraphSpec {
+  let virtual_inserts = (0..num_nodes as u64).map(|insert_id| {
+                                               let inputs = match insert_id {
+                                                 | 0 => vec![],
+                                                 | _ => vec![OutputId::VirtualInsert(insert_id - 1, 0)],
+                                               };
+
+                                               (insert_id,
+                                                VirtualInsertSpec { inputs:   vec![inputs],
+                                                                    model_id: GAIN_MODEL_ID.to_owned(), })
+                                             })
+                                             .collect();
+
+  AudioGraphSpec { virtual_inserts,
+                   ..Default::default() }
+}
+
+fn render_gain_chain(num_nodes: usize, path: &PathBuf) -> OfflineRenderer {
+  let request = OfflineRenderRequest { play_id:     1,
+                                       start:       0,
+                                       end:         PLAYER_CYCLES * BUFFER_SIZE as u64,
+                                       sample_rate: SAMPLE_RATE,
+                                       buffer_size: BUFFER_SIZE as u32,
+                                       outputs:     vec![vec![OutputId::VirtualInsert(num_nodes as u64 - 1, 0)]],
+                                       sinks:       Default::default(),
+                                       format:      WavSampleFormat::Float32,
+                                       path:        path.clone(), };
+
+  OfflineRenderer::new(Box::new(NoMedia), gain_chain(num_nodes), request).expect("Failed to create renderer")
+}
+
+fn player_cycle(c: &mut Criterion) {
+  let runtime = Runtime::new().expect("Failed to create runtime");
+  let path = std::env::temp_dir().join("worker-pool-bench.wav");
+
+  let mut group = c.benchmark_group("player_cycle");
+
+  // the time of one cycle, the renderer is created outside of the measurement
+  group.bench_with_input(BenchmarkId::new("offline_gain_chain", NUM_NODES), &NUM_NODES, |b, num_nodes| {
+         b.iter_custom(|iters| {
+            runtime.block_on(async {
+                     let mut elapsed = Duration::ZERO;
+                     for _ in 0..iters {
+                       let renderer = render_gain_chain(*num_nodes, &path);
+                       let summary = renderer.render(|_| {}).await.expect("Failed to render");
+                       elapsed += summary.elapsed / summary.num_cycles as u32;
+                     }
+                     elapsed
+                   })
+          });
+       });
+
+  group.finish();
+
+  let _ = std::fs::remove_file(&path);
+}
+
+criterion_group!(benches, cycle_latency, player_cycle);
+criterion_main!(benches);
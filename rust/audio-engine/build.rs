@@ -1,8 +1,13 @@
+#[cfg(feature = "juce")]
 use std::env;
 
-use cmake;
-
 fn main() {
+  #[cfg(feature = "juce")]
+  build_juce();
+}
+
+#[cfg(feature = "juce")]
+fn build_juce() {
   let dst = cmake::Config::new(".").profile("Release").build();
   let target = env::var("TARGET").expect("Cargo build scripts always have TARGET");
   let target_os = get_os_from_triple(target.as_str()).unwrap();
@@ -46,6 +51,7 @@ fn main() {
   }
 }
 
+#[cfg(feature = "juce")]
 fn get_os_from_triple(triple: &str) -> Option<&str> {
   triple.splitn(3, "-").nth(2)
 }
@@ -1,4 +1,6 @@
+#include <map>
 #include <memory>
+#include <mutex>
 #include <juce_audio_devices/juce_audio_devices.h>
 #include <juce_audio_formats/juce_audio_formats.h>
 
@@ -40,6 +42,8 @@ struct RustAudioIoCallback : public juce::AudioIODeviceCallback {
 std::unique_ptr<juce::AudioDeviceManager> device_manager = std::make_unique<juce::AudioDeviceManager>();
 std::map<uint32_t, std::unique_ptr<juce::AudioIODevice>> audio_devices;
 std::atomic_int32_t audio_device_id{0};
+// devices are created, enumerated and deleted from different threads
+std::mutex audio_devices_mutex;
 
 extern "C" {
 int32_t create_audio_device(const char *type_name,
@@ -47,6 +51,8 @@ int32_t create_audio_device(const char *type_name,
                             const int input_channel_count, const int output_channel_count,
                             const int sample_rate,
                             const int buffer_size) {
+    std::lock_guard<std::mutex> lock(audio_devices_mutex);
+
     auto our_device_id = audio_device_id.fetch_add(1);
 
     try {
@@ -106,6 +112,7 @@ int32_t start_audio_device(int32_t device_id,
                                    const float *const *, int,
                                    float *const *, int,
                                    int), void *data) {
+    std::lock_guard<std::mutex> lock(audio_devices_mutex);
 
     if (audio_devices.count(device_id) == 0) {
         std::cerr << "start_audio_mgr: device not found: " << device_id << std::endl;
@@ -123,9 +130,13 @@ int32_t start_audio_device(int32_t device_id,
     callback->data = data;
 
     audio_devices[device_id]->start(callback.release());
+
+    return 0;
 }
 
 int32_t get_audio_device_latency(int32_t device_id) {
+    std::lock_guard<std::mutex> lock(audio_devices_mutex);
+
     if (audio_devices.count(device_id) == 0) {
         std::cerr << "get_audio_device_latency: device not found: " << device_id << std::endl;
         return -1;
@@ -135,7 +146,103 @@ int32_t get_audio_device_latency(int32_t device_id) {
     return static_cast<int32_t>(device->getInputLatencyInSamples() + device->getOutputLatencyInSamples());
 }
 
+int32_t list_audio_device_names(void (*device_name_callback)(
+        void *data,
+        const char *type_name, const char *device_name), void *data) {
+    std::lock_guard<std::mutex> lock(audio_devices_mutex);
+
+    if (!device_name_callback) {
+        std::cerr << "list_audio_device_names: callback not set" << std::endl;
+        return -1;
+    }
+
+    try {
+        for (auto dev_type: device_manager->getAvailableDeviceTypes()) {
+            dev_type->scanForDevices();
+
+            auto names = dev_type->getDeviceNames(false);
+            names.addArray(dev_type->getDeviceNames(true));
+            names.removeDuplicates(false);
+
+            for (auto &name: names) {
+                device_name_callback(data, dev_type->getTypeName().toRawUTF8(), name.toRawUTF8());
+            }
+        }
+    } catch (...) {
+        std::cerr << "list_audio_device_names: exception" << std::endl;
+        return -2;
+    }
+
+    return 0;
+}
+
+int32_t enumerate_audio_devices(void (*device_info_callback)(
+        void *data,
+        const char *type_name, const char *device_name,
+        int num_inputs, int num_outputs,
+        const double *sample_rates, int num_sample_rates,
+        const int *buffer_sizes, int num_buffer_sizes), void *data) {
+    std::lock_guard<std::mutex> lock(audio_devices_mutex);
+
+    if (!device_info_callback) {
+        std::cerr << "enumerate_audio_devices: callback not set" << std::endl;
+        return -1;
+    }
+
+    try {
+        for (auto dev_type: device_manager->getAvailableDeviceTypes()) {
+            dev_type->scanForDevices();
+
+            auto input_names = dev_type->getDeviceNames(true);
+            auto output_names = dev_type->getDeviceNames(false);
+
+            auto names = output_names;
+            names.addArray(input_names);
+            names.removeDuplicates(false);
+
+            for (auto &name: names) {
+                // devices in use are described as they are, some drivers can't open a device twice
+                juce::AudioIODevice *device = nullptr;
+                for (auto &entry: audio_devices) {
+                    auto &open_device = entry.second;
+                    if (open_device->getTypeName() == dev_type->getTypeName() && open_device->getName() == name) {
+                        device = open_device.get();
+                        break;
+                    }
+                }
+
+                std::unique_ptr<juce::AudioIODevice> created_device;
+                if (device == nullptr) {
+                    created_device.reset(dev_type->createDevice(output_names.contains(name) ? name : juce::String(),
+                                                                input_names.contains(name) ? name : juce::String()));
+                    device = created_device.get();
+                }
+
+                if (device == nullptr) {
+                    continue;
+                }
+
+                auto sample_rates = device->getAvailableSampleRates();
+                auto buffer_sizes = device->getAvailableBufferSizes();
+
+                device_info_callback(data,
+                                     dev_type->getTypeName().toRawUTF8(), name.toRawUTF8(),
+                                     device->getInputChannelNames().size(), device->getOutputChannelNames().size(),
+                                     sample_rates.begin(), sample_rates.size(),
+                                     buffer_sizes.begin(), buffer_sizes.size());
+            }
+        }
+    } catch (...) {
+        std::cerr << "enumerate_audio_devices: exception" << std::endl;
+        return -2;
+    }
+
+    return 0;
+}
+
 void stop_audio_device(int32_t device_id) {
+    std::lock_guard<std::mutex> lock(audio_devices_mutex);
+
     if (audio_devices.count(device_id) == 0) {
         std::cerr << "stop_audio_device: device not found: " << device_id << std::endl;
         return;
@@ -145,6 +252,8 @@ void stop_audio_device(int32_t device_id) {
 }
 
 void delete_audio_device(int32_t device_id) {
+    std::lock_guard<std::mutex> lock(audio_devices_mutex);
+
     if (audio_devices.count(device_id) == 0) {
         std::cerr << "shutdown_audio_device: device not found: " << device_id << std::endl;
         return;
@@ -0,0 +1,755 @@
+use std::f64::consts::PI;
+use std::sync::atomic::{fence, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use r8brain_rs::Sample;
+
+// interpolation filter taps on each side of the interpolated position
+const HALF_TAPS: usize = 16;
+const TAPS: usize = 2 * HALF_TAPS;
+// filter phases tabulated between two input frames, the ones in between are interpolated linearly
+const PHASES: usize = 128;
+// time constant of the fill level smoothing
+const SMOOTHING: f64 = 0.05;
+
+/// Options of an asynchronous sample rate converter
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AsrcOptions {
+  /// Frames held between writer and reader, at the source rate. This is the latency the converter adds.
+  pub target_latency: usize,
+  /// Frames the ring between writer and reader holds, writes that do not fit are dropped
+  pub capacity:       usize,
+  /// Largest relative deviation of the conversion ratio from the nominal ratio, 0.002 is 2000 ppm
+  pub max_deviation:  f64,
+  /// Time it takes the ratio to settle after the drift between the clocks changed
+  pub settle_time:    Duration,
+}
+
+impl Default for AsrcOptions {
+  fn default() -> Self {
+    Self { target_latency: 1024,
+           capacity:       16384,
+           max_deviation:  0.002,
+           settle_time:    Duration::from_secs(5), }
+  }
+}
+
+/// Counters and state of an asynchronous sample rate converter, as seen by the reader
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct AsrcStats {
+  /// Input frames per output frame used for the last read
+  pub ratio:     f64,
+  /// Smoothed number of frames between writer and reader, at the source rate
+  pub fill:      f64,
+  /// Number of reads that ran out of input frames, the converter starts over after each
+  pub underruns: u64,
+  /// Number of writes dropped because the ring was full
+  pub overruns:  u64,
+}
+
+/// Create an asynchronous sample rate converter between two clock domains
+///
+/// The writer runs on the source clock and the reader on the destination clock. The rates are nominal, the reader
+/// adjusts the conversion ratio to whatever drift there is between the actual clocks, holding
+/// [AsrcOptions::target_latency] frames between the two. Both ends take the time of the buffer they process, which has
+/// to come from the same time base, e.g. the deadlines of device flips.
+pub fn asrc(num_channels: usize, src_rate: u32, dst_rate: u32, options: AsrcOptions) -> (AsrcWriter, AsrcReader) {
+  assert!(num_channels > 0, "at least one channel is required");
+  assert!(src_rate > 0 && dst_rate > 0, "sample rates must be positive");
+  assert!(options.target_latency >= HALF_TAPS && options.target_latency < options.capacity,
+          "the target latency must cover the interpolation filter and fit the capacity");
+
+  let (tx, rx) = rtrb::RingBuffer::new(options.capacity * num_channels);
+  let shared = Arc::new(Shared::new(Instant::now()));
+
+  let src_rate = src_rate as f64;
+  let dst_rate = dst_rate as f64;
+  let nominal_ratio = src_rate / dst_rate;
+
+  let writer = AsrcWriter { tx,
+                            num_channels,
+                            src_rate,
+                            shared: shared.clone(),
+                            frames_written: 0,
+                            last_write: None };
+
+  // downsampling needs the filter to cut below the destination nyquist frequency
+  let cutoff = 0.9 * (1.0 / nominal_ratio).min(1.0);
+
+  let reader = AsrcReader { rx,
+                            shared,
+                            num_channels,
+                            src_rate,
+                            dst_rate,
+                            nominal_ratio,
+                            target_latency: options.target_latency as f64,
+                            table: SincTable::new(cutoff),
+                            history: (0..num_channels).map(|_| Vec::with_capacity(options.capacity)).collect(),
+                            history_start: 0,
+                            position: 0.0,
+                            running: false,
+                            last_read: None,
+                            controller: DriftController::new(src_rate, options.max_deviation, options.settle_time),
+                            steps: vec![],
+                            taps: vec![],
+                            stats: AsrcStats { ratio: nominal_ratio,
+                                               ..Default::default() } };
+
+  (writer, reader)
+}
+
+/// Source clock end of an asynchronous sample rate converter
+pub struct AsrcWriter {
+  tx:             rtrb::Producer<f64>,
+  num_channels:   usize,
+  src_rate:       f64,
+  shared:         Arc<Shared>,
+  frames_written: u64,
+  last_write:     Option<Instant>,
+}
+
+impl AsrcWriter {
+  pub fn num_channels(&self) -> usize {
+    self.num_channels
+  }
+
+  /// Write one plane per channel, all of the same length, for the buffer processed at `time`
+  ///
+  /// Buffers the source clock skipped since the last write are written as silence, as far as they fit. Returns false
+  /// and drops the frames if they do not fit the ring, which happens only when the reader stalls.
+  pub fn write<'a, S: Sample + 'a>(&mut self, planes: impl IntoIterator<Item = &'a [S]>, time: Instant) -> bool {
+    let mut planes = planes.into_iter().peekable();
+    let num_frames = planes.peek().map(|plane| plane.len()).unwrap_or_default();
+    if num_frames == 0 {
+      return true;
+    }
+
+    // without the silence, the reader would take the frames after a skipped buffer for late and fall behind
+    let num_skipped = missed_buffers(self.last_write.replace(time), time, num_frames, self.src_rate) * num_frames;
+    let num_skipped = num_skipped.min((self.tx.slots() / self.num_channels).saturating_sub(num_frames));
+
+    let Ok(mut chunk) = self.tx.write_chunk((num_skipped + num_frames) * self.num_channels) else {
+      self.shared.overruns.fetch_add(1, Ordering::Relaxed);
+      return false;
+    };
+
+    let (first, second) = chunk.as_mut_slices();
+    for index in 0..num_skipped * self.num_channels {
+      if index < first.len() {
+        first[index] = 0.0;
+      } else {
+        second[index - first.len()] = 0.0;
+      }
+    }
+
+    for (channel, plane) in planes.take(self.num_channels).enumerate() {
+      for (frame, sample) in plane.iter().enumerate() {
+        let index = (num_skipped + frame) * self.num_channels + channel;
+        if index < first.len() {
+          first[index] = sample.to_f64();
+        } else {
+          second[index - first.len()] = sample.to_f64();
+        }
+      }
+    }
+
+    chunk.commit_all();
+
+    self.frames_written += (num_skipped + num_frames) as u64;
+    self.shared.publish(self.frames_written, time);
+
+    true
+  }
+}
+
+/// Destination clock end of an asynchronous sample rate converter
+pub struct AsrcReader {
+  rx:             rtrb::Consumer<f64>,
+  shared:         Arc<Shared>,
+  num_channels:   usize,
+  src_rate:       f64,
+  dst_rate:       f64,
+  nominal_ratio:  f64,
+  target_latency: f64,
+  table:          SincTable,
+  // input frames still needed for interpolation, one plane per channel
+  history:        Vec<Vec<f64>>,
+  // absolute input frame index of the first history frame
+  history_start:  u64,
+  // absolute input position of the next output frame
+  position:       f64,
+  // false until enough frames arrived, and again after an underrun
+  running:        bool,
+  last_read:      Option<Instant>,
+  controller:     DriftController,
+  // per output frame of the current read: first history frame and filter taps
+  steps:          Vec<usize>,
+  taps:           Vec<f64>,
+  stats:          AsrcStats,
+}
+
+impl AsrcReader {
+  pub fn num_channels(&self) -> usize {
+    self.num_channels
+  }
+
+  /// Frames between writer and reader the converter holds, at the source rate
+  pub fn latency(&self) -> usize {
+    self.target_latency as usize
+  }
+
+  /// Input frames per output frame if both clocks ran exactly at their nominal rates
+  pub fn nominal_ratio(&self) -> f64 {
+    self.nominal_ratio
+  }
+
+  pub fn stats(&self) -> AsrcStats {
+    AsrcStats { overruns: self.shared.overruns.load(Ordering::Relaxed),
+                ..self.stats }
+  }
+
+  /// Fill one plane per channel, all of the same length, for the buffer processed at `time`
+  ///
+  /// Returns the number of frames converted, the rest of the planes is silent. Output is silent until the writer is
+  /// [AsrcOptions::target_latency] frames ahead, which it also has to catch up to again after an underrun.
+  pub fn read<'a, S: Sample + 'a>(&mut self, planes: impl IntoIterator<Item = &'a mut [S]>, time: Instant) -> usize {
+    let mut planes = planes.into_iter().peekable();
+    let num_frames = planes.peek().map(|plane| plane.len()).unwrap_or_default();
+
+    self.receive();
+
+    let (frames_written, written_at) = self.shared.load();
+    // frames the writer got to by `time`, as if it wrote continuously instead of one buffer at a time
+    let writer_position = frames_written as f64 + signed_seconds(time, written_at) * self.src_rate;
+
+    if !self.running && frames_written > 0 {
+      self.start(writer_position);
+    }
+
+    // reads the destination clock skipped would have consumed their frames, which are skipped too to stay on time
+    let num_skipped = missed_buffers(self.last_read.replace(time), time, num_frames, self.dst_rate) * num_frames;
+    if self.running {
+      self.position += num_skipped as f64 * self.stats.ratio;
+    }
+
+    let mut num_converted = 0;
+
+    if self.running {
+      let deviation = self.controller
+                          .update(writer_position - self.position - self.target_latency, num_frames as f64 / self.dst_rate);
+      let ratio = self.nominal_ratio * (1.0 + deviation);
+
+      self.stats.ratio = ratio;
+      self.stats.fill = self.controller.smoothed + self.target_latency;
+
+      num_converted = self.plan(num_frames, ratio);
+      if num_converted < num_frames {
+        self.running = false;
+        self.stats.underruns += 1;
+      }
+    }
+
+    for (channel, plane) in planes.take(self.num_channels).enumerate() {
+      let history = &self.history[channel];
+
+      for (frame, output) in plane[..num_converted].iter_mut().enumerate() {
+        let input = &history[self.steps[frame]..][..TAPS];
+        let taps = &self.taps[frame * TAPS..][..TAPS];
+
+        *output = S::from_f64(input.iter().zip(taps).map(|(sample, tap)| sample * tap).sum());
+      }
+
+      for output in &mut plane[num_converted..] {
+        *output = S::from_f64(0.0);
+      }
+    }
+
+    self.trim(writer_position);
+
+    num_converted
+  }
+
+  fn receive(&mut self) {
+    let num_frames = self.rx.slots() / self.num_channels;
+    let Ok(chunk) = self.rx.read_chunk(num_frames * self.num_channels) else { return };
+
+    let (first, second) = chunk.as_slices();
+    for (index, sample) in first.iter().chain(second).enumerate() {
+      self.history[index % self.num_channels].push(*sample);
+    }
+
+    chunk.commit_all();
+  }
+
+  fn history_end(&self) -> u64 {
+    self.history_start + self.history[0].len() as u64
+  }
+
+  fn start(&mut self, writer_position: f64) {
+    let position = writer_position - self.target_latency;
+    let first_needed = position.floor() - (HALF_TAPS - 1) as f64;
+
+    if first_needed >= self.history_start as f64 && position.floor() as u64 + (HALF_TAPS as u64) < self.history_end() {
+      self.position = position;
+      self.running = true;
+      self.controller.restart();
+    }
+  }
+
+  // compute where every output frame reads from, returns how many frames there is input for
+  fn plan(&mut self, num_frames: usize, ratio: f64) -> usize {
+    self.steps.clear();
+    self.taps.resize(num_frames * TAPS, 0.0);
+
+    let history_end = self.history_end();
+
+    for frame in 0..num_frames {
+      let index = self.position.floor();
+      if index as u64 + HALF_TAPS as u64 >= history_end {
+        return frame;
+      }
+
+      self.steps.push((index as u64 - self.history_start) as usize + 1 - HALF_TAPS);
+      self.table.fill(self.position - index, &mut self.taps[frame * TAPS..][..TAPS]);
+
+      self.position += ratio;
+    }
+
+    num_frames
+  }
+
+  // drop the history frames no interpolation needs anymore
+  fn trim(&mut self, writer_position: f64) {
+    let keep_from = if self.running {
+      (self.position.floor() as u64 + 1).saturating_sub(HALF_TAPS as u64)
+    } else {
+      // waiting for the writer to get ahead again, only the frames a start can use are kept. The start is relative to
+      // the writer position, which is behind the last frame written when the writer stamps its buffers ahead of time.
+      ((writer_position - self.target_latency).floor().max(0.0) as u64).saturating_sub(2 * HALF_TAPS as u64)
+    };
+
+    let num_dropped = keep_from.saturating_sub(self.history_start).min(self.history[0].len() as u64) as usize;
+    if num_dropped > 0 {
+      for plane in &mut self.history {
+        plane.drain(..num_dropped);
+      }
+
+      self.history_start += num_dropped as u64;
+    }
+  }
+}
+
+/// Buffers of `num_frames` at `rate` missing between a buffer processed at `last` and the next one at `time`
+fn missed_buffers(last: Option<Instant>, time: Instant, num_frames: usize, rate: f64) -> usize {
+  let Some(last) = last else { return 0 };
+  let num_buffers = signed_seconds(time, last) * rate / num_frames as f64;
+
+  (num_buffers.round() - 1.0).max(0.0) as usize
+}
+
+fn signed_seconds(time: Instant, since: Instant) -> f64 {
+  match time.checked_duration_since(since) {
+    | Some(elapsed) => elapsed.as_secs_f64(),
+    | None => -since.duration_since(time).as_secs_f64(),
+  }
+}
+
+/// Write position and time of the writer, published with a sequence lock so the reader never sees them torn
+struct Shared {
+  epoch:          Instant,
+  sequence:       AtomicU64,
+  frames_written: AtomicU64,
+  written_at:     AtomicU64,
+  overruns:       AtomicU64,
+}
+
+impl Shared {
+  fn new(epoch: Instant) -> Self {
+    Self { epoch,
+           sequence: AtomicU64::new(0),
+           frames_written: AtomicU64::new(0),
+           written_at: AtomicU64::new(0),
+           overruns: AtomicU64::new(0) }
+  }
+
+  fn publish(&self, frames_written: u64, time: Instant) {
+    let sequence = self.sequence.load(Ordering::Relaxed);
+    self.sequence.store(sequence + 1, Ordering::Relaxed);
+    fence(Ordering::Release);
+
+    self.frames_written.store(frames_written, Ordering::Relaxed);
+    self.written_at
+        .store(time.saturating_duration_since(self.epoch).as_nanos() as u64, Ordering::Relaxed);
+
+    self.sequence.store(sequence + 2, Ordering::Release);
+  }
+
+  fn load(&self) -> (u64, Instant) {
+    loop {
+      let before = self.sequence.load(Ordering::Acquire);
+      let frames_written = self.frames_written.load(Ordering::Relaxed);
+      let written_at = self.written_at.load(Ordering::Relaxed);
+      fence(Ordering::Acquire);
+
+      if before & 1 == 0 && self.sequence.load(Ordering::Relaxed) == before {
+        return (frames_written, self.epoch + Duration::from_nanos(written_at));
+      }
+
+      std::hint::spin_loop();
+    }
+  }
+}
+
+/// Proportional-integral control of the ratio deviation from the fill level error
+///
+/// With `R` input frames consumed per second, the fill level changes by the drift minus `R` times the deviation, so
+/// the gains place both poles of the loop at `-ωn`, a critically damped loop settling in about `6/ωn` seconds.
+struct DriftController {
+  proportional:  f64,
+  integral_gain: f64,
+  max_deviation: f64,
+  integral:      f64,
+  smoothed:      f64,
+  primed:        bool,
+}
+
+impl DriftController {
+  fn new(src_rate: f64, max_deviation: f64, settle_time: Duration) -> Self {
+    let natural_frequency = 6.0 / settle_time.as_secs_f64().max(0.01);
+
+    Self { proportional: 2.0 * natural_frequency / src_rate,
+           integral_gain: natural_frequency * natural_frequency / src_rate,
+           max_deviation,
+           integral: 0.0,
+           smoothed: 0.0,
+           primed: false }
+  }
+
+  /// Forget the fill level history, keeping the drift learned so far
+  fn restart(&mut self) {
+    self.primed = false;
+  }
+
+  /// Update with the fill level error in frames, `elapsed` seconds after the last update, and return the deviation
+  fn update(&mut self, error: f64, elapsed: f64) -> f64 {
+    if self.primed {
+      self.smoothed += (elapsed / SMOOTHING).min(1.0) * (error - self.smoothed);
+    } else {
+      self.smoothed = error;
+      self.primed = true;
+    }
+
+    // the integral alone never asks for more than the largest deviation, so it unwinds quickly
+    let max_integral = self.max_deviation / self.integral_gain;
+    self.integral = (self.integral + self.smoothed * elapsed).clamp(-max_integral, max_integral);
+
+    (self.proportional * self.smoothed + self.integral_gain * self.integral).clamp(-self.max_deviation, self.max_deviation)
+  }
+}
+
+/// Windowed sinc filter tabulated for [PHASES] fractional positions between two input frames
+struct SincTable {
+  coefficients: Vec<f64>,
+}
+
+impl SincTable {
+  /// `cutoff` is relative to the source nyquist frequency
+  fn new(cutoff: f64) -> Self {
+    let mut coefficients = Vec::with_capacity((PHASES + 1) * TAPS);
+
+    for phase in 0..=PHASES {
+      let fraction = phase as f64 / PHASES as f64;
+      let start = coefficients.len();
+
+      for tap in 0..TAPS {
+        // distance of the tap from the interpolated position, in input frames
+        let distance = tap as f64 - (HALF_TAPS - 1) as f64 - fraction;
+        coefficients.push(cutoff * sinc(cutoff * distance) * blackman(distance / HALF_TAPS as f64));
+      }
+
+      // unity gain at DC for every phase
+      let sum = coefficients[start..].iter().sum::<f64>();
+      coefficients[start..].iter_mut().for_each(|coefficient| *coefficient /= sum);
+    }
+
+    Self { coefficients }
+  }
+
+  /// Filter taps for a position `fraction` of a frame past the input frame at tap `HALF_TAPS - 1`
+  fn fill(&self, fraction: f64, taps: &mut [f64]) {
+    let scaled = fraction * PHASES as f64;
+    let phase = (scaled as usize).min(PHASES - 1);
+    let weight = scaled - phase as f64;
+
+    let before = &self.coefficients[phase * TAPS..][..TAPS];
+    let after = &self.coefficients[(phase + 1) * TAPS..][..TAPS];
+
+    for ((tap, before), after) in taps.iter_mut().zip(before).zip(after) {
+      *tap = before + (after - before) * weight;
+    }
+  }
+}
+
+fn sinc(x: f64) -> f64 {
+  if x.abs() < 1e-12 {
+    1.0
+  } else {
+    (PI * x).sin() / (PI * x)
+  }
+}
+
+// `x` from -1 to 1
+fn blackman(x: f64) -> f64 {
+  0.42 + 0.5 * (PI * x).cos() + 0.08 * (2.0 * PI * x).cos()
+}
+
+#[cfg(test)]
+mod test {
+  use std::f64::consts::TAU;
+
+  use tokio::select;
+  use tokio::sync::mpsc;
+
+  use crate::audio_device::simulator_device::new_simulator_device;
+  use crate::audio_device::{AudioDeviceInfo, AudioDevices, DeviceClientCommand, DeviceCommand};
+
+  use super::*;
+
+  const BUFFER_SIZE: usize = 64;
+
+  #[test]
+  fn test_interpolation_is_transparent() {
+    let table = SincTable::new(0.9);
+    let mut taps = vec![0.0; TAPS];
+
+    // a slow sine interpolated anywhere between two frames matches the sine at that position
+    let input = (0..TAPS + 1).map(|frame| (frame as f64 * 0.05 * TAU).sin()).collect::<Vec<_>>();
+
+    for step in 0..10 {
+      let fraction = step as f64 / 10.0;
+      table.fill(fraction, &mut taps);
+
+      let interpolated = input.iter().zip(&taps).map(|(sample, tap)| sample * tap).sum::<f64>();
+      let expected = (((HALF_TAPS - 1) as f64 + fraction) * 0.05 * TAU).sin();
+
+      assert!((interpolated - expected).abs() < 1e-3, "{interpolated} != {expected} at {fraction}");
+    }
+  }
+
+  #[test]
+  fn test_controller_converges() {
+    let src_rate = 48_000.0;
+    let mut controller = DriftController::new(src_rate, 0.002, Duration::from_secs(5));
+
+    // the writer is 200 ppm faster, the fill level grows until the deviation catches up with the drift
+    let drift = 200e-6;
+    let elapsed = BUFFER_SIZE as f64 / src_rate;
+    let mut error = 0.0;
+    let mut deviation = 0.0;
+
+    for _ in 0..(20.0 / elapsed) as usize {
+      deviation = controller.update(error, elapsed);
+      error += (drift - deviation) * src_rate * elapsed;
+    }
+
+    assert!((deviation - drift).abs() < 1e-6, "deviation {deviation}");
+    assert!(error.abs() < 0.5, "error {error}");
+  }
+
+  #[test]
+  fn test_holds_latency_between_drifting_clocks() {
+    let options = AsrcOptions::default();
+    let (mut writer, mut reader) = asrc(1, 48_000, 48_000, options);
+
+    // the reader clock is 300 ppm faster than the writer clock
+    let writer_period = BUFFER_SIZE as f64 / 48_000.0;
+    let reader_period = BUFFER_SIZE as f64 / (48_000.0 * 1.0003);
+
+    let epoch = Instant::now();
+    let mut writer_time = 0.0;
+    let mut reader_time = 0.0;
+    let mut phase = 0.0;
+    let mut input = vec![0.0f32; BUFFER_SIZE];
+    let mut output = vec![0.0f32; BUFFER_SIZE];
+    let mut peak = 0.0f32;
+
+    while reader_time < 30.0 {
+      if writer_time <= reader_time {
+        for sample in &mut input {
+          *sample = (phase * TAU).sin() as f32 * 0.5;
+          phase = (phase + 1_000.0 / 48_000.0) % 1.0;
+        }
+
+        writer_time += writer_period;
+        writer.write([&input[..]], epoch + Duration::from_secs_f64(writer_time));
+      } else {
+        reader_time += reader_period;
+        reader.read([&mut output[..]], epoch + Duration::from_secs_f64(reader_time));
+
+        if reader_time > 20.0 {
+          peak = output.iter().fold(peak, |peak, sample| peak.max(sample.abs()));
+        }
+      }
+    }
+
+    let stats = reader.stats();
+
+    assert_eq!(stats.underruns, 0);
+    assert_eq!(stats.overruns, 0);
+    assert!((stats.fill - options.target_latency as f64).abs() < 2.0, "fill {}", stats.fill);
+    assert!((stats.ratio - 1.0 / 1.0003).abs() < 10e-6, "ratio {}", stats.ratio);
+    assert!((peak - 0.5).abs() < 0.01, "peak {peak}");
+  }
+
+  #[test]
+  fn test_restart_after_underrun() {
+    let options = AsrcOptions::default();
+    let (mut writer, mut reader) = asrc(1, 48_000, 48_000, options);
+
+    // the writer stamps its buffers with a deadline 4 ms after it writes them, and stalls for half a second
+    let period = BUFFER_SIZE as f64 / 48_000.0;
+    let stamp = 0.004;
+
+    let epoch = Instant::now();
+    let mut writer_time = 0.0;
+    let mut reader_time = 0.0;
+    let mut phase = 0.0;
+    let mut input = vec![0.0f32; BUFFER_SIZE];
+    let mut output = vec![0.0f32; BUFFER_SIZE];
+    let mut peak = 0.0f32;
+
+    while reader_time < 5.0 {
+      if writer_time <= reader_time {
+        for sample in &mut input {
+          *sample = (phase * TAU).sin() as f32 * 0.5;
+          phase = (phase + 1_000.0 / 48_000.0) % 1.0;
+        }
+
+        if !(1.0..1.5).contains(&writer_time) {
+          writer.write([&input[..]], epoch + Duration::from_secs_f64(writer_time + stamp));
+        }
+        writer_time += period;
+      } else {
+        reader_time += period;
+        reader.read([&mut output[..]], epoch + Duration::from_secs_f64(reader_time));
+
+        if reader_time > 4.0 {
+          peak = output.iter().fold(peak, |peak, sample| peak.max(sample.abs()));
+        }
+      }
+    }
+
+    assert_eq!(reader.stats().underruns, 1);
+    assert!((peak - 0.5).abs() < 0.01, "peak {peak}");
+  }
+
+  #[test]
+  fn test_skipped_buffers_keep_latency() {
+    let options = AsrcOptions::default();
+    let (mut writer, mut reader) = asrc(1, 48_000, 48_000, options);
+
+    // the writer skips a buffer after one second and the reader after two, both clocks keep running
+    let period = BUFFER_SIZE as f64 / 48_000.0;
+    let skipped = |time: f64, at: f64| (at..at + period).contains(&time);
+
+    let epoch = Instant::now();
+    let mut writer_time = 0.0;
+    let mut reader_time = 0.0;
+    let input = vec![0.5f32; BUFFER_SIZE];
+    let mut output = vec![0.0f32; BUFFER_SIZE];
+
+    while reader_time < 3.0 {
+      if writer_time <= reader_time {
+        if !skipped(writer_time, 1.0) {
+          writer.write([&input[..]], epoch + Duration::from_secs_f64(writer_time));
+        }
+        writer_time += period;
+      } else {
+        if !skipped(reader_time, 2.0) {
+          reader.read([&mut output[..]], epoch + Duration::from_secs_f64(reader_time));
+        }
+        reader_time += period;
+      }
+    }
+
+    let stats = reader.stats();
+
+    assert_eq!(stats.underruns, 0);
+    assert!((stats.fill - options.target_latency as f64).abs() < 2.0, "fill {}", stats.fill);
+    assert!((stats.ratio - 1.0).abs() < 10e-6, "ratio {}", stats.ratio);
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn test_simulator_devices_with_drifting_clocks() {
+    // both devices are nominally 48k, the clock of the second one runs 208 ppm fast
+    let info = |sample_rate| AudioDeviceInfo { latency: 0,
+                                               buffer_size: BUFFER_SIZE as u32,
+                                               sample_rate,
+                                               num_inputs: 2,
+                                               num_outputs: 2 };
+
+    let mut devices = AudioDevices::default();
+    devices.add_device("a".to_string(), new_simulator_device("a".to_string(), info(48_000)));
+    devices.add_device("b".to_string(), new_simulator_device("b".to_string(), info(48_010)));
+
+    let (tx_a, mut rx_a) = mpsc::channel(0x100);
+    let (tx_b, mut rx_b) = mpsc::channel(0x100);
+
+    for (device_id, tx_client) in [("a", tx_a), ("b", tx_b)] {
+      devices.send_command(device_id, DeviceCommand::Register { client_id: "asrc".to_string(),
+                                                                tx_client })
+             .expect("Failed to register");
+    }
+
+    let options = AsrcOptions::default();
+    let (mut writer, mut reader) = asrc(2, 48_000, 48_000, options);
+
+    let mut left = vec![0.0f32; BUFFER_SIZE];
+    let mut right = vec![0.0f32; BUFFER_SIZE];
+    let mut phase = 0.0;
+    let mut underruns_after_start = None;
+
+    loop {
+      let (device_id, command) = select! {
+        Some(command) = rx_a.recv() => ("a", command),
+        Some(command) = rx_b.recv() => ("b", command),
+      };
+
+      let DeviceClientCommand::Flip { buffers, generation, deadline, .. } = command else { continue };
+
+      if device_id == "a" {
+        for (left, right) in left.iter_mut().zip(right.iter_mut()) {
+          *left = (phase * TAU).sin() as f32 * 0.5;
+          *right = -*left;
+          phase = (phase + 1_000.0 / 48_000.0) % 1.0;
+        }
+
+        writer.write([&left[..], &right[..]], deadline);
+      } else {
+        reader.read((0..2).map(|plane| buffers.output_plane(plane)), deadline);
+
+        // 2 seconds to start and settle, then 10 more that must run clean
+        if generation == 2 * 48_010 / BUFFER_SIZE as u64 {
+          underruns_after_start = Some(reader.stats().underruns);
+        } else if generation == 12 * 48_010 / BUFFER_SIZE as u64 {
+          break;
+        }
+      }
+
+      devices.send_command(device_id, DeviceCommand::FlipFinished { client_id: "asrc".to_string(),
+                                                                    generation })
+             .expect("Failed to finish flip");
+    }
+
+    devices.terminate_device("a");
+    devices.terminate_device("b");
+
+    let stats = reader.stats();
+
+    assert_eq!(underruns_after_start, Some(stats.underruns));
+    assert_eq!(stats.overruns, 0);
+    assert!((stats.fill - options.target_latency as f64).abs() < 2.0, "fill {}", stats.fill);
+    assert!((stats.ratio - 48_000.0 / 48_010.0).abs() < 10e-6, "ratio {}", stats.ratio);
+  }
+}
@@ -0,0 +1,184 @@
+use api::audio_device::{AudioDeviceDescription, AudioDeviceEvent};
+
+use crate::Result;
+
+/// Lists the audio devices a host has available
+///
+/// Enumerating can take a while, drivers may have to open each device to find out what it supports, so it is best done
+/// off the async runtime.
+pub trait AudioDeviceEnumerator: Send {
+  /// The devices the drivers know of, without opening them to describe them
+  fn list_names(&mut self) -> Result<Vec<AudioDeviceName>>;
+
+  fn enumerate(&mut self) -> Result<Vec<AudioDeviceDescription>>;
+}
+
+/// Identifies a device without describing it, see [AudioDeviceEnumerator::list_names]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct AudioDeviceName {
+  pub device_type: String,
+  pub name:        String,
+}
+
+pub type BoxedAudioDeviceEnumerator = Box<dyn AudioDeviceEnumerator>;
+
+/// Enumerator for hosts without audio device drivers, which never has any devices
+#[derive(Default)]
+pub struct NoAudioDevices;
+
+impl AudioDeviceEnumerator for NoAudioDevices {
+  fn list_names(&mut self) -> Result<Vec<AudioDeviceName>> {
+    Ok(vec![])
+  }
+
+  fn enumerate(&mut self) -> Result<Vec<AudioDeviceDescription>> {
+    Ok(vec![])
+  }
+}
+
+/// Enumerator backed by the drivers the engine was built with
+#[cfg(feature = "juce")]
+pub fn default_enumerator() -> BoxedAudioDeviceEnumerator {
+  Box::new(super::juce_device::JuceAudioDeviceEnumerator)
+}
+
+/// Enumerator backed by the drivers the engine was built with
+#[cfg(not(feature = "juce"))]
+pub fn default_enumerator() -> BoxedAudioDeviceEnumerator {
+  Box::new(NoAudioDevices)
+}
+
+/// Tracks the devices an enumerator lists to tell when they are plugged in or out
+///
+/// Describing the devices is expensive, so they are only enumerated again when the names of the devices change.
+pub struct AudioDeviceWatcher {
+  enumerator: BoxedAudioDeviceEnumerator,
+  names:      Option<Vec<AudioDeviceName>>,
+  devices:    Vec<AudioDeviceDescription>,
+}
+
+impl AudioDeviceWatcher {
+  pub fn new(enumerator: BoxedAudioDeviceEnumerator) -> Self {
+    Self { enumerator,
+           names: None,
+           devices: vec![] }
+  }
+
+  /// Devices found by the last enumeration
+  pub fn devices(&self) -> &[AudioDeviceDescription] {
+    &self.devices
+  }
+
+  /// List the devices again and return what changed since the last poll
+  ///
+  /// A device whose capabilities changed disappears and then appears again with the new ones, which is noticed the next
+  /// time any device is plugged in or out. The first poll reports every device as appeared.
+  pub fn poll(&mut self) -> Result<Vec<AudioDeviceEvent>> {
+    let mut names = self.enumerator.list_names()?;
+    names.sort();
+
+    if self.names.as_ref() == Some(&names) {
+      return Ok(vec![]);
+    }
+
+    let devices = self.enumerator.enumerate()?;
+
+    let disappeared = self.devices
+                          .iter()
+                          .filter(|known| !devices.contains(known))
+                          .map(|device| AudioDeviceEvent::Disappeared { device: device.clone() });
+
+    let appeared = devices.iter()
+                          .filter(|device| !self.devices.contains(device))
+                          .map(|device| AudioDeviceEvent::Appeared { device: device.clone() });
+
+    let events = disappeared.chain(appeared).collect();
+
+    self.names = Some(names);
+    self.devices = devices;
+
+    Ok(events)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use std::sync::{Arc, Mutex};
+
+  use super::*;
+
+  #[derive(Clone, Default)]
+  struct FakeDevices {
+    devices:      Arc<Mutex<Vec<AudioDeviceDescription>>>,
+    enumerations: Arc<AtomicUsize>,
+  }
+
+  impl AudioDeviceEnumerator for FakeDevices {
+    fn list_names(&mut self) -> Result<Vec<AudioDeviceName>> {
+      Ok(self.devices
+             .lock()
+             .unwrap()
+             .iter()
+             .map(|device| AudioDeviceName { device_type: device.device_type.clone(),
+                                             name:        device.name.clone(), })
+             .collect())
+    }
+
+    fn enumerate(&mut self) -> Result<Vec<AudioDeviceDescription>> {
+      self.enumerations.fetch_add(1, Ordering::Relaxed);
+      Ok(self.devices.lock().unwrap().clone())
+    }
+  }
+
+  fn device(name: &str, num_inputs: usize) -> AudioDeviceDescription {
+    AudioDeviceDescription { device_type: "ALSA".to_owned(),
+                             name: name.to_owned(),
+                             num_inputs,
+                             num_outputs: 2,
+                             sample_rates: vec![44_100, 48_000],
+                             buffer_sizes: vec![64, 128, 256] }
+  }
+
+  #[test]
+  fn test_plug_and_unplug() {
+    let fake = FakeDevices::default();
+    let mut watcher = AudioDeviceWatcher::new(Box::new(fake.clone()));
+
+    *fake.devices.lock().unwrap() = vec![device("a", 2), device("b", 2)];
+    assert_eq!(watcher.poll().unwrap(),
+               vec![AudioDeviceEvent::Appeared { device: device("a", 2) },
+                    AudioDeviceEvent::Appeared { device: device("b", 2) }]);
+    assert_eq!(watcher.devices(), &[device("a", 2), device("b", 2)]);
+
+    assert_eq!(watcher.poll().unwrap(), vec![]);
+
+    // b unplugged, a reconfigured with more inputs, c plugged in
+    *fake.devices.lock().unwrap() = vec![device("a", 8), device("c", 2)];
+    assert_eq!(watcher.poll().unwrap(),
+               vec![AudioDeviceEvent::Disappeared { device: device("a", 2) },
+                    AudioDeviceEvent::Disappeared { device: device("b", 2) },
+                    AudioDeviceEvent::Appeared { device: device("a", 8) },
+                    AudioDeviceEvent::Appeared { device: device("c", 2) }]);
+  }
+
+  #[test]
+  fn test_enumerate_only_when_names_change() {
+    let fake = FakeDevices::default();
+    let mut watcher = AudioDeviceWatcher::new(Box::new(fake.clone()));
+
+    *fake.devices.lock().unwrap() = vec![device("a", 2), device("b", 2)];
+    watcher.poll().unwrap();
+    assert_eq!(fake.enumerations.load(Ordering::Relaxed), 1);
+
+    // the same devices listed in another order
+    *fake.devices.lock().unwrap() = vec![device("b", 2), device("a", 2)];
+    assert_eq!(watcher.poll().unwrap(), vec![]);
+    assert_eq!(fake.enumerations.load(Ordering::Relaxed), 1);
+
+    *fake.devices.lock().unwrap() = vec![device("b", 2)];
+    assert_eq!(watcher.poll().unwrap(),
+               vec![AudioDeviceEvent::Disappeared { device: device("a", 2) }]);
+    assert_eq!(fake.enumerations.load(Ordering::Relaxed), 2);
+  }
+}
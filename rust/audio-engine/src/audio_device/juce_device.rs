@@ -1,12 +1,17 @@
 use std::collections::{HashMap, HashSet};
-use std::ffi::{c_void, CString};
+use std::ffi::{c_char, c_void, CStr, CString};
+use std::slice::from_raw_parts;
 use std::time::{Duration, Instant};
 
 use anyhow::bail;
 use derive_more::Display;
+use tokio::spawn;
 use tokio::sync::mpsc;
 
-use crate::audio_device::{DeviceClientCommand, DeviceCommand};
+use api::audio_device::AudioDeviceDescription;
+
+use crate::audio_device::enumeration::{AudioDeviceEnumerator, AudioDeviceName};
+use crate::audio_device::{AudioDevice, AudioDeviceInfo, DeviceClientCommand, DeviceCommand};
 use crate::buffer::DeviceBuffers;
 use crate::juce;
 use crate::Result;
@@ -33,7 +38,7 @@ impl Drop for JuceAudioDevice {
 
 impl JuceAudioDevice {
   pub fn new(id: String,
-             type_name: JuceAudioDeviceType,
+             type_name: impl AsRef<str>,
              input_name: &str,
              output_name: &str,
              input_channel_count: usize,
@@ -42,7 +47,7 @@ impl JuceAudioDevice {
              buffer_size: usize)
              -> Result<(crossbeam_channel::Sender<DeviceCommand>, Box<Self>)> {
     let (tx_cmd, rx_cmd) = crossbeam_channel::bounded(0x100);
-    let type_name = type_name.to_cstring();
+    let type_name = CString::new(type_name.as_ref())?;
     let input_name = CString::new(input_name)?;
     let output_name = CString::new(output_name)?;
 
@@ -190,9 +195,15 @@ pub enum JuceAudioDeviceType {
 
 impl JuceAudioDeviceType {
   pub fn to_cstring(&self) -> CString {
+    CString::new(self.as_ref()).unwrap()
+  }
+}
+
+impl AsRef<str> for JuceAudioDeviceType {
+  fn as_ref(&self) -> &str {
     match self {
-      | Self::CoreAudio => CString::new("CoreAudio").unwrap(),
-      | Self::ASIO => CString::new("ASIO").unwrap(),
+      | Self::CoreAudio => "CoreAudio",
+      | Self::ASIO => "ASIO",
     }
   }
 }
@@ -200,3 +211,120 @@ impl JuceAudioDeviceType {
 struct Client {
   tx_cmd: mpsc::Sender<DeviceClientCommand>,
 }
+
+/// Opens a JUCE device by the name it was enumerated with, and starts it
+///
+/// The device is stopped and closed once it is terminated, or every handle to it was dropped. Closing happens in the
+/// background, so opening the same device again right away can fail.
+pub fn new_juce_device(device_id: String, type_name: &str, name: &str, info: AudioDeviceInfo) -> Result<AudioDevice> {
+  let input_name = if info.num_inputs > 0 { name } else { "" };
+  let output_name = if info.num_outputs > 0 { name } else { "" };
+
+  let (tx_juce, mut device) = JuceAudioDevice::new(device_id,
+                                                   type_name,
+                                                   input_name,
+                                                   output_name,
+                                                   info.num_inputs,
+                                                   info.num_outputs,
+                                                   info.sample_rate as usize,
+                                                   info.buffer_size as usize)?;
+
+  let juce_device_id = device.device_id;
+  let latency = unsafe { juce::get_audio_device_latency(juce_device_id) }.max(0) as u32;
+
+  device.start();
+
+  let (tx_cmd, mut rx_cmd) = mpsc::channel(0x100);
+
+  spawn(async move {
+    while let Some(cmd) = rx_cmd.recv().await {
+      // terminating from the audio callback would have the device wait for its own callback to finish
+      if matches!(cmd, DeviceCommand::Terminate) || tx_juce.send(cmd).is_err() {
+        break;
+      }
+    }
+
+    // stopping waits for the callback in progress, after which the device can be deleted
+    unsafe {
+      juce::stop_audio_device(juce_device_id);
+    }
+
+    drop(device);
+    // only now, so that waiting for the device to close also waits for JUCE to release it
+    drop(rx_cmd);
+  });
+
+  Ok(AudioDevice { tx_cmd,
+                   info: AudioDeviceInfo { latency, ..info } })
+}
+
+/// Lists the devices of every device type JUCE supports on the platform
+///
+/// Devices that are already open are described as they are, instead of being opened a second time.
+pub struct JuceAudioDeviceEnumerator;
+
+impl AudioDeviceEnumerator for JuceAudioDeviceEnumerator {
+  fn list_names(&mut self) -> Result<Vec<AudioDeviceName>> {
+    let mut names = Vec::<AudioDeviceName>::new();
+
+    let rv = unsafe { juce::list_audio_device_names(device_name_callback, &mut names as *mut _ as *mut _) };
+    if rv < 0 {
+      bail!("Failed to list audio devices, error code: {rv}");
+    }
+
+    Ok(names)
+  }
+
+  fn enumerate(&mut self) -> Result<Vec<AudioDeviceDescription>> {
+    let mut devices = Vec::<AudioDeviceDescription>::new();
+
+    let rv = unsafe { juce::enumerate_audio_devices(device_info_callback, &mut devices as *mut _ as *mut _) };
+    if rv < 0 {
+      bail!("Failed to enumerate audio devices, error code: {rv}");
+    }
+
+    Ok(devices)
+  }
+}
+
+extern "C" fn device_name_callback(data: *mut c_void, type_name: *const c_char, device_name: *const c_char) {
+  let names = unsafe { &mut *(data as *mut Vec<AudioDeviceName>) };
+
+  names.push(AudioDeviceName { device_type: unsafe { CStr::from_ptr(type_name) }.to_string_lossy().into_owned(),
+                               name:        unsafe { CStr::from_ptr(device_name) }.to_string_lossy().into_owned(), });
+}
+
+extern "C" fn device_info_callback(data: *mut c_void,
+                                   type_name: *const c_char,
+                                   device_name: *const c_char,
+                                   num_inputs: i32,
+                                   num_outputs: i32,
+                                   sample_rates: *const f64,
+                                   num_sample_rates: i32,
+                                   buffer_sizes: *const i32,
+                                   num_buffer_sizes: i32) {
+  let devices = unsafe { &mut *(data as *mut Vec<AudioDeviceDescription>) };
+
+  let sample_rates = unsafe { ffi_slice(sample_rates, num_sample_rates) }.iter()
+                                                                         .map(|rate| rate.round() as u32)
+                                                                         .collect();
+
+  let buffer_sizes = unsafe { ffi_slice(buffer_sizes, num_buffer_sizes) }.iter()
+                                                                         .map(|size| *size as u32)
+                                                                         .collect();
+
+  devices.push(AudioDeviceDescription { device_type: unsafe { CStr::from_ptr(type_name) }.to_string_lossy().into_owned(),
+                                        name: unsafe { CStr::from_ptr(device_name) }.to_string_lossy().into_owned(),
+                                        num_inputs: num_inputs.max(0) as usize,
+                                        num_outputs: num_outputs.max(0) as usize,
+                                        sample_rates,
+                                        buffer_sizes });
+}
+
+unsafe fn ffi_slice<'a, T>(ptr: *const T, len: i32) -> &'a [T] {
+  if ptr.is_null() || len <= 0 {
+    &[]
+  } else {
+    from_raw_parts(ptr, len as usize)
+  }
+}
@@ -0,0 +1,244 @@
+use std::cmp::Ordering;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail};
+use nanoid::nanoid;
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+
+use api::instance::InstanceLatencyCalibration;
+
+use crate::audio_device::{AudioDevices, DeviceClientCommand, DeviceCommand};
+use crate::events::db_to_gain_factor;
+use crate::player::DeviceInstanceAttachment;
+use crate::Result;
+
+/// How the round trip latency of a device insert is measured
+#[derive(Clone, Debug)]
+pub struct CalibrationOptions {
+  /// Order of the maximum length sequence played through the sends, the burst is `2^order - 1` samples long
+  pub mls_order:           u32,
+  /// Level of the burst written to the sends
+  pub level_db:            f64,
+  /// The longest round trip to look for
+  pub max_round_trip:      Duration,
+  /// Returns that bring the burst back quieter than this are treated as not connected
+  pub min_return_level_db: f64,
+  /// Give up if the device did not run the whole burst through by then
+  pub timeout:             Duration,
+}
+
+impl Default for CalibrationOptions {
+  fn default() -> Self {
+    Self { mls_order:           12,
+           level_db:            -12.0,
+           max_round_trip:      Duration::from_millis(250),
+           min_return_level_db: -40.0,
+           timeout:             Duration::from_secs(5), }
+  }
+}
+
+/// Measure the round trip latency of a device insert
+///
+/// A maximum length sequence burst is written to all sends at once and the returns are recorded until the longest
+/// round trip has passed. The round trip of every return is the lag at which it correlates best with the burst. When
+/// returns disagree, the longest round trip is reported, so the compensated signal never arrives before the others.
+pub async fn calibrate_device_insert(devices: &AudioDevices,
+                                     attachment: &DeviceInstanceAttachment,
+                                     options: &CalibrationOptions)
+                                     -> Result<InstanceLatencyCalibration> {
+  let device_id = &attachment.device_id;
+  let info = devices.get_info(device_id)?;
+
+  if let Some(send) = attachment.sends.iter().find(|send| **send as usize >= info.num_outputs) {
+    bail!("Send {send} is out of range for device {device_id} with {} outputs", info.num_outputs);
+  }
+
+  if let Some(ret) = attachment.returns.iter().find(|ret| **ret as usize >= info.num_inputs) {
+    bail!("Return {ret} is out of range for device {device_id} with {} inputs", info.num_inputs);
+  }
+
+  let level = db_to_gain_factor(options.level_db) as f32;
+  let burst = mls_burst(options.mls_order)?.into_iter().map(|sample| sample * level).collect::<Vec<_>>();
+  let max_round_trip = (options.max_round_trip.as_secs_f64() * info.sample_rate as f64).ceil() as usize;
+
+  let client_id = format!("latency_calibration_{}", nanoid!());
+  let (tx_client, mut rx_client) = mpsc::channel(0x10);
+
+  devices.send_command(device_id,
+                       DeviceCommand::Register { client_id: client_id.clone(),
+                                                 tx_client })?;
+
+  let recorded = timeout(options.timeout,
+                         record_returns(devices, &client_id, attachment, &burst, burst.len() + max_round_trip, &mut rx_client)).await;
+
+  let _ = devices.send_command(device_id, DeviceCommand::Unregister { client_id });
+
+  let recorded = recorded.map_err(|_| anyhow!("Device {device_id} did not play the calibration burst in time"))??;
+
+  let min_gain = db_to_gain_factor(options.min_return_level_db);
+  let returns = recorded.iter()
+                        .map(|plane| find_round_trip(&burst, plane, max_round_trip, min_gain))
+                        .collect::<Vec<_>>();
+
+  let Some(round_trip) = returns.iter().flatten().copied().max() else {
+    bail!("The calibration burst did not come back on any return of device {device_id}");
+  };
+
+  Ok(InstanceLatencyCalibration { device: device_id.clone(),
+                                  sample_rate: info.sample_rate,
+                                  round_trip,
+                                  additional_latency: round_trip.saturating_sub(info.latency as usize),
+                                  returns,
+                                  measured_at: api::time::new() })
+}
+
+/// Play the burst into the sends and record `num_samples` of every return, starting with the cycle the burst starts in
+async fn record_returns(devices: &AudioDevices,
+                        client_id: &str,
+                        attachment: &DeviceInstanceAttachment,
+                        burst: &[f32],
+                        num_samples: usize,
+                        rx_client: &mut mpsc::Receiver<DeviceClientCommand>)
+                        -> Result<Vec<Vec<f32>>> {
+  let mut recorded = vec![Vec::with_capacity(num_samples); attachment.returns.len()];
+  let mut position = 0;
+
+  while position < num_samples {
+    let Some(cmd) = rx_client.recv().await else { bail!("Device {} stopped during calibration", attachment.device_id) };
+    let DeviceClientCommand::Flip { buffers, generation, .. } = cmd else { continue };
+
+    let burst = burst.get(position..).unwrap_or_default();
+    for send in &attachment.sends {
+      let plane = buffers.output_plane(*send as usize);
+      plane.fill(0.0);
+      plane.iter_mut().zip(burst).for_each(|(sample, burst)| *sample = *burst);
+    }
+
+    for (recorded, ret) in recorded.iter_mut().zip(&attachment.returns) {
+      recorded.extend_from_slice(buffers.input_plane(*ret as usize));
+    }
+
+    position += buffers.buffer_size;
+
+    devices.send_command(&attachment.device_id,
+                         DeviceCommand::FlipFinished { client_id: client_id.to_owned(),
+                                                       generation })?;
+  }
+
+  Ok(recorded)
+}
+
+/// A maximum length sequence of `2^order - 1` samples, each either 1 or -1
+pub fn mls_burst(order: u32) -> Result<Vec<f32>> {
+  // feedback taps of maximal length linear feedback shift registers
+  let taps: &[u32] = match order {
+    | 8 => &[8, 6, 5, 4],
+    | 9 => &[9, 5],
+    | 10 => &[10, 7],
+    | 11 => &[11, 9],
+    | 12 => &[12, 6, 4, 1],
+    | 13 => &[13, 4, 3, 1],
+    | 14 => &[14, 5, 3, 1],
+    | 15 => &[15, 14],
+    | 16 => &[16, 15, 13, 4],
+    | _ => bail!("MLS order {order} is not supported, it must be between 8 and 16"),
+  };
+
+  let mask = taps.iter().fold(0u32, |mask, tap| mask | 1 << (order - tap));
+  let mut state = 1u32;
+
+  Ok((0..(1usize << order) - 1).map(|_| {
+                                  let feedback = (state & mask).count_ones() & 1;
+                                  let output = state & 1;
+                                  state = (state >> 1) | (feedback << (order - 1));
+
+                                  if output == 1 {
+                                    1.0
+                                  } else {
+                                    -1.0
+                                  }
+                                })
+                                .collect())
+}
+
+/// The lag, up to `max_lag` samples, at which `burst` correlates best with `recorded`
+///
+/// Returns `None` if the burst did not come back with at least `min_gain`. The polarity of the return does not matter.
+pub fn find_round_trip(burst: &[f32], recorded: &[f32], max_lag: usize, min_gain: f64) -> Option<usize> {
+  let energy = burst.iter().map(|sample| (*sample as f64).powi(2)).sum::<f64>();
+  if energy == 0.0 {
+    return None;
+  }
+
+  let (lag, gain) = (0..=max_lag).take_while(|lag| lag + burst.len() <= recorded.len())
+                                 .map(|lag| {
+                                   let correlation = burst.iter()
+                                                          .zip(&recorded[lag..])
+                                                          .map(|(burst, recorded)| *burst as f64 * *recorded as f64)
+                                                          .sum::<f64>();
+
+                                   (lag, correlation / energy)
+                                 })
+                                 .max_by(|(_, a), (_, b)| a.abs().partial_cmp(&b.abs()).unwrap_or(Ordering::Equal))?;
+
+  (gain.abs() >= min_gain).then_some(lag)
+}
+
+#[cfg(test)]
+mod test {
+  use crate::audio_device::simulator_device::{new_loopback_simulator_device, SimulatorLoopback, SimulatorOptions};
+  use crate::audio_device::AudioDeviceInfo;
+
+  use super::*;
+
+  #[test]
+  fn test_find_round_trip() {
+    let burst = mls_burst(10).expect("Failed to create burst");
+    let mut recorded = vec![0.0; 2_000];
+    for (index, sample) in burst.iter().enumerate() {
+      recorded[index + 123] = -0.5 * sample;
+    }
+
+    assert_eq!(find_round_trip(&burst, &recorded, 500, 0.01), Some(123));
+    assert_eq!(find_round_trip(&burst, &vec![0.0; 2_000], 500, 0.01), None);
+  }
+
+  #[tokio::test]
+  async fn test_calibrate_loopback() {
+    let info = AudioDeviceInfo { latency:     64,
+                                 buffer_size: 64,
+                                 sample_rate: 48_000,
+                                 num_inputs:  3,
+                                 num_outputs: 1, };
+
+    let options = SimulatorOptions { loopbacks: vec![SimulatorLoopback { output: 0,
+                                                                          input:  1,
+                                                                          delay:  37,
+                                                                          gain:   0.5, }],
+                                     ..Default::default() };
+
+    let (simulator, _) = new_loopback_simulator_device("test".to_string(), info, options).expect("Failed to create simulator");
+
+    let mut devices = AudioDevices::default();
+    devices.add_device("test".to_string(), simulator);
+
+    let attachment = DeviceInstanceAttachment { device_id:          "test".to_string(),
+                                                sends:              vec![0],
+                                                returns:            vec![1, 2],
+                                                additional_latency: 0, };
+
+    let options = CalibrationOptions { mls_order: 10,
+                                       max_round_trip: Duration::from_millis(10),
+                                       ..Default::default() };
+
+    let calibration = calibrate_device_insert(&devices, &attachment, &options).await
+                                                                             .expect("Failed to calibrate");
+
+    devices.terminate_device("test");
+
+    assert_eq!(calibration.round_trip, 64 + 37);
+    assert_eq!(calibration.additional_latency, 37);
+    assert_eq!(calibration.returns, vec![Some(64 + 37), None]);
+  }
+}
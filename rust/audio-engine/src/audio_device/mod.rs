@@ -5,12 +5,18 @@ use anyhow::anyhow;
 use derive_more::Display;
 use tokio::sync::mpsc;
 
+use api::audio_device::{AudioDeviceDriverSpec, AudioDeviceSpec};
+
 use crate::buffer::DeviceBuffers;
 
 use super::Result;
 
+pub mod asrc;
 pub mod audio_device_insert_node;
+pub mod enumeration;
+#[cfg(feature = "juce")]
 pub mod juce_device;
+pub mod latency_calibration;
 pub mod simulator_device;
 
 /// Command sent to the device
@@ -76,7 +82,9 @@ pub enum DeviceClientCommand {
 
 #[derive(Clone, Debug, Default)]
 pub struct AudioDevices {
-  devices: HashMap<String, AudioDevice>,
+  devices:      HashMap<String, AudioDevice>,
+  /// Device whose clock drives the graphs, the others are bridged to it
+  clock_device: Option<String>,
 }
 
 #[derive(Clone, Debug)]
@@ -113,13 +121,114 @@ impl AudioDevices {
            .info)
   }
 
+  /// Add a device, the first device added becomes the clock device
   pub fn add_device(&mut self, device_id: String, device: AudioDevice) {
+    self.clock_device.get_or_insert_with(|| device_id.clone());
     self.devices.insert(device_id, device);
   }
 
-  pub fn terminate_device(&mut self, device_id: &str) {
-    if let Some(device) = self.devices.remove(device_id) {
+  /// Remove a device and tell it to close, returning it so the caller can wait for [AudioDevice::closed]
+  pub fn terminate_device(&mut self, device_id: &str) -> Option<AudioDevice> {
+    let device = self.devices.remove(device_id);
+    if let Some(device) = device.as_ref() {
       let _ = device.tx_cmd.try_send(DeviceCommand::Terminate);
     }
+
+    // the lowest remaining device id takes over the clock
+    if self.clock_device.as_deref() == Some(device_id) {
+      self.clock_device = self.devices.keys().min().cloned();
+    }
+
+    device
+  }
+
+  /// The device clocking the graphs, all other devices a graph uses run in clock domains of their own
+  pub fn clock_device(&self) -> Option<&str> {
+    self.clock_device.as_deref()
+  }
+  /// True if the device is missing from both, or open in both as the same device with the same info
+  pub fn is_same_device(&self, other: &AudioDevices, device_id: &str) -> bool {
+    match (self.devices.get(device_id), other.devices.get(device_id)) {
+      | (Some(device), Some(other)) => device.info == other.info && device.tx_cmd.same_channel(&other.tx_cmd),
+      | (None, None) => true,
+      | _ => false,
+    }
+  }
+
+
+  pub fn set_clock_device(&mut self, device_id: &str) -> Result {
+    if !self.devices.contains_key(device_id) {
+      return Err(anyhow!("Device {device_id} not found"));
+    }
+
+    self.clock_device = Some(device_id.to_owned());
+
+    Ok(())
+  }
+}
+
+impl AudioDevice {
+  /// Resolves once the device stopped taking commands, which it does after it was terminated and closed
+  pub async fn closed(&self) {
+    self.tx_cmd.closed().await
+  }
+}
+
+/// Open a device as specified, JUCE devices need the engine built with the `juce` feature
+pub fn open_device(device_id: String, spec: &AudioDeviceSpec) -> Result<AudioDevice> {
+  // JUCE reports the actual latency once the device is open
+  let info = AudioDeviceInfo { latency:     0,
+                               buffer_size: spec.buffer_size,
+                               sample_rate: spec.sample_rate,
+                               num_inputs:  spec.num_inputs,
+                               num_outputs: spec.num_outputs, };
+
+  match &spec.driver {
+    #[cfg(feature = "juce")]
+    | AudioDeviceDriverSpec::Juce(juce) => juce_device::new_juce_device(device_id, &juce.device_type, &juce.name, info),
+    #[cfg(not(feature = "juce"))]
+    | AudioDeviceDriverSpec::Juce(_) => Err(anyhow!("Device {device_id} needs JUCE, which the engine was built without")),
+    | AudioDeviceDriverSpec::Simulator => Ok(simulator_device::new_simulator_device(device_id, info)),
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use api::audio_device::JuceAudioDeviceSpec;
+
+  use super::*;
+
+  #[tokio::test]
+  async fn test_open_device_from_spec() {
+    let spec = AudioDeviceSpec { driver:      AudioDeviceDriverSpec::Simulator,
+                                 sample_rate: 48_000,
+                                 buffer_size: 128,
+                                 num_inputs:  2,
+                                 num_outputs: 8, };
+
+    let mut devices = AudioDevices::default();
+    devices.add_device("b".to_owned(), open_device("b".to_owned(), &spec).expect("open b"));
+    devices.add_device("a".to_owned(), open_device("a".to_owned(), &spec).expect("open a"));
+
+    assert_eq!(devices.get_info("a").unwrap(),
+               AudioDeviceInfo { latency:     0,
+                                 buffer_size: 128,
+                                 sample_rate: 48_000,
+                                 num_inputs:  2,
+                                 num_outputs: 8, });
+
+    // the first device added clocks the graphs until it goes away
+    assert_eq!(devices.clock_device(), Some("b"));
+    devices.terminate_device("b");
+    assert_eq!(devices.clock_device(), Some("a"));
+    devices.terminate_device("a");
+    assert_eq!(devices.clock_device(), None);
+
+    let juce = AudioDeviceSpec { driver: AudioDeviceDriverSpec::Juce(JuceAudioDeviceSpec { device_type: "ALSA".to_owned(),
+                                                                                            name:        "none".to_owned(), }),
+                                 ..spec };
+
+    // there is no such device, and without the juce feature there are no JUCE devices at all
+    assert!(open_device("juce".to_owned(), &juce).is_err());
   }
 }
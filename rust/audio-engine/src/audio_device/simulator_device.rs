@@ -1,22 +1,204 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::PathBuf;
 use std::time::{Duration, Instant};
 
+use anyhow::bail;
 use tokio::sync::mpsc;
 use tokio::{select, spawn};
 
 use crate::audio_device::{AudioDevice, AudioDeviceInfo, DeviceClientCommand, DeviceCommand};
 use crate::buffer::DeviceBuffers;
+use crate::wav::WavReader;
+use crate::Result;
 
+/// Creates a simulated device with silent inputs, which discards its outputs
 pub fn new_simulator_device(device_id: String, info: AudioDeviceInfo) -> AudioDevice {
+  spawn_simulator_device(device_id, info, 0.0, SimulatorIo::default())
+}
+
+/// Creates a simulated device that feeds its inputs from its outputs or a WAV file, optionally capturing the outputs
+///
+/// A signal looped back arrives at the input one buffer plus [SimulatorLoopback::delay] frames after it was written to
+/// the output, which is the round trip latency to report in [AudioDeviceInfo::latency] when testing latency compensation.
+pub fn new_loopback_simulator_device(device_id: String,
+                                     info: AudioDeviceInfo,
+                                     options: SimulatorOptions)
+                                     -> Result<(AudioDevice, SimulatorCapture)> {
+  let clock_ppm = options.clock_ppm;
+  let io = SimulatorIo::new(&info, options)?;
+  let capture = io.capture.clone();
+
+  Ok((spawn_simulator_device(device_id, info, clock_ppm, io), capture))
+}
+
+/// How a simulated device fills its inputs and what it does with its outputs
+#[derive(Clone, Debug, Default)]
+pub struct SimulatorOptions {
+  /// Outputs routed back to inputs, mixed on top of the input file
+  pub loopbacks:       Vec<SimulatorLoopback>,
+  /// WAV file played from the start into the inputs, channel by channel, followed by silence
+  pub input_file:      Option<PathBuf>,
+  /// Keep all output samples for [SimulatorCapture::take]
+  pub capture_outputs: bool,
+  /// Deviation of the device clock from its nominal sample rate, positive values make the device run fast
+  pub clock_ppm:       f64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SimulatorLoopback {
+  pub output: usize,
+  pub input:  usize,
+  /// Frames of delay on top of the one buffer every loopback takes
+  pub delay:  usize,
+  pub gain:   f64,
+}
+
+/// Output samples captured by a simulated device, one plane per output
+#[derive(Clone, Debug)]
+pub struct SimulatorCapture {
+  tx_cycles: crossbeam_channel::Sender<Vec<Vec<f32>>>,
+  rx_cycles: crossbeam_channel::Receiver<Vec<Vec<f32>>>,
+}
+
+impl Default for SimulatorCapture {
+  fn default() -> Self {
+    let (tx_cycles, rx_cycles) = crossbeam_channel::unbounded();
+    Self { tx_cycles, rx_cycles }
+  }
+}
+
+impl SimulatorCapture {
+  /// Everything captured since the last call, starting with the first cycle clients could write to
+  pub fn take(&self) -> Vec<Vec<f32>> {
+    let mut planes: Vec<Vec<f32>> = vec![];
+
+    for cycle in self.rx_cycles.try_iter() {
+      planes.resize_with(cycle.len(), Vec::new);
+      for (plane, captured) in planes.iter_mut().zip(cycle) {
+        plane.extend(captured);
+      }
+    }
+
+    planes
+  }
+}
+
+#[derive(Default)]
+struct SimulatorIo {
+  // every delay line holds the loopback delay worth of samples between cycles
+  loopbacks:       Vec<(SimulatorLoopback, VecDeque<f32>)>,
+  input_file:      Option<WavReader<BufReader<File>>>,
+  file_position:   u64,
+  file_buffers:    Vec<Vec<f64>>,
+  capture_outputs: bool,
+  capture:         SimulatorCapture,
+}
+
+impl SimulatorIo {
+  fn new(info: &AudioDeviceInfo, options: SimulatorOptions) -> Result<Self> {
+    for loopback in &options.loopbacks {
+      if loopback.output >= info.num_outputs || loopback.input >= info.num_inputs {
+        bail!("Loopback from output {} to input {} is out of range for a device with {} outputs and {} inputs",
+              loopback.output,
+              loopback.input,
+              info.num_outputs,
+              info.num_inputs);
+      }
+    }
+
+    let input_file = options.input_file.map(WavReader::open).transpose()?;
+    if let Some(reader) = &input_file {
+      if reader.sample_rate() != info.sample_rate {
+        bail!("Input file sample rate {} does not match the device sample rate {}",
+              reader.sample_rate(),
+              info.sample_rate);
+      }
+    }
+
+    let file_channels = input_file.as_ref()
+                                  .map(|reader| reader.num_channels().min(info.num_inputs))
+                                  .unwrap_or_default();
+
+    let loopbacks = options.loopbacks
+                           .into_iter()
+                           .map(|loopback| (loopback, VecDeque::from(vec![0.0; loopback.delay])))
+                           .collect();
+
+    Ok(Self { loopbacks,
+              input_file,
+              file_position: 0,
+              file_buffers: vec![vec![0.0; info.buffer_size as usize]; file_channels],
+              capture_outputs: options.capture_outputs,
+              capture: Default::default() })
+  }
+
+  /// Collect the outputs clients wrote during the last cycle and prepare the inputs of the next one
+  ///
+  /// Called when the deadline of the last cycle passed and before the next flip, when the inputs may be written.
+  fn cycle(&mut self, buffers: &DeviceBuffers) {
+    if self.capture_outputs && buffers.generation > 0 {
+      let planes = (0..buffers.num_outputs).map(|plane| buffers.output_plane(plane).to_vec()).collect();
+      let _ = self.capture.tx_cycles.send(planes);
+    }
+
+    for (loopback, delay_line) in &mut self.loopbacks {
+      delay_line.extend(buffers.output_plane(loopback.output).iter().copied());
+    }
+
+    for plane in 0..buffers.num_inputs {
+      unsafe { buffers.input_plane_mut(plane) }.fill(0.0);
+    }
+
+    self.read_input_file(buffers);
+
+    for (loopback, delay_line) in &mut self.loopbacks {
+      let gain = loopback.gain as f32;
+      let input = unsafe { buffers.input_plane_mut(loopback.input) };
+      for (sample, delayed) in input.iter_mut().zip(delay_line.drain(..buffers.buffer_size)) {
+        *sample += delayed * gain;
+      }
+    }
+
+    buffers.zero_outputs();
+  }
+
+  fn read_input_file(&mut self, buffers: &DeviceBuffers) {
+    let Some(reader) = &mut self.input_file else { return };
+
+    match reader.read_planar(self.file_position, &mut self.file_buffers, buffers.buffer_size) {
+      | Ok(num_read) if num_read > 0 => {
+        for (plane, read) in self.file_buffers.iter().enumerate() {
+          let input = unsafe { buffers.input_plane_mut(plane) };
+          for (sample, read) in input.iter_mut().zip(&read[..num_read]) {
+            *sample = *read as f32;
+          }
+        }
+
+        self.file_position += num_read as u64;
+      }
+      // the file is finished or can no longer be read, from here on the inputs are silent
+      | _ => self.input_file = None,
+    }
+  }
+}
+
+fn spawn_simulator_device(device_id: String, info: AudioDeviceInfo, clock_ppm: f64, io: SimulatorIo) -> AudioDevice {
   let (tx_cmd, rx_cmd) = mpsc::channel(0x100);
-  let cycle_time = Duration::from_secs_f64(info.buffer_size as f64 / info.sample_rate as f64);
+  let sample_rate = info.sample_rate as f64 * (1.0 + clock_ppm * 1e-6);
+  let cycle_time = Duration::from_secs_f64(info.buffer_size as f64 / sample_rate);
 
-  spawn(run_simulator_device(device_id, cycle_time, info, rx_cmd));
+  spawn(run_simulator_device(device_id, cycle_time, info, io, rx_cmd));
 
   AudioDevice { tx_cmd, info }
 }
 
-async fn run_simulator_device(device_id: String, cycle_time: Duration, info: AudioDeviceInfo, mut rx_cmd: mpsc::Receiver<DeviceCommand>) {
+async fn run_simulator_device(device_id: String,
+                              cycle_time: Duration,
+                              info: AudioDeviceInfo,
+                              mut io: SimulatorIo,
+                              mut rx_cmd: mpsc::Receiver<DeviceCommand>) {
   let mut clients: HashMap<String, mpsc::Sender<DeviceClientCommand>> = HashMap::new();
   let mut new_clients = HashMap::new();
   let mut waiting_for = HashMap::new();
@@ -39,7 +221,7 @@ async fn run_simulator_device(device_id: String, cycle_time: Duration, info: Aud
             if waiting_for.get(&client_id) == Some(&generation) {
               waiting_for.remove(&client_id);
             } else {
-              // spurious flip finished
+              // spurious or late flip finished
             }
           },
           DeviceCommand::Terminate => {
@@ -54,12 +236,13 @@ async fn run_simulator_device(device_id: String, cycle_time: Duration, info: Aud
       _ = tokio::time::sleep_until(tokio::time::Instant::from_std(next_time)) => {
         next_time = next_time + cycle_time;
 
+        io.cycle(&dev_buffers);
+
         // buffer flick page time
         dev_buffers.generation += 1;
 
-        for (id, _) in waiting_for.drain() {
-          clients.remove(&id);
-        }
+        // clients that missed the deadline keep being flipped, they are expected to catch up by skipping work
+        waiting_for.clear();
 
         clients.extend(new_clients.drain());
 
@@ -96,6 +279,63 @@ mod test {
 
     let _ = tokio::time::sleep(Duration::from_secs(1));
 
+    let simulator = devices.terminate_device("test").expect("device was added");
+    tokio::time::timeout(Duration::from_secs(1), simulator.closed()).await
+                                                                    .expect("device closed");
+  }
+
+  #[tokio::test]
+  async fn test_loopback_and_capture() {
+    let info = AudioDeviceInfo { latency:     512 + 10,
+                                 buffer_size: 512,
+                                 sample_rate: 48_000,
+                                 num_inputs:  2,
+                                 num_outputs: 1, };
+
+    let options = SimulatorOptions { loopbacks:       vec![SimulatorLoopback { output: 0,
+                                                                                input:  1,
+                                                                                delay:  10,
+                                                                                gain:   0.5, }],
+                                     input_file:      None,
+                                     capture_outputs: true,
+                                     clock_ppm:       0.0, };
+
+    let (simulator, capture) = new_loopback_simulator_device("test".to_string(), info, options).expect("Failed to create simulator");
+
+    let mut devices = AudioDevices::default();
+    devices.add_device("test".to_string(), simulator);
+
+    let (tx_client, mut rx_client) = mpsc::channel(0x10);
+    devices.send_command("test", DeviceCommand::Register { client_id: "client".to_string(),
+                                                           tx_client })
+           .expect("Failed to register");
+
+    let mut returned = vec![];
+    while let Some(DeviceClientCommand::Flip { buffers, generation, .. }) = rx_client.recv().await {
+      if generation == 1 {
+        buffers.output_plane(0)[3] = 1.0;
+      }
+
+      returned.extend_from_slice(buffers.input_plane(1));
+
+      devices.send_command("test", DeviceCommand::FlipFinished { client_id: "client".to_string(),
+                                                                 generation })
+             .expect("Failed to finish flip");
+
+      if generation == 4 {
+        break;
+      }
+    }
+
     devices.terminate_device("test");
+
+    // one buffer of round trip plus the loopback delay
+    assert_eq!(returned[3 + 512 + 10], 0.5);
+    assert_eq!(returned.iter().filter(|sample| **sample != 0.0).count(), 1);
+
+    let captured = capture.take();
+    assert_eq!(captured.len(), 1);
+    assert_eq!(captured[0][3], 1.0);
+    assert_eq!(captured[0].iter().filter(|sample| **sample != 0.0).count(), 1);
   }
 }
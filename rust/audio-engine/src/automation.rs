@@ -0,0 +1,239 @@
+use std::collections::VecDeque;
+
+use api::instance::spec::SetParameterCommand;
+use api::task::player::{ParameterEvent, ParameterRamp, PlayHead};
+
+/// A parameter change starting within a buffer
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScheduledParameter {
+  /// Sample offset within the buffer
+  pub offset: usize,
+  pub change: SetParameterCommand,
+  pub ramp:   ParameterRamp,
+}
+
+/// Parameter events of a node, sorted by play position
+#[derive(Clone, Debug, Default)]
+pub struct AutomationLanes {
+  events: Vec<ParameterEvent>,
+}
+
+impl From<Vec<ParameterEvent>> for AutomationLanes {
+  fn from(mut events: Vec<ParameterEvent>) -> Self {
+    events.sort_by_key(|event| event.position);
+
+    Self { events }
+  }
+}
+
+impl AutomationLanes {
+  /// Append the events in the buffer starting at the play head to `scheduled`, with their offsets within the buffer
+  ///
+  /// A buffer running past the end of a looping region continues at the start of the region, like the play head does.
+  /// Nothing is allocated while `scheduled` has room for the events in the buffer.
+  pub fn events_in(&self, play: PlayHead, scheduled: &mut Vec<ScheduledParameter>) {
+    let region = play.play_region;
+    let end = play.position + play.buffer_size as u64;
+
+    // the second segment is empty unless the buffer wraps around
+    let segments = if region.looping && play.position < region.end && end > region.end {
+      [(play.position, region.end, 0), (region.start, region.start + (end - region.end), region.end - play.position)]
+    } else {
+      [(play.position, end, 0), (end, end, 0)]
+    };
+
+    for (start, end, offset) in segments {
+      let first = self.events.partition_point(|event| event.position < start);
+      let last = self.events.partition_point(|event| event.position < end);
+
+      scheduled.extend(self.events[first..last].iter().map(|event| {
+                                                          ScheduledParameter { offset: (event.position - start + offset) as usize,
+                                                                               change: event.change.clone(),
+                                                                               ramp:   event.ramp, }
+                                                        }));
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Ramp {
+  from:        f64,
+  to:          f64,
+  position:    u64,
+  length:      u64,
+  exponential: bool,
+}
+
+impl Ramp {
+  fn value(&self) -> f64 {
+    let x = self.position as f64 / self.length as f64;
+
+    if self.exponential {
+      self.from * (self.to / self.from).powf(x)
+    } else {
+      self.from + (self.to - self.from) * x
+    }
+  }
+}
+
+/// A parameter value that follows scheduled steps and ramps sample by sample
+#[derive(Clone, Debug)]
+pub struct AutomatedValue {
+  value:   f64,
+  ramp:    Option<Ramp>,
+  pending: VecDeque<(usize, f64, ParameterRamp)>,
+}
+
+impl AutomatedValue {
+  pub fn new(value: f64) -> Self {
+    Self { value,
+           ramp: None,
+           pending: VecDeque::new() }
+  }
+
+  /// Jump to `value` right away, dropping anything scheduled
+  pub fn set(&mut self, value: f64) {
+    self.value = value;
+    self.ramp = None;
+    self.pending.clear();
+  }
+
+  /// Move to `value` starting at `offset` in the next buffer
+  ///
+  /// Exponential ramps need both ends to be positive and fall back to linear otherwise.
+  pub fn schedule(&mut self, offset: usize, value: f64, ramp: ParameterRamp) {
+    let index = self.pending.partition_point(|(pending_offset, _, _)| *pending_offset <= offset);
+    self.pending.insert(index, (offset, value, ramp));
+  }
+
+  /// The value at the start of the next buffer
+  pub fn value(&self) -> f64 {
+    self.value
+  }
+
+  /// True if the value does not change during the next buffer
+  pub fn is_steady(&self) -> bool {
+    self.ramp.is_none() && self.pending.is_empty()
+  }
+
+  /// Write the value of every sample of the next buffer to `values`
+  pub fn fill(&mut self, values: &mut [f64]) {
+    for (index, value) in values.iter_mut().enumerate() {
+      while let Some((offset, target, ramp)) = self.pending.front().copied() {
+        if offset > index {
+          break;
+        }
+
+        self.pending.pop_front();
+        self.start(target, ramp);
+      }
+
+      *value = self.value;
+      self.advance();
+    }
+
+    // changes scheduled past the end of this buffer carry over to the next one
+    for (offset, _, _) in &mut self.pending {
+      *offset = offset.saturating_sub(values.len());
+    }
+  }
+
+  fn start(&mut self, to: f64, ramp: ParameterRamp) {
+    let from = self.value;
+
+    self.ramp = match ramp {
+      | ParameterRamp::Step | ParameterRamp::Linear { length: 0 } | ParameterRamp::Exponential { length: 0 } => None,
+      | ParameterRamp::Linear { length } => Some(Ramp { from,
+                                                        to,
+                                                        position: 0,
+                                                        length,
+                                                        exponential: false }),
+      | ParameterRamp::Exponential { length } => Some(Ramp { from,
+                                                             to,
+                                                             position: 0,
+                                                             length,
+                                                             exponential: from > 0.0 && to > 0.0 }),
+    };
+
+    if self.ramp.is_none() {
+      self.value = to;
+    }
+  }
+
+  fn advance(&mut self) {
+    let Some(ramp) = &mut self.ramp else { return };
+
+    ramp.position += 1;
+
+    if ramp.position >= ramp.length {
+      self.value = ramp.to;
+      self.ramp = None;
+    } else {
+      self.value = ramp.value();
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use api::task::player::PlayRegion;
+
+  use super::*;
+
+  fn event(position: u64, parameter: &str, value: f64) -> ParameterEvent {
+    ParameterEvent { position,
+                     change: SetParameterCommand { parameter: parameter.to_owned(),
+                                                   channel: 0,
+                                                   value },
+                     ramp: ParameterRamp::Step }
+  }
+
+  #[test]
+  fn test_events_in_looping_buffer() {
+    let lanes = AutomationLanes::from(vec![event(2, "gain", -6.0), event(98, "gain", 0.0), event(50, "gain", -3.0)]);
+
+    let play = PlayHead { buffer_size: 8,
+                          position: 96,
+                          play_region: PlayRegion { start: 0,
+                                                    end: 100,
+                                                    looping: true,
+                                                    ..Default::default() },
+                          ..Default::default() };
+
+    let mut scheduled = vec![];
+    lanes.events_in(play, &mut scheduled);
+
+    let offsets = scheduled.iter()
+                           .map(|scheduled| (scheduled.offset, scheduled.change.value))
+                           .collect::<Vec<_>>();
+
+    assert_eq!(offsets, vec![(2, 0.0), (6, -6.0)]);
+  }
+
+  #[test]
+  fn test_ramps() {
+    let mut value = AutomatedValue::new(1.0);
+    value.schedule(2, 0.0, ParameterRamp::Linear { length: 4 });
+
+    let mut values = [0.0; 8];
+    value.fill(&mut values);
+
+    assert_eq!(values, [1.0, 1.0, 1.0, 0.75, 0.5, 0.25, 0.0, 0.0]);
+    assert!(value.is_steady());
+
+    value.set(1.0);
+    value.schedule(0, 0.01, ParameterRamp::Exponential { length: 2 });
+    value.schedule(6, 0.5, ParameterRamp::Step);
+
+    let mut values = [0.0; 4];
+    value.fill(&mut values);
+
+    assert!((values[1] - 0.1).abs() < 1e-12);
+    assert!((values[2] - 0.01).abs() < 1e-12);
+    assert!(!value.is_steady());
+
+    // the step lands in the next buffer
+    value.fill(&mut values);
+    assert_eq!(values, [0.01, 0.01, 0.5, 0.5]);
+  }
+}
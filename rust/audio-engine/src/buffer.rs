@@ -60,13 +60,32 @@ unsafe impl Send for DeviceBuffers {}
 
 unsafe impl Sync for DeviceBuffers {}
 
+/// Buffers of the devices that flipped in the current cycle, shared by all nodes processing it
+///
+/// Devices of earlier cycles keep their entry without buffers, so their ids are only allocated once.
 #[derive(Default, Clone, Debug)]
-pub struct DevicesBuffers(pub(crate) HashMap<String, DeviceBuffers>);
+pub struct DevicesBuffers(pub(crate) Arc<HashMap<String, Option<DeviceBuffers>>>);
 
 impl DevicesBuffers {
   pub fn device(&self, name: &str) -> Result<DeviceBuffers> {
-    let rv = self.0.get(name).ok_or_else(|| anyhow!("No device named {name}"))?;
-    Ok(*rv)
+    let rv = self.0.get(name).copied().flatten().ok_or_else(|| anyhow!("No device named {name}"))?;
+    Ok(rv)
+  }
+
+  /// Set the buffers of a device, the devices are copied only if a node still holds on to them
+  pub(crate) fn set(&mut self, name: &str, buffers: DeviceBuffers) {
+    let devices = Arc::make_mut(&mut self.0);
+    match devices.get_mut(name) {
+      | Some(slot) => *slot = Some(buffers),
+      | None => {
+        devices.insert(name.to_owned(), Some(buffers));
+      }
+    }
+  }
+
+  /// Forget the buffers of all devices, keeping their ids
+  pub(crate) fn clear(&mut self) {
+    Arc::make_mut(&mut self.0).values_mut().for_each(|buffers| *buffers = None);
   }
 }
 
@@ -81,6 +100,24 @@ impl DeviceBuffers {
     unsafe { from_raw_parts_mut(*self.outputs.add(plane), self.buffer_size) }
   }
 
+  /// Write access to an input plane, for devices that produce their inputs themselves
+  ///
+  /// # Safety
+  ///
+  /// The buffers must come from [DeviceBuffers::allocate_and_forget], hardware devices own their input buffers. No other
+  /// slice of the plane may be in use while the returned one is. Clients read the inputs only until the deadline of the
+  /// cycle they were flipped for, so the device may write them once that deadline passed and before the next flip.
+  pub(crate) unsafe fn input_plane_mut(&self, plane: usize) -> &mut [f32] {
+    assert!(plane < self.num_inputs);
+    from_raw_parts_mut(*self.inputs.add(plane) as *mut f32, self.buffer_size)
+  }
+
+  pub fn zero_outputs(&self) {
+    for plane in 0..self.num_outputs {
+      self.output_plane(plane).iter_mut().for_each(|sample| *sample = 0.0);
+    }
+  }
+
   pub fn allocate_and_forget(num_inputs: usize, num_outputs: usize, buffer_size: usize) -> Self {
     let mut inputs = vec![vec![0.0; buffer_size as usize]; num_inputs];
     let mut outputs = vec![vec![0.0; buffer_size as usize]; num_outputs];
@@ -95,8 +132,11 @@ impl DeviceBuffers {
                              num_inputs,
                              num_outputs };
 
+    // the plane pointer arrays are referenced by the buffers too, not just the planes
     mem::forget(inputs);
     mem::forget(outputs);
+    mem::forget(input_ptrs);
+    mem::forget(output_ptrs);
 
     rv
   }
@@ -1,12 +1,13 @@
+use std::f64::consts::FRAC_PI_4;
 use std::time::Instant;
 
 use anyhow::bail;
 
 use api::instance::spec::SetParameterCommand;
-use api::task::graph::BusId;
+use api::task::graph::{BusId, BusMode};
 use api::task::player::PlayHead;
 
-use crate::buffer::{fill_slice, DevicesBuffers, NodeBuffers};
+use crate::buffer::{add_slice, fill_slice, zero_slice, DevicesBuffers, NodeBuffers};
 use crate::{Node, NodeEvent, NodeInfo, Result};
 
 mod parameters {
@@ -20,6 +21,10 @@ mod parameters {
   pub const INPUT_LEVEL: &'static str = "inputLevel";
   pub const OUTPUT_LEVEL: &'static str = "outputLevel";
   pub const MID_SIDE_MODE: &'static str = "midSideMode";
+  pub const INPUT_PAN: &'static str = "inputPan";
+  pub const INPUT_MUTE: &'static str = "inputMute";
+  pub const INPUT_SOLO: &'static str = "inputSolo";
+  pub const ROUTING: &'static str = "routing";
 
   fn mid_side_mode() -> ParameterModel {
     ParameterModel { range: ValueRange::List { values: vec![0.0, 1.0, 2.0], },
@@ -38,6 +43,33 @@ mod parameters {
                      ..Default::default() }
   }
 
+  fn pan(num_channels: usize) -> ParameterModel {
+    ParameterModel { range: ValueRange::Bounded { min:  -1.0,
+                                                  max:  1.0,
+                                                  step: None, },
+                     channels: num_channels,
+                     ..Default::default() }
+  }
+
+  fn toggle(num_channels: usize) -> ParameterModel {
+    ParameterModel { range: ValueRange::Toggle,
+                     channels: num_channels,
+                     ..Default::default() }
+  }
+
+  /// Channel `input * num_outputs + output` is the gain from the input to the output
+  fn routing(num_inputs: usize, num_outputs: usize) -> ParameterModel {
+    ParameterModel { range: ValueRange::Bounded { min:  0.0,
+                                                  max:  1.0,
+                                                  step: None, },
+                     channels: num_inputs * num_outputs,
+                     metadata: hashmap! {
+                       "numInputs".to_owned() => json!(num_inputs),
+                       "numOutputs".to_owned() => json!(num_outputs),
+                     },
+                     ..Default::default() }
+  }
+
   pub fn create(num_inputs: usize, num_outputs: usize) -> HashMap<String, ParameterModel> {
     hashmap! {
       MID_SIDE_MODE.to_owned() => mid_side_mode(),
@@ -45,6 +77,17 @@ mod parameters {
       OUTPUT_LEVEL.to_owned() => io_level(num_outputs),
     }
   }
+
+  pub fn create_matrix(num_inputs: usize, num_outputs: usize) -> HashMap<String, ParameterModel> {
+    hashmap! {
+      INPUT_LEVEL.to_owned() => io_level(num_inputs),
+      OUTPUT_LEVEL.to_owned() => io_level(num_outputs),
+      INPUT_PAN.to_owned() => pan(num_inputs),
+      INPUT_MUTE.to_owned() => toggle(num_inputs),
+      INPUT_SOLO.to_owned() => toggle(num_inputs),
+      ROUTING.to_owned() => routing(num_inputs, num_outputs),
+    }
+  }
 }
 
 mod reports {
@@ -113,9 +156,39 @@ mod reports {
 pub struct BusNode {
   id:             BusId,
   info:           NodeInfo,
+  mode:           BusMode,
   mid_side_mode:  Option<MidSideMode>,
   input_volumes:  Vec<f64>,
   output_volumes: Vec<f64>,
+  matrix:         Matrix,
+}
+
+/// Mixer state of a bus in [BusMode::Matrix]
+///
+/// A new matrix routes every input to the output with the same index, wrapping around when there are more inputs than
+/// outputs. Inputs are panned hard to the side of their output, so they pass at unity gain.
+struct Matrix {
+  pan:     Vec<f64>,
+  mute:    Vec<bool>,
+  solo:    Vec<bool>,
+  routing: Vec<f64>,
+}
+
+impl Matrix {
+  fn new(num_inputs: usize, num_outputs: usize) -> Self {
+    let output_of = |input: usize| input % num_outputs.max(1);
+
+    let pan = (0..num_inputs).map(|input| if output_of(input) % 2 == 0 { -1.0 } else { 1.0 }).collect();
+    let routing = (0..num_inputs).flat_map(|input| {
+                                   (0..num_outputs).map(move |output| if output == output_of(input) { 1.0 } else { 0.0 })
+                                 })
+                                 .collect();
+
+    Self { pan,
+           mute: vec![false; num_inputs],
+           solo: vec![false; num_inputs],
+           routing }
+  }
 }
 
 enum MidSideMode {
@@ -124,19 +197,23 @@ enum MidSideMode {
 }
 
 impl BusNode {
-  pub fn new(id: BusId, num_inputs: usize, num_outputs: usize) -> Result<Self> {
+  pub fn new(id: BusId, num_inputs: usize, num_outputs: usize, mode: BusMode) -> Result<Self> {
     let latency = 0;
 
-    match (num_inputs, num_outputs) {
-      | (1, 2) | (2, 1) => {}
-      | (i, j) if i == j && i >= 1 => {}
-      | (_, _) => bail!("Bus node must have either: 1 input and 2 outputs, 2 inputs and 1 output or the same number of inputs and outputs"),
-    }
+    let parameters = match (mode, num_inputs, num_outputs) {
+      | (BusMode::Matrix, i, j) if i >= 1 && j >= 1 => parameters::create_matrix(num_inputs, num_outputs),
+      | (BusMode::Matrix, _, _) => bail!("Matrix bus node must have at least one input and one output"),
+      | (BusMode::Summing, 1, 2) | (BusMode::Summing, 2, 1) => parameters::create(num_inputs, num_outputs),
+      | (BusMode::Summing, i, j) if i == j && i >= 1 => parameters::create(num_inputs, num_outputs),
+      | (BusMode::Summing, _, _) => {
+        bail!("Bus node must have either: 1 input and 2 outputs, 2 inputs and 1 output or the same number of inputs and outputs")
+      }
+    };
 
     let info = NodeInfo { latency,
                           num_inputs,
                           num_outputs,
-                          parameters: parameters::create(num_inputs, num_outputs),
+                          parameters,
                           reports: reports::create(num_inputs, num_outputs) };
 
     let input_volumes = vec![1.0; num_inputs];
@@ -144,11 +221,71 @@ impl BusNode {
 
     let mid_side_mode = None;
 
+    let matrix = Matrix::new(num_inputs, num_outputs);
+
     Ok(Self { id,
               info,
+              mode,
               mid_side_mode,
               input_volumes,
-              output_volumes })
+              output_volumes,
+              matrix })
+  }
+
+  /// Gain of the constant-power pan law from an input to an output
+  ///
+  /// Outputs are panned in left / right pairs, so even outputs are left and odd outputs are right. A mono bus or the last
+  /// output of an odd numbered bus is not panned. At the center both sides are 3 dB down.
+  fn pan_gain(&self, input: usize, output: usize) -> f64 {
+    if output % 2 == 0 && output + 1 == self.info.num_outputs {
+      return 1.0;
+    }
+
+    let angle = (self.matrix.pan[input].clamp(-1.0, 1.0) + 1.0) * FRAC_PI_4;
+
+    if output % 2 == 0 {
+      angle.cos()
+    } else {
+      angle.sin()
+    }
+  }
+
+  /// Mix the inputs to the outputs through the routing matrix
+  ///
+  /// Like in the other bus modes, the levels are reported after the gains: input levels include the input level, mute
+  /// and solo, output levels include the output level.
+  fn mix_matrix(&mut self, node_buffers: &NodeBuffers, events: &mut Vec<NodeEvent>) -> Result {
+    let num_outputs = self.info.num_outputs;
+    let any_solo = self.matrix.solo.iter().any(|solo| *solo);
+
+    node_buffers.outputs().for_each(zero_slice);
+
+    for (input_index, input) in node_buffers.inputs().enumerate() {
+      let audible = !self.matrix.mute[input_index] && (!any_solo || self.matrix.solo[input_index]);
+      let input_level = if audible { self.input_volumes[input_index] } else { 0.0 };
+
+      for sample in input.iter_mut() {
+        *sample *= input_level;
+      }
+
+      for (output_index, output) in node_buffers.outputs().enumerate() {
+        let gain = self.matrix.routing[input_index * num_outputs + output_index] * self.pan_gain(input_index, output_index);
+        if gain != 0.0 {
+          add_slice(output, input.iter().map(|x| *x * gain));
+        }
+      }
+    }
+
+    for (output, level) in node_buffers.outputs().zip(self.output_volumes.iter().copied()) {
+      for sample in output.iter_mut() {
+        *sample *= level;
+      }
+    }
+
+    events.extend(reports::io_levels_peak(node_buffers.inputs().map(|x| x as &_), node_buffers.outputs().map(|x| x as &_), 0, 0));
+    events.extend(reports::io_levels_rms(node_buffers.inputs().map(|x| x as &_), node_buffers.outputs().map(|x| x as &_), 0, 0));
+
+    Ok(())
   }
 
   fn stereo_unwrap(&mut self, source: &mut [f64], left: &mut [f64], right: &mut [f64], events: &mut Vec<NodeEvent>) -> Result {
@@ -218,6 +355,10 @@ impl Node for BusNode {
       | (parameters::MID_SIDE_MODE, 0, 2.0) => self.mid_side_mode = Some(MidSideMode::Decode),
       | (parameters::INPUT_LEVEL, ch, val) if ch < self.info.num_inputs => self.input_volumes[ch] = val,
       | (parameters::OUTPUT_LEVEL, ch, val) if ch < self.info.num_outputs => self.output_volumes[ch] = val,
+      | (parameters::INPUT_PAN, ch, val) if ch < self.info.num_inputs => self.matrix.pan[ch] = val,
+      | (parameters::INPUT_MUTE, ch, val) if ch < self.info.num_inputs => self.matrix.mute[ch] = val != 0.0,
+      | (parameters::INPUT_SOLO, ch, val) if ch < self.info.num_inputs => self.matrix.solo[ch] = val != 0.0,
+      | (parameters::ROUTING, ch, val) if ch < self.matrix.routing.len() => self.matrix.routing[ch] = val,
       | _ => {}
     }
   }
@@ -233,6 +374,10 @@ impl Node for BusNode {
              _deadline: Instant,
              events: &mut Vec<NodeEvent>)
              -> Result {
+    if self.mode == BusMode::Matrix {
+      return self.mix_matrix(&node_buffers, events);
+    }
+
     match (node_buffers.num_inputs, node_buffers.num_outputs) {
       | (1, 2) => self.stereo_unwrap(node_buffers.input_plane(0),
                                      node_buffers.output_plane(0),
@@ -246,3 +391,64 @@ impl Node for BusNode {
     }
   }
 }
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn set(node: &mut BusNode, parameter: &str, channel: usize, value: f64) {
+    node.set_parameter(&SetParameterCommand { parameter: parameter.to_owned(),
+                                              channel,
+                                              value });
+  }
+
+  fn mix(node: &mut BusNode) -> Vec<f64> {
+    let inputs = vec![vec![1.0; 4]; node.info.num_inputs];
+    let outputs = vec![vec![0.0; 4]; node.info.num_outputs];
+    let buffers = NodeBuffers::new(inputs, outputs, 4);
+
+    node.process(PlayHead::default(), DevicesBuffers::default(), buffers.clone(), Instant::now(), &mut vec![])
+        .expect("Failed to process");
+
+    buffers.outputs().map(|output| output[0]).collect()
+  }
+
+  #[test]
+  fn test_matrix() {
+    let mut node = BusNode::new(1, 2, 2, BusMode::Matrix).expect("Failed to create bus");
+
+    // a new matrix passes every input to its own output
+    assert!(mix(&mut node).iter().all(|level| (level - 1.0).abs() < 1e-9));
+
+    // both inputs routed to both outputs at the center, 3 dB down on both sides
+    set(&mut node, parameters::ROUTING, 1, 1.0);
+    set(&mut node, parameters::ROUTING, 2, 1.0);
+    set(&mut node, parameters::INPUT_PAN, 0, 0.0);
+    set(&mut node, parameters::INPUT_PAN, 1, 0.0);
+    let center = mix(&mut node);
+    assert!(center.iter().all(|level| (level - 2.0 * FRAC_PI_4.cos()).abs() < 1e-9));
+
+    set(&mut node, parameters::INPUT_PAN, 0, -1.0);
+    set(&mut node, parameters::INPUT_PAN, 1, 1.0);
+    assert!(mix(&mut node).iter().all(|level| (level - 1.0).abs() < 1e-9));
+
+    set(&mut node, parameters::INPUT_MUTE, 1, 1.0);
+    assert!(mix(&mut node)[1].abs() < 1e-9);
+
+    // solo wins over other inputs, but not over mute
+    set(&mut node, parameters::INPUT_SOLO, 1, 1.0);
+    assert_eq!(mix(&mut node)[0], 0.0);
+    set(&mut node, parameters::INPUT_MUTE, 1, 0.0);
+    let soloed = mix(&mut node);
+    assert!(soloed[0].abs() < 1e-9);
+    assert!((soloed[1] - 1.0).abs() < 1e-9);
+
+    // route the right input to the left output only
+    set(&mut node, parameters::INPUT_PAN, 1, 0.0);
+    set(&mut node, parameters::ROUTING, 2 + 1, 0.0);
+    set(&mut node, parameters::INPUT_LEVEL, 1, 0.5);
+    let routed = mix(&mut node);
+    assert!((routed[0] - 0.5 * FRAC_PI_4.cos()).abs() < 1e-9);
+    assert_eq!(routed[1], 0.0);
+  }
+}
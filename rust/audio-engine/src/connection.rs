@@ -1,9 +1,12 @@
-use std::collections::VecDeque;
+use std::collections::{HashMap, HashSet, VecDeque};
 
-use anyhow::{anyhow, bail};
+use anyhow::bail;
 
-use api::task::graph::{InputId, NodeId, OutputId};
+use api::task::graph::NodeId;
+use api::task::player::GraphPlayerEvent;
 
+use crate::buffer::add_slice;
+use crate::crossfade::equal_power_gains;
 use crate::player::{GraphPlayer, PlayerCommandOutcome};
 use crate::Result;
 
@@ -12,9 +15,84 @@ pub struct Connection {
   pub samples:           VecDeque<f64>,
   pub remaining_latency: usize,
   pub latency:           usize,
+  pub fade:              Option<ConnectionFade>,
+}
+
+/// An equal-power fade of a connection that was just made or broken
+///
+/// The fade follows the graph cycles rather than the samples transferred, so it finishes even if the node on the other
+/// end of the connection stopped executing.
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionFade {
+  pub fade_in:  bool,
+  pub position: usize,
+  pub length:   usize,
+}
+
+impl ConnectionFade {
+  /// Gain `offset` samples into the current cycle
+  fn gain(&self, offset: usize) -> f64 {
+    let (fade_out, fade_in) = equal_power_gains(self.position + offset, self.length);
+    if self.fade_in {
+      fade_in
+    } else {
+      fade_out
+    }
+  }
+
+  fn is_finished(&self) -> bool {
+    self.position >= self.length
+  }
 }
 
 impl Connection {
+  pub fn fading_in(length: usize) -> Self {
+    Self { fade: (length > 0).then_some(ConnectionFade { fade_in: true,
+                                                        position: 0,
+                                                        length }),
+           ..Default::default() }
+  }
+
+  /// Fade the connection in again, picking up at the gain a fade out in progress has reached
+  pub fn fade_in(&mut self) {
+    if let Some(fade) = &mut self.fade {
+      if !fade.fade_in {
+        *fade = ConnectionFade { fade_in:  true,
+                                 position: fade.length - fade.position,
+                                 length:   fade.length, };
+      }
+    }
+  }
+
+  /// Fade the connection out, picking up at the gain a fade in progress has reached
+  pub fn fade_out(&mut self, length: usize) {
+    self.fade = match self.fade {
+      | None => Some(ConnectionFade { fade_in: false,
+                                      position: 0,
+                                      length }),
+      | Some(fade) if fade.fade_in => Some(ConnectionFade { fade_in:  false,
+                                                            position: fade.length - fade.position,
+                                                            length:   fade.length, }),
+      | fade => fade,
+    };
+  }
+
+  /// Move the fade on by the samples of a graph cycle
+  pub fn advance_fade(&mut self, num_frames: usize) {
+    let Some(fade) = &mut self.fade else { return };
+
+    fade.position = (fade.position + num_frames).min(fade.length);
+
+    if fade.fade_in && fade.is_finished() {
+      self.fade = None;
+    }
+  }
+
+  /// The connection faded out completely and can be removed without a click
+  pub fn is_faded_out(&self) -> bool {
+    matches!(self.fade, Some(fade) if !fade.fade_in && fade.is_finished())
+  }
+
   pub fn set_latency(&mut self, new_latency: usize) -> PlayerCommandOutcome {
     let already_delayed = self.latency - self.remaining_latency;
     let diff = new_latency as isize - already_delayed as isize;
@@ -38,109 +116,212 @@ impl Connection {
   pub fn reset(&mut self) {
     self.remaining_latency = self.latency;
     self.samples.clear();
+
+    // there is nothing to fade from after a reset, so fades finish right away
+    match &mut self.fade {
+      | Some(fade) if !fade.fade_in => fade.position = fade.length,
+      | _ => self.fade = None,
+    }
   }
-}
 
-impl GraphPlayer {
-  pub(crate) fn update_latency(&mut self) -> anyhow::Result<PlayerCommandOutcome> {
-    #[derive(Debug)]
-    struct Edge {
-      from:         OutputId,
-      to:           InputId,
-      previous:     Option<usize>,
-      contributed:  Option<usize>,
-      compensation: Option<usize>,
-    }
-
-    #[derive(Debug)]
-    struct NodeLatency {
-      id:      NodeId,
-      latency: Option<usize>,
-    }
-
-    let mut edges = vec![];
-    for node in self.node_state.values() {
-      for (input_id, inputs) in node.node_inputs.iter() {
-        for output_id in inputs.iter() {
-          edges.push(Edge { from:         *output_id,
-                            to:           *input_id,
-                            previous:     None,
-                            contributed:  None,
-                            compensation: None, });
-        }
-      }
+  /// Push the source output through the connection delay and mix the result into the target input
+  pub fn transfer(&mut self, source: &[f64], target: &mut [f64]) {
+    if self.remaining_latency > 0 {
+      self.samples.resize(self.samples.len() + self.remaining_latency, 0.0);
+      self.remaining_latency = 0;
     }
 
-    let mut total_latencies = vec![];
-    for (id, node) in self.node_state.iter() {
-      total_latencies.push(NodeLatency { id:      *id,
-                                         latency: Some(node.info.latency), });
+    self.samples.extend(source.iter().copied());
+
+    let len = target.len().min(self.samples.len());
+
+    match &self.fade {
+      | None => add_slice(&mut target[..len], self.samples.drain(..len)),
+      | Some(fade) =>
+        for (offset, (target, sample)) in target[..len].iter_mut().zip(self.samples.drain(..len)).enumerate() {
+          *target += sample * fade.gain(offset);
+        },
     }
+  }
+}
 
-    for edge in &mut edges {
-      // TODO: maybe more than edge contributed
-      let node_id = edge.from.into();
-      edge.contributed = Some(self.node_state
-                                  .get(&node_id)
-                                  .ok_or_else(|| anyhow!("Node {node_id} not found"))?
-                                  .info
-                                  .latency).filter(|latency| *latency != 0);
+impl GraphPlayer {
+  /// Compute the latency arriving at every node and delay the shorter branches into each input
+  ///
+  /// Every connection is delayed so that all signals summed into the same node arrive aligned with the slowest branch,
+  /// and the total latency of the graph is reported whenever it changes.
+  pub(crate) fn update_latency(&mut self) -> Result<PlayerCommandOutcome> {
+    let mut input_latencies = HashMap::new();
+
+    for node_id in self.node_state.keys().copied().collect::<Vec<_>>() {
+      self.input_latency(node_id, &mut input_latencies, &mut HashSet::new())?;
     }
 
-    'outer: loop {
-      for next_node in total_latencies.iter_mut().filter(|n| n.latency.is_none()) {
-        let id = next_node.id;
+    let mut rv = PlayerCommandOutcome::NoAction;
 
-        let filter = |e: &&Edge| Into::<NodeId>::into(e.to) == id;
-        let filter_mut = |e: &&mut Edge| Into::<NodeId>::into(e.to) == id;
+    for ((output, input), connection) in self.connections.iter_mut() {
+      let output_node: NodeId = (*output).into();
+      let input_node: NodeId = (*input).into();
 
-        if edges.iter().filter(filter).any(|e| e.previous.is_none() || e.contributed.is_none()) {
-          continue;
-        }
+      let arrival = input_latencies.get(&output_node).copied().unwrap_or_default()
+                    + self.node_state
+                          .get(&output_node)
+                          .map(|state| state.info.latency)
+                          .unwrap_or_default();
 
-        let max_latency = edges.iter()
-                               .filter(filter)
-                               .map(|e| e.previous.unwrap() + e.contributed.unwrap())
-                               .max()
-                               .ok_or_else(|| anyhow!("Node has no input edges"))?;
+      let required = input_latencies.get(&input_node).copied().unwrap_or_default();
 
-        for edge in edges.iter_mut().filter(filter_mut) {
-          let edge_latency = edge.previous.unwrap_or_default() + edge.contributed.unwrap_or_default();
-          edge.compensation = Some(max_latency - edge_latency);
-        }
+      rv |= connection.set_latency(required.saturating_sub(arrival));
+    }
 
-        next_node.latency = Some(max_latency);
+    let mut graph_latency = 0;
 
-        for edge in edges.iter_mut().filter(|e| Into::<NodeId>::into(e.from) == id) {
-          edge.previous = Some(max_latency);
-        }
+    for (node_id, state) in self.node_state.iter_mut() {
+      state.accumulated_latency = input_latencies.get(node_id).copied().unwrap_or_default();
+      graph_latency = graph_latency.max(state.accumulated_latency + state.info.latency);
+    }
 
-        continue 'outer;
-      }
+    if graph_latency != self.graph_latency {
+      self.graph_latency = graph_latency;
+      let _ = self.tx_events
+                  .try_send(GraphPlayerEvent::GraphLatencyChanged { latency: graph_latency });
+    }
 
-      break;
+    Ok(rv)
+  }
+
+  /// The maximum latency of all signals arriving at the inputs of the node
+  fn input_latency(&self, node_id: NodeId, memo: &mut HashMap<NodeId, usize>, visiting: &mut HashSet<NodeId>) -> Result<usize> {
+    if let Some(latency) = memo.get(&node_id) {
+      return Ok(*latency);
     }
 
-    for node in total_latencies {
-      let latency = node.latency.unwrap_or_default();
-      let node = self.node_state.get_mut(&node.id).unwrap();
-      if node.accumulated_latency != latency {
-        node.accumulated_latency = latency;
-      }
+    if !visiting.insert(node_id) {
+      bail!("Loop detected at {node_id} while computing latency");
     }
 
-    let mut rv = PlayerCommandOutcome::NoAction;
-    for edge in edges {
-      if self.set_delay(edge.from, edge.to, edge.compensation.unwrap_or_default())? == PlayerCommandOutcome::Reset {
-        rv = PlayerCommandOutcome::Reset;
+    let mut latency = 0;
+
+    if let Some(state) = self.node_state.get(&node_id) {
+      for output_id in state.node_inputs.values().flatten() {
+        let output_node: NodeId = (*output_id).into();
+        let Some(output_state) = self.node_state.get(&output_node) else { continue };
+
+        latency = latency.max(self.input_latency(output_node, memo, visiting)? + output_state.info.latency);
       }
     }
 
-    Ok(rv)
+    visiting.remove(&node_id);
+    memo.insert(node_id, latency);
+
+    Ok(latency)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::fs;
+  use std::path::PathBuf;
+
+  use maplit::hashmap;
+  use nanoid::nanoid;
+
+  use api::task::graph::modify::AudioGraphModification;
+  use api::task::graph::{AudioGraphSpec, BusSpec, InsertId, OutputId, VirtualInsertSpec};
+  use api::task::player::PlayerControlCommand;
+
+  use crate::player::fixtures::{read_float_wav, renderer, CONSTANT_MODEL_ID, IMPULSE_MODEL_ID};
+  use crate::player::offline::OfflineRenderer;
+  use crate::virtual_inserts::{GAIN_MODEL_ID, LIMITER_MODEL_ID};
+
+  use super::*;
+
+  #[tokio::test(flavor = "multi_thread")]
+  async fn test_parallel_paths_are_aligned() {
+    let (impulse, limiter, gain): (InsertId, InsertId, InsertId) = (1, 2, 3);
+
+    // the limiter lookahead delays one branch, the gain branch has to be delayed to match it in the bus
+    let spec = AudioGraphSpec { virtual_inserts: hashmap! {
+                                  impulse => VirtualInsertSpec { inputs:   vec![vec![]],
+                                                                 model_id: IMPULSE_MODEL_ID.to_owned(), },
+                                  limiter => VirtualInsertSpec { inputs:   vec![vec![OutputId::VirtualInsert(impulse, 0)]],
+                                                                 model_id: LIMITER_MODEL_ID.to_owned(), },
+                                  gain => VirtualInsertSpec { inputs:   vec![vec![OutputId::VirtualInsert(impulse, 0)]],
+                                                              model_id: GAIN_MODEL_ID.to_owned(), },
+                                },
+                                busses: hashmap! {
+                                  4 => BusSpec { inputs:      vec![vec![OutputId::VirtualInsert(limiter, 0), OutputId::VirtualInsert(gain, 0)]],
+                                                 num_outputs: 1,
+                                                 mode:        Default::default(), },
+                                },
+                                ..Default::default() };
+
+    let path = std::env::temp_dir().join(format!("offline-render-{}.wav", nanoid!()));
+
+    let renderer = renderer(spec, 256, 64, vec![vec![OutputId::Bus(4, 0)]], path.clone());
+
+    let mut reported_latency = None;
+    let summary = renderer.render(|event| {
+                            if let GraphPlayerEvent::GraphLatencyChanged { latency } = event {
+                              reported_latency = Some(latency);
+                            }
+                          })
+                          .await
+                          .expect("Failed to render");
+
+    let samples = read_float_wav(&path);
+    let _ = fs::remove_file(&path);
+
+    // 1.5ms of lookahead at 48kHz
+    assert_eq!(summary.latency, 72);
+    assert_eq!(reported_latency, Some(72));
+    assert_eq!(samples[0], 1.0);
+    assert!(samples[1..].iter().all(|sample| *sample == 0.0));
   }
 
-  fn set_delay(&mut self, from: OutputId, to: InputId, latency: usize) -> Result<PlayerCommandOutcome> {
-    let Some(connection) = self.connections.get_mut(&(from, to)) else { bail!("Connection {from} -> {to} does not exist") };
-    Ok(connection.set_latency(latency))
+  #[tokio::test(flavor = "multi_thread")]
+  async fn test_disconnect_fades_out() {
+    let (constant, gain): (InsertId, InsertId) = (1, 2);
+    let output_id = OutputId::VirtualInsert(constant, 0);
+
+    let spec = AudioGraphSpec { virtual_inserts: hashmap! {
+                                  constant => VirtualInsertSpec { inputs:   vec![vec![]],
+                                                                  model_id: CONSTANT_MODEL_ID.to_owned(), },
+                                  gain => VirtualInsertSpec { inputs:   vec![vec![output_id]],
+                                                              model_id: GAIN_MODEL_ID.to_owned(), },
+                                },
+                                ..Default::default() };
+
+    let mut renderer = renderer(spec, 1_000, 64, vec![vec![OutputId::VirtualInsert(gain, 0)]], PathBuf::new());
+
+    let gain_output = |renderer: &OfflineRenderer| renderer.player.node_state[&NodeId::VirtualInsert(gain)].buffers.output_plane(0).to_vec();
+
+    renderer.cycle().await.expect("Failed to render cycle");
+    let level = gain_output(&renderer)[0];
+    assert!(level > 0.0);
+
+    let input_id = NodeId::VirtualInsert(gain).input(0).expect("Gain has no input");
+    let modifications = vec![AudioGraphModification::Disconnect { component:     NodeId::VirtualInsert(gain),
+                                                                  input_channel: 0,
+                                                                  output:        output_id, }];
+
+    let player = &mut renderer.player;
+    player.play_head.play_region.crossfade = 96;
+    player.pending_commands
+          .push_back(PlayerControlCommand::ModifyGraph { modifications }.into());
+    player.apply_pending_commands_and_sync().await.expect("Failed to disconnect");
+
+    // the connection fades out one and a half cycles in, and is removed once the cycle finished
+    let mut faded = vec![];
+    for cycle in 0..3 {
+      assert_eq!(renderer.player.connections.contains_key(&(output_id, input_id)), cycle < 2);
+      renderer.cycle().await.expect("Failed to render cycle");
+      faded.extend(gain_output(&renderer));
+    }
+
+    for (position, sample) in faded[..96].iter().enumerate() {
+      assert!((sample - level * equal_power_gains(position, 96).0).abs() < 1e-9);
+    }
+    assert!(faded[96..].iter().all(|sample| *sample == 0.0));
   }
 }
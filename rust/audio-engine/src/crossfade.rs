@@ -0,0 +1,150 @@
+use std::f64::consts::{FRAC_PI_2, PI};
+
+use api::task::graph::FadeCurve;
+
+/// Dynamic range of an exponential fade, 60 dB
+const EXPONENTIAL_FADE_RANGE: f64 = 1_000.0;
+
+/// Gain at `position` of a fade from silence to full level that is `length` samples long
+pub fn fade_gain(curve: FadeCurve, position: u64, length: u64) -> f64 {
+  if position >= length {
+    return 1.0;
+  }
+
+  let x = position as f64 / length as f64;
+
+  match curve {
+    | FadeCurve::Linear => x,
+    | FadeCurve::EqualPower => (x * FRAC_PI_2).sin(),
+    | FadeCurve::SCurve => (1.0 - (x * PI).cos()) / 2.0,
+    | FadeCurve::Exponential => (EXPONENTIAL_FADE_RANGE.powf(x) - 1.0) / (EXPONENTIAL_FADE_RANGE - 1.0),
+  }
+}
+
+/// Gains of the signal fading out and the signal fading in at `position` of an equal-power crossfade of `length` samples
+pub fn equal_power_gains(position: usize, length: usize) -> (f64, f64) {
+  if position >= length {
+    return (0.0, 1.0);
+  }
+
+  let angle = (position as f64 + 0.5) / length as f64 * FRAC_PI_2;
+
+  (angle.cos(), angle.sin())
+}
+
+/// Equal-power crossfade across a discontinuity in a multichannel stream
+///
+/// When the stream jumps, the samples that would have followed without the jump are kept as the tail and faded out
+/// while the stream after the jump fades in. The crossfade may span several buffers.
+#[derive(Debug, Default)]
+pub struct Crossfade {
+  tail:     Vec<Vec<f64>>,
+  position: usize,
+  length:   usize,
+}
+
+impl Crossfade {
+  pub fn new(num_channels: usize, length: usize) -> Self {
+    Self { tail: vec![vec![0.0; length]; num_channels],
+           position: length,
+           length }
+  }
+
+  pub fn length(&self) -> usize {
+    self.length
+  }
+
+  pub fn is_active(&self) -> bool {
+    self.position < self.length
+  }
+
+  /// Start fading out `tail`, the continuation of each channel of the stream before the jump
+  ///
+  /// A crossfade still in progress is applied to the tail first, so the stream it was fading out keeps fading out.
+  pub fn start<'a>(&mut self, tail: impl Iterator<Item = &'a mut [f64]>) {
+    let (position, length) = (self.position, self.length);
+
+    for (kept, plane) in self.tail.iter_mut().zip(tail) {
+      let len = kept.len().min(plane.len());
+
+      // the kept samples ahead of the one written are still those of the crossfade in progress
+      for index in 0..len {
+        kept[index] = if position + index < length {
+          let (fade_out, fade_in) = equal_power_gains(position + index, length);
+          plane[index] * fade_in + kept[position + index] * fade_out
+        } else {
+          plane[index]
+        };
+      }
+
+      kept[len..].fill(0.0);
+    }
+
+    self.position = 0;
+  }
+
+  /// Mix the tail into the stream after the jump
+  pub fn apply<'a>(&mut self, planes: impl Iterator<Item = &'a mut [f64]>) {
+    let mut mixed = 0;
+
+    for (plane, tail) in planes.zip(&self.tail) {
+      mixed = plane.len().min(self.length - self.position);
+
+      for (index, sample) in plane[..mixed].iter_mut().enumerate() {
+        let (fade_out, fade_in) = equal_power_gains(self.position + index, self.length);
+        *sample = *sample * fade_in + tail[self.position + index] * fade_out;
+      }
+    }
+
+    self.position += mixed;
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_equal_power_gains() {
+    for position in 0..64 {
+      let (fade_out, fade_in) = equal_power_gains(position, 64);
+      assert!((fade_out.powi(2) + fade_in.powi(2) - 1.0).abs() < 1e-12);
+    }
+
+    assert_eq!(equal_power_gains(64, 64), (0.0, 1.0));
+  }
+
+  #[test]
+  fn test_fade_curves() {
+    for curve in [FadeCurve::Linear, FadeCurve::EqualPower, FadeCurve::SCurve, FadeCurve::Exponential] {
+      assert_eq!(fade_gain(curve, 0, 100), 0.0);
+      assert_eq!(fade_gain(curve, 100, 100), 1.0);
+      assert!((1..100).all(|position| fade_gain(curve, position, 100) > fade_gain(curve, position - 1, 100)));
+    }
+
+    assert_eq!(fade_gain(FadeCurve::Linear, 25, 100), 0.25);
+    assert!((fade_gain(FadeCurve::SCurve, 50, 100) - 0.5).abs() < 1e-12);
+  }
+
+  #[test]
+  fn test_crossfade_across_buffers() {
+    let mut crossfade = Crossfade::new(1, 8);
+    assert!(!crossfade.is_active());
+
+    let mut tail = vec![1.0; 8];
+    crossfade.start([&mut tail[..]].into_iter());
+
+    let mut first = vec![0.0; 4];
+    crossfade.apply([&mut first[..]].into_iter());
+    assert!(crossfade.is_active());
+
+    let mut second = vec![0.0; 6];
+    crossfade.apply([&mut second[..]].into_iter());
+    assert!(!crossfade.is_active());
+
+    // the tail fades out over the first eight samples, whatever follows is left alone
+    assert_eq!(first[0], equal_power_gains(0, 8).0);
+    assert_eq!(second[3], equal_power_gains(7, 8).0);
+    assert_eq!(&second[4..], &[0.0, 0.0]);
+  }
+}
@@ -22,6 +22,10 @@ pub fn gain_factor_to_db(factor: f64) -> f64 {
   20.0 * factor.log10()
 }
 
+pub fn db_to_gain_factor(db: f64) -> f64 {
+  10.0_f64.powf(db / 20.0)
+}
+
 pub fn make_report(name: &str, channel_offset: usize) -> impl Fn((usize, f64)) -> NodeEvent + '_ {
   move |(channel, value)| {
     let name = name.to_owned();
@@ -18,6 +18,14 @@ impl JuceAudioReaderPtr {
 
 type AudioMgrCallback = extern "C" fn(*mut c_void, *const *const f32, i32, *mut *mut f32, i32, i32);
 
+/// Called once per device found, with its type name, device name, input and output channel counts, supported sample
+/// rates and supported buffer sizes
+pub type AudioDeviceInfoCallback =
+  extern "C" fn(*mut c_void, *const c_char, *const c_char, i32, i32, *const f64, i32, *const i32, i32);
+
+/// Called once per device found, with its type name and device name
+pub type AudioDeviceNameCallback = extern "C" fn(*mut c_void, *const c_char, *const c_char);
+
 extern "C" {
   pub fn create_audio_device(type_name: *const c_char,
                              input_name: *const c_char,
@@ -36,6 +44,10 @@ extern "C" {
 
   pub fn get_audio_device_latency(device_id: i32) -> i32;
 
+  pub fn list_audio_device_names(callback: AudioDeviceNameCallback, user_data: *mut c_void) -> i32;
+
+  pub fn enumerate_audio_devices(callback: AudioDeviceInfoCallback, user_data: *mut c_void) -> i32;
+
   fn create_file_reader(path: *const c_char) -> JuceAudioReaderPtr;
 
   fn delete_file_reader(reader: JuceAudioReaderPtr);
@@ -56,6 +68,7 @@ extern "C" {
 
 pub struct JuceAudioReader {
   reader_ptr: JuceAudioReaderPtr,
+  slice_ptrs: Vec<*mut f32>,
 }
 
 impl JuceAudioReader {
@@ -68,7 +81,10 @@ impl JuceAudioReader {
       return Err(anyhow!("Failed to create file reader"));
     }
 
-    Ok(Self { reader_ptr })
+    let num_channels = unsafe { file_reader_get_channels(reader_ptr) }.max(0) as usize;
+
+    Ok(Self { reader_ptr,
+              slice_ptrs: Vec::with_capacity(num_channels) })
   }
 
   pub fn get_sample_rate(&self) -> i32 {
@@ -83,21 +99,19 @@ impl JuceAudioReader {
     unsafe { file_reader_get_total_length(self.reader_ptr) }
   }
 
-  pub fn read_samples(&self, buffers: &mut [&mut [f32]], pos: i64, len: i32) -> i32 {
-    let mut slice_ptrs = [null_mut(),
-                          null_mut(),
-                          null_mut(),
-                          null_mut(),
-                          null_mut(),
-                          null_mut(),
-                          null_mut(),
-                          null_mut()];
-
-    for (i, buffer) in buffers.iter_mut().enumerate() {
-      slice_ptrs[i] = buffer.as_mut_ptr();
+  /// Read `len` samples at `pos` into the buffers, one per channel
+  ///
+  /// The pointer array passed to JUCE is reused between calls, so reading does not allocate once the reader has seen as
+  /// many buffers as the file has channels.
+  pub fn read_samples(&mut self, buffers: &mut [impl AsMut<[f32]>], pos: i64, len: i32) -> i32 {
+    if buffers.iter_mut().any(|buffer| buffer.as_mut().len() < len.max(0) as usize) {
+      return -1;
     }
 
-    unsafe { file_reader_read_samples(self.reader_ptr, slice_ptrs.as_ptr(), buffers.len() as i32, pos, len as i32) as i32 }
+    self.slice_ptrs.clear();
+    self.slice_ptrs.extend(buffers.iter_mut().map(|buffer| buffer.as_mut().as_mut_ptr()));
+
+    unsafe { file_reader_read_samples(self.reader_ptr, self.slice_ptrs.as_ptr(), buffers.len() as i32, pos, len) }
   }
 }
 
@@ -7,27 +7,45 @@ use itertools::Itertools;
 use tokio::sync::RwLock;
 
 use api::instance::spec::SetParameterCommand;
-use api::task::player::{NodeEvent, NodeInfo, PlayHead};
+use api::task::player::{LoudnessSummary, NodeEvent, NodeInfo, ParameterRamp, PlayHead};
 
 use crate::buffer::{DevicesBuffers, NodeBuffers};
 
 pub type Result<T = ()> = anyhow::Result<T>;
 
 pub mod audio_device;
+pub mod automation;
 pub mod buffer;
 pub mod bus_node;
 pub mod connection;
+pub mod crossfade;
 pub mod events;
+#[cfg(feature = "juce")]
 pub mod juce;
+pub mod ogg;
 pub mod player;
 pub mod sinks;
 pub mod sources;
+pub mod virtual_inserts;
+pub mod wav;
 
 #[allow(unused_variables)]
 pub trait Node: Send + Sync {
   /// Set the parameters of the node
   fn set_parameter(&mut self, parameter: &SetParameterCommand) {}
 
+  /// Schedule a parameter change within the next buffer the node processes
+  ///
+  /// Nodes that can move a parameter sample by sample override this, the others apply the change to the whole buffer.
+  ///
+  /// # Parameters
+  /// * `parameter`: The parameter and its new value
+  /// * `offset`: The sample offset within the next buffer at which the change starts
+  /// * `ramp`: How the parameter moves to the new value
+  fn schedule_parameter(&mut self, parameter: &SetParameterCommand, offset: usize, ramp: ParameterRamp) {
+    self.set_parameter(parameter);
+  }
+
   /// Returns the linking information about the node - latency, number of inputs and outputs
   ///
   /// # Parameters
@@ -45,6 +63,17 @@ pub trait Node: Send + Sync {
     Ok(())
   }
 
+  /// Called between cycles when the play head jumps and the graph crossfades instead of resetting
+  ///
+  /// The node keeps playing what it played before, nodes that crossfade the jump themselves prepare for it here instead
+  /// of on the realtime thread.
+  ///
+  /// # Parameters
+  /// * `play`: The play head after the jump
+  fn prepare_to_seek(&mut self, play: PlayHead) -> Result {
+    Ok(())
+  }
+
   /// Called when the node inputs are ready for reading
   ///
   /// # Parameters
@@ -56,6 +85,11 @@ pub trait Node: Send + Sync {
     Ok(())
   }
 
+  /// The EBU R128 summary of everything the node processed since [prepare_to_play], if the node measures loudness
+  fn loudness_summary(&self) -> Option<LoudnessSummary> {
+    None
+  }
+
   /// Called when the node will no longer be played and a new [prepare_to_play] will be called
   ///
   /// # Parameters
@@ -67,17 +101,3 @@ pub trait Node: Send + Sync {
 
 pub type BoxedNode = Box<dyn Node>;
 pub type SharedBoxedNode = Arc<RwLock<BoxedNode>>;
-
-#[macro_export]
-macro_rules! buffers8 {
-  ($x:ident) => {
-    [&mut $x.buf0[..],
-     &mut $x.buf1[..],
-     &mut $x.buf2[..],
-     &mut $x.buf3[..],
-     &mut $x.buf4[..],
-     &mut $x.buf5[..],
-     &mut $x.buf6[..],
-     &mut $x.buf7[..]]
-  };
-}
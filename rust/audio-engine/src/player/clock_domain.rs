@@ -0,0 +1,325 @@
+use std::time::Instant;
+
+use api::task::player::ClockDomainStats;
+
+use crate::audio_device::asrc::{asrc, AsrcOptions, AsrcReader, AsrcWriter};
+use crate::audio_device::AudioDeviceInfo;
+use crate::buffer::DeviceBuffers;
+
+/// A device running on a clock of its own, bridged to the device clocking the graph
+///
+/// Within the graph, the nodes using the device see proxy buffers at the graph buffer size, which are flipped along with
+/// the clock device. Between the proxy and the device, the samples go through an asynchronous sample rate converter in
+/// each direction, which takes up the drift between the two clocks.
+pub(crate) struct ClockDomain {
+  device_id: String,
+  proxy:     ProxyBuffers,
+  /// Graph to device, written on the graph clock
+  sends:     Option<Bridge>,
+  /// Device to graph, written on the device clock
+  returns:   Option<Bridge>,
+  latency:   usize,
+  clock:     AudioDeviceInfo,
+  device:    AudioDeviceInfo,
+}
+
+struct Bridge {
+  writer: AsrcWriter,
+  reader: AsrcReader,
+}
+
+impl Bridge {
+  fn new(num_channels: usize, src: &AudioDeviceInfo, dst: &AudioDeviceInfo) -> Option<Self> {
+    if num_channels == 0 {
+      return None;
+    }
+
+    let (writer, reader) = asrc(num_channels, src.sample_rate, dst.sample_rate, bridge_options(src, dst));
+
+    Some(Self { writer, reader })
+  }
+}
+
+impl ClockDomain {
+  pub(crate) fn new(device_id: String, clock: &AudioDeviceInfo, device: &AudioDeviceInfo) -> Self {
+    Self { proxy: ProxyBuffers::new(device.num_inputs, device.num_outputs, clock.buffer_size as usize),
+           sends: Bridge::new(device.num_outputs, clock, device),
+           returns: Bridge::new(device.num_inputs, device, clock),
+           latency: bridge_latency(clock, device),
+           clock: *clock,
+           device: *device,
+           device_id }
+  }
+
+  /// Whether the domain was set up to bridge these device and clock infos, so its converters can be kept
+  pub(crate) fn bridges(&self, clock: &AudioDeviceInfo, device: &AudioDeviceInfo) -> bool {
+    self.clock == *clock && self.device == *device
+  }
+
+  /// Hand the proxy outputs of the last graph cycle to the device and fill the proxy inputs for the next one
+  pub(crate) fn graph_cycle(&mut self, generation: u64, time: Instant) -> DeviceBuffers {
+    if let Some(sends) = &mut self.sends {
+      sends.writer.write(self.proxy.outputs.iter().map(|plane| &plane[..]), time);
+    }
+
+    for plane in &mut self.proxy.outputs {
+      plane.fill(0.0);
+    }
+
+    if let Some(returns) = &mut self.returns {
+      returns.reader.read(self.proxy.inputs.iter_mut().map(|plane| &mut plane[..]), time);
+    }
+
+    self.proxy.device_buffers(generation)
+  }
+
+  /// Exchange samples with the device when it flips, which it can move on from right away
+  pub(crate) fn device_flip(&mut self, buffers: &DeviceBuffers, time: Instant) {
+    match &mut self.sends {
+      | Some(sends) => {
+        sends.reader.read((0..buffers.num_outputs).map(|plane| buffers.output_plane(plane)), time);
+      }
+      | None => buffers.zero_outputs(),
+    }
+
+    if let Some(returns) = &mut self.returns {
+      returns.writer.write((0..buffers.num_inputs).map(|plane| buffers.input_plane(plane)), time);
+    }
+  }
+
+  pub(crate) fn stats(&self) -> ClockDomainStats {
+    let bridges = self.sends.iter().chain(self.returns.iter()).map(|bridge| bridge.reader.stats());
+
+    ClockDomainStats { device_id: self.device_id.clone(),
+                       latency:   self.latency,
+                       drift_ppm: self.drift_ppm(),
+                       underruns: bridges.clone().map(|stats| stats.underruns).sum(),
+                       overruns:  bridges.map(|stats| stats.overruns).sum(), }
+  }
+
+  fn drift_ppm(&self) -> f64 {
+    let deviation = |reader: &AsrcReader| reader.stats().ratio / reader.nominal_ratio() - 1.0;
+
+    // a faster device delivers more returns than the graph expects, and takes fewer sends
+    match (&self.returns, &self.sends) {
+      | (Some(returns), _) => deviation(&returns.reader) * 1e6,
+      | (None, Some(sends)) => -deviation(&sends.reader) * 1e6,
+      | (None, None) => 0.0,
+    }
+  }
+}
+
+/// Latency a round trip through a bridged device adds, at the sample rate of the clock device
+///
+/// The sends are held for one graph cycle in the proxy buffers and then for the target latency of their converter, the
+/// returns for the target latency of theirs.
+pub(crate) fn bridge_latency(clock: &AudioDeviceInfo, device: &AudioDeviceInfo) -> usize {
+  let sends = clock.buffer_size as usize + bridge_options(clock, device).target_latency;
+  let returns = convert_frames(bridge_options(device, clock).target_latency, device, clock);
+
+  sends + returns
+}
+
+/// Frames at the rate of `from` converted to the rate of `to`, rounded up
+pub(crate) fn convert_frames(num_frames: usize, from: &AudioDeviceInfo, to: &AudioDeviceInfo) -> usize {
+  if from.sample_rate == to.sample_rate || from.sample_rate == 0 {
+    return num_frames;
+  }
+
+  (num_frames as u64 * to.sample_rate as u64).div_ceil(from.sample_rate as u64) as usize
+}
+
+fn bridge_options(src: &AudioDeviceInfo, dst: &AudioDeviceInfo) -> AsrcOptions {
+  // either side may hand over its buffer up to a buffer late, the converter holds enough to ride that out
+  let buffers = src.buffer_size as usize + convert_frames(dst.buffer_size as usize, dst, src);
+  let target_latency = (2 * buffers).max(64);
+
+  AsrcOptions { target_latency,
+                capacity: (8 * target_latency).max(AsrcOptions::default().capacity),
+                ..Default::default() }
+}
+
+/// Device buffers owned by the player, standing in for a bridged device
+struct ProxyBuffers {
+  inputs:      Vec<Vec<f32>>,
+  outputs:     Vec<Vec<f32>>,
+  // the device buffers point into these
+  input_ptrs:  Vec<*const f32>,
+  output_ptrs: Vec<*mut f32>,
+}
+
+// the pointers only ever point into the planes owned alongside them
+unsafe impl Send for ProxyBuffers {}
+unsafe impl Sync for ProxyBuffers {}
+
+impl ProxyBuffers {
+  fn new(num_inputs: usize, num_outputs: usize, buffer_size: usize) -> Self {
+    let inputs = vec![vec![0.0; buffer_size]; num_inputs];
+    let mut outputs = vec![vec![0.0; buffer_size]; num_outputs];
+
+    let input_ptrs = inputs.iter().map(|plane| plane.as_ptr()).collect();
+    let output_ptrs = outputs.iter_mut().map(|plane| plane.as_mut_ptr()).collect();
+
+    Self { inputs,
+           outputs,
+           input_ptrs,
+           output_ptrs }
+  }
+
+  fn device_buffers(&mut self, generation: u64) -> DeviceBuffers {
+    DeviceBuffers { inputs: self.input_ptrs.as_ptr(),
+                    outputs: self.output_ptrs.as_mut_ptr(),
+                    num_inputs: self.inputs.len(),
+                    num_outputs: self.outputs.len(),
+                    buffer_size: self.inputs.first().or(self.outputs.first()).map(Vec::len).unwrap_or_default(),
+                    generation }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::collections::HashMap;
+  use std::f64::consts::TAU;
+  use std::time::Duration;
+
+  use anyhow::bail;
+  use maplit::hashmap;
+  use tokio::select;
+
+  use api::task::graph::{AudioGraphSpec, DeviceInsertSpec, OutputId};
+  use api::task::player::GraphPlayerEvent;
+  use api::task::PlayRequest;
+
+  use crate::audio_device::simulator_device::{new_loopback_simulator_device, SimulatorLoopback, SimulatorOptions};
+  use crate::audio_device::AudioDevices;
+  use crate::player::fixtures::NoMedia;
+  use crate::player::{DeviceInstanceAttachment, DeviceInstanceResolver, GraphPlayerHandle};
+  use crate::wav::{WavSampleFormat, WavWriter};
+  use crate::Result;
+
+  use super::*;
+
+  struct Instances;
+
+  impl DeviceInstanceResolver for Instances {
+    fn resolve(&self, instance_id: &str) -> Result<DeviceInstanceAttachment> {
+      let (device_id, sends, returns) = match instance_id {
+        | "a-in" => ("a", vec![], vec![0]),
+        | "b" => ("b", vec![0], vec![0]),
+        | "a-out" => ("a", vec![0], vec![]),
+        | _ => bail!("No instance {instance_id}"),
+      };
+
+      Ok(DeviceInstanceAttachment { device_id: device_id.to_owned(),
+                                    sends,
+                                    returns,
+                                    additional_latency: 0 })
+    }
+  }
+
+  // the simulators, the worker threads and the deadlines all run on the wall clock, which paused tokio time can't drive
+  #[tokio::test(flavor = "multi_thread")]
+  #[ignore = "runs two simulator devices in real time for five seconds, a loaded machine misses cycles"]
+  async fn test_insert_on_bridged_device() {
+    // device a clocks the graph and plays a sine into its input, device b loops its output back and runs 208 ppm fast
+    let a = AudioDeviceInfo { latency:     0,
+                              buffer_size: 256,
+                              sample_rate: 48_000,
+                              num_inputs:  1,
+                              num_outputs: 1, };
+    let b = AudioDeviceInfo { latency: 256, ..a };
+
+    let path = std::env::temp_dir().join(format!("clock_domain_{}.wav", std::process::id()));
+    let sine = (0..10 * 48_000).map(|i| (i as f64 * 1_000.0 / 48_000.0 * TAU).sin() * 0.5).collect::<Vec<_>>();
+
+    let mut writer = WavWriter::create(&path, 1, 48_000, WavSampleFormat::Float32).expect("create WAV file");
+    writer.write_planar(&[&sine], sine.len()).expect("write samples");
+    writer.finalize().expect("finalize WAV file");
+
+    let (device_a, capture) = new_loopback_simulator_device("a".to_string(),
+                                                            a,
+                                                            SimulatorOptions { input_file: Some(path.clone()),
+                                                                               capture_outputs: true,
+                                                                               ..Default::default() }).expect("Failed to create device a");
+
+    let (device_b, _) = new_loopback_simulator_device("b".to_string(),
+                                                      b,
+                                                      SimulatorOptions { loopbacks: vec![SimulatorLoopback { output: 0,
+                                                                                                             input:  0,
+                                                                                                             delay:  0,
+                                                                                                             gain:   1.0, }],
+                                                                         clock_ppm: 208.0,
+                                                                         ..Default::default() }).expect("Failed to create device b");
+
+    let mut devices = AudioDevices::default();
+    devices.add_device("a".to_string(), device_a);
+    devices.add_device("b".to_string(), device_b);
+    assert_eq!(devices.clock_device(), Some("a"));
+
+    let insert = |instance_id: &str, inputs| DeviceInsertSpec { instance_id: instance_id.to_owned(),
+                                                                inputs };
+
+    let spec = AudioGraphSpec { device_inserts: hashmap! {
+                                  1 => insert("a-in", vec![]),
+                                  2 => insert("b", vec![vec![OutputId::DeviceInsert(1, 0)]]),
+                                  3 => insert("a-out", vec![vec![OutputId::DeviceInsert(2, 0)]]),
+                                },
+                                ..Default::default() };
+
+    let mut handle = GraphPlayerHandle::new(devices.clone(), Box::new(NoMedia), Box::new(Instances), spec).expect("Failed to create player");
+    let mut events = handle.take_events().expect("Events already taken");
+
+    handle.set_play(PlayRequest { play_id:    1,
+                                  start:      0,
+                                  end:        10 * 48_000,
+                                  start_from: 0,
+                                  looping:    false,
+                                  sinks:      HashMap::new(),
+                                  recordings: HashMap::new(),
+                                  crossfade:  0, })
+          .await
+          .expect("Failed to play");
+
+    let mut latency = None;
+    let mut domains = None;
+    let end = tokio::time::sleep(Duration::from_secs(4));
+    tokio::pin!(end);
+
+    loop {
+      select! {
+        Some(event) = events.recv() => match event {
+          | GraphPlayerEvent::GraphLatencyChanged { latency: changed } => latency = Some(changed),
+          | GraphPlayerEvent::GraphClockDomains { clock_device, domains: stats, .. } => {
+            assert_eq!(clock_device, "a");
+            domains = Some(stats);
+          }
+          | _ => {}
+        },
+        _ = &mut end => break,
+      }
+    }
+
+    // only the last second is checked, the converters are settled by then
+    capture.take();
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    let captured = capture.take();
+
+    handle.stop().await.expect("Failed to stop");
+    devices.terminate_device("a");
+    devices.terminate_device("b");
+    let _ = std::fs::remove_file(&path);
+
+    assert_eq!(latency, Some(convert_frames(256, &b, &a) + bridge_latency(&a, &b)));
+
+    let domains = domains.expect("No clock domains reported");
+    assert_eq!(domains.len(), 1);
+    assert_eq!(domains[0].device_id, "b");
+    assert_eq!(domains[0].latency, bridge_latency(&a, &b));
+    assert!(domains[0].drift_ppm > 150.0 && domains[0].drift_ppm < 260.0, "drift {}", domains[0].drift_ppm);
+
+    // a cycle the player misses leaves a step behind, which the level of the whole second hardly notices
+    let rms = (captured[0].iter().map(|sample| (*sample as f64).powi(2)).sum::<f64>() / captured[0].len() as f64).sqrt();
+    assert!(captured[0].len() > 40_000);
+    assert!((rms - 0.5 / 2f64.sqrt()).abs() < 0.01, "rms {rms}");
+  }
+}
@@ -1,74 +1,370 @@
 use std::collections::HashMap;
+use std::path::PathBuf;
 
-use api::task::graph::{SinkId, SinkSpec};
-use api::task::player::{PlayId, PlayRegion, PlayerControlCommand};
+use anyhow::anyhow;
+use maplit::hashset;
+use tokio::spawn;
+use tokio::task::spawn_blocking;
 
-use crate::player::{GraphPlayer, PlayerCommandOutcome};
-use crate::Result;
+use api::media::spec::MediaId;
+use api::task::graph::modify::GraphValidationFailed;
+use api::task::graph::{InputId, NodeId, RecordingSpec, SinkCodec, SinkId, SinkSpec};
+use api::task::player::{GraphPlaybackError, GraphPlaybackState, GraphPlayerEvent, PlayHead, PlayId, PlayRegion, PlayerControlCommand};
+
+use crate::player::{ControlRequest, GraphPlayer, PlayerCommandOutcome, StoppingSink};
+use crate::sinks::opus_sink_node::OpusSinkNode;
+use crate::sinks::recording_sink_node::RecordingSinkNode;
+use crate::sinks::streaming_sink_node::StreamingSinkNode;
+use crate::{BoxedNode, Result};
+
+const STREAMING_SINK_BITS_PER_SAMPLE: usize = 16;
 
 impl GraphPlayer {
   pub(crate) fn apply_pending_commands(&mut self) -> Result<PlayerCommandOutcome> {
-    let mut outcome = PlayerCommandOutcome::NoAction;
+    let mut outcome = self.remove_faded_out_connections();
+    let mut first_error = None;
 
-    for command in self.pending_commands.drain(..).collect::<Vec<_>>().into_iter() {
-      match command {
-        | PlayerControlCommand::Play { play_id,
-                                       sinks: outputs,
-                                       region,
-                                       start_from, } => {
-          outcome |= self.play(play_id, outputs, region, start_from)?;
+    for ControlRequest { command, ack } in self.pending_commands.drain(..).collect::<Vec<_>>().into_iter() {
+      match self.apply_command(command) {
+        | Ok(command_outcome) => {
+          outcome |= command_outcome;
+          self.pending_acks.extend(ack);
         }
-        | PlayerControlCommand::Stop { play_id } =>
-          if self.play_head.play_id == play_id {
-            outcome |= self.stop();
-          },
-        | PlayerControlCommand::Seek { play_id,
-                                       set_position,
-                                       set_region, } =>
-          if self.play_head.play_id == PlayId::default() || self.play_head.play_id == play_id {
-            if let Some(position) = set_position {
-              self.play_head.position = position;
-            }
-            if let Some(region) = set_region {
-              self.play_head.play_region = region;
-            }
+        | Err(err) => match ack {
+          | Some(ack) => {
+            let _ = ack.send(Err(err));
+          }
+          | None => {
+            first_error.get_or_insert(err);
+          }
+        },
+      }
+    }
 
-            outcome |= PlayerCommandOutcome::Reset;
-          },
-        | PlayerControlCommand::ModifyGraph { modifications } => {
-          for modification in modifications {
-            outcome |= self.apply_graph_modification(modification)?;
+    outcome |= self.update_latency()?;
+
+    match first_error {
+      | Some(err) => Err(err),
+      | None => Ok(outcome),
+    }
+  }
+
+  /// Apply pending commands, then sync or reset the graph as required and acknowledge the applied commands
+  pub(crate) async fn apply_pending_commands_and_sync(&mut self) -> Result {
+    let devices_outcome = match self.pending_audio_devices.take() {
+      | Some(devices) => self.set_audio_devices(devices),
+      | None => PlayerCommandOutcome::NoAction,
+    };
+
+    let result = match self.apply_pending_commands().map(|outcome| outcome | devices_outcome) {
+      | Err(err) => Err(err),
+      | Ok(PlayerCommandOutcome::NoAction) => Ok(()),
+      | Ok(PlayerCommandOutcome::ConnectionSync) => {
+        self.sync_all_connections();
+        Ok(())
+      }
+      | Ok(PlayerCommandOutcome::Reset) => self.reset().await,
+    };
+
+    for ack in self.pending_acks.drain(..) {
+      let _ = ack.send(match &result {
+                 | Ok(()) => Ok(()),
+                 | Err(err) => Err(anyhow!("Failed to apply command: {err}")),
+               });
+    }
+
+    result
+  }
+
+  fn apply_command(&mut self, command: PlayerControlCommand) -> Result<PlayerCommandOutcome> {
+    let mut outcome = PlayerCommandOutcome::NoAction;
+
+    match command {
+      | PlayerControlCommand::Play { play_id,
+                                     sinks: outputs,
+                                     recordings,
+                                     region,
+                                     start_from, } => {
+        outcome |= self.play(play_id, outputs, recordings, region, start_from)?;
+      }
+      | PlayerControlCommand::Stop { play_id } =>
+        if self.play_head.play_id == play_id {
+          outcome |= self.stop();
+        },
+      | PlayerControlCommand::Seek { play_id,
+                                     set_position,
+                                     set_region, } =>
+        if self.play_head.play_id == PlayId::default() || self.play_head.play_id == play_id {
+          if let Some(position) = set_position {
+            self.play_head.position = position;
+          }
+          if let Some(region) = set_region {
+            self.play_head.play_region = region;
+          }
+
+          if self.playback_state != GraphPlaybackState::Stopped {
+            self.set_playback_state(GraphPlaybackState::Buffering(self.play_head.position));
           }
 
-          if !matches!(outcome, PlayerCommandOutcome::NoAction) {
-            self.sync_all_connections();
+          // sources crossfade to the new position by themselves, without a crossfade the graph is reset
+          if self.play_head.play_region.crossfade == 0 {
+            outcome |= PlayerCommandOutcome::Reset;
+          } else {
+            self.prepare_to_seek()?;
           }
+        },
+      | PlayerControlCommand::ModifyGraph { modifications } => {
+        let specs = self.graph_validator(&modifications)
+                        .validate_modifications(&self.specs, &modifications)
+                        .map_err(GraphValidationFailed)?;
+
+        outcome |= self.apply_graph_modifications(modifications)?;
+
+        self.specs = specs;
+
+        if !matches!(outcome, PlayerCommandOutcome::NoAction) {
+          self.sync_all_connections();
         }
       }
     }
 
-    outcome |= self.update_latency()?;
-
     Ok(outcome)
   }
 
   fn play(&mut self,
           play_id: PlayId,
           sinks: HashMap<SinkId, SinkSpec>,
+          recordings: HashMap<SinkId, RecordingSpec>,
           region: PlayRegion,
           start_from: u64)
           -> Result<PlayerCommandOutcome> {
+    let validator = self.graph_validator(&[]);
+    validator.validate_sinks(&self.specs, &sinks).map_err(GraphValidationFailed)?;
+    validator.validate_recordings(&self.specs, &recordings)
+             .map_err(GraphValidationFailed)?;
+
+    // sinks and recordings of the previous play are finished under its play id
+    self.remove_streaming_sinks();
+    self.finish_recordings();
+
     self.play_head.play_id = play_id;
     self.play_head.position = start_from;
+    self.xruns = Default::default();
     self.play_head.play_region = region;
 
-    // TODO: Create outputs
-    // TODO: Set desired state
+    for (sink_id, spec) in sinks {
+      self.add_streaming_sink(sink_id, spec)?;
+    }
+
+    for (sink_id, spec) in recordings {
+      self.add_recording_sink(sink_id, spec)?;
+    }
+
+    self.sync_all_connections();
+
+    self.set_playback_state(GraphPlaybackState::Buffering(start_from));
 
     Ok(PlayerCommandOutcome::Reset)
   }
 
   fn stop(&mut self) -> PlayerCommandOutcome {
-    PlayerCommandOutcome::NoAction
+    // a play that reached the end of its region reported its loudness then
+    if self.playback_state != GraphPlaybackState::Stopped {
+      self.emit_loudness_summary();
+    }
+
+    self.remove_streaming_sinks();
+    self.finish_recordings();
+    self.sync_all_connections();
+
+    self.set_playback_state(GraphPlaybackState::Stopped);
+
+    PlayerCommandOutcome::ConnectionSync
+  }
+
+  fn add_streaming_sink(&mut self, sink_id: SinkId, spec: SinkSpec) -> Result {
+    let node_id = NodeId::StreamingSink(sink_id);
+
+    // the graph sample rate is only known once a device is driving the graph
+    let native_sample_rate = match self.play_head.sample_rate {
+      | 0 => spec.sample_rate,
+      | sample_rate => sample_rate,
+    };
+
+    let num_channels = spec.inputs.len();
+    let (node, captured): (BoxedNode, _) = match spec.codec {
+      | SinkCodec::Flac => {
+        let node = StreamingSinkNode::new(num_channels, spec.sample_rate, native_sample_rate, STREAMING_SINK_BITS_PER_SAMPLE)?;
+        let captured = node.captured();
+        (Box::new(node), captured)
+      }
+      | SinkCodec::Opus { bitrate } => {
+        let node = OpusSinkNode::new(num_channels, native_sample_rate, bitrate)?;
+        let captured = node.captured();
+        (Box::new(node), captured)
+      }
+    };
+
+    self.node_state.insert(node_id,
+                           Self::new_node_state(node_id,
+                                                node.as_ref(),
+                                                self.play_head,
+                                                hashset! {},
+                                                |i| InputId::StreamingSink(sink_id, i),
+                                                spec.inputs)?);
+
+    self.streaming_sinks.insert(sink_id, captured);
+    self.node_apis.insert(node_id, node);
+
+    Ok(())
+  }
+
+  /// Remove the streaming sinks, sending the tail of their encoded output to the capture listener
+  ///
+  /// A sink that is still with a worker is stopped once the worker hands it back, so its stream still ends with the final
+  /// frame.
+  fn remove_streaming_sinks(&mut self) {
+    let play_head = self.play_head;
+
+    for (sink_id, captured) in self.streaming_sinks.drain().collect::<Vec<_>>() {
+      let node_id = NodeId::StreamingSink(sink_id);
+      let processing = self.node_state.remove(&node_id).and_then(|state| state.processing);
+
+      match (self.node_apis.remove(&node_id), processing) {
+        | (Some(node), _) => self.stop_streaming_sink(sink_id, node, play_head, captured),
+        | (None, Some(generation)) => {
+          self.stopping_sinks.insert((sink_id, generation), StoppingSink { play_head, captured });
+        }
+        | (None, None) => self.flush_sink_captured(sink_id, play_head, captured),
+      }
+    }
+  }
+
+  /// Stop a removed streaming sink and send the rest of its encoded output to the capture listener
+  pub(crate) fn stop_streaming_sink(&mut self,
+                                    sink_id: SinkId,
+                                    mut node: BoxedNode,
+                                    play_head: PlayHead,
+                                    captured: crossbeam_channel::Receiver<bytes::Bytes>) {
+    if let Err(err) = node.stop(play_head) {
+      self.handle_node_error(NodeId::StreamingSink(sink_id), err);
+    }
+
+    self.flush_sink_captured(sink_id, play_head, captured);
+  }
+
+  fn add_recording_sink(&mut self, sink_id: SinkId, spec: RecordingSpec) -> Result {
+    let node_id = NodeId::RecordingSink(sink_id);
+
+    let sample_rate = match self.play_head.sample_rate {
+      | 0 => spec.sample_rate,
+      | sample_rate => sample_rate,
+    };
+
+    let media_id = MediaId::new(&spec.app_id);
+    let path = PathBuf::from(self.media_resolver.create(&media_id)?);
+
+    let (node, recording) = RecordingSinkNode::new(path, media_id, spec.inputs.len(), sample_rate, spec.format)?;
+    let node: BoxedNode = Box::new(node);
+
+    self.node_state.insert(node_id,
+                           Self::new_node_state(node_id,
+                                                node.as_ref(),
+                                                self.play_head,
+                                                hashset! {},
+                                                |i| InputId::RecordingSink(sink_id, i),
+                                                spec.inputs)?);
+
+    self.recordings.insert(sink_id, recording);
+    self.node_apis.insert(node_id, node);
+
+    Ok(())
+  }
+
+  /// Remove the recording sinks and report the media once the files are finalized
+  ///
+  /// Dropping a node closes its recording, which is finished on a blocking task so the player is not held up by the disk.
+  fn finish_recordings(&mut self) {
+    let play_id = self.play_head.play_id;
+
+    for (sink_id, recording) in self.recordings.drain() {
+      let node_id = NodeId::RecordingSink(sink_id);
+
+      self.node_apis.remove(&node_id);
+      self.node_state.remove(&node_id);
+
+      let tx_events = self.tx_events.clone();
+
+      spawn(async move {
+        let finished = spawn_blocking(move || recording.finish()).await.map_err(anyhow::Error::from);
+
+        let event = match finished.and_then(|finished| finished) {
+          | Ok(recorded) => GraphPlayerEvent::GraphRecordingFinished { play_id,
+                                                                       sink_id,
+                                                                       media: recorded.media,
+                                                                       num_frames: recorded.num_frames },
+          | Err(err) => GraphPlayerEvent::Error(GraphPlaybackError::RecordingFailed { sink:  sink_id,
+                                                                                      error: err.to_string(), }),
+        };
+
+        let _ = tx_events.send(event).await;
+      });
+    }
+  }
+
+  /// Send the encoded output of a streaming sink to the capture listener
+  ///
+  /// Unlike the other player events, captured output is never dropped: what does not fit in the channel stays buffered
+  /// in the sink until the next cycle. Without a listener it is discarded.
+  pub(crate) fn emit_sink_captured(&self, sink_id: SinkId, play_head: PlayHead) {
+    let Some(rx_captured) = self.streaming_sinks.get(&sink_id) else { return };
+
+    if self.tx_captured.is_closed() {
+      rx_captured.try_iter().for_each(drop);
+      return;
+    }
+
+    while let Ok(permit) = self.tx_captured.try_reserve() {
+      let Ok(data) = rx_captured.try_recv() else { break };
+
+      permit.send(GraphPlayerEvent::GraphSinkCaptured { play_id: play_head.play_id,
+                                                        play_head,
+                                                        sink_id,
+                                                        data });
+    }
+  }
+
+  /// Send what is left of the encoded output of a removed streaming sink, waiting for room in the channel on a separate
+  /// task like the finished recordings do
+  fn flush_sink_captured(&self, sink_id: SinkId, play_head: PlayHead, rx_captured: crossbeam_channel::Receiver<bytes::Bytes>) {
+    let tx_captured = self.tx_captured.clone();
+
+    spawn(async move {
+      for data in rx_captured.try_iter() {
+        let event = GraphPlayerEvent::GraphSinkCaptured { play_id: play_head.play_id,
+                                                          play_head,
+                                                          sink_id,
+                                                          data };
+
+        if tx_captured.send(event).await.is_err() {
+          break;
+        }
+      }
+    });
+  }
+
+  pub(crate) fn set_playback_state(&mut self, state: GraphPlaybackState) {
+    if self.playback_state == state {
+      return;
+    }
+
+    self.playback_state = state.clone();
+
+    let _ = self.tx_events.try_send(GraphPlayerEvent::GraphStateChanged { state });
+  }
+
+  /// True if no device cycle is currently being processed
+  pub(crate) fn is_idle(&self) -> bool {
+    !self.current_work_set.has_device_flips() && self.partial_work_sets.is_empty()
   }
 }
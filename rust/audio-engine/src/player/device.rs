@@ -1,46 +1,77 @@
 use std::collections::HashSet;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use anyhow::bail;
+use itertools::Itertools;
 
-use crate::audio_device::DeviceCommand;
+use api::task::player::GraphPlayerEvent;
+
+use crate::audio_device::{AudioDevices, DeviceCommand};
 use crate::buffer::DeviceBuffers;
-use crate::player::GraphPlayer;
+use crate::player::clock_domain::{bridge_latency, convert_frames, ClockDomain};
+use crate::player::{GraphPlayer, PlayerCommandOutcome};
 use crate::Result;
 
 impl GraphPlayer {
   pub(crate) async fn device_flip_buffers(&mut self,
                                           device_id: String,
                                           buffers: DeviceBuffers,
-                                          _generation: u64,
+                                          generation: u64,
                                           deadline: Instant)
                                           -> Result {
-    if self.current_work_set.device_flips_started.contains_key(&device_id) {
+    // bridged devices only exchange samples with their converters, the graph cycles with the clock device
+    if let Some(domain) = self.clock_domains.get_mut(&device_id) {
+      domain.device_flip(&buffers, deadline);
+
+      return self.audio_devices
+                 .send_command(&device_id, DeviceCommand::FlipFinished { client_id: self.client_id.clone(),
+                                                                         generation });
+    }
+
+    // a device the graph stopped using can have flips queued still
+    if self.audio_devices.clock_device() != Some(device_id.as_str()) {
+      return Ok(());
+    }
+
+    if self.current_work_set.device_flip(&device_id).is_some() {
       bail!("Device {} already has a flip in progress in the current WorkSet", device_id)
     }
 
-    self.current_work_set.device_flips_started.insert(device_id.clone(), buffers);
+    if let Ok(info) = self.audio_devices.get_info(&device_id) {
+      let cycle_time = Duration::from_secs_f64(buffers.buffer_size as f64 / info.sample_rate as f64);
+      self.current_work_set.cycle_time = self.current_work_set.cycle_time.max(cycle_time);
+    }
+
+    self.current_work_set.start_device_flip(&device_id, buffers);
     self.current_work_set.deadline = self.current_work_set
                                          .deadline
                                          .map(|prev_deadline| prev_deadline.min(deadline))
                                          .or(Some(deadline));
 
+    // the bridged devices flip along with the clock device
+    for (bridged_id, domain) in &mut self.clock_domains {
+      let proxy = domain.graph_cycle(buffers.generation, deadline);
+      self.current_work_set.start_device_flip(bridged_id, proxy);
+    }
+
     // add nodes to execute - it will be monitors and inserts
+    let work_set = &mut self.current_work_set;
     for (node_id, node) in &self.node_state {
-      if node.audio_device_requirements.contains(&device_id) {
-        self.current_work_set.nodes_to_execute.insert(*node_id);
+      if node.audio_device_requirements.iter().any(|required| work_set.device_flip(required).is_some()) {
+        work_set.nodes_to_execute.insert(*node_id);
       }
     }
 
     loop {
-      let mut to_add = HashSet::new();
+      let to_add = &mut work_set.scratch;
+      to_add.clear();
 
-      for node_id in &self.current_work_set.nodes_to_execute {
+      for node_id in &work_set.nodes_to_execute {
         let Some(node) = self.node_state.get(node_id) else { continue };
 
         for input_id in &node.node_requirements {
-          if !self.current_work_set.nodes_to_execute.contains(input_id) {
-            to_add.insert(*input_id);
+          if !work_set.nodes_to_execute.contains(input_id) {
+            to_add.push(*input_id);
           }
         }
       }
@@ -48,7 +79,7 @@ impl GraphPlayer {
       if to_add.is_empty() {
         break;
       } else {
-        self.current_work_set.nodes_to_execute.extend(to_add);
+        work_set.nodes_to_execute.extend(to_add.drain(..));
       }
     }
 
@@ -76,11 +107,93 @@ impl GraphPlayer {
     Ok(())
   }
 
+  /// The devices used by the nodes, and the clock device, which drives the graph even if no node uses it
   pub(crate) fn referenced_device_ids(&mut self) -> HashSet<String> {
     self.node_state
         .values()
         .flat_map(|n| n.audio_device_requirements.iter())
-        .cloned()
+        .map(String::as_str)
+        .chain(self.audio_devices.clock_device())
+        .map(str::to_owned)
         .collect::<HashSet<_>>()
   }
+
+  /// Switch to a new set of open devices, the graph needs a reset if the clock device or a device it uses changed
+  pub(crate) fn set_audio_devices(&mut self, devices: AudioDevices) -> PlayerCommandOutcome {
+    let referenced = self.referenced_device_ids();
+    let changed = self.audio_devices.clock_device() != devices.clock_device()
+                  || referenced.iter()
+                               .any(|device_id| !self.audio_devices.is_same_device(&devices, device_id));
+
+    if changed {
+      // the reset only unsubscribes from the devices the graph still uses, the others would keep waiting on the player
+      for device_id in &referenced {
+        let _ = self.audio_devices
+                    .send_command(device_id, DeviceCommand::Unregister { client_id: self.client_id.clone(), });
+      }
+    }
+
+    self.audio_devices = devices;
+
+    PlayerCommandOutcome::from_needs_reset(changed)
+  }
+
+  /// Bridge every device other than the clock device to the clock
+  ///
+  /// Domains of devices whose info did not change keep their converters and measured drift, the others start afresh.
+  pub(crate) fn sync_clock_domains(&mut self, all_devices: &HashSet<String>) -> Result {
+    let Some(clock_device) = self.audio_devices.clock_device().map(str::to_owned) else {
+      self.clock_domains.clear();
+      return Ok(());
+    };
+    let clock = self.audio_devices.get_info(&clock_device)?;
+
+    let audio_devices = &self.audio_devices;
+    self.clock_domains.retain(|device_id, domain| {
+                        *device_id != clock_device
+                        && all_devices.contains(device_id)
+                        && audio_devices.get_info(device_id).is_ok_and(|info| domain.bridges(&clock, &info))
+                      });
+
+    for device_id in all_devices {
+      if *device_id != clock_device && !self.clock_domains.contains_key(device_id) {
+        let info = self.audio_devices.get_info(device_id)?;
+        self.clock_domains.insert(device_id.clone(), ClockDomain::new(device_id.clone(), &clock, &info));
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Round trip latency of a device at the graph sample rate, including the bridge to the clock device if it needs one
+  pub(crate) fn device_latency(&self, device_id: &str) -> Result<usize> {
+    let info = self.audio_devices.get_info(device_id)?;
+
+    match self.audio_devices.clock_device() {
+      | Some(clock_device) if clock_device != device_id => {
+        let clock = self.audio_devices.get_info(clock_device)?;
+        Ok(convert_frames(info.latency as usize, &info, &clock) + bridge_latency(&clock, &info))
+      }
+      | _ => Ok(info.latency as usize),
+    }
+  }
+
+  /// Report the latency and drift of the bridged devices, if the graph uses any
+  pub(crate) fn emit_clock_domains(&self) {
+    let Some(clock_device) = self.audio_devices.clock_device() else { return };
+    if self.clock_domains.is_empty() {
+      return;
+    }
+
+    let domains = self.clock_domains
+                      .values()
+                      .map(ClockDomain::stats)
+                      .sorted_by(|a, b| a.device_id.cmp(&b.device_id))
+                      .collect();
+
+    let _ = self.tx_events
+                .try_send(GraphPlayerEvent::GraphClockDomains { play_id: self.play_head.play_id,
+                                                                clock_device: clock_device.to_owned(),
+                                                                domains });
+  }
 }
@@ -0,0 +1,201 @@
+//! Resolvers, nodes and renderers shared by the player tests
+
+use std::f64::consts::TAU;
+use std::fs;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::bail;
+
+use api::instance::spec::SetParameterCommand;
+use api::media::spec::MediaId;
+use api::task::graph::{AudioGraphSpec, OutputId};
+use api::task::player::{LoudnessSummary, NodeEvent, NodeInfo, PlayHead};
+
+use crate::buffer::{DevicesBuffers, NodeBuffers};
+use crate::player::offline::{OfflineRenderRequest, OfflineRenderer};
+use crate::player::{DeviceInstanceAttachment, DeviceInstanceResolver, MediaResolver};
+use crate::virtual_inserts::VirtualInsertRegistry;
+use crate::wav::WavSampleFormat;
+use crate::{BoxedNode, Node, Result};
+
+/// Virtual insert model of a [ConstantNode] in the graphs of [renderer]
+pub(crate) const CONSTANT_MODEL_ID: &str = "test_constant";
+/// Virtual insert model of an [ImpulseNode] in the graphs of [renderer]
+pub(crate) const IMPULSE_MODEL_ID: &str = "test_impulse";
+/// Virtual insert model of a [SineNode] in the graphs of [renderer]
+pub(crate) const SINE_MODEL_ID: &str = "test_sine";
+/// Virtual insert model of a [SlowNode] in the graphs of [renderer]
+pub(crate) const SLOW_MODEL_ID: &str = "test_slow";
+/// How long a [SlowNode] takes to process a buffer
+pub(crate) const SLOW_NODE_DELAY: Duration = Duration::from_millis(100);
+
+/// Resolves no media at all, for graphs without sources
+pub(crate) struct NoMedia;
+
+impl MediaResolver for NoMedia {
+  fn resolve(&self, media_id: &MediaId) -> Result<String> {
+    bail!("No media {media_id}")
+  }
+
+  fn create(&self, media_id: &MediaId) -> Result<String> {
+    bail!("No media {media_id}")
+  }
+}
+
+/// Resolves no device instances at all, for graphs without device inserts
+pub(crate) struct NoInstances;
+
+impl DeviceInstanceResolver for NoInstances {
+  fn resolve(&self, instance_id: &str) -> Result<DeviceInstanceAttachment> {
+    bail!("No instance {instance_id}")
+  }
+}
+
+/// Outputs a constant on every channel, ignoring the inputs
+pub(crate) struct ConstantNode(pub usize);
+
+impl Node for ConstantNode {
+  fn set_parameter(&mut self, _parameter: &SetParameterCommand) {}
+
+  fn get_node_info(&self, _play: PlayHead) -> NodeInfo {
+    NodeInfo { num_inputs: self.0,
+               num_outputs: self.0,
+               ..Default::default() }
+  }
+
+  fn loudness_summary(&self) -> Option<LoudnessSummary> {
+    Some(LoudnessSummary { integrated: -6.0,
+                           ..Default::default() })
+  }
+
+  fn process(&mut self,
+             _play: PlayHead,
+             _devices: DevicesBuffers,
+             io: NodeBuffers,
+             _deadline: Instant,
+             _events: &mut Vec<NodeEvent>)
+             -> Result {
+    io.outputs().for_each(|output| output.iter_mut().for_each(|sample| *sample = 0.5));
+    Ok(())
+  }
+}
+
+/// Outputs a single sample at the start of the play region
+pub(crate) struct ImpulseNode(pub usize);
+
+impl Node for ImpulseNode {
+  fn set_parameter(&mut self, _parameter: &SetParameterCommand) {}
+
+  fn get_node_info(&self, _play: PlayHead) -> NodeInfo {
+    NodeInfo { num_inputs: self.0,
+               num_outputs: self.0,
+               ..Default::default() }
+  }
+
+  fn process(&mut self,
+             play: PlayHead,
+             _devices: DevicesBuffers,
+             io: NodeBuffers,
+             _deadline: Instant,
+             _events: &mut Vec<NodeEvent>)
+             -> Result {
+    for output in io.outputs() {
+      output.iter_mut().for_each(|sample| *sample = 0.0);
+      if play.position == play.play_region.start {
+        output[0] = 0.5;
+      }
+    }
+    Ok(())
+  }
+}
+
+/// Outputs a 1 kHz sine at -23 dBFS on every channel, the EBU reference for -23 LUFS in stereo
+pub(crate) struct SineNode(pub usize);
+
+impl Node for SineNode {
+  fn set_parameter(&mut self, _parameter: &SetParameterCommand) {}
+
+  fn get_node_info(&self, _play: PlayHead) -> NodeInfo {
+    NodeInfo { num_inputs: self.0,
+               num_outputs: self.0,
+               ..Default::default() }
+  }
+
+  fn process(&mut self,
+             play: PlayHead,
+             _devices: DevicesBuffers,
+             io: NodeBuffers,
+             _deadline: Instant,
+             _events: &mut Vec<NodeEvent>)
+             -> Result {
+    let amplitude = 10f64.powf(-23.0 / 20.0);
+    let step = 1_000.0 / play.sample_rate as f64 * TAU;
+
+    for output in io.outputs() {
+      for (i, sample) in output.iter_mut().enumerate() {
+        *sample = ((play.position + i as u64) as f64 * step).sin() * amplitude;
+      }
+    }
+    Ok(())
+  }
+}
+
+/// Outputs a constant like [ConstantNode], but blocks its worker for [SLOW_NODE_DELAY] first
+pub(crate) struct SlowNode(pub usize);
+
+impl Node for SlowNode {
+  fn set_parameter(&mut self, _parameter: &SetParameterCommand) {}
+
+  fn get_node_info(&self, _play: PlayHead) -> NodeInfo {
+    NodeInfo { num_inputs: self.0,
+               num_outputs: self.0,
+               ..Default::default() }
+  }
+
+  fn process(&mut self,
+             _play: PlayHead,
+             _devices: DevicesBuffers,
+             io: NodeBuffers,
+             _deadline: Instant,
+             _events: &mut Vec<NodeEvent>)
+             -> Result {
+    thread::sleep(SLOW_NODE_DELAY);
+    io.outputs().for_each(|output| output.iter_mut().for_each(|sample| *sample = 0.5));
+    Ok(())
+  }
+}
+
+/// Render a graph at 48 kHz from the start of the timeline, with the test nodes registered as virtual insert models
+pub(crate) fn renderer(spec: AudioGraphSpec, end: u64, buffer_size: u32, outputs: Vec<Vec<OutputId>>, path: PathBuf) -> OfflineRenderer {
+  let mut virtual_inserts = VirtualInsertRegistry::default();
+  virtual_inserts.register(CONSTANT_MODEL_ID, |num_channels| Ok(Box::new(ConstantNode(num_channels)) as BoxedNode));
+  virtual_inserts.register(IMPULSE_MODEL_ID, |num_channels| Ok(Box::new(ImpulseNode(num_channels)) as BoxedNode));
+  virtual_inserts.register(SINE_MODEL_ID, |num_channels| Ok(Box::new(SineNode(num_channels)) as BoxedNode));
+  virtual_inserts.register(SLOW_MODEL_ID, |num_channels| Ok(Box::new(SlowNode(num_channels)) as BoxedNode));
+
+  OfflineRenderer::with_virtual_inserts(Box::new(NoMedia),
+                                        virtual_inserts,
+                                        spec,
+                                        OfflineRenderRequest { play_id: 1,
+                                                               start: 0,
+                                                               end,
+                                                               sample_rate: 48_000,
+                                                               buffer_size,
+                                                               outputs,
+                                                               sinks: Default::default(),
+                                                               format: WavSampleFormat::Float32,
+                                                               path }).expect("Failed to create renderer")
+}
+
+/// The samples of a rendered 32 bit float WAV file, interleaved
+pub(crate) fn read_float_wav(path: &PathBuf) -> Vec<f32> {
+  let data = fs::read(path).expect("Failed to read rendered file");
+  assert_eq!(&data[0..4], b"RIFF");
+  assert_eq!(&data[36..40], b"data");
+
+  data[44..].chunks_exact(4)
+            .map(|chunk| f32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+            .collect()
+}
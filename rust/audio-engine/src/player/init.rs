@@ -5,14 +5,17 @@ use tokio::sync::mpsc;
 
 use api::task::graph::modify::AudioGraphModification;
 use api::task::graph::{AudioGraphSpec, InputId, NodeId, OutputId};
-use api::task::player::{GraphPlayerEvent, PlayHead};
+use api::task::player::{GraphPlaybackState, GraphPlayerEvent, PlayHead};
 
 use crate::audio_device::AudioDevices;
 use crate::buffer::NodeBuffers;
 use crate::player::work_set::WorkSet;
+use crate::player::worker_pool::{completion_queue, WorkerPool};
 use crate::player::{
-  BoxedDeviceInstanceResolver, BoxedMediaResolver, GraphPlayer, PlayerControlCommand, PlayerNodeState, PlayerParameterCommand,
+  BoxedDeviceInstanceResolver, BoxedMediaResolver, ControlRequest, GraphPlayer, PlayerControlCommand, PlayerNodeState,
+  PlayerParameterCommand,
 };
+use crate::virtual_inserts::VirtualInsertRegistry;
 use crate::{Node, Result};
 
 impl GraphPlayer {
@@ -20,13 +23,48 @@ impl GraphPlayer {
              use_media_resolver: BoxedMediaResolver,
              use_device_instance_resolver: BoxedDeviceInstanceResolver,
              spec: AudioGraphSpec,
-             rx_control_ch: mpsc::Receiver<PlayerControlCommand>,
+             rx_control_ch: mpsc::Receiver<ControlRequest>,
              rx_params_ch: mpsc::Receiver<PlayerParameterCommand>,
-             tx_events_ch: mpsc::Sender<GraphPlayerEvent>)
+             tx_events_ch: mpsc::Sender<GraphPlayerEvent>,
+             tx_captured_ch: mpsc::Sender<GraphPlayerEvent>)
              -> Result<Self> {
+    // the graph runs at the sample rate and buffer size of the device clocking it
+    let play_head = devices.clock_device()
+                           .and_then(|device_id| devices.get_info(device_id).ok())
+                           .map(|info| PlayHead { sample_rate: info.sample_rate,
+                                                  buffer_size: info.buffer_size,
+                                                  ..Default::default() })
+                           .unwrap_or_default();
+
+    Self::new_with(devices,
+                   use_media_resolver,
+                   use_device_instance_resolver,
+                   VirtualInsertRegistry::default(),
+                   play_head,
+                   spec,
+                   rx_control_ch,
+                   rx_params_ch,
+                   tx_events_ch,
+                   tx_captured_ch)
+  }
+
+  /// Create a player with a custom virtual insert registry and an initial play head
+  ///
+  /// The play head sample rate and buffer size are used to allocate the node buffers.
+  pub(crate) fn new_with(devices: AudioDevices,
+                         use_media_resolver: BoxedMediaResolver,
+                         use_device_instance_resolver: BoxedDeviceInstanceResolver,
+                         use_virtual_inserts: VirtualInsertRegistry,
+                         play_head: PlayHead,
+                         spec: AudioGraphSpec,
+                         rx_control_ch: mpsc::Receiver<ControlRequest>,
+                         rx_params_ch: mpsc::Receiver<PlayerParameterCommand>,
+                         tx_events_ch: mpsc::Sender<GraphPlayerEvent>,
+                         tx_captured_ch: mpsc::Sender<GraphPlayerEvent>)
+                         -> Result<Self> {
     let (tx_device_ch, rx_device_ch) = mpsc::channel(0xff);
-    let (tx_tasks_ch, rx_tasks_ch) = mpsc::channel(0xff);
-    let play_head = PlayHead::default();
+    let (tx_tasks_ch, rx_tasks_ch) = completion_queue();
+    let (tx_audio_devices_ch, rx_audio_devices_ch) = mpsc::channel(0xf);
     let work_set = WorkSet::from(play_head);
 
     let mut rv = Self { client_id:                nanoid!(),
@@ -37,17 +75,36 @@ impl GraphPlayer {
                         tx_device:                tx_device_ch,
                         rx_device:                rx_device_ch,
                         rx_params:                rx_params_ch,
+                        tx_audio_devices:         tx_audio_devices_ch,
+                        rx_audio_devices:         rx_audio_devices_ch,
                         tx_tasks:                 tx_tasks_ch,
                         rx_tasks:                 rx_tasks_ch,
                         tx_events:                tx_events_ch,
-                        play_head:                Default::default(),
+                        tx_captured:              tx_captured_ch,
+                        play_head,
                         node_state:               Default::default(),
                         audio_devices:            devices,
+                        pending_audio_devices:    None,
                         current_work_set:         work_set,
                         partial_work_sets:        Default::default(),
+                        spare_work_sets:          Default::default(),
+                        spare_job_vecs:           Default::default(),
                         pending_commands:         Default::default(),
+                        pending_acks:             Default::default(),
+                        playback_state:           GraphPlaybackState::Stopped,
                         media_resolver:           use_media_resolver,
-                        device_instance_resolver: use_device_instance_resolver, };
+                        device_instance_resolver: use_device_instance_resolver,
+                        virtual_inserts:          use_virtual_inserts,
+                        streaming_sinks:          Default::default(),
+                        stopping_sinks:           Default::default(),
+                        recordings:               Default::default(),
+                        graph_latency:            0,
+                        xruns:                    Default::default(),
+                        processing_times:         Default::default(),
+                        workers:                  WorkerPool::shared(),
+                        pending_parameters:       Default::default(),
+                        automation:               Default::default(),
+                        clock_domains:            Default::default(), };
 
     let mut modifications = vec![];
 
@@ -64,7 +121,8 @@ impl GraphPlayer {
       modifications.push(AudioGraphModification::AddOrReplaceVirtualInsert { insert_id, insert_spec });
     }
 
-    rv.pending_commands.push_back(PlayerControlCommand::ModifyGraph { modifications });
+    rv.pending_commands
+      .push_back(PlayerControlCommand::ModifyGraph { modifications }.into());
 
     rv.apply_pending_commands()?;
 
@@ -78,7 +136,7 @@ impl GraphPlayer {
                                               gen_input_ids: GenInputId,
                                               inputs: Vec<Vec<OutputId>>)
                                               -> Result<PlayerNodeState>
-    where N: Node,
+    where N: Node + ?Sized,
           GenInputId: Fn(usize) -> InputId
   {
     let node_info = node.get_node_info(play_head);
@@ -1,32 +1,52 @@
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::ops::{BitOr, BitOrAssign};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use anyhow::anyhow;
 use tokio::sync::mpsc::{Receiver, Sender};
-use tokio::sync::{mpsc, RwLock};
+use tokio::sync::{mpsc, oneshot};
 use tokio::{select, spawn};
 
+use api::instance::spec::SetParameterCommand;
 use api::media::spec::MediaId;
-use api::task::graph::{AudioGraphSpec, InputId, NodeId, OutputId};
-use api::task::player::{GraphPlayerEvent, NodeEvent, PlayHead, PlayId, PlayerControlCommand, PlayerParameterCommand};
+use api::task::graph::{AudioGraphSpec, InputId, NodeId, OutputId, SinkId};
+use api::task::player::{
+  GraphPlaybackState, GraphPlayerEvent, NodeEvent, PlayHead, PlayId, PlayRegion, PlayerControlCommand, PlayerParameterCommand, XRunStats,
+};
 use api::task::PlayRequest;
 
 use crate::audio_device::{AudioDevices, DeviceClientCommand};
+use crate::automation::{AutomationLanes, ScheduledParameter};
 use crate::buffer::NodeBuffers;
 use crate::connection::Connection;
+use crate::player::clock_domain::ClockDomain;
+use crate::player::processing_times::ProcessingTimes;
 use crate::player::work_set::WorkSet;
+use crate::player::worker_pool::{CompletionReceiver, CompletionSender, SpareJobVecs, WorkerPool};
+use crate::sinks::recording_sink_node::RecordingHandle;
+use crate::virtual_inserts::VirtualInsertRegistry;
 use crate::BoxedNode;
 use crate::{NodeInfo, Result};
 
+mod clock_domain;
 mod command;
 mod device;
 mod error;
+#[cfg(test)]
+pub(crate) mod fixtures;
 mod init;
+pub mod offline;
+mod processing_times;
 mod structure;
 mod work_set;
+pub mod worker_pool;
 
 pub trait MediaResolver: Send + Sync {
   fn resolve(&self, media_id: &MediaId) -> Result<String>;
+
+  /// Path at which new media is written, with the folders leading to it created
+  fn create(&self, media_id: &MediaId) -> Result<String>;
 }
 pub type BoxedMediaResolver = Box<dyn MediaResolver>;
 
@@ -47,40 +67,78 @@ pub struct GraphPlayer {
   pub(crate) client_id:                String,
   /// current specifications
   pub(crate) specs:                    AudioGraphSpec,
-  /// materialized nodes
-  pub(crate) node_apis:                HashMap<NodeId, Arc<RwLock<BoxedNode>>>,
+  /// materialized nodes, a node is missing while a worker is processing it
+  pub(crate) node_apis:                HashMap<NodeId, BoxedNode>,
   /// connection buffers
   pub(crate) connections:              HashMap<(OutputId, InputId), Connection>,
   /// Receive control messages
-  pub(crate) rx_control:               Receiver<PlayerControlCommand>,
+  pub(crate) rx_control:               Receiver<ControlRequest>,
   /// Send device commands (clone when subscribing to device)
   pub(crate) tx_device:                Sender<DeviceClientCommand>,
   /// Receive device commands
   pub(crate) rx_device:                Receiver<DeviceClientCommand>,
   /// Receive parameter updates
   pub(crate) rx_params:                Receiver<PlayerParameterCommand>,
-  /// Send internal updates from spawned tasks
-  pub(crate) tx_tasks:                 Sender<InternalTaskEvent>,
-  /// Receive internal updates from spawned tasks
-  pub(crate) rx_tasks:                 Receiver<InternalTaskEvent>,
+  /// Send audio device updates (cloned into the handle)
+  pub(crate) tx_audio_devices:         Sender<AudioDevices>,
+  /// Receive audio device updates
+  pub(crate) rx_audio_devices:         Receiver<AudioDevices>,
+  /// Send internal updates from the workers
+  pub(crate) tx_tasks:                 CompletionSender,
+  /// Receive internal updates from the workers
+  pub(crate) rx_tasks:                 CompletionReceiver,
   /// Send player events
   pub(crate) tx_events:                Sender<GraphPlayerEvent>,
+  /// Send the encoded output of the streaming sinks, which is never dropped
+  pub(crate) tx_captured:              Sender<GraphPlayerEvent>,
   /// Play head
   pub(crate) play_head:                PlayHead,
   /// Node info and buffers
   pub(crate) node_state:               HashMap<NodeId, PlayerNodeState>,
   /// Audio devices to send commands to
   pub(crate) audio_devices:            AudioDevices,
+  /// Audio devices to switch to once the current cycle finished
+  pub(crate) pending_audio_devices:    Option<AudioDevices>,
   /// Current work set
   pub(crate) current_work_set:         WorkSet,
   /// Partial work sets that have pending
   pub(crate) partial_work_sets:        VecDeque<WorkSet>,
+  /// Finished work sets, reused for the next cycles so their collections keep their capacity
+  pub(crate) spare_work_sets:          Vec<WorkSet>,
+  /// Vectors of completed jobs, reused for the next jobs
+  pub(crate) spare_job_vecs:           SpareJobVecs,
   /// Pending structural changes
-  pub(crate) pending_commands:         VecDeque<PlayerControlCommand>,
+  pub(crate) pending_commands:         VecDeque<ControlRequest>,
+  /// Acknowledgements to send once the pending commands have been applied
+  pub(crate) pending_acks:             Vec<oneshot::Sender<Result>>,
+  /// Current playback state, as last reported to the event listeners
+  pub(crate) playback_state:           GraphPlaybackState,
   /// Media resolver
   pub(crate) media_resolver:           Box<dyn MediaResolver>,
   /// Device instance resolver
   pub(crate) device_instance_resolver: Box<dyn DeviceInstanceResolver>,
+  /// Virtual insert models
+  pub(crate) virtual_inserts:          VirtualInsertRegistry,
+  /// Encoded output of the streaming sinks created for the current play
+  pub(crate) streaming_sinks:          HashMap<SinkId, crossbeam_channel::Receiver<bytes::Bytes>>,
+  /// Streaming sinks removed while a worker was processing them, stopped once the worker hands them back
+  pub(crate) stopping_sinks:           HashMap<(SinkId, u64), StoppingSink>,
+  /// Media written by the recording sinks created for the current play
+  pub(crate) recordings:               HashMap<SinkId, RecordingHandle>,
+  /// Total latency of the graph, as last reported to the event listeners
+  pub(crate) graph_latency:            usize,
+  /// Deadline misses since the play started
+  pub(crate) xruns:                    XRunStats,
+  /// Node processing times since the last report
+  pub(crate) processing_times:         ProcessingTimes,
+  /// Threads processing the nodes
+  pub(crate) workers:                  Arc<WorkerPool>,
+  /// Parameter changes for nodes that were processing when the changes arrived
+  pub(crate) pending_parameters:       HashMap<NodeId, Vec<SetParameterCommand>>,
+  /// Parameter events of the nodes, applied whenever the play head passes them
+  pub(crate) automation:               HashMap<NodeId, AutomationLanes>,
+  /// Devices used by the graph that are bridged to the clock device, by device id
+  pub(crate) clock_domains:            HashMap<String, ClockDomain>,
 }
 
 #[derive(Debug)]
@@ -103,27 +161,129 @@ pub struct PlayerNodeState {
   pub(crate) accumulated_latency:       usize,
 }
 
+/// A streaming sink that was removed while a worker was processing it
+#[derive(Debug)]
+pub(crate) struct StoppingSink {
+  /// Play head of the play the sink was removed from
+  pub(crate) play_head: PlayHead,
+  /// Encoded output of the sink
+  pub(crate) captured:  crossbeam_channel::Receiver<bytes::Bytes>,
+}
+
+/// A control command sent to the player, optionally with a channel on which the player acknowledges it
+#[derive(Debug)]
+pub struct ControlRequest {
+  /// The command to apply
+  pub command: PlayerControlCommand,
+  /// If present, the player will send the result once the command has been applied
+  pub ack:     Option<oneshot::Sender<Result>>,
+}
+
+impl From<PlayerControlCommand> for ControlRequest {
+  fn from(command: PlayerControlCommand) -> Self {
+    Self { command, ack: None }
+  }
+}
+
 #[derive(Debug)]
 pub struct GraphPlayerHandle {
-  play_id:    PlayId,
-  tx_params:  Sender<PlayerParameterCommand>,
-  tx_control: Sender<PlayerControlCommand>,
-  rx_events:  Receiver<GraphPlayerEvent>,
+  play_id:          PlayId,
+  tx_params:        Sender<PlayerParameterCommand>,
+  tx_control:       Sender<ControlRequest>,
+  tx_audio_devices: Sender<AudioDevices>,
+  rx_events:        Option<Receiver<GraphPlayerEvent>>,
+  rx_captured:      Option<Receiver<GraphPlayerEvent>>,
 }
 
 impl GraphPlayerHandle {
+  /// Start playing the graph, replacing any previous play request
   pub async fn set_play(&mut self, request: PlayRequest) -> Result {
-    todo!()
+    let PlayRequest { play_id,
+                      start,
+                      end,
+                      start_from,
+                      looping,
+                      sinks,
+                      recordings,
+                      crossfade, } = request;
+
+    self.control(PlayerControlCommand::Play { play_id,
+                                              sinks,
+                                              recordings,
+                                              region: PlayRegion { start,
+                                                                   end,
+                                                                   looping,
+                                                                   crossfade },
+                                              start_from })
+        .await?;
+
+    self.play_id = play_id;
+
+    Ok(())
   }
 
+  /// Stop playing the graph
   pub async fn stop(&mut self) -> Result {
-    todo!()
+    self.control(PlayerControlCommand::Stop { play_id: self.play_id }).await
   }
 
+  /// Move the play head to a new position within the current play region
   pub async fn seek(&mut self, seek_to: u64) -> Result {
-    todo!()
+    self.control(PlayerControlCommand::Seek { play_id:      self.play_id,
+                                              set_position: Some(seek_to),
+                                              set_region:   None, })
+        .await
+  }
+
+  /// Send a parameter change to a node in the graph
+  pub async fn set_parameters(&self, command: PlayerParameterCommand) -> Result {
+    self.tx_params
+        .send(command)
+        .await
+        .map_err(|_| anyhow!("Player is no longer running"))
+  }
+
+  /// Switch the player to the devices as they are open now
+  ///
+  /// The graph is reset when the clock device or any device it uses was opened, closed or reopened. The player keeps the
+  /// sample rate and buffer size it was created with, so a clock device with a different format needs a new player.
+  pub async fn set_audio_devices(&self, devices: AudioDevices) -> Result {
+    self.tx_audio_devices
+        .send(devices)
+        .await
+        .map_err(|_| anyhow!("Player is no longer running"))
+  }
+
+  /// Take the receiving end of the player event stream.
+  ///
+  /// Returns `None` if it was already taken. The player never waits for the consumer: events it emits while the channel is
+  /// full are dropped. Only finished recordings, which are reported from a separate task, wait for room in the channel.
+  /// The encoded output of the streaming sinks is sent on its own stream, see [GraphPlayerHandle::take_captured].
+  pub fn take_events(&mut self) -> Option<Receiver<GraphPlayerEvent>> {
+    self.rx_events.take()
+  }
+
+  /// Take the receiving end of the encoded output of the streaming sinks, as `GraphSinkCaptured` events
+  ///
+  /// Returns `None` if it was already taken. Nothing is dropped: while the channel is full the output stays buffered in the
+  /// sinks. Once the receiver is dropped the output is discarded.
+  pub fn take_captured(&mut self) -> Option<Receiver<GraphPlayerEvent>> {
+    self.rx_captured.take()
   }
 
+  async fn control(&self, command: PlayerControlCommand) -> Result {
+    let (tx_ack, rx_ack) = oneshot::channel();
+
+    self.tx_control
+        .send(ControlRequest { command,
+                               ack: Some(tx_ack) })
+        .await
+        .map_err(|_| anyhow!("Player is no longer running"))?;
+
+    rx_ack.await.map_err(|_| anyhow!("Player stopped before acknowledging the command"))?
+  }
+
+  /// Start a player for the graph on the devices, it runs until the handle is dropped
   pub fn new(devices: AudioDevices,
              media_resolver: BoxedMediaResolver,
              device_instance_resolver: BoxedDeviceInstanceResolver,
@@ -132,23 +292,30 @@ impl GraphPlayerHandle {
     let (tx_control, rx_control) = mpsc::channel(0x100);
     let (tx_params, rx_params) = mpsc::channel(0x100);
     let (tx_events, rx_events) = mpsc::channel(0x100);
+    let (tx_captured, rx_captured) = mpsc::channel(0x100);
 
-    let mut rv = GraphPlayer::new(devices,
-                                  media_resolver,
-                                  device_instance_resolver,
-                                  spec,
-                                  rx_control,
-                                  rx_params,
-                                  tx_events)?;
+    let rv = GraphPlayer::new(devices,
+                              media_resolver,
+                              device_instance_resolver,
+                              spec,
+                              rx_control,
+                              rx_params,
+                              tx_events,
+                              tx_captured)?;
 
     let play_id = PlayId::default();
+    let rx_events = Some(rx_events);
+    let rx_captured = Some(rx_captured);
+    let tx_audio_devices = rv.tx_audio_devices.clone();
 
     spawn(rv.run());
 
     Ok(Self { play_id,
               tx_params,
               tx_control,
-              rx_events })
+              tx_audio_devices,
+              rx_events,
+              rx_captured })
   }
 }
 
@@ -156,8 +323,10 @@ impl GraphPlayer {
   pub async fn run(mut self) {
     loop {
       select! {
-        Some(control_msg) = self.rx_control.recv() => {
-          self.handle_control_cmd(control_msg);
+        maybe_control_msg = self.rx_control.recv() => {
+          // the handle was dropped, nothing can control the player any more
+          let Some(control_msg) = maybe_control_msg else { break };
+          self.handle_control_cmd(control_msg).await;
         },
         Some(device_msg) = self.rx_device.recv() => {
           self.handle_device_cmd(device_msg).await;
@@ -165,12 +334,30 @@ impl GraphPlayer {
         Some(params_msg) = self.rx_params.recv() => {
           self.handle_params_cmd(params_msg);
         }
+        Some(devices) = self.rx_audio_devices.recv() => {
+          self.handle_audio_devices(devices).await;
+        }
         Some(task_msg) = self.rx_tasks.recv() => {
           self.handle_task_msg(task_msg).await;
         }
+        _ = Self::deadline_elapsed(self.current_work_set.deadline), if self.current_work_set.deadline.is_some() => {
+          if let Err(err) = self.deadline_missed().await {
+            self.handle_error(err);
+          }
+        }
         else => break,
       }
     }
+
+    let devices = self.referenced_device_ids();
+    if let Err(err) = self.unsubscribe_from_devices(&devices) {
+      self.handle_error(err);
+    }
+  }
+
+  async fn deadline_elapsed(deadline: Option<Instant>) {
+    let deadline = deadline.unwrap_or_else(Instant::now);
+    tokio::time::sleep_until(deadline.into()).await
   }
 
   async fn handle_device_cmd(&mut self, cmd: DeviceClientCommand) {
@@ -187,40 +374,78 @@ impl GraphPlayer {
     }
   }
 
-  fn handle_control_cmd(&mut self, cmd: PlayerControlCommand) {
+  async fn handle_control_cmd(&mut self, cmd: ControlRequest) {
     self.pending_commands.push_back(cmd);
+
+    // with no device cycle in flight, nothing would pick up the commands, so apply them right away
+    if self.is_idle() {
+      if let Err(err) = self.apply_pending_commands_and_sync().await {
+        self.handle_error(err);
+      }
+    }
   }
 
-  fn handle_params_cmd(&mut self, PlayerParameterCommand { node, changes }: PlayerParameterCommand) {
-    let Some(node) = self.node_apis.get(&node) else { return };
-    let node = node.clone();
+  async fn handle_audio_devices(&mut self, devices: AudioDevices) {
+    self.pending_audio_devices = Some(devices);
+
+    if self.is_idle() {
+      if let Err(err) = self.apply_pending_commands_and_sync().await {
+        self.handle_error(err);
+      }
+    }
+  }
 
-    spawn(async move {
-      let mut node = node.write().await;
-      for change in changes {
-        node.set_parameter(&change);
+  fn handle_params_cmd(&mut self,
+                       PlayerParameterCommand { node,
+                                                changes,
+                                                automation, }: PlayerParameterCommand) {
+    match automation {
+      | Some(events) if events.is_empty() => {
+        self.automation.remove(&node);
+      }
+      | Some(events) if self.node_state.contains_key(&node) => {
+        self.automation.insert(node, events.into());
       }
-    });
+      | _ => {}
+    }
+
+    match self.node_apis.get_mut(&node) {
+      | Some(node) =>
+        for change in &changes {
+          node.set_parameter(change);
+        },
+      // applied when the node comes back from the worker
+      | None if self.node_state.contains_key(&node) => self.pending_parameters.entry(node).or_default().extend(changes),
+      | None => {}
+    }
   }
 
   async fn handle_task_msg(&mut self, cmd: InternalTaskEvent) {
     match cmd {
       | InternalTaskEvent::Completed { node_id,
+                                       node,
                                        result,
-                                       generation, } =>
-        if let Err(err) = self.task_completed(node_id, generation, result).await {
+                                       parameters,
+                                       generation,
+                                       elapsed, } =>
+        if let Err(err) = self.task_completed(node_id, generation, node, result, parameters, elapsed).await {
           self.handle_error(err);
         },
     }
   }
 }
 
-#[derive(Debug)]
 pub enum InternalTaskEvent {
   Completed {
     node_id:    NodeId,
+    /// The node, returned by the worker that processed it
+    node:       BoxedNode,
     result:     Result<Vec<NodeEvent>>,
+    /// The automation of the job, returned to be reused
+    parameters: Vec<ScheduledParameter>,
     generation: u64,
+    /// Time spent in [crate::Node::process]
+    elapsed:    Duration,
   },
 }
 
@@ -258,3 +483,127 @@ impl BitOr for PlayerCommandOutcome {
     }
   }
 }
+
+#[cfg(test)]
+mod test {
+  use std::collections::HashMap;
+
+  use tokio::time::timeout;
+
+  use super::*;
+  use crate::audio_device::simulator_device::new_simulator_device;
+  use crate::audio_device::{AudioDevice, AudioDeviceInfo};
+  use crate::player::fixtures::{NoInstances, NoMedia};
+
+  #[tokio::test(flavor = "multi_thread")]
+  async fn test_play_stop_transitions() {
+    let mut handle = GraphPlayerHandle::new(AudioDevices::default(),
+                                            Box::new(NoMedia),
+                                            Box::new(NoInstances),
+                                            AudioGraphSpec::default()).expect("Failed to create player");
+
+    let mut events = handle.take_events().expect("Events already taken");
+    assert!(handle.take_events().is_none());
+
+    handle.set_play(PlayRequest { play_id:    1,
+                                  start:      0,
+                                  end:        48_000,
+                                  start_from: 0,
+                                  looping:    false,
+                                  sinks:      HashMap::new(),
+                                  recordings: HashMap::new(),
+                                  crossfade:  0, })
+          .await
+          .expect("Failed to play");
+
+    assert!(matches!(events.recv().await,
+                     Some(GraphPlayerEvent::GraphStateChanged { state: GraphPlaybackState::Buffering(0), })));
+
+    handle.seek(1_024).await.expect("Failed to seek");
+    assert!(matches!(events.recv().await,
+                     Some(GraphPlayerEvent::GraphStateChanged { state: GraphPlaybackState::Buffering(1_024), })));
+
+    handle.stop().await.expect("Failed to stop");
+    assert!(matches!(events.recv().await,
+                     Some(GraphPlayerEvent::GraphStateChanged { state: GraphPlaybackState::Stopped, })));
+  }
+
+  fn simulator(device_id: &str) -> AudioDevice {
+    new_simulator_device(device_id.to_owned(), AudioDeviceInfo { latency:     0,
+                                                                 buffer_size: 128,
+                                                                 sample_rate: 48_000,
+                                                                 num_inputs:  2,
+                                                                 num_outputs: 2, })
+  }
+
+  #[tokio::test(flavor = "multi_thread")]
+  async fn test_reset_on_device_changes() {
+    let mut devices = AudioDevices::default();
+    devices.add_device("a".to_owned(), simulator("a"));
+
+    let (_tx_control, rx_control) = mpsc::channel(1);
+    let (_tx_params, rx_params) = mpsc::channel(1);
+    let (tx_events, _rx_events) = mpsc::channel(1);
+    let (tx_captured, _rx_captured) = mpsc::channel(1);
+
+    let mut player = GraphPlayer::new(devices.clone(),
+                                      Box::new(NoMedia),
+                                      Box::new(NoInstances),
+                                      AudioGraphSpec::default(),
+                                      rx_control,
+                                      rx_params,
+                                      tx_events,
+                                      tx_captured).expect("Failed to create player");
+
+    assert_eq!(player.set_audio_devices(devices.clone()), PlayerCommandOutcome::NoAction);
+
+    // the graph does not use the new device
+    devices.add_device("b".to_owned(), simulator("b"));
+    assert_eq!(player.set_audio_devices(devices.clone()), PlayerCommandOutcome::NoAction);
+
+    devices.set_clock_device("b").unwrap();
+    assert_eq!(player.set_audio_devices(devices.clone()), PlayerCommandOutcome::Reset);
+
+    // the same device id, opened again
+    devices.terminate_device("b");
+    devices.add_device("b".to_owned(), simulator("b"));
+    devices.set_clock_device("b").unwrap();
+    assert_eq!(player.set_audio_devices(devices.clone()), PlayerCommandOutcome::Reset);
+  }
+
+  #[tokio::test(flavor = "multi_thread")]
+  async fn test_play_once_the_clock_device_opens() {
+    let mut handle = GraphPlayerHandle::new(AudioDevices::default(),
+                                            Box::new(NoMedia),
+                                            Box::new(NoInstances),
+                                            AudioGraphSpec::default()).expect("Failed to create player");
+
+    let mut events = handle.take_events().expect("Events already taken");
+
+    handle.set_play(PlayRequest { play_id:    1,
+                                  start:      0,
+                                  end:        48_000,
+                                  start_from: 0,
+                                  looping:    true,
+                                  sinks:      HashMap::new(),
+                                  recordings: HashMap::new(),
+                                  crossfade:  0, })
+          .await
+          .expect("Failed to play");
+
+    assert!(matches!(events.recv().await,
+                     Some(GraphPlayerEvent::GraphStateChanged { state: GraphPlaybackState::Buffering(0), })));
+
+    // nothing clocks the graph until a device opens
+    let mut devices = AudioDevices::default();
+    devices.add_device("a".to_owned(), simulator("a"));
+    handle.set_audio_devices(devices).await.expect("Failed to set devices");
+
+    timeout(Duration::from_secs(5), async {
+      while !matches!(events.recv().await.expect("Player stopped"),
+                      GraphPlayerEvent::GraphStateChanged { state: GraphPlaybackState::Playing(_), })
+      {}
+    }).await
+      .expect("Player did not start playing");
+  }
+}
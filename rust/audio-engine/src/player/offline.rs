@@ -0,0 +1,403 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::mem;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail};
+use tokio::sync::mpsc;
+
+use api::task::graph::modify::GraphValidationFailed;
+use api::task::graph::{AudioGraphSpec, NodeId, OutputId, SinkId, SinkSpec};
+use api::task::player::{GraphPlayerEvent, PlayHead, PlayId, PlayRegion, PlayerControlCommand};
+
+use crate::audio_device::AudioDevices;
+use crate::player::{
+  BoxedMediaResolver, ControlRequest, DeviceInstanceAttachment, DeviceInstanceResolver, GraphPlayer, InternalTaskEvent,
+};
+use crate::virtual_inserts::VirtualInsertRegistry;
+use crate::wav::{WavSampleFormat, WavWriter};
+use crate::Result;
+
+/// How long a node may take to process a buffer when rendering offline
+const OFFLINE_DEADLINE: Duration = Duration::from_secs(60);
+
+/// Parameters of an offline render
+#[derive(Debug, Clone)]
+pub struct OfflineRenderRequest {
+  /// Play id reported in the node events
+  pub play_id:     PlayId,
+  /// First sample to render
+  pub start:       u64,
+  /// Sample after the last sample to render
+  pub end:         u64,
+  /// Sample rate of the graph and of the rendered file
+  pub sample_rate: u32,
+  /// Number of samples processed per virtual clock cycle
+  pub buffer_size: u32,
+  /// Graph outputs mixed into each channel of the rendered file
+  pub outputs:     Vec<Vec<OutputId>>,
+  /// Streaming sinks fed during the render, their encoded output is passed to the event callback
+  pub sinks:       HashMap<SinkId, SinkSpec>,
+  /// Sample format of the rendered file
+  pub format:      WavSampleFormat,
+  /// Path of the rendered file
+  pub path:        PathBuf,
+}
+
+/// Result of a successful offline render
+#[derive(Debug, Clone, Copy)]
+pub struct OfflineRenderSummary {
+  /// Number of frames written to the file
+  pub num_frames: u64,
+  /// Number of cycles of the virtual clock
+  pub num_cycles: u64,
+  /// Latency of the graph outputs that was compensated by skipping samples, in samples
+  pub latency:    usize,
+  /// Wall clock time spent rendering
+  pub elapsed:    Duration,
+}
+
+/// Renders a graph faster than real-time, driven by a virtual clock instead of an audio device
+///
+/// Every node is executed once per cycle, and the next cycle is started as soon as all nodes have finished. Graphs
+/// containing device inserts can not be rendered offline.
+pub struct OfflineRenderer {
+  pub(crate) player:      GraphPlayer,
+  pub(crate) rx_events:   mpsc::Receiver<GraphPlayerEvent>,
+  pub(crate) rx_captured: mpsc::Receiver<GraphPlayerEvent>,
+  request:                OfflineRenderRequest,
+}
+
+struct NoDeviceInstances;
+
+impl DeviceInstanceResolver for NoDeviceInstances {
+  fn resolve(&self, instance_id: &str) -> Result<DeviceInstanceAttachment> {
+    bail!("Device insert with instance {instance_id} can not be rendered offline")
+  }
+}
+
+impl OfflineRenderer {
+  pub fn new(media_resolver: BoxedMediaResolver, spec: AudioGraphSpec, request: OfflineRenderRequest) -> Result<Self> {
+    Self::with_virtual_inserts(media_resolver, VirtualInsertRegistry::default(), spec, request)
+  }
+
+  pub fn with_virtual_inserts(media_resolver: BoxedMediaResolver,
+                              virtual_inserts: VirtualInsertRegistry,
+                              spec: AudioGraphSpec,
+                              request: OfflineRenderRequest)
+                              -> Result<Self> {
+    if request.sample_rate == 0 || request.buffer_size == 0 {
+      bail!("Offline render needs a non-zero sample rate and buffer size");
+    }
+
+    if request.end <= request.start {
+      bail!("Offline render region {}..{} is empty", request.start, request.end);
+    }
+
+    if request.outputs.is_empty() {
+      bail!("Offline render needs at least one output channel");
+    }
+
+    let num_nodes =
+      spec.sources.len() + spec.device_inserts.len() + spec.virtual_inserts.len() + spec.busses.len() + request.sinks.len();
+
+    // the events are drained once per cycle, and every node reports once per cycle
+    let (tx_events, rx_events) = mpsc::channel(0x100 + num_nodes);
+    let (tx_captured, rx_captured) = mpsc::channel(0x100);
+    let (_, rx_control) = mpsc::channel(1);
+    let (_, rx_params) = mpsc::channel(1);
+
+    let play_head = PlayHead { sample_rate: request.sample_rate,
+                               buffer_size: request.buffer_size,
+                               ..Default::default() };
+
+    let player = GraphPlayer::new_with(AudioDevices::default(),
+                                       media_resolver,
+                                       Box::new(NoDeviceInstances),
+                                       virtual_inserts,
+                                       play_head,
+                                       spec,
+                                       rx_control,
+                                       rx_params,
+                                       tx_events,
+                                       tx_captured)?;
+
+    for output_id in request.outputs.iter().flatten() {
+      let node_id: NodeId = (*output_id).into();
+      let state = player.node_state
+                        .get(&node_id)
+                        .ok_or_else(|| anyhow!("Offline render output {output_id} references unknown node {node_id}"))?;
+
+      if output_id.channel_index() >= state.info.num_outputs {
+        bail!("Offline render output {output_id} is out of range");
+      }
+    }
+
+    player.graph_validator(&[])
+          .validate_sinks(&player.specs, &request.sinks)
+          .map_err(GraphValidationFailed)?;
+
+    Ok(Self { player,
+              rx_events,
+              rx_captured,
+              request })
+  }
+
+  /// Render the requested region to a file, passing every player event to `on_event`
+  ///
+  /// The encoded output of the streaming sinks is passed as `GraphSinkCaptured` events, the streams end with the region.
+  pub async fn render(mut self, mut on_event: impl FnMut(GraphPlayerEvent)) -> Result<OfflineRenderSummary> {
+    let started = Instant::now();
+    let OfflineRenderRequest { play_id,
+                               start,
+                               end,
+                               sample_rate,
+                               buffer_size,
+                               format,
+                               .. } = self.request;
+
+    let num_channels = self.request.outputs.len();
+    let buffer_size = buffer_size as usize;
+    let latency = self.output_latency();
+
+    self.player
+        .pending_commands
+        .push_back(ControlRequest::from(PlayerControlCommand::Play { play_id,
+                                                                     sinks: mem::take(&mut self.request.sinks),
+                                                                     recordings: Default::default(),
+                                                                     region: PlayRegion { start,
+                                                                                          end: end + latency as u64,
+                                                                                          looping: false,
+                                                                                          crossfade: 0 },
+                                                                     start_from: start }));
+
+    self.player.apply_pending_commands_and_sync().await?;
+
+    let num_frames = end - start;
+
+    let mut writer = WavWriter::create(&self.request.path, num_channels, sample_rate, format)?;
+    let mut planes = vec![vec![0.0; buffer_size]; num_channels];
+    let mut to_skip = latency;
+    let mut num_cycles = 0;
+
+    while writer.num_frames() < num_frames {
+      self.cycle().await?;
+      num_cycles += 1;
+
+      while let Ok(event) = self.rx_events.try_recv() {
+        on_event(event);
+      }
+
+      while let Ok(event) = self.rx_captured.try_recv() {
+        on_event(event);
+      }
+
+      self.capture_outputs(&mut planes);
+
+      let skipped = to_skip.min(buffer_size);
+      to_skip -= skipped;
+
+      let remaining = (num_frames - writer.num_frames()).min((buffer_size - skipped) as u64) as usize;
+      let captured = planes.iter().map(|plane| &plane[skipped..skipped + remaining]).collect::<Vec<_>>();
+
+      writer.write_planar(&captured, remaining)?;
+    }
+
+    let num_frames = writer.num_frames();
+    writer.finalize()?;
+
+    // the tails of the streaming sinks are sent by tasks of their own, the channel closes once they are all sent
+    let Self { player, mut rx_captured, .. } = self;
+    drop(player);
+
+    while let Some(event) = rx_captured.recv().await {
+      on_event(event);
+    }
+
+    Ok(OfflineRenderSummary { num_frames,
+                              num_cycles,
+                              latency,
+                              elapsed: started.elapsed() })
+  }
+
+  /// Execute all nodes once, then advance the play head
+  pub(crate) async fn cycle(&mut self) -> Result {
+    let player = &mut self.player;
+    let generation = player.play_head.generation;
+
+    player.current_work_set.deadline = Some(Instant::now() + OFFLINE_DEADLINE);
+    player.current_work_set.cycle_time =
+      Duration::from_secs_f64(player.play_head.buffer_size as f64 / player.play_head.sample_rate as f64);
+    player.current_work_set
+          .nodes_to_execute
+          .extend(player.node_state.keys().copied());
+
+    player.update_work_sets().await?;
+
+    while player.play_head.generation == generation {
+      match player.rx_tasks.recv().await {
+        | Some(InternalTaskEvent::Completed { node_id,
+                                              node,
+                                              result,
+                                              parameters,
+                                              generation,
+                                              elapsed, }) => {
+          player.task_completed(node_id, generation, node, result, parameters, elapsed).await?;
+        }
+        | None => bail!("Task channel closed while rendering offline"),
+      }
+    }
+
+    Ok(())
+  }
+
+  fn output_latency(&self) -> usize {
+    self.request
+        .outputs
+        .iter()
+        .flatten()
+        .filter_map(|output_id| self.player.node_state.get(&Into::<NodeId>::into(*output_id)))
+        .map(|state| state.accumulated_latency + state.info.latency)
+        .max()
+        .unwrap_or_default()
+  }
+
+  fn capture_outputs(&self, planes: &mut [Vec<f64>]) {
+    for (plane, output_ids) in planes.iter_mut().zip(self.request.outputs.iter()) {
+      plane.iter_mut().for_each(|sample| *sample = 0.0);
+
+      for output_id in output_ids {
+        let Some(state) = self.player.node_state.get(&Into::<NodeId>::into(*output_id)) else { continue };
+        let source = state.buffers.output_plane(output_id.channel_index());
+
+        plane.iter_mut().zip(source.iter()).for_each(|(a, b)| *a += *b);
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::collections::HashMap;
+  use std::fs;
+
+  use maplit::hashmap;
+  use nanoid::nanoid;
+
+  use api::task::graph::{InsertId, SinkCodec, VirtualInsertSpec};
+  use api::task::player::LoudnessSummary;
+
+  use crate::player::fixtures::{read_float_wav, renderer, NoMedia, CONSTANT_MODEL_ID};
+  use crate::virtual_inserts::GAIN_MODEL_ID;
+
+  use super::*;
+
+  #[tokio::test(flavor = "multi_thread")]
+  async fn test_render_constant_through_gain() {
+    let constant: InsertId = 1;
+    let gain: InsertId = 2;
+
+    let spec = AudioGraphSpec { virtual_inserts: hashmap! {
+                                  constant => VirtualInsertSpec { inputs:   vec![vec![]],
+                                                                  model_id: CONSTANT_MODEL_ID.to_owned(), },
+                                  gain => VirtualInsertSpec { inputs:   vec![vec![OutputId::VirtualInsert(constant, 0)]],
+                                                              model_id: GAIN_MODEL_ID.to_owned(), },
+                                },
+                                ..Default::default() };
+
+    let path = std::env::temp_dir().join(format!("offline-render-{}.wav", nanoid!()));
+
+    let renderer = renderer(spec,
+                            1_000,
+                            64,
+                            vec![vec![OutputId::VirtualInsert(gain, 0)], vec![OutputId::VirtualInsert(constant, 0)]],
+                            path.clone());
+
+    let mut node_events = HashMap::<NodeId, usize>::new();
+    let mut loudness = vec![];
+    let summary = renderer.render(|event| match event {
+                            | GraphPlayerEvent::NodeEvents { node_id, .. } => *node_events.entry(node_id).or_default() += 1,
+                            | GraphPlayerEvent::GraphLoudnessSummary { summaries, .. } => loudness.push(summaries),
+                            | _ => {}
+                          })
+                          .await
+                          .expect("Failed to render");
+
+    assert_eq!(summary.num_frames, 1_000);
+    assert_eq!(summary.num_cycles, 16);
+    assert_eq!(node_events.get(&NodeId::VirtualInsert(gain)), Some(&16));
+    // reported once, when the play reaches the end of the region
+    assert_eq!(loudness.len(), 1);
+    assert_eq!(loudness[0],
+               vec![(NodeId::VirtualInsert(constant),
+                     LoudnessSummary { integrated: -6.0,
+                                       ..Default::default() })]);
+
+    let samples = read_float_wav(&path);
+    let _ = fs::remove_file(&path);
+
+    assert_eq!(samples.len(), 2_000);
+    assert!(samples.chunks_exact(2).all(|frame| frame == [0.5, 0.5]));
+  }
+
+  #[tokio::test(flavor = "multi_thread")]
+  async fn test_render_streaming_sinks() {
+    let constant: InsertId = 1;
+
+    let spec = AudioGraphSpec { virtual_inserts: hashmap! {
+                                  constant => VirtualInsertSpec { inputs:   vec![vec![]],
+                                                                  model_id: CONSTANT_MODEL_ID.to_owned(), },
+                                },
+                                ..Default::default() };
+
+    let path = std::env::temp_dir().join(format!("offline-render-{}.wav", nanoid!()));
+
+    let mut renderer = renderer(spec, 4_800, 480, vec![vec![OutputId::VirtualInsert(constant, 0)]], path.clone());
+    renderer.request.sinks = hashmap! {
+      1 => SinkSpec { inputs:      vec![vec![OutputId::VirtualInsert(constant, 0)]],
+                      sample_rate: 48_000,
+                      codec:       SinkCodec::Flac, },
+    };
+
+    let mut stream = vec![];
+    renderer.render(|event| {
+              if let GraphPlayerEvent::GraphSinkCaptured { sink_id, data, .. } = event {
+                assert_eq!(sink_id, 1);
+                stream.extend_from_slice(&data);
+              }
+            })
+            .await
+            .expect("Failed to render");
+
+    let _ = fs::remove_file(&path);
+
+    assert_eq!(&stream[..4], b"fLaC");
+  }
+
+  #[test]
+  fn test_reject_sinks_of_unknown_nodes() {
+    let gain: InsertId = 1;
+
+    let spec = AudioGraphSpec { virtual_inserts: hashmap! {
+                                  gain => VirtualInsertSpec { inputs:   vec![vec![]],
+                                                              model_id: GAIN_MODEL_ID.to_owned(), },
+                                },
+                                ..Default::default() };
+
+    let request = OfflineRenderRequest { play_id:     1,
+                                         start:       0,
+                                         end:         4_800,
+                                         sample_rate: 48_000,
+                                         buffer_size: 480,
+                                         outputs:     vec![vec![OutputId::VirtualInsert(gain, 0)]],
+                                         sinks:       hashmap! {
+                                           1 => SinkSpec { inputs:      vec![vec![OutputId::VirtualInsert(2, 0)]],
+                                                           sample_rate: 48_000,
+                                                           codec:       SinkCodec::Flac, },
+                                         },
+                                         format:      WavSampleFormat::Float32,
+                                         path:        PathBuf::new(), };
+
+    assert!(OfflineRenderer::new(Box::new(NoMedia), spec, request).is_err());
+  }
+}
@@ -0,0 +1,126 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use itertools::Itertools;
+
+use api::task::graph::NodeId;
+use api::task::player::{NodeProcessingStats, ProcessingStats};
+
+/// How much audio is processed between two processing time reports
+const REPORT_PERIOD: Duration = Duration::from_secs(1);
+
+/// Collects the time nodes spend processing, until a report is due
+#[derive(Debug, Default)]
+pub struct ProcessingTimes {
+  cycles: u64,
+  budget: Duration,
+  nodes:  HashMap<NodeId, NodeTimes>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct NodeTimes {
+  count: u32,
+  total: Duration,
+  min:   Duration,
+  max:   Duration,
+}
+
+impl ProcessingTimes {
+  pub fn add_node(&mut self, node_id: NodeId, elapsed: Duration) {
+    let times = self.nodes.entry(node_id).or_insert(NodeTimes { count: 0,
+                                                                total: Duration::ZERO,
+                                                                min:   elapsed,
+                                                                max:   elapsed, });
+
+    times.count += 1;
+    times.total += elapsed;
+    times.min = times.min.min(elapsed);
+    times.max = times.max.max(elapsed);
+  }
+
+  /// Count a finished cycle, returning the stats and starting over once a report period worth of cycles was processed
+  pub fn cycle_finished(&mut self, budget: Duration) -> Option<ProcessingStats> {
+    self.cycles += 1;
+    self.budget += budget;
+
+    if self.budget < REPORT_PERIOD {
+      return None;
+    }
+
+    let ProcessingTimes { cycles, budget, nodes } = std::mem::take(self);
+    let budget_us = budget.as_secs_f64() * 1_000_000.0 / cycles as f64;
+    let load = |elapsed_us: f64| if budget_us > 0.0 { 100.0 * elapsed_us / budget_us } else { 0.0 };
+
+    let nodes = nodes.into_iter()
+                     .sorted_by_key(|(node_id, _)| *node_id)
+                     .map(|(node_id, times)| {
+                       let avg_us = times.total.as_secs_f64() * 1_000_000.0 / times.count as f64;
+                       let max_us = times.max.as_secs_f64() * 1_000_000.0;
+
+                       (node_id,
+                        NodeProcessingStats { min_us: times.min.as_secs_f64() * 1_000_000.0,
+                                              avg_us,
+                                              max_us,
+                                              avg_load: load(avg_us),
+                                              max_load: load(max_us) })
+                     })
+                     .collect();
+
+    Some(ProcessingStats { cycles, budget_us, nodes })
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::fs;
+
+  use maplit::hashmap;
+  use nanoid::nanoid;
+
+  use api::task::graph::{AudioGraphSpec, InsertId, OutputId, VirtualInsertSpec};
+  use api::task::player::GraphPlayerEvent;
+
+  use crate::player::fixtures::{renderer, CONSTANT_MODEL_ID};
+  use crate::virtual_inserts::GAIN_MODEL_ID;
+
+  use super::*;
+
+  #[tokio::test(flavor = "multi_thread")]
+  async fn test_processing_stats_reported_every_second() {
+    let (constant, gain): (InsertId, InsertId) = (1, 2);
+
+    let spec = AudioGraphSpec { virtual_inserts: hashmap! {
+                                  constant => VirtualInsertSpec { inputs:   vec![vec![]],
+                                                                  model_id: CONSTANT_MODEL_ID.to_owned(), },
+                                  gain => VirtualInsertSpec { inputs:   vec![vec![OutputId::VirtualInsert(constant, 0)]],
+                                                              model_id: GAIN_MODEL_ID.to_owned(), },
+                                },
+                                ..Default::default() };
+
+    let path = std::env::temp_dir().join(format!("offline-render-{}.wav", nanoid!()));
+
+    let renderer = renderer(spec, 52_800, 480, vec![vec![OutputId::VirtualInsert(gain, 0)]], path.clone());
+
+    let mut reports = vec![];
+    renderer.render(|event| {
+              if let GraphPlayerEvent::GraphProcessingStats { stats, .. } = event {
+                reports.push(stats);
+              }
+            })
+            .await
+            .expect("Failed to render");
+
+    let _ = fs::remove_file(&path);
+
+    // 110 cycles of 10ms each, one report after the first second
+    assert_eq!(reports.len(), 1);
+    assert!((100..=101).contains(&reports[0].cycles));
+    assert!((reports[0].budget_us - 10_000.0).abs() < 1.0);
+
+    let nodes = reports[0].nodes.iter().map(|(node_id, _)| *node_id).collect::<Vec<_>>();
+    assert_eq!(nodes, vec![NodeId::VirtualInsert(constant), NodeId::VirtualInsert(gain)]);
+    assert!(reports[0].nodes
+                      .iter()
+                      .all(|(_, stats)| stats.min_us <= stats.avg_us && stats.avg_us <= stats.max_us));
+  }
+}
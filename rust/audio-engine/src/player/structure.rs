@@ -1,11 +1,9 @@
-use std::sync::Arc;
-
-use anyhow::anyhow;
+use anyhow::{anyhow, bail};
 use maplit::hashset;
-use tokio::sync::RwLock;
 use tokio::task::block_in_place;
 
 use api::task::graph::modify::AudioGraphModification;
+use api::task::graph::validate::GraphValidator;
 use api::task::graph::{BusId, BusSpec, DeviceInsertSpec, InputId, InsertId, NodeId, OutputId, SourceId, SourceSpec, VirtualInsertSpec};
 
 use crate::audio_device::audio_device_insert_node::AudioDeviceInsertNode;
@@ -13,25 +11,43 @@ use crate::bus_node::BusNode;
 use crate::connection::Connection;
 use crate::player::GraphPlayer;
 use crate::player::PlayerCommandOutcome;
+use crate::player::PlayerNodeState;
+use crate::sources::file_source_node::FileSourceNode;
+#[cfg(feature = "juce")]
 use crate::sources::juce_source_reader_node::JuceSourceReaderNode;
-use crate::{Node, Result};
+use crate::{BoxedNode, Result};
 
 impl GraphPlayer {
-  pub(crate) fn apply_graph_modification(&mut self, change: AudioGraphModification) -> Result<PlayerCommandOutcome> {
+  /// Apply the modifications all or nothing
+  ///
+  /// The nodes the modifications add are created before the graph is touched, creating them is what fails when media
+  /// or device instances can't be resolved, so a failing modification leaves the graph and its specs as they were.
+  /// The connections were validated against the specs already.
+  pub(crate) fn apply_graph_modifications(&mut self, modifications: Vec<AudioGraphModification>) -> Result<PlayerCommandOutcome> {
+    let new_nodes = modifications.iter()
+                                 .map(|modification| self.create_node(modification))
+                                 .collect::<Result<Vec<_>>>()?;
+
+    let mut outcome = PlayerCommandOutcome::NoAction;
+
+    for (modification, new_node) in modifications.into_iter().zip(new_nodes) {
+      outcome |= self.apply_graph_modification(modification, new_node)?;
+    }
+
+    Ok(outcome)
+  }
+
+  fn apply_graph_modification(&mut self, change: AudioGraphModification, new_node: Option<NewNode>) -> Result<PlayerCommandOutcome> {
     let mut outcome = PlayerCommandOutcome::NoAction;
 
     match change {
-      | AudioGraphModification::AddOrReplaceSource { source_id, source_spec } => {
-        outcome |= self.add_source(source_id, source_spec)?;
-      }
-      | AudioGraphModification::AddOrReplaceDeviceInsert { insert_id, insert_spec } => {
-        outcome |= self.add_device_insert(insert_id, insert_spec)?;
-      }
-      | AudioGraphModification::AddOrReplaceVirtualInsert { insert_id, insert_spec } => {
-        outcome |= self.add_virtual_insert(insert_id, insert_spec)?;
-      }
-      | AudioGraphModification::AddOrReplaceBus { bus_id, bus_spec } => {
-        outcome |= self.add_bus(bus_id, bus_spec)?;
+      | AudioGraphModification::AddOrReplaceSource { .. }
+      | AudioGraphModification::AddOrReplaceDeviceInsert { .. }
+      | AudioGraphModification::AddOrReplaceVirtualInsert { .. }
+      | AudioGraphModification::AddOrReplaceBus { .. } => {
+        if let Some(new_node) = new_node {
+          outcome |= self.add_node(new_node);
+        }
       }
       | AudioGraphModification::RemoveSource { source_id } => {
         outcome |= self.remove_source(source_id)?;
@@ -60,17 +76,66 @@ impl GraphPlayer {
     Ok(outcome)
   }
 
+  /// Create the node a modification adds, without adding it to the graph
+  fn create_node(&self, change: &AudioGraphModification) -> Result<Option<NewNode>> {
+    Ok(match change {
+      | AudioGraphModification::AddOrReplaceSource { source_id, source_spec } => Some(self.create_source(*source_id, source_spec)?),
+      | AudioGraphModification::AddOrReplaceDeviceInsert { insert_id, insert_spec } => {
+        Some(self.create_device_insert(*insert_id, insert_spec)?)
+      }
+      | AudioGraphModification::AddOrReplaceVirtualInsert { insert_id, insert_spec } => {
+        Some(self.create_virtual_insert(*insert_id, insert_spec)?)
+      }
+      | AudioGraphModification::AddOrReplaceBus { bus_id, bus_spec } => Some(self.create_bus(*bus_id, bus_spec)?),
+      | _ => None,
+    })
+  }
+
+  fn add_node(&mut self, new_node: NewNode) -> PlayerCommandOutcome {
+    let NewNode { state, node } = new_node;
+    let node_id = state.id;
+
+    self.node_state.insert(node_id, state);
+    self.node_apis.insert(node_id, node);
+
+    match node_id {
+      // adding a source always needs a reset because the player needs to buffer samples
+      | NodeId::Source(_) => PlayerCommandOutcome::Reset,
+      | _ => PlayerCommandOutcome::ConnectionSync,
+    }
+  }
+
+  /// A validator that knows the number of outputs of the device instances in the graph and the modifications
+  pub(crate) fn graph_validator(&self, modifications: &[AudioGraphModification]) -> GraphValidator {
+    let added = modifications.iter().filter_map(|modification| match modification {
+                                      | AudioGraphModification::AddOrReplaceDeviceInsert { insert_spec, .. } => Some(insert_spec),
+                                      | _ => None,
+                                    });
+
+    self.specs
+        .device_inserts
+        .values()
+        .chain(added)
+        .fold(GraphValidator::default(), |validator, spec| {
+          match self.device_instance_resolver.resolve(&spec.instance_id) {
+            | Ok(attachment) => validator.with_device_instance_outputs(&spec.instance_id, attachment.returns.len()),
+            // unresolvable instances are reported when the insert is created
+            | Err(_) => validator,
+          }
+        })
+  }
+
   pub(crate) async fn reset(&mut self) -> Result {
     let all_devices = self.referenced_device_ids();
 
     self.unsubscribe_from_devices(&all_devices)?;
+    self.sync_clock_domains(&all_devices)?;
 
     // TODO: rewrite this with spawn_blocking, collect into futures unordered and
     // TODO: yield (node_id, node_info) pairs with which to update &mut self.node_state
 
-    for (node_id, node) in &self.node_apis {
+    for (node_id, node) in &mut self.node_apis {
       let Some(state) = self.node_state.get_mut(node_id) else { continue };
-      let mut node = node.write().await;
 
       state.info = node.get_node_info(self.play_head);
       block_in_place(|| node.prepare_to_play(self.play_head, state.accumulated_latency))?;
@@ -85,57 +150,88 @@ impl GraphPlayer {
     Ok(())
   }
 
-  fn add_source(&mut self, source_id: SourceId, spec: SourceSpec) -> Result<PlayerCommandOutcome> {
+  /// Let the nodes prepare for a jump of the play head they crossfade, the commands are applied between cycles so all
+  /// nodes are back from the workers
+  pub(crate) fn prepare_to_seek(&mut self) -> Result {
+    for node in self.node_apis.values_mut() {
+      node.prepare_to_seek(self.play_head)?;
+    }
+
+    Ok(())
+  }
+
+  fn create_source(&self, source_id: SourceId, spec: &SourceSpec) -> Result<NewNode> {
     let node_id = NodeId::Source(source_id);
     let path = self.media_resolver.resolve(&spec.media_id)?;
-    let node = JuceSourceReaderNode::new(&path, self.play_head, spec.num_channels)?;
+    let node = self.open_source(&path, spec)?;
+    let state = Self::new_node_state(node_id, node.as_ref(), self.play_head, hashset! {}, |_| unreachable!(), vec![])?;
 
-    self.node_state.insert(node_id,
-                           Self::new_node_state(node_id, &node, self.play_head, hashset! {}, |_| unreachable!(), vec![])?);
+    Ok(NewNode { state, node })
+  }
 
-    self.node_apis.insert(node_id, Arc::new(RwLock::new(Box::new(node))));
+  fn open_source(&self, path: &str, spec: &SourceSpec) -> Result<BoxedNode> {
+    match FileSourceNode::new(path, spec.num_channels) {
+      | Ok(node) => Ok(Box::new(node.with_clip(spec))),
+      // formats we can't read natively are left to JUCE, when it is available, which only plays whole files
+      #[cfg(feature = "juce")]
+      | Err(err) if !Self::is_whole_file(spec) => bail!("Clips of {} need a natively supported format: {err}", spec.media_id),
+      #[cfg(feature = "juce")]
+      | Err(_) => Ok(Box::new(JuceSourceReaderNode::new(path, self.play_head, spec.num_channels)?)),
+      #[cfg(not(feature = "juce"))]
+      | Err(err) => Err(err),
+    }
+  }
 
-    // adding a source always needs a reset because the player needs to buffer samples
-    Ok(PlayerCommandOutcome::Reset)
+  /// The source plays the whole file from the start of the timeline, as it is
+  #[cfg(feature = "juce")]
+  fn is_whole_file(spec: &SourceSpec) -> bool {
+    spec.start_at == 0
+      && spec.media_in == 0
+      && spec.media_out.is_none()
+      && spec.fade_in.length == 0
+      && spec.fade_out.length == 0
+      && spec.gain_db == 0.0
   }
 
-  fn add_device_insert(&mut self, insert_id: InsertId, spec: DeviceInsertSpec) -> Result<PlayerCommandOutcome> {
+  fn create_device_insert(&self, insert_id: InsertId, spec: &DeviceInsertSpec) -> Result<NewNode> {
     let node_id = NodeId::DeviceInsert(insert_id);
     let device_attachment = self.device_instance_resolver.resolve(&spec.instance_id)?;
-    let device_info = self.audio_devices.get_info(&device_attachment.device_id)?;
-    let node = AudioDeviceInsertNode::new(&device_attachment, device_info.latency as usize)?;
-
-    self.node_state.insert(node_id,
-                           Self::new_node_state(node_id,
-                                                &node,
-                                                self.play_head,
-                                                hashset! {device_attachment.device_id},
-                                                |i| InputId::DeviceInsert(insert_id, i),
-                                                spec.inputs)?);
-    self.node_apis.insert(node_id, Arc::new(RwLock::new(Box::new(node))));
-
-    Ok(PlayerCommandOutcome::ConnectionSync)
+    let device_latency = self.device_latency(&device_attachment.device_id)?;
+    let node = AudioDeviceInsertNode::new(&device_attachment, device_latency)?;
+    let state = Self::new_node_state(node_id,
+                                     &node,
+                                     self.play_head,
+                                     hashset! {device_attachment.device_id},
+                                     |i| InputId::DeviceInsert(insert_id, i),
+                                     spec.inputs.clone())?;
+
+    Ok(NewNode { state, node: Box::new(node) })
   }
 
-  fn add_virtual_insert(&self, insert_id: InsertId, spec: VirtualInsertSpec) -> Result<PlayerCommandOutcome> {
-    Err(anyhow!("Virtual inserts are not yet implemented (ref: {insert_id}, spec: {spec:?})"))
+  fn create_virtual_insert(&self, insert_id: InsertId, spec: &VirtualInsertSpec) -> Result<NewNode> {
+    let node_id = NodeId::VirtualInsert(insert_id);
+    let node = self.virtual_inserts.create(&spec.model_id, spec.inputs.len())?;
+    let state = Self::new_node_state(node_id,
+                                     node.as_ref(),
+                                     self.play_head,
+                                     hashset! {},
+                                     |i| InputId::VirtualInsert(insert_id, i),
+                                     spec.inputs.clone())?;
+
+    Ok(NewNode { state, node })
   }
 
-  fn add_bus(&mut self, bus_id: BusId, spec: BusSpec) -> Result<PlayerCommandOutcome> {
+  fn create_bus(&self, bus_id: BusId, spec: &BusSpec) -> Result<NewNode> {
     let node_id = NodeId::Bus(bus_id);
-    let node = BusNode::new(bus_id, spec.inputs.len(), spec.num_outputs)?;
-
-    self.node_state.insert(node_id,
-                           Self::new_node_state(node_id,
-                                                &node,
-                                                self.play_head,
-                                                hashset! {},
-                                                |i| InputId::Bus(bus_id, i),
-                                                spec.inputs)?);
-
-    self.node_apis.insert(node_id, Arc::new(RwLock::new(Box::new(node))));
-
-    Ok(PlayerCommandOutcome::ConnectionSync)
+    let node = BusNode::new(bus_id, spec.inputs.len(), spec.num_outputs, spec.mode)?;
+    let state = Self::new_node_state(node_id,
+                                     &node,
+                                     self.play_head,
+                                     hashset! {},
+                                     |i| InputId::Bus(bus_id, i),
+                                     spec.inputs.clone())?;
+
+    Ok(NewNode { state, node: Box::new(node) })
   }
 
   fn remove_source(&mut self, source: SourceId) -> Result<PlayerCommandOutcome> {
@@ -143,6 +239,7 @@ impl GraphPlayer {
 
     self.node_apis.remove(&node_id);
     self.node_state.remove(&node_id);
+    self.automation.remove(&node_id);
 
     Ok(PlayerCommandOutcome::ConnectionSync)
   }
@@ -152,6 +249,7 @@ impl GraphPlayer {
 
     self.node_apis.remove(&node_id);
     self.node_state.remove(&node_id);
+    self.automation.remove(&node_id);
 
     Ok(PlayerCommandOutcome::ConnectionSync)
   }
@@ -161,6 +259,7 @@ impl GraphPlayer {
 
     self.node_apis.remove(&node_id);
     self.node_state.remove(&node_id);
+    self.automation.remove(&node_id);
 
     Ok(PlayerCommandOutcome::ConnectionSync)
   }
@@ -170,6 +269,7 @@ impl GraphPlayer {
 
     self.node_apis.remove(&node_id);
     self.node_state.remove(&node_id);
+    self.automation.remove(&node_id);
 
     Ok(PlayerCommandOutcome::ConnectionSync)
   }
@@ -187,7 +287,15 @@ impl GraphPlayer {
       inputs.push(output_id);
     }
 
-    state.node_requirements.insert(input_id.into());
+    // the node waits for the node feeding the input, as with the inputs of the spec in unwrap_inputs
+    state.node_requirements.insert(output_id.into());
+
+    // fade new connections in, and connections that are still fading out back in
+    let crossfade = self.play_head.play_region.crossfade as usize;
+    self.connections
+        .entry((output_id, input_id))
+        .and_modify(|connection| connection.fade_in())
+        .or_insert_with(|| Connection::fading_in(crossfade));
 
     Ok(PlayerCommandOutcome::ConnectionSync)
   }
@@ -195,20 +303,58 @@ impl GraphPlayer {
   fn disconnect(&mut self, component: NodeId, input_channels: usize, output_id: OutputId) -> Result<PlayerCommandOutcome> {
     let input_id = component.input(input_channels)?;
 
-    let state = self.node_state
-                    .get_mut(&component)
-                    .ok_or_else(|| anyhow!("Node not found: {:?}", component))?;
+    if !self.node_state.contains_key(&component) {
+      bail!("Node not found: {:?}", component);
+    }
+
+    // the connection stays in place until it faded out, then it is removed like any other
+    let crossfade = self.play_head.play_region.crossfade as usize;
+    if let Some(connection) = self.connections.get_mut(&(output_id, input_id)) {
+      if crossfade > 0 {
+        connection.fade_out(crossfade);
+        return Ok(PlayerCommandOutcome::NoAction);
+      }
+    }
+
+    self.remove_connection(output_id, input_id);
+
+    Ok(PlayerCommandOutcome::ConnectionSync)
+  }
+
+  /// Remove the connections that finished fading out
+  pub(crate) fn remove_faded_out_connections(&mut self) -> PlayerCommandOutcome {
+    let faded_out = self.connections
+                        .iter()
+                        .filter(|(_, connection)| connection.is_faded_out())
+                        .map(|(key, _)| *key)
+                        .collect::<Vec<_>>();
+
+    for (output_id, input_id) in &faded_out {
+      self.remove_connection(*output_id, *input_id);
+      self.connections.remove(&(*output_id, *input_id));
+    }
+
+    if faded_out.is_empty() {
+      PlayerCommandOutcome::NoAction
+    } else {
+      PlayerCommandOutcome::ConnectionSync
+    }
+  }
+
+  fn remove_connection(&mut self, output_id: OutputId, input_id: InputId) {
+    let node_id: NodeId = input_id.into();
+    let Some(state) = self.node_state.get_mut(&node_id) else { return };
 
     let inputs = state.node_inputs.entry(input_id).or_default();
 
     inputs.retain(|&id| id != output_id);
 
+    let node_inputs = &state.node_inputs;
     state.node_requirements.retain(|&required_node_id| {
-                             inputs.iter()
-                                   .any(|output_id| <OutputId as Into<NodeId>>::into(*output_id) == required_node_id)
+                             node_inputs.values()
+                                        .flatten()
+                                        .any(|output_id| <OutputId as Into<NodeId>>::into(*output_id) == required_node_id)
                            });
-
-    Ok(PlayerCommandOutcome::ConnectionSync)
   }
 
   pub(crate) fn sync_all_connections(&mut self) {
@@ -263,3 +409,74 @@ impl GraphPlayer {
     PlayerCommandOutcome::NoAction
   }
 }
+
+/// A node created for a graph modification, before it is added to the graph
+struct NewNode {
+  state: PlayerNodeState,
+  node:  BoxedNode,
+}
+
+#[cfg(test)]
+mod test {
+  use std::path::PathBuf;
+
+  use maplit::hashmap;
+
+  use api::media::spec::MediaId;
+  use api::task::graph::{AudioGraphSpec, VirtualInsertSpec};
+  use api::task::player::PlayerControlCommand;
+
+  use crate::player::fixtures::{renderer, CONSTANT_MODEL_ID};
+  use crate::virtual_inserts::GAIN_MODEL_ID;
+
+  use super::*;
+
+  #[tokio::test(flavor = "multi_thread")]
+  async fn test_failed_modification_leaves_graph_unchanged() {
+    let (constant, gain): (InsertId, InsertId) = (1, 2);
+    let output_id = OutputId::VirtualInsert(constant, 0);
+
+    let spec = AudioGraphSpec { virtual_inserts: hashmap! {
+                                  constant => VirtualInsertSpec { inputs:   vec![vec![]],
+                                                                  model_id: CONSTANT_MODEL_ID.to_owned(), },
+                                  gain => VirtualInsertSpec { inputs:   vec![vec![output_id]],
+                                                              model_id: GAIN_MODEL_ID.to_owned(), },
+                                },
+                                ..Default::default() };
+
+    let mut renderer = renderer(spec, 1_000, 64, vec![vec![OutputId::VirtualInsert(gain, 0)]], PathBuf::new());
+    let player = &mut renderer.player;
+    let specs = player.specs.clone();
+
+    // the media of the source can't be resolved, after the bus and the disconnect would have been applied
+    let modifications =
+      vec![AudioGraphModification::AddOrReplaceBus { bus_id:   3,
+                                                     bus_spec: BusSpec { inputs:      vec![vec![output_id]],
+                                                                         num_outputs: 1,
+                                                                         mode:        Default::default(), }, },
+           AudioGraphModification::Disconnect { component:     NodeId::VirtualInsert(gain),
+                                                input_channel: 0,
+                                                output:        output_id, },
+           AudioGraphModification::AddOrReplaceSource { source_id:   4,
+                                                        source_spec: SourceSpec { media_id:     MediaId::new("missing"),
+                                                                                  start_at:     0,
+                                                                                  num_channels: 1,
+                                                                                  media_in:     0,
+                                                                                  media_out:    None,
+                                                                                  fade_in:      Default::default(),
+                                                                                  fade_out:     Default::default(),
+                                                                                  gain_db:      0.0, }, }];
+
+    player.pending_commands
+          .push_back(PlayerControlCommand::ModifyGraph { modifications }.into());
+    assert!(player.apply_pending_commands_and_sync().await.is_err());
+
+    assert_eq!(player.specs, specs);
+    assert!(!player.node_apis.contains_key(&NodeId::Bus(3)));
+    assert!(!player.node_state.contains_key(&NodeId::Bus(3)));
+
+    let gain_input = NodeId::VirtualInsert(gain).input(0).expect("Gain has no input");
+    assert!(player.connections.contains_key(&(output_id, gain_input)));
+    assert_eq!(player.node_state[&NodeId::VirtualInsert(gain)].node_inputs[&gain_input], vec![output_id]);
+  }
+}
@@ -1,80 +1,148 @@
 use std::collections::{HashMap, HashSet};
 use std::mem;
-use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use anyhow::bail;
-use tokio::spawn;
-use tokio::sync::{mpsc, RwLock};
-use tokio::task::block_in_place;
+use itertools::Itertools;
+use tokio::task::spawn_blocking;
 
-use api::task::graph::NodeId;
-use api::task::player::{GraphPlayerEvent, NodeEvent, PlayHead};
+use api::task::graph::{InputId, NodeId, OutputId};
+use api::task::player::{GraphPlaybackState, GraphPlayerEvent, NodeEvent, PlayHead, PlayerControlCommand};
 
 use crate::audio_device::DeviceCommand;
-use crate::buffer::{DeviceBuffers, DevicesBuffers, NodeBuffers};
-use crate::player::{GraphPlayer, InternalTaskEvent, PlayerCommandOutcome, PlayerNodeState};
+use crate::automation::{AutomationLanes, ScheduledParameter};
+use crate::buffer::{zero_slice, DeviceBuffers, DevicesBuffers};
+use crate::connection::Connection;
+use crate::player::worker_pool::{execute_job, CompletionSender, NodeJob, SpareJobVecs, WorkerPool};
+use crate::player::{GraphPlayer, PlayerNodeState, StoppingSink};
 use crate::{BoxedNode, Result};
 
+/// The nodes and devices of one graph cycle
+///
+/// Work sets are reused once their cycle finished, so after the first cycles their collections have grown to fit the
+/// graph and scheduling the nodes no longer allocates. Devices keep their entries between cycles for the same reason.
+/// The jobs get their automation and event vectors from [SpareJobVecs]. Events the nodes report leave the player, and
+/// so do their vectors, and the commands sent to the devices still allocate.
 #[derive(Debug)]
 pub struct WorkSet {
-  pub(crate) play_head:             PlayHead,
-  pub(crate) nodes_to_execute:      HashSet<NodeId>,
-  pub(crate) nodes_executing:       HashSet<NodeId>,
-  pub(crate) nodes_executed:        HashSet<NodeId>,
-  pub(crate) device_flips_started:  HashMap<String, DeviceBuffers>,
-  pub(crate) device_flips_finished: HashSet<String>,
-  pub(crate) deadline:              Option<Instant>,
+  pub(crate) play_head:        PlayHead,
+  pub(crate) nodes_to_execute: HashSet<NodeId>,
+  pub(crate) nodes_executing:  HashSet<NodeId>,
+  pub(crate) nodes_executed:   HashSet<NodeId>,
+  /// Flips of the devices taking part in this cycle, devices of earlier cycles map to `None`
+  pub(crate) device_flips:     HashMap<String, Option<DeviceFlip>>,
+  /// Buffers of the flipped devices, shared with the nodes
+  pub(crate) devices:          DevicesBuffers,
+  pub(crate) deadline:         Option<Instant>,
+  /// Duration of the device cycle, the time budget the nodes share
+  pub(crate) cycle_time:       Duration,
+  /// Node ids collected while walking the graph
+  pub(crate) scratch:          Vec<NodeId>,
 }
 
-impl WorkSet {
-  fn devices_buffers(&self) -> DevicesBuffers {
-    DevicesBuffers(self.device_flips_started.clone())
-  }
+#[derive(Debug, Copy, Clone)]
+pub struct DeviceFlip {
+  pub(crate) buffers:  DeviceBuffers,
+  /// The nodes using the device are done with it and the device was told so
+  pub(crate) finished: bool,
+}
 
+impl WorkSet {
   fn is_empty(&self) -> bool {
     self.nodes_executing.is_empty() && self.nodes_to_execute.is_empty()
   }
+
+  pub(crate) fn device_flip(&self, device_id: &str) -> Option<&DeviceFlip> {
+    self.device_flips.get(device_id)?.as_ref()
+  }
+
+  pub(crate) fn has_device_flips(&self) -> bool {
+    self.device_flips.values().any(Option::is_some)
+  }
+
+  pub(crate) fn start_device_flip(&mut self, device_id: &str, buffers: DeviceBuffers) {
+    let flip = Some(DeviceFlip { buffers, finished: false });
+
+    match self.device_flips.get_mut(device_id) {
+      | Some(slot) => *slot = flip,
+      | None => {
+        self.device_flips.insert(device_id.to_owned(), flip);
+      }
+    }
+
+    self.devices.set(device_id, buffers);
+  }
+
+  /// Prepare a finished work set for another cycle
+  fn reset(&mut self, play_head: PlayHead) {
+    self.play_head = play_head;
+    self.nodes_to_execute.clear();
+    self.nodes_executing.clear();
+    self.nodes_executed.clear();
+    self.device_flips.values_mut().for_each(|flip| *flip = None);
+    self.devices.clear();
+    self.deadline = None;
+    self.cycle_time = Duration::ZERO;
+    self.scratch.clear();
+  }
 }
 
 impl From<PlayHead> for WorkSet {
   fn from(current_play_head: PlayHead) -> Self {
-    Self { play_head:             current_play_head,
-           nodes_to_execute:      Default::default(),
-           nodes_executing:       Default::default(),
-           nodes_executed:        Default::default(),
-           device_flips_started:  Default::default(),
-           device_flips_finished: Default::default(),
-           deadline:              None, }
+    Self { play_head:        current_play_head,
+           nodes_to_execute: Default::default(),
+           nodes_executing:  Default::default(),
+           nodes_executed:   Default::default(),
+           device_flips:     Default::default(),
+           devices:          Default::default(),
+           deadline:         None,
+           cycle_time:       Duration::ZERO,
+           scratch:          Default::default(), }
   }
 }
 
 impl GraphPlayer {
   pub(crate) async fn update_work_sets(&mut self) -> Result {
-    Self::execute_work_set(&mut self.current_work_set, &mut self.node_state, &self.node_apis, &self.tx_tasks);
+    // automation follows the play head, which does not move while stopped
+    let automation = (self.playback_state != GraphPlaybackState::Stopped).then_some(&self.automation);
+
+    Self::execute_work_set(&mut self.current_work_set,
+                           &mut self.node_state,
+                           &mut self.node_apis,
+                           &mut self.connections,
+                           automation,
+                           &mut self.spare_job_vecs,
+                           &self.workers,
+                           &self.tx_tasks);
 
     for work_set in &mut self.partial_work_sets {
-      Self::execute_work_set(work_set, &mut self.node_state, &self.node_apis, &self.tx_tasks);
+      Self::execute_work_set(work_set,
+                             &mut self.node_state,
+                             &mut self.node_apis,
+                             &mut self.connections,
+                             automation,
+                             &mut self.spare_job_vecs,
+                             &self.workers,
+                             &self.tx_tasks);
     }
 
-    self.partial_work_sets.retain(|ws| !ws.is_empty());
+    let mut index = 0;
+    while index < self.partial_work_sets.len() {
+      if !self.partial_work_sets[index].is_empty() {
+        index += 1;
+      } else if let Some(work_set) = self.partial_work_sets.remove(index) {
+        self.spare_work_sets.push(work_set);
+      }
+    }
 
-    if self.current_work_set.is_empty() {
+    // a work set without a deadline was not started by a device flip or the offline clock yet
+    if self.current_work_set.is_empty() && self.current_work_set.deadline.is_some() {
       self.current_work_set_finished();
     }
 
     if self.partial_work_sets.is_empty() && self.current_work_set.is_empty() {
-      match self.apply_pending_commands() {
-        | Err(err) => {
-          self.handle_error(err);
-        }
-        | Ok(PlayerCommandOutcome::NoAction) => { /* oh happy days */ }
-        | Ok(PlayerCommandOutcome::ConnectionSync) => {
-          self.sync_all_connections();
-        }
-        | Ok(PlayerCommandOutcome::Reset) => {
-          self.reset().await?;
-        }
+      if let Err(err) = self.apply_pending_commands_and_sync().await {
+        self.handle_error(err);
       }
     }
 
@@ -83,10 +151,18 @@ impl GraphPlayer {
 
   fn execute_work_set(work_set: &mut WorkSet,
                       node_states: &mut HashMap<NodeId, PlayerNodeState>,
-                      node_apis: &HashMap<NodeId, Arc<RwLock<BoxedNode>>>,
-                      tx_tasks: &mpsc::Sender<InternalTaskEvent>) {
-    'next_node: for ref node_id in work_set.nodes_to_execute.clone() {
-      let Some(node) = node_states.get_mut(node_id) else { continue };
+                      node_apis: &mut HashMap<NodeId, BoxedNode>,
+                      connections: &mut HashMap<(OutputId, InputId), Connection>,
+                      automation: Option<&HashMap<NodeId, AutomationLanes>>,
+                      spare_job_vecs: &mut SpareJobVecs,
+                      workers: &WorkerPool,
+                      tx_tasks: &CompletionSender) {
+    let mut candidates = mem::take(&mut work_set.scratch);
+    candidates.clear();
+    candidates.extend(work_set.nodes_to_execute.iter().copied());
+
+    'next_node: for node_id in &candidates {
+      let Some(node) = node_states.get(node_id) else { continue };
       if node.processing.is_some() {
         continue;
       };
@@ -100,51 +176,128 @@ impl GraphPlayer {
 
       // have all devices that we depend on started flipping?
       for required_device in &node.audio_device_requirements {
-        if !work_set.device_flips_started.contains_key(required_device) {
+        if work_set.device_flip(required_device).is_none() {
           continue 'next_node;
         }
       }
 
-      // node is executable
-      let Some(node_api) = node_apis.get(node_id) else { continue };
+      // node is executable, the worker takes it until it completes
+      let Some(node_api) = node_apis.remove(node_id) else { continue };
+
+      Self::gather_inputs(node, node_states, connections);
+
+      let Some(node) = node_states.get_mut(node_id) else { continue };
 
       work_set.nodes_to_execute.remove(node_id);
+      work_set.nodes_executing.insert(*node_id);
 
       node.processing = Some(work_set.play_head.generation);
-      spawn(execute_node(*node_id,
-                         node_api.clone(),
-                         work_set.devices_buffers(),
-                         node.buffers.clone(),
-                         work_set.play_head,
-                         work_set.deadline.expect("WorkSet Deadline not set"),
-                         tx_tasks.clone()));
+
+      let mut parameters = spare_job_vecs.parameters();
+      if let Some(lanes) = automation.and_then(|automation| automation.get(node_id)) {
+        lanes.events_in(work_set.play_head, &mut parameters);
+      }
+
+      let job = NodeJob { node_id:      *node_id,
+                          node:         node_api,
+                          devices:      work_set.devices.clone(),
+                          buffers:      node.buffers.clone(),
+                          play_head:    work_set.play_head,
+                          deadline:     work_set.deadline.expect("WorkSet Deadline not set"),
+                          parameters,
+                          events:       spare_job_vecs.events(),
+                          tx_completed: tx_tasks.clone(), };
+
+      // with the workers backed up, the node is late either way, but it must not get lost
+      if let Err(job) = workers.submit(job) {
+        spawn_blocking(move || execute_job(*job));
+      }
+    }
+
+    work_set.scratch = candidates;
+  }
+
+  /// Mix the outputs of all connected nodes into the node inputs
+  fn gather_inputs(node: &PlayerNodeState,
+                   node_states: &HashMap<NodeId, PlayerNodeState>,
+                   connections: &mut HashMap<(OutputId, InputId), Connection>) {
+    let buffers = &node.buffers;
+
+    for input in buffers.inputs() {
+      zero_slice(input);
+    }
+
+    for (input_id, outputs) in &node.node_inputs {
+      let input_index = input_id.channel_index();
+      if input_index >= buffers.num_inputs {
+        continue;
+      }
+
+      let target = buffers.input_plane(input_index);
+
+      for output_id in outputs {
+        let output_index = output_id.channel_index();
+        let Some(source) = node_states.get(&(*output_id).into()) else { continue };
+        let Some(connection) = connections.get_mut(&(*output_id, *input_id)) else { continue };
+
+        if output_index < source.buffers.num_outputs {
+          connection.transfer(source.buffers.output_plane(output_index), target);
+        }
+      }
     }
   }
 
-  pub(crate) async fn task_completed(&mut self, task_id: NodeId, generation: u64, result: Result<Vec<NodeEvent>>) -> Result {
+  pub(crate) async fn task_completed(&mut self,
+                                     task_id: NodeId,
+                                     generation: u64,
+                                     node: BoxedNode,
+                                     result: Result<Vec<NodeEvent>>,
+                                     parameters: Vec<ScheduledParameter>,
+                                     elapsed: Duration)
+                                     -> Result {
+    self.processing_times.add_node(task_id, elapsed);
+    self.return_node(task_id, generation, node);
+    self.spare_job_vecs.recycle_parameters(parameters);
+
     match result {
       | Err(err) => {
         bail!("Task {task_id} generation {generation} failed: {err}");
       }
+      | Ok(node_events) if node_events.is_empty() => {
+        self.spare_job_vecs.recycle_events(node_events);
+      }
       | Ok(node_events) => {
         let _ = self.tx_events
-                    .send(GraphPlayerEvent::NodeEvents { play_id: self.play_head.play_id,
-                                                         node_id: task_id,
-                                                         events:  node_events, })
-                    .await;
+                    .try_send(GraphPlayerEvent::NodeEvents { play_id: self.play_head.play_id,
+                                                             node_id: task_id,
+                                                             events:  node_events, });
       }
     }
 
-    let Some(node) = self.node_state.get_mut(&task_id) else { bail!("Task {task_id} generation {generation} completed but node not found") };
-
-    if node.processing != Some(generation) {
-      bail!("Task {task_id} generation {generation} completed but node is not processing");
+    match self.node_state.get_mut(&task_id) {
+      | Some(node) if node.processing == Some(generation) => node.processing = None,
+      | Some(_) => bail!("Task {task_id} generation {generation} completed but node is not processing"),
+      // a streaming sink removed while processing was stopped when it was returned, its cycle still has to finish
+      | None if matches!(task_id, NodeId::StreamingSink(_)) => {}
+      | None => bail!("Task {task_id} generation {generation} completed but node not found"),
     }
 
-    node.processing = None;
+    let work_set = if self.current_work_set.play_head.generation == generation {
+      &mut self.current_work_set
+    } else {
+      match self.partial_work_sets.iter_mut().find(|ws| ws.play_head.generation == generation) {
+        | Some(work_set) => work_set,
+        | None => bail!("Task {task_id} generation {generation} completed but no WorkSet with that generation exists"),
+      }
+    };
 
-    self.current_work_set.nodes_executing.remove(&task_id);
-    self.current_work_set.nodes_executed.insert(task_id);
+    work_set.nodes_executing.remove(&task_id);
+    work_set.nodes_executed.insert(task_id);
+
+    if let NodeId::StreamingSink(sink_id) = task_id {
+      let play_head = work_set.play_head;
+      self.emit_sink_captured(sink_id, play_head);
+    }
 
     self.update_device_flips();
 
@@ -153,57 +306,448 @@ impl GraphPlayer {
     Ok(())
   }
 
+  /// Take the node back from the worker, unless it was removed or replaced while it was processing
+  ///
+  /// Streaming sinks removed while processing are stopped now, so their stream ends with the frame they just encoded.
+  fn return_node(&mut self, node_id: NodeId, generation: u64, mut node: BoxedNode) {
+    if let NodeId::StreamingSink(sink_id) = node_id {
+      if let Some(StoppingSink { play_head, captured }) = self.stopping_sinks.remove(&(sink_id, generation)) {
+        self.stop_streaming_sink(sink_id, node, play_head, captured);
+        return;
+      }
+    }
+
+    let Some(state) = self.node_state.get(&node_id) else { return };
+    if state.processing != Some(generation) {
+      return;
+    }
+
+    for change in self.pending_parameters.remove(&node_id).into_iter().flatten() {
+      node.set_parameter(&change);
+    }
+
+    self.node_apis.insert(node_id, node);
+  }
+
   fn update_device_flips(&mut self) {
-    // if all nodes requiring device dev finished flipping...
-    let mut required_devices = HashSet::new();
+    let work_set = &mut self.current_work_set;
+
+    for (device_id, flip) in &mut work_set.device_flips {
+      let Some(flip) = flip else { continue };
+      if flip.finished {
+        continue;
+      }
+
+      // if all nodes requiring device dev finished flipping...
+      let required = work_set.nodes_to_execute
+                             .iter()
+                             .filter_map(|node_id| self.node_state.get(node_id))
+                             .any(|node| node.audio_device_requirements.contains(device_id));
+
+      if required {
+        continue;
+      }
 
-    for node_id in &self.current_work_set.nodes_to_execute {
-      let Some(node) = self.node_state.get(node_id) else { continue };
-      if !node.audio_device_requirements.is_empty() {
-        required_devices.extend(node.audio_device_requirements.iter().cloned());
+      flip.finished = true;
+
+      // bridged devices were released when they flipped, their proxies have no one to notify
+      if self.clock_domains.contains_key(device_id) {
+        continue;
       }
+
+      self.audio_devices
+          .send_command(device_id, DeviceCommand::FlipFinished { client_id:  self.client_id.clone(),
+                                                                 generation: flip.buffers.generation, })
+          .expect("Failed to send device flip finished command");
     }
+  }
+
+  /// Called when the current work set is still running at its deadline
+  ///
+  /// Nodes that have not started yet are skipped and output silence, as do the devices they would have written to. Nodes
+  /// that are still processing finish in a partial work set. The devices are released either way, so they can move on to
+  /// the next cycle.
+  pub(crate) async fn deadline_missed(&mut self) -> Result {
+    let work_set = &mut self.current_work_set;
+    if work_set.is_empty() {
+      return Ok(());
+    }
+
+    let late_nodes = work_set.nodes_to_execute
+                             .iter()
+                             .chain(work_set.nodes_executing.iter())
+                             .copied()
+                             .sorted()
+                             .collect::<Vec<_>>();
+
+    for node_id in mem::take(&mut work_set.nodes_to_execute) {
+      let Some(node) = self.node_state.get(&node_id) else { continue };
+
+      for output in node.buffers.outputs() {
+        zero_slice(output);
+      }
 
-    for (device_id, buffers) in &self.current_work_set.device_flips_started {
-      if !self.current_work_set.device_flips_finished.contains(device_id) && !required_devices.contains(device_id) {
-        self.current_work_set.device_flips_finished.insert(device_id.clone());
-        self.audio_devices
-            .send_command(device_id, DeviceCommand::FlipFinished { client_id:  self.client_id.clone(),
-                                                                   generation: buffers.generation, })
-            .expect("Failed to send device flip finished command");
+      for device_id in &node.audio_device_requirements {
+        if let Some(flip) = work_set.device_flip(device_id) {
+          flip.buffers.zero_outputs();
+        }
       }
+
+      work_set.nodes_executed.insert(node_id);
     }
+
+    let play_head = work_set.play_head;
+
+    self.xruns.add_missed(&late_nodes);
+    self.current_work_set_finished();
+
+    let _ = self.tx_events
+                .try_send(GraphPlayerEvent::GraphXRun { play_id: play_head.play_id,
+                                                        play_head,
+                                                        late_nodes,
+                                                        stats: self.xruns.clone() });
+
+    self.update_work_sets().await
   }
 
   fn current_work_set_finished(&mut self) {
     self.update_device_flips();
+    self.xruns.cycles += 1;
+
+    let buffer_size = self.current_work_set.play_head.buffer_size as usize;
+    for connection in self.connections.values_mut() {
+      connection.advance_fade(buffer_size);
+    }
+
+    if let Some(stats) = self.processing_times.cycle_finished(self.current_work_set.cycle_time) {
+      let _ = self.tx_events
+                  .try_send(GraphPlayerEvent::GraphProcessingStats { play_id: self.play_head.play_id,
+                                                                     stats });
+
+      self.emit_clock_domains();
+    }
 
     // create a new current WorkSet
-    self.play_head = self.play_head.advance_position();
-    let prev_work_set = mem::replace(&mut self.current_work_set, self.play_head.into());
+    match self.playback_state {
+      | GraphPlaybackState::Stopped => {
+        self.play_head.generation += 1;
+      }
+      | GraphPlaybackState::Buffering(_) => {
+        self.play_head = self.play_head.advance_position();
+        self.set_playback_state(GraphPlaybackState::Playing(self.play_head.position));
+      }
+      | GraphPlaybackState::Playing(_) => {
+        self.play_head = self.play_head.advance_position();
+        // only transitions are reported, position updates are implied by the node events
+        self.playback_state = GraphPlaybackState::Playing(self.play_head.position);
+
+        let region = self.play_head.play_region;
+        if !region.looping && self.play_head.position >= region.end {
+          self.set_playback_state(GraphPlaybackState::Stopped);
+          self.emit_loudness_summary();
+
+          // recordings and streams end with the region, nodes are only removed between cycles
+          if !self.recordings.is_empty() || !self.streaming_sinks.is_empty() {
+            self.pending_commands
+                .push_back(PlayerControlCommand::Stop { play_id: self.play_head.play_id }.into());
+          }
+        }
+      }
+    }
+
+    let next_work_set = match self.spare_work_sets.pop() {
+      | Some(mut work_set) => {
+        work_set.reset(self.play_head);
+        work_set
+      }
+      | None => self.play_head.into(),
+    };
+
+    let prev_work_set = mem::replace(&mut self.current_work_set, next_work_set);
 
     // store partial WorkSet if it is non-empty
-    if !prev_work_set.nodes_to_execute.is_empty() || !prev_work_set.nodes_executing.is_empty() {
+    if !prev_work_set.is_empty() {
       self.partial_work_sets.push_back(prev_work_set);
+    } else {
+      self.spare_work_sets.push(prev_work_set);
+    }
+  }
+
+  /// Report the loudness measured by the nodes during a play that completed its region or was stopped
+  pub(crate) fn emit_loudness_summary(&self) {
+    // nodes still processing a late cycle are with a worker, they are left out rather than waited for
+    let summaries = self.node_apis
+                        .iter()
+                        .filter_map(|(node_id, node)| Some((*node_id, node.loudness_summary()?)))
+                        .sorted_by_key(|(node_id, _)| *node_id)
+                        .collect::<Vec<_>>();
+
+    if !summaries.is_empty() {
+      let _ = self.tx_events
+                  .try_send(GraphPlayerEvent::GraphLoudnessSummary { play_id: self.play_head.play_id,
+                                                                     summaries });
     }
   }
 }
 
-async fn execute_node(id: NodeId,
-                      node_api: Arc<RwLock<BoxedNode>>,
-                      devices: DevicesBuffers,
-                      buffers: NodeBuffers,
-                      play_head: PlayHead,
-                      deadline: Instant,
-                      tx_tasks: mpsc::Sender<InternalTaskEvent>) {
-  // TODO: reuse events buffer?
-  let mut source = node_api.write().await;
-  let mut events = vec![];
-  let result = block_in_place(|| source.process(play_head, devices, buffers, deadline, &mut events));
-
-  tx_tasks.send(InternalTaskEvent::Completed { node_id:    id,
-                                               generation: play_head.generation,
-                                               result:     result.map(|_| events), })
+#[cfg(test)]
+mod test {
+  use std::path::PathBuf;
+
+  use maplit::hashmap;
+
+  use api::task::graph::{AudioGraphSpec, InsertId, SinkCodec, SinkSpec, VirtualInsertSpec};
+  use api::task::player::{LoudnessSummary, PlayRegion};
+
+  use crate::player::fixtures::{renderer, CONSTANT_MODEL_ID, SINE_MODEL_ID, SLOW_MODEL_ID, SLOW_NODE_DELAY};
+  use crate::player::offline::OfflineRenderer;
+  use crate::player::{ControlRequest, InternalTaskEvent};
+  use crate::virtual_inserts::GAIN_MODEL_ID;
+
+  use super::*;
+
+  #[tokio::test(flavor = "multi_thread")]
+  async fn test_deadline_miss_silences_late_nodes() {
+    let (slow, gain): (InsertId, InsertId) = (1, 2);
+
+    let spec = AudioGraphSpec { virtual_inserts: hashmap! {
+                                  slow => VirtualInsertSpec { inputs:   vec![vec![]],
+                                                              model_id: SLOW_MODEL_ID.to_owned(), },
+                                  gain => VirtualInsertSpec { inputs:   vec![vec![OutputId::VirtualInsert(slow, 0)]],
+                                                              model_id: GAIN_MODEL_ID.to_owned(), },
+                                },
+                                ..Default::default() };
+
+    let mut renderer = renderer(spec, 48_000, 64, vec![vec![OutputId::VirtualInsert(gain, 0)]], PathBuf::new());
+
+    // the first cycle has all the time it needs and leaves its output in the buffers
+    renderer.cycle().await.expect("Failed to render first cycle");
+
+    let gain_id = NodeId::VirtualInsert(gain);
+    let gain_output = |player: &GraphPlayer| player.node_state[&gain_id].buffers.output_plane(0).to_vec();
+    assert!(gain_output(&renderer.player).iter().all(|sample| *sample == 0.5));
+
+    // the second cycle is due long before the slow node finishes, the gain is still waiting for it by then
+    let player = &mut renderer.player;
+    let generation = player.play_head.generation;
+    let deadline = Instant::now() + SLOW_NODE_DELAY / 10;
+
+    player.current_work_set.deadline = Some(deadline);
+    player.current_work_set
+          .nodes_to_execute
+          .extend(player.node_state.keys().copied());
+    player.update_work_sets().await.expect("Failed to start second cycle");
+
+    tokio::select! {
+      _ = GraphPlayer::deadline_elapsed(Some(deadline)) => player.deadline_missed().await.expect("Failed to handle missed deadline"),
+      _ = player.rx_tasks.recv() => panic!("Slow node completed before the deadline"),
+    }
+
+    assert_eq!(player.play_head.generation, generation + 1);
+    assert!(gain_output(player).iter().all(|sample| *sample == 0.0));
+
+    // the slow node finishes in a partial work set, without running the gain it was late for
+    let Some(InternalTaskEvent::Completed { node_id,
+                                            node,
+                                            result,
+                                            parameters,
+                                            generation: task_generation,
+                                            elapsed, }) = player.rx_tasks.recv().await else { panic!("Task channel closed") };
+
+    assert_eq!(node_id, NodeId::VirtualInsert(slow));
+    player.task_completed(node_id, task_generation, node, result, parameters, elapsed)
           .await
-          .expect("Failed to send Task completion");
+          .expect("Failed to complete slow node");
+    assert!(player.partial_work_sets.is_empty());
+    assert!(gain_output(player).iter().all(|sample| *sample == 0.0));
+
+    let mut xrun = None;
+    while let Ok(event) = renderer.rx_events.try_recv() {
+      if let GraphPlayerEvent::GraphXRun { late_nodes, stats, .. } = event {
+        xrun = Some((late_nodes, stats));
+      }
+    }
+
+    let (late_nodes, stats) = xrun.expect("No xrun reported");
+    assert_eq!(late_nodes, vec![NodeId::VirtualInsert(slow), gain_id]);
+    assert_eq!(stats.cycles, 2);
+    assert_eq!(stats.missed_cycles, 1);
+  }
+
+  #[tokio::test(flavor = "multi_thread")]
+  async fn test_loudness_summary_on_stop() {
+    let sine: InsertId = 1;
+    let sine_outputs = vec![vec![OutputId::VirtualInsert(sine, 0)], vec![OutputId::VirtualInsert(sine, 1)]];
+
+    let spec = AudioGraphSpec { virtual_inserts: hashmap! {
+                                  sine => VirtualInsertSpec { inputs:   vec![vec![], vec![]],
+                                                              model_id: SINE_MODEL_ID.to_owned(), },
+                                },
+                                ..Default::default() };
+
+    let mut renderer = renderer(spec, 48_000 * 60, 480, vec![vec![OutputId::VirtualInsert(sine, 0)]], PathBuf::new());
+
+    // the sinks meter what they encode, whether lossless or not
+    let sinks = hashmap! {
+      1 => SinkSpec { inputs:      sine_outputs.clone(),
+                      sample_rate: 48_000,
+                      codec:       SinkCodec::Flac, },
+      2 => SinkSpec { inputs:      sine_outputs,
+                      sample_rate: 48_000,
+                      codec:       SinkCodec::Opus { bitrate: 128_000 }, },
+    };
+
+    let player = &mut renderer.player;
+    player.pending_commands
+          .push_back(ControlRequest::from(PlayerControlCommand::Play { play_id: 1,
+                                                                       sinks,
+                                                                       recordings: Default::default(),
+                                                                       region: PlayRegion { start:     0,
+                                                                                            end:       48_000 * 60,
+                                                                                            looping:   false,
+                                                                                            crossfade: 0, },
+                                                                       start_from: 0 }));
+    player.apply_pending_commands_and_sync().await.expect("Failed to play");
+
+    let mut loudness = vec![];
+    let mut drain_events = |renderer: &mut OfflineRenderer| {
+      while let Ok(event) = renderer.rx_events.try_recv() {
+        if let GraphPlayerEvent::GraphLoudnessSummary { play_id, summaries } = event {
+          assert_eq!(play_id, 1);
+          loudness.push(summaries);
+        }
+      }
+    };
+
+    // five seconds into a minute long region
+    for _ in 0..500 {
+      renderer.cycle().await.expect("Failed to render cycle");
+      drain_events(&mut renderer);
+    }
+
+    let player = &mut renderer.player;
+    player.pending_commands
+          .push_back(ControlRequest::from(PlayerControlCommand::Stop { play_id: 1 }));
+    player.apply_pending_commands_and_sync().await.expect("Failed to stop");
+    drain_events(&mut renderer);
+
+    assert_eq!(loudness.len(), 1);
+
+    let nodes = loudness[0].iter().map(|(node_id, _)| *node_id).collect::<Vec<_>>();
+    assert_eq!(nodes, vec![NodeId::StreamingSink(1), NodeId::StreamingSink(2)]);
+
+    for (node_id, LoudnessSummary { integrated, max_true_peak, .. }) in &loudness[0] {
+      assert!((integrated + 23.0).abs() < 0.1, "{node_id} integrated {integrated}");
+      assert!((max_true_peak + 23.0).abs() < 0.2, "{node_id} true peak {max_true_peak}");
+    }
+  }
+
+  /// Play the region with a FLAC streaming sink fed by a constant
+  async fn play_streaming_sink(end: u64) -> OfflineRenderer {
+    let constant: InsertId = 1;
+
+    let spec = AudioGraphSpec { virtual_inserts: hashmap! {
+                                  constant => VirtualInsertSpec { inputs:   vec![vec![]],
+                                                                  model_id: CONSTANT_MODEL_ID.to_owned(), },
+                                },
+                                ..Default::default() };
+
+    let mut renderer = renderer(spec, end, 480, vec![vec![OutputId::VirtualInsert(constant, 0)]], PathBuf::new());
+
+    let sinks = hashmap! {
+      1 => SinkSpec { inputs:      vec![vec![OutputId::VirtualInsert(1, 0)]],
+                      sample_rate: 48_000,
+                      codec:       SinkCodec::Flac, },
+    };
+
+    let player = &mut renderer.player;
+    player.pending_commands
+          .push_back(ControlRequest::from(PlayerControlCommand::Play { play_id: 1,
+                                                                       sinks,
+                                                                       recordings: Default::default(),
+                                                                       region: PlayRegion { start: 0,
+                                                                                            end,
+                                                                                            looping: false,
+                                                                                            crossfade: 0 },
+                                                                       start_from: 0 }));
+    player.apply_pending_commands_and_sync().await.expect("Failed to play");
+
+    renderer
+  }
+
+  /// The encoded output of the sinks, once the player and the tasks sending the tails are gone
+  async fn captured_streams(renderer: OfflineRenderer) -> Vec<u8> {
+    let OfflineRenderer { player, mut rx_captured, .. } = renderer;
+    drop(player);
+
+    let mut stream = vec![];
+    while let Some(event) = rx_captured.recv().await {
+      if let GraphPlayerEvent::GraphSinkCaptured { sink_id, data, .. } = event {
+        assert_eq!(sink_id, 1);
+        stream.extend_from_slice(&data);
+      }
+    }
+
+    stream
+  }
+
+  #[tokio::test(flavor = "multi_thread")]
+  async fn test_streams_end_with_region() {
+    let mut renderer = play_streaming_sink(4_800).await;
+
+    for _ in 0..10 {
+      renderer.cycle().await.expect("Failed to render cycle");
+    }
+
+    assert!(renderer.player.streaming_sinks.is_empty());
+    assert!(!renderer.player.node_apis.contains_key(&NodeId::StreamingSink(1)));
+
+    let stream = captured_streams(renderer).await;
+    assert_eq!(&stream[..4], b"fLaC");
+  }
+
+  #[tokio::test(flavor = "multi_thread")]
+  async fn test_stop_waits_for_sink_held_by_worker() {
+    let mut renderer = play_streaming_sink(48_000).await;
+
+    let sink = NodeId::StreamingSink(1);
+    let player = &mut renderer.player;
+    let generation = player.play_head.generation;
+
+    player.current_work_set.deadline = Some(Instant::now() + Duration::from_secs(60));
+    player.current_work_set
+          .nodes_to_execute
+          .extend(player.node_state.keys().copied());
+    player.update_work_sets().await.expect("Failed to start cycle");
+
+    let mut stopped = false;
+    while player.play_head.generation == generation {
+      let Some(InternalTaskEvent::Completed { node_id,
+                                              node,
+                                              result,
+                                              parameters,
+                                              generation: task_generation,
+                                              elapsed, }) = player.rx_tasks.recv().await else { panic!("Task channel closed") };
+
+      player.task_completed(node_id, task_generation, node, result, parameters, elapsed)
+            .await
+            .expect("Failed to complete task");
+
+      // the sink was dispatched once its input was ready, it is with the worker until its completion is handled
+      if !stopped && !player.node_apis.contains_key(&sink) {
+        player.pending_commands
+              .push_back(ControlRequest::from(PlayerControlCommand::Stop { play_id: 1 }));
+        player.apply_pending_commands().expect("Failed to stop");
+
+        assert!(player.stopping_sinks.contains_key(&(1, generation)));
+        stopped = true;
+      }
+    }
+
+    assert!(stopped);
+    assert!(player.stopping_sinks.is_empty());
+
+    let stream = captured_streams(renderer).await;
+    assert_eq!(&stream[..4], b"fLaC");
+  }
 }
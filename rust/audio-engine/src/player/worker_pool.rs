@@ -0,0 +1,401 @@
+use std::future::poll_fn;
+use std::sync::{Arc, Once};
+use std::task::{Context, Poll};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::{Receiver, Sender, TryRecvError, TrySendError};
+use futures::task::AtomicWaker;
+use lazy_static::lazy_static;
+use tracing::warn;
+
+use api::task::graph::NodeId;
+use api::task::player::{NodeEvent, PlayHead};
+
+use crate::automation::ScheduledParameter;
+use crate::buffer::{DevicesBuffers, NodeBuffers};
+use crate::player::InternalTaskEvent;
+use crate::{BoxedNode, Result};
+
+/// Maximum number of jobs waiting for a worker, across all players sharing the pool
+const MAX_QUEUED_JOBS: usize = 0x400;
+
+/// Maximum number of completed nodes waiting for a player
+const MAX_COMPLETED_JOBS: usize = 0xff;
+
+/// How long an idle worker keeps polling for jobs before it parks, trading a little CPU for wake up latency
+///
+/// Workers only poll if a core is left for the player, otherwise they would hold up the nodes they wait for.
+const SPIN_BEFORE_PARK: Duration = Duration::from_micros(200);
+
+/// Realtime priority of the workers below the highest one, which is left to the audio device threads
+#[cfg(unix)]
+const PRIORITY_BELOW_MAX: libc::c_int = 10;
+
+lazy_static! {
+  static ref SHARED: Arc<WorkerPool> = Arc::new(WorkerPool::new(default_num_workers()));
+}
+
+static PRIORITY_WARNING: Once = Once::new();
+
+/// A node handed to a worker for one cycle
+///
+/// The node moves into the job and comes back with [InternalTaskEvent::Completed], so the worker has exclusive access
+/// to it without taking a lock.
+pub struct NodeJob {
+  pub node_id:      NodeId,
+  pub node:         BoxedNode,
+  pub devices:      DevicesBuffers,
+  pub buffers:      NodeBuffers,
+  pub play_head:    PlayHead,
+  pub deadline:     Instant,
+  /// Automated parameter changes within the buffer, applied right before processing
+  pub parameters:   Vec<ScheduledParameter>,
+  /// Empty vector the node reports its events into
+  pub events:       Vec<NodeEvent>,
+  pub tx_completed: CompletionSender,
+}
+
+/// Vectors of completed jobs, kept by a player to hand them to the next jobs
+///
+/// The vectors keep their capacity, so jobs only allocate when their automation or events outgrow it. Vectors holding
+/// events leave with the player events and are not returned, so only nodes that report no events in a cycle keep
+/// theirs.
+#[derive(Debug, Default)]
+pub struct SpareJobVecs {
+  parameters: Vec<Vec<ScheduledParameter>>,
+  events:     Vec<Vec<NodeEvent>>,
+}
+
+impl SpareJobVecs {
+  pub fn parameters(&mut self) -> Vec<ScheduledParameter> {
+    self.parameters.pop().unwrap_or_default()
+  }
+
+  pub fn events(&mut self) -> Vec<NodeEvent> {
+    self.events.pop().unwrap_or_default()
+  }
+
+  pub fn recycle_parameters(&mut self, mut parameters: Vec<ScheduledParameter>) {
+    parameters.clear();
+    self.parameters.push(parameters);
+  }
+
+  pub fn recycle_events(&mut self, mut events: Vec<NodeEvent>) {
+    events.clear();
+    self.events.push(events);
+  }
+}
+
+/// Dedicated threads processing nodes, fed through a lock-free queue
+///
+/// Workers poll the queue for a short while after every job before parking, so nodes that become ready in quick
+/// succession within a cycle are picked up without a thread wake up. The players keep walking the dependency graph and
+/// submit nodes as soon as their inputs are ready.
+///
+/// The workers ask for realtime scheduling when they start, which needs `CAP_SYS_NICE` or an `RLIMIT_RTPRIO` covering
+/// their priority on Linux. Without it they run at normal priority and a warning is logged once.
+pub struct WorkerPool {
+  tx_jobs:     Sender<NodeJob>,
+  num_workers: usize,
+}
+
+impl WorkerPool {
+  pub fn new(num_workers: usize) -> Self {
+    let num_workers = num_workers.max(1);
+    let (tx_jobs, rx_jobs) = crossbeam_channel::bounded(MAX_QUEUED_JOBS);
+    let spin = if num_cores() > num_workers { SPIN_BEFORE_PARK } else { Duration::ZERO };
+
+    for index in 0..num_workers {
+      let rx_jobs = rx_jobs.clone();
+
+      thread::Builder::new().name(format!("audio-worker-{index}"))
+                            .spawn(move || run_worker(rx_jobs, spin))
+                            .expect("Failed to spawn audio worker thread");
+    }
+
+    Self { tx_jobs, num_workers }
+  }
+
+  /// The pool shared by all players in the process, with one worker per core except one
+  pub fn shared() -> Arc<Self> {
+    SHARED.clone()
+  }
+
+  pub fn num_workers(&self) -> usize {
+    self.num_workers
+  }
+
+  /// Queue the node for processing, returning the job if the queue is full
+  pub fn submit(&self, job: NodeJob) -> std::result::Result<(), Box<NodeJob>> {
+    self.tx_jobs.try_send(job).map_err(|err| Box::new(err.into_inner()))
+  }
+}
+
+/// Create the queue through which the workers return the nodes of a player
+pub fn completion_queue() -> (CompletionSender, CompletionReceiver) {
+  let (tx, rx) = crossbeam_channel::bounded(MAX_COMPLETED_JOBS);
+  let waker = Arc::new(AtomicWaker::new());
+
+  (CompletionSender { tx, waker: waker.clone() }, CompletionReceiver { rx, waker })
+}
+
+/// The worker end of a completion queue, cloned into every job
+#[derive(Clone)]
+pub struct CompletionSender {
+  tx:    Sender<InternalTaskEvent>,
+  waker: Arc<AtomicWaker>,
+}
+
+impl CompletionSender {
+  /// Queue the completed node and wake the player, returning false if the player is gone
+  ///
+  /// The worker only waits for the player if it fell so far behind that the queue is full.
+  pub fn send(&self, event: InternalTaskEvent) -> bool {
+    let rv = match self.tx.try_send(event) {
+      | Ok(()) => true,
+      | Err(TrySendError::Full(event)) => {
+        warn!("Completion queue full, waiting for the player");
+        self.waker.wake();
+        self.tx.send(event).is_ok()
+      }
+      | Err(TrySendError::Disconnected(_)) => false,
+    };
+
+    self.waker.wake();
+
+    rv
+  }
+}
+
+/// The player end of a completion queue
+pub struct CompletionReceiver {
+  rx:    Receiver<InternalTaskEvent>,
+  waker: Arc<AtomicWaker>,
+}
+
+impl CompletionReceiver {
+  /// Wait for the next completed node, returns `None` once all senders are gone
+  pub async fn recv(&mut self) -> Option<InternalTaskEvent> {
+    poll_fn(|cx| self.poll_recv(cx)).await
+  }
+
+  fn poll_recv(&self, cx: &mut Context<'_>) -> Poll<Option<InternalTaskEvent>> {
+    match self.rx.try_recv() {
+      | Err(TryRecvError::Empty) => {}
+      | result => return Poll::Ready(result.ok()),
+    }
+
+    // a node completed before the waker was registered would not wake us, so look again
+    self.waker.register(cx.waker());
+
+    match self.rx.try_recv() {
+      | Err(TryRecvError::Empty) => Poll::Pending,
+      | result => Poll::Ready(result.ok()),
+    }
+  }
+}
+
+fn num_cores() -> usize {
+  thread::available_parallelism().map(|cores| cores.get()).unwrap_or(1)
+}
+
+fn default_num_workers() -> usize {
+  num_cores().saturating_sub(1)
+}
+
+fn run_worker(rx_jobs: Receiver<NodeJob>, spin: Duration) {
+  if let Err(err) = promote_to_realtime() {
+    PRIORITY_WARNING.call_once(|| warn!(?err, "Audio workers run without realtime priority: {err}"));
+  }
+
+  loop {
+    let job = match poll_job(&rx_jobs, spin) {
+      | Some(job) => job,
+      | None => match rx_jobs.recv() {
+        | Ok(job) => job,
+        | Err(_) => break,
+      },
+    };
+
+    execute_job(job);
+  }
+}
+
+#[cfg(unix)]
+fn promote_to_realtime() -> Result {
+  unsafe {
+    let max = libc::sched_get_priority_max(libc::SCHED_FIFO);
+    let min = libc::sched_get_priority_min(libc::SCHED_FIFO);
+
+    // zeroed, some platforms have more fields than the priority
+    let mut param: libc::sched_param = std::mem::zeroed();
+    param.sched_priority = (max - PRIORITY_BELOW_MAX).max(min);
+
+    match libc::pthread_setschedparam(libc::pthread_self(), libc::SCHED_FIFO, &param) {
+      | 0 => Ok(()),
+      | err => Err(std::io::Error::from_raw_os_error(err).into()),
+    }
+  }
+}
+
+#[cfg(not(unix))]
+fn promote_to_realtime() -> Result {
+  anyhow::bail!("Realtime priority is not supported on this platform")
+}
+
+fn poll_job(rx_jobs: &Receiver<NodeJob>, spin: Duration) -> Option<NodeJob> {
+  let started = Instant::now();
+
+  while started.elapsed() < spin {
+    match rx_jobs.try_recv() {
+      | Ok(job) => return Some(job),
+      | Err(TryRecvError::Empty) => std::hint::spin_loop(),
+      | Err(TryRecvError::Disconnected) => return None,
+    }
+  }
+
+  None
+}
+
+pub(crate) fn execute_job(NodeJob { node_id,
+                                    mut node,
+                                    devices,
+                                    buffers,
+                                    play_head,
+                                    deadline,
+                                    parameters,
+                                    mut events,
+                                    tx_completed, }: NodeJob) {
+  for ScheduledParameter { offset, change, ramp } in &parameters {
+    node.schedule_parameter(change, *offset, *ramp);
+  }
+
+  let started = Instant::now();
+  let result = node.process(play_head, devices, buffers, deadline, &mut events);
+  let elapsed = started.elapsed();
+
+  let completed = InternalTaskEvent::Completed { node_id,
+                                                 node,
+                                                 generation: play_head.generation,
+                                                 result: result.map(|_| events),
+                                                 parameters,
+                                                 elapsed };
+
+  if !tx_completed.send(completed) {
+    warn!(%node_id, "Player did not take the completed node");
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::collections::HashSet;
+  use std::sync::Mutex;
+
+  use api::task::player::NodeInfo;
+
+  use super::*;
+  use crate::Node;
+
+  struct HalfGainNode;
+
+  impl Node for HalfGainNode {
+    fn process(&mut self, _play: PlayHead, _devices: DevicesBuffers, io: NodeBuffers, _deadline: Instant, _events: &mut Vec<NodeEvent>) -> Result {
+      for (output, input) in io.outputs().zip(io.inputs()) {
+        output.iter_mut().zip(input.iter()).for_each(|(output, input)| *output = *input * 0.5);
+      }
+
+      Ok(())
+    }
+  }
+
+  /// Holds up the worker processing it until the test lets go of the lock
+  struct BlockingNode(Arc<Mutex<()>>);
+
+  impl Node for BlockingNode {
+    fn process(&mut self, _play: PlayHead, _devices: DevicesBuffers, _io: NodeBuffers, _deadline: Instant, _events: &mut Vec<NodeEvent>) -> Result {
+      let _guard = self.0.lock().unwrap();
+      Ok(())
+    }
+  }
+
+  fn job(node_id: NodeId, node: BoxedNode, buffers: &NodeBuffers, tx_completed: &CompletionSender) -> NodeJob {
+    NodeJob { node_id,
+              node,
+              devices: DevicesBuffers::default(),
+              buffers: buffers.clone(),
+              play_head: PlayHead { generation: 7,
+                                    ..Default::default() },
+              deadline: Instant::now() + Duration::from_secs(1),
+              parameters: vec![],
+              events: vec![],
+              tx_completed: tx_completed.clone() }
+  }
+
+  fn node_buffers() -> NodeBuffers {
+    NodeBuffers::allocate(&NodeInfo { num_inputs: 1,
+                                      num_outputs: 1,
+                                      ..Default::default() },
+                          32)
+  }
+
+  #[tokio::test]
+  async fn test_return_completed_nodes() {
+    let workers = WorkerPool::new(2);
+    let (tx_completed, mut rx_completed) = completion_queue();
+
+    let buffers = (0..8).map(|_| node_buffers()).collect::<Vec<_>>();
+    for (index, buffers) in buffers.iter().enumerate() {
+      buffers.input_plane(0).fill(index as f64);
+
+      if workers.submit(job(NodeId::Bus(index as u64), Box::new(HalfGainNode), buffers, &tx_completed)).is_err() {
+        panic!("Worker queue full");
+      }
+    }
+
+    let mut completed = HashSet::new();
+    while completed.len() < buffers.len() {
+      let Some(InternalTaskEvent::Completed { node_id, generation, result, .. }) = rx_completed.recv().await else { panic!("Queue closed") };
+
+      assert_eq!(generation, 7);
+      assert!(result.is_ok());
+      assert!(completed.insert(node_id), "{node_id} completed twice");
+    }
+
+    for (index, buffers) in buffers.iter().enumerate() {
+      assert!(buffers.output_plane(0).iter().all(|sample| *sample == index as f64 * 0.5));
+    }
+  }
+
+  #[tokio::test]
+  async fn test_return_job_when_queue_full() {
+    let workers = WorkerPool::new(1);
+    let (tx_completed, mut rx_completed) = completion_queue();
+    let buffers = node_buffers();
+    let lock = Arc::new(Mutex::new(()));
+
+    let guard = lock.lock().unwrap();
+
+    let submit = |index: usize| workers.submit(job(NodeId::Bus(index as u64), Box::new(BlockingNode(lock.clone())), &buffers, &tx_completed));
+
+    // the worker takes the first job and blocks on it, then the queue fills up
+    assert!(submit(0).is_ok());
+    while !workers.tx_jobs.is_empty() {
+      thread::yield_now();
+    }
+
+    for index in 1..=MAX_QUEUED_JOBS {
+      assert!(submit(index).is_ok());
+    }
+
+    let rejected = submit(MAX_QUEUED_JOBS + 1).err().expect("Job accepted by a full queue");
+    assert_eq!(rejected.node_id, NodeId::Bus(MAX_QUEUED_JOBS as u64 + 1));
+
+    drop(guard);
+
+    // more completions than the completion queue holds, the worker waits for them to be taken
+    for _ in 0..=MAX_QUEUED_JOBS {
+      assert!(rx_completed.recv().await.is_some());
+    }
+  }
+}
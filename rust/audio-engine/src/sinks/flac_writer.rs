@@ -0,0 +1,194 @@
+use std::ffi::{CStr, CString};
+use std::path::Path;
+use std::ptr::null_mut;
+
+use anyhow::{anyhow, bail};
+
+use libflac_sys::*;
+
+use crate::Result;
+
+/// Encodes planar `f64` samples to a FLAC file using libFLAC
+///
+/// The stream info is rewritten in [FlacWriter::finalize], which must be called for the file to declare its length.
+pub struct FlacWriter {
+  encoder:         *mut FLAC__StreamEncoder,
+  num_channels:    usize,
+  bits_per_sample: u32,
+  planes:          Vec<Vec<i32>>,
+  num_frames:      u64,
+}
+
+unsafe impl Send for FlacWriter {}
+unsafe impl Sync for FlacWriter {}
+
+impl Drop for FlacWriter {
+  fn drop(&mut self) {
+    unsafe {
+      if !self.encoder.is_null() {
+        FLAC__stream_encoder_delete(self.encoder);
+        self.encoder = null_mut();
+      }
+    }
+  }
+}
+
+impl FlacWriter {
+  pub fn create(path: impl AsRef<Path>, num_channels: usize, sample_rate: u32, bits_per_sample: u32) -> Result<Self> {
+    let path = path.as_ref();
+    let c_path = CString::new(path.to_string_lossy().as_bytes())?;
+
+    if num_channels == 0 || num_channels > 8 {
+      bail!("Unsupported number of FLAC channels: {num_channels}");
+    }
+
+    if !matches!(bits_per_sample, 16 | 24) {
+      bail!("FlacWriter supports only 16 or 24 bits per sample");
+    }
+
+    let encoder = unsafe { FLAC__stream_encoder_new() };
+    if encoder.is_null() {
+      bail!("FLAC__stream_encoder_new failed");
+    }
+
+    // constructed before init, so the encoder is cleaned up on every error path
+    let writer = Self { encoder,
+                        num_channels,
+                        bits_per_sample,
+                        planes: vec![vec![]; num_channels],
+                        num_frames: 0 };
+
+    unsafe {
+      if FLAC__stream_encoder_set_bits_per_sample(encoder, bits_per_sample) != 1 {
+        bail!("FLAC__stream_encoder_set_bits_per_sample failed: {bits_per_sample}")
+      }
+
+      if FLAC__stream_encoder_set_channels(encoder, num_channels as u32) != 1 {
+        bail!("FLAC__stream_encoder_set_channels failed: {num_channels}")
+      }
+
+      if FLAC__stream_encoder_set_sample_rate(encoder, sample_rate) != 1 {
+        bail!("FLAC__stream_encoder_set_sample_rate failed: {sample_rate}")
+      }
+    }
+
+    let init_rv = unsafe { FLAC__stream_encoder_init_file(encoder, c_path.as_ptr(), None, null_mut()) };
+
+    if init_rv != FLAC__STREAM_ENCODER_INIT_STATUS_OK {
+      return Err(anyhow!("FLAC__stream_encoder_init_file failed for {}: {init_rv}", path.display()));
+    }
+
+    Ok(writer)
+  }
+
+  pub fn num_frames(&self) -> u64 {
+    self.num_frames
+  }
+
+  /// Write `num_frames` frames, taking one plane per channel
+  pub fn write_planar(&mut self, planes: &[&[f64]], num_frames: usize) -> Result {
+    if planes.len() != self.num_channels {
+      bail!("Expected {} planes, got {}", self.num_channels, planes.len());
+    }
+
+    if planes.iter().any(|plane| plane.len() < num_frames) {
+      bail!("Not all planes contain {num_frames} frames");
+    }
+
+    let scale = ((1u32 << (self.bits_per_sample - 1)) - 1) as f64;
+
+    for (encoded, plane) in self.planes.iter_mut().zip(planes) {
+      encoded.clear();
+      encoded.extend(plane[..num_frames].iter()
+                                        .map(|sample| (sample.clamp(-1.0, 1.0) * scale).round() as i32));
+    }
+
+    let pointers = self.planes.iter().map(|plane| plane.as_ptr()).collect::<Vec<_>>();
+
+    if unsafe { FLAC__stream_encoder_process(self.encoder, pointers.as_ptr(), num_frames as u32) } == 0 {
+      bail!("FLAC__stream_encoder_process failed: {}", self.state_string());
+    }
+
+    self.num_frames += num_frames as u64;
+
+    Ok(())
+  }
+
+  /// Flush the remaining samples and rewrite the stream info with the final length
+  pub fn finalize(self) -> Result {
+    if unsafe { FLAC__stream_encoder_finish(self.encoder) } == 0 {
+      bail!("FLAC__stream_encoder_finish failed: {}", self.state_string());
+    }
+
+    Ok(())
+  }
+
+  fn state_string(&self) -> String {
+    unsafe {
+      CStr::from_ptr(FLAC__stream_encoder_get_resolved_state_string(self.encoder)).to_string_lossy()
+                                                                                  .into_owned()
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use nanoid::nanoid;
+
+  use crate::sources::flac_reader::FlacReader;
+
+  use super::*;
+
+  #[test]
+  fn test_write_and_read_back() {
+    let path = std::env::temp_dir().join(format!("flac-writer-{}.flac", nanoid!()));
+    let mut writer = FlacWriter::create(&path, 2, 44_100, 16).expect("Failed to create writer");
+
+    let left = (0..1000).map(|i| (i as f64 / 100.0).sin()).collect::<Vec<_>>();
+    let right = left.iter().map(|sample| -sample * 0.5).collect::<Vec<_>>();
+
+    writer.write_planar(&[&left[..600], &right[..600]], 600).unwrap();
+    writer.write_planar(&[&left[600..], &right[600..]], 400).unwrap();
+    assert_eq!(writer.num_frames(), 1000);
+
+    // samples out of range are clipped
+    writer.write_planar(&[&[2.0][..], &[-2.0][..]], 1).unwrap();
+    writer.finalize().expect("Failed to finalize");
+
+    let mut reader = FlacReader::open(&path).expect("Failed to open");
+    assert_eq!(reader.num_channels(), 2);
+    assert_eq!(reader.sample_rate(), 44_100);
+    assert_eq!(reader.num_frames(), 1001);
+
+    let mut planes = vec![vec![0.0; 1001]; 2];
+    assert_eq!(reader.read_planar(0, &mut planes, 1001).unwrap(), 1001);
+
+    let tolerance = 1.0 / (1 << 14) as f64;
+    for i in 0..1000 {
+      assert!((planes[0][i] - left[i]).abs() < tolerance, "left sample {i}");
+      assert!((planes[1][i] - right[i]).abs() < tolerance, "right sample {i}");
+    }
+
+    assert!(planes[0][1000] > 1.0 - tolerance);
+    assert!(planes[1][1000] < -1.0 + tolerance);
+
+    let _ = std::fs::remove_file(&path);
+  }
+
+  #[test]
+  fn test_reject_invalid_settings() {
+    let path = std::env::temp_dir().join(format!("flac-writer-{}.flac", nanoid!()));
+
+    assert!(FlacWriter::create(&path, 0, 48_000, 24).is_err());
+    assert!(FlacWriter::create(&path, 9, 48_000, 24).is_err());
+    assert!(FlacWriter::create(&path, 2, 48_000, 32).is_err());
+
+    let mut writer = FlacWriter::create(&path, 2, 48_000, 24).expect("Failed to create writer");
+    assert!(writer.write_planar(&[&[0.0; 4][..]], 4).is_err());
+    assert!(writer.write_planar(&[&[0.0; 4][..], &[0.0; 2][..]], 4).is_err());
+    assert_eq!(writer.num_frames(), 0);
+
+    drop(writer);
+    let _ = std::fs::remove_file(&path);
+  }
+}
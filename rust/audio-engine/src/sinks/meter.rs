@@ -0,0 +1,111 @@
+use ebur128::{EbuR128, Mode};
+
+use api::task::player::{LoudnessSummary, NodeEvent};
+
+use crate::events::make_report;
+use crate::Result;
+
+use super::reports;
+
+/// Peak and loudness reports of the sinks, measured on the samples as they are played or encoded
+pub struct SinkMeter {
+  measurements:     EbuR128,
+  num_channels:     usize,
+  measure_position: u64,
+  measure_interval: u64,
+  max_short_term:   f64,
+  interleaved:      Vec<f64>,
+}
+
+impl SinkMeter {
+  /// `max_frames` is the most frames passed to a single [SinkMeter::measure] call, measuring more will allocate
+  pub fn new(channels: usize, sample_rate: u32, max_frames: usize) -> Result<Self> {
+    let measurements = EbuR128::new(channels as u32, sample_rate, Mode::TRUE_PEAK | Mode::I | Mode::LRA)?;
+    let measure_interval = (sample_rate as f64 * reports::MEASURE_LUFS_FACTOR).floor() as u64;
+
+    Ok(Self { measurements,
+              num_channels: channels,
+              measure_position: 0,
+              measure_interval,
+              max_short_term: f64::NEG_INFINITY,
+              interleaved: Vec::with_capacity(channels * max_frames) })
+  }
+
+  /// Forget everything measured so far
+  pub fn reset(&mut self) {
+    self.measurements.reset();
+    self.measure_position = 0;
+    self.max_short_term = f64::NEG_INFINITY;
+  }
+
+  /// Measure the first `num_frames` of every channel plane, reporting the true peak per channel and the momentary loudness
+  pub fn measure<'a>(&mut self, plane: impl Fn(usize) -> &'a [f64], num_frames: usize, events: &mut Vec<NodeEvent>) -> Result {
+    let num_channels = self.num_channels;
+
+    let plane = &plane;
+    self.interleaved.clear();
+    self.interleaved
+        .extend((0..num_frames).flat_map(|frame| (0..num_channels).map(move |channel| plane(channel)[frame])));
+
+    self.measurements.add_frames_f64(&self.interleaved)?;
+    events.extend((0..num_channels).map(|i| (i, self.measurements.true_peak(i as u32).unwrap_or_default()))
+                                   .map(make_report(reports::PEAK_LEVEL, 0)));
+
+    self.measure_position += num_frames as u64;
+    while self.measure_position > self.measure_interval {
+      events.push(make_report(reports::LUFS_LEVEL, 0)((0, self.measurements.loudness_momentary()?)));
+      self.max_short_term = self.max_short_term.max(self.measurements.loudness_shortterm()?);
+      self.measure_position -= self.measure_interval;
+    }
+
+    Ok(())
+  }
+
+  /// The EBU R128 summary of everything measured since the meter was created or reset
+  pub fn summary(&self) -> Result<LoudnessSummary> {
+    let max_true_peak = (0..self.num_channels).try_fold(0.0f64, |max, i| self.measurements.true_peak(i as u32).map(|peak| max.max(peak)))?;
+
+    Ok(LoudnessSummary { integrated:     self.measurements.loudness_global()?,
+                         range:          self.measurements.loudness_range()?,
+                         max_true_peak:  20.0 * max_true_peak.log10(),
+                         max_short_term: self.max_short_term, })
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::f64::consts::TAU;
+
+  use super::*;
+
+  #[test]
+  fn test_reference_sine() {
+    // EBU Tech 3341 case 1, a 1 kHz sine at -23 dBFS on both channels of a stereo signal measures -23 LUFS
+    let amplitude = 10f64.powf(-23.0 / 20.0);
+    let sine = (0..4_800).map(|i| (i as f64 * 1_000.0 / 48_000.0 * TAU).sin() * amplitude)
+                         .collect::<Vec<_>>();
+
+    let mut meter = SinkMeter::new(2, 48_000, sine.len()).expect("Failed to create meter");
+    let mut events = vec![];
+
+    // 4800 samples are a whole number of periods, so 100ms blocks repeat seamlessly
+    for _ in 0..100 {
+      meter.measure(|_| &sine[..], sine.len(), &mut events).expect("Failed to measure");
+    }
+
+    let summary = meter.summary().expect("Failed to summarize");
+    assert!((summary.integrated + 23.0).abs() < 0.1, "integrated {}", summary.integrated);
+    assert!((summary.max_short_term + 23.0).abs() < 0.1, "short term {}", summary.max_short_term);
+    assert!((summary.max_true_peak + 23.0).abs() < 0.2, "true peak {}", summary.max_true_peak);
+    assert!(summary.range < 0.1, "range {}", summary.range);
+
+    // a momentary loudness report every 400ms
+    let num_lufs_reports = events.iter()
+                                 .filter(|event| matches!(event, NodeEvent::Report { name, .. } if name == reports::LUFS_LEVEL))
+                                 .count();
+    assert_eq!(num_lufs_reports, 24);
+
+    meter.reset();
+    assert!(meter.summary().expect("Failed to summarize").integrated < -70.0);
+  }
+}
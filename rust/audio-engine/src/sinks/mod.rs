@@ -1,4 +1,8 @@
+pub mod flac_writer;
+pub mod meter;
 pub mod monitor_sink_node;
+pub mod opus_sink_node;
+pub mod recording_sink_node;
 pub mod streaming_sink_node;
 
 pub mod reports {
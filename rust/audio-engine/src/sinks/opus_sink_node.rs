@@ -0,0 +1,371 @@
+use std::ffi::{c_int, CStr};
+use std::ptr::null_mut;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::bail;
+use audiopus_sys::*;
+use r8brain_rs::{MultiChannelResampler, PrecisionProfile};
+
+use api::task::player::{LoudnessSummary, NodeEvent, NodeInfo, PlayHead};
+
+use crate::buffer::{DevicesBuffers, NodeBuffers};
+use crate::ogg::OggPageWriter;
+use crate::{Node, Result};
+
+use super::meter::SinkMeter;
+use super::{parameters, reports};
+
+/// Ogg/Opus granule positions always count samples at 48 kHz, so we encode at that rate
+pub const OPUS_SAMPLE_RATE: u32 = 48_000;
+
+// 20ms frames
+const FRAME_SIZE: usize = 960;
+const MAX_RESAMPLED: usize = 8192;
+// the largest packet a single Opus stream can produce, plus the self-delimiting overhead of multistream packets
+const MAX_STREAM_PACKET_SIZE: usize = 1277;
+const MAX_CHANNELS: usize = 255;
+
+/// Encodes its inputs to Ogg/Opus pages for low bandwidth streaming
+///
+/// Mono and stereo sinks use the RTP channel mapping, more channels are encoded as independent mono streams without a
+/// channel layout. Every chunk sent to [OpusSinkNode::captured] consists of whole Ogg pages. The stream ends with the page
+/// marked as its end when the node is stopped.
+pub struct OpusSinkNode {
+  info:             NodeInfo,
+  encoder:          *mut OpusMSEncoder,
+  resampler:        MultiChannelResampler,
+  resampled:        Vec<Vec<f64>>,
+  // interleaved samples waiting for a complete frame
+  pcm:              Vec<f32>,
+  packet:           Vec<u8>,
+  pages:            OggPageWriter,
+  granule_position: u64,
+  /// Granule position of the last sample received, the encoder pads the final frame past it
+  end_position:     u64,
+  encoded:          Vec<u8>,
+  meter:            SinkMeter,
+  tx_captured:      crossbeam_channel::Sender<bytes::Bytes>,
+  rx_captured:      crossbeam_channel::Receiver<bytes::Bytes>,
+  /// The stream was ended by [Node::stop], nothing more is encoded
+  finished:         bool,
+}
+
+unsafe impl Send for OpusSinkNode {}
+unsafe impl Sync for OpusSinkNode {}
+
+impl Drop for OpusSinkNode {
+  fn drop(&mut self) {
+    unsafe {
+      if !self.encoder.is_null() {
+        opus_multistream_encoder_destroy(self.encoder);
+        self.encoder = null_mut();
+      }
+    }
+  }
+}
+
+impl OpusSinkNode {
+  pub fn new(channels: usize, native_sample_rate: u32, bitrate: u32) -> Result<Self> {
+    if channels == 0 || channels > MAX_CHANNELS {
+      bail!("OpusSinkNode supports 1 to {MAX_CHANNELS} channels, got {channels}");
+    }
+
+    let (mapping_family, streams, coupled_streams) = match channels {
+      | 1 | 2 => (0u8, 1, channels - 1),
+      | _ => (255u8, channels, 0),
+    };
+
+    let mapping = (0..channels as u8).collect::<Vec<_>>();
+    let meter = SinkMeter::new(channels, OPUS_SAMPLE_RATE, MAX_RESAMPLED)?;
+    let mut error = 0;
+
+    let encoder = unsafe {
+      opus_multistream_encoder_create(OPUS_SAMPLE_RATE as i32,
+                                      channels as c_int,
+                                      streams as c_int,
+                                      coupled_streams as c_int,
+                                      mapping.as_ptr(),
+                                      OPUS_APPLICATION_AUDIO as c_int,
+                                      &mut error)
+    };
+
+    if encoder.is_null() || error != OPUS_OK as c_int {
+      bail!("opus_multistream_encoder_create failed: {}", error_string(error));
+    }
+
+    let info = NodeInfo { num_inputs:  channels,
+                          num_outputs: 0,
+                          latency:     0,
+                          reports:     reports::create(channels),
+                          parameters:  parameters::create(channels), };
+
+    let resampler = MultiChannelResampler::new(channels,
+                                               native_sample_rate as f64,
+                                               OPUS_SAMPLE_RATE as f64,
+                                               MAX_RESAMPLED,
+                                               2.0,
+                                               PrecisionProfile::Bits32);
+
+    let serial = SystemTime::now().duration_since(UNIX_EPOCH).map(|since| since.subsec_nanos()).unwrap_or_default();
+    let (tx_captured, rx_captured) = crossbeam_channel::unbounded();
+
+    let mut rv = Self { info,
+                        encoder,
+                        resampler,
+                        resampled: vec![vec![0.0; MAX_RESAMPLED]; channels],
+                        pcm: Vec::with_capacity(channels * (FRAME_SIZE + MAX_RESAMPLED)),
+                        packet: vec![0; MAX_STREAM_PACKET_SIZE * streams],
+                        pages: OggPageWriter::new(serial),
+                        granule_position: 0,
+                        end_position: 0,
+                        encoded: vec![],
+                        meter,
+                        tx_captured,
+                        rx_captured,
+                        finished: false };
+
+    // from here on the encoder is released when rv is dropped
+    let mut lookahead = 0i32;
+
+    unsafe {
+      let rv_ctl = opus_multistream_encoder_ctl(rv.encoder, OPUS_SET_BITRATE_REQUEST as c_int, bitrate as i32);
+      if rv_ctl != OPUS_OK as c_int {
+        bail!("Failed to set Opus bitrate {bitrate}: {}", error_string(rv_ctl));
+      }
+
+      let rv_ctl = opus_multistream_encoder_ctl(rv.encoder, OPUS_GET_LOOKAHEAD_REQUEST as c_int, &mut lookahead as *mut i32);
+      if rv_ctl != OPUS_OK as c_int {
+        bail!("Failed to get Opus lookahead: {}", error_string(rv_ctl));
+      }
+    }
+
+    rv.write_headers(mapping_family, streams, coupled_streams, &mapping, lookahead as u16, native_sample_rate);
+
+    Ok(rv)
+  }
+
+  /// Receiver of the encoded stream, one chunk of Ogg pages per processed buffer
+  ///
+  /// The first chunk contains the OpusHead and OpusTags header pages.
+  pub fn captured(&self) -> crossbeam_channel::Receiver<bytes::Bytes> {
+    self.rx_captured.clone()
+  }
+
+  fn write_headers(&mut self,
+                   mapping_family: u8,
+                   streams: usize,
+                   coupled_streams: usize,
+                   mapping: &[u8],
+                   pre_skip: u16,
+                   input_sample_rate: u32) {
+    let mut head = b"OpusHead".to_vec();
+    head.push(1);
+    head.push(mapping.len() as u8);
+    head.extend_from_slice(&pre_skip.to_le_bytes());
+    head.extend_from_slice(&input_sample_rate.to_le_bytes());
+    head.extend_from_slice(&0i16.to_le_bytes());
+    head.push(mapping_family);
+
+    if mapping_family != 0 {
+      head.push(streams as u8);
+      head.push(coupled_streams as u8);
+      head.extend_from_slice(mapping);
+    }
+
+    let vendor = "audiocloud";
+    let mut tags = b"OpusTags".to_vec();
+    tags.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+    tags.extend_from_slice(vendor.as_bytes());
+    tags.extend_from_slice(&0u32.to_le_bytes());
+
+    // the identification header must be alone on the first page, the comment header starts on the second
+    self.pages.write_packet(&head, 0, &mut self.encoded);
+    self.pages.flush(&mut self.encoded);
+    self.pages.write_packet(&tags, 0, &mut self.encoded);
+    self.pages.flush(&mut self.encoded);
+
+    // audio granule positions include the samples the decoder skips
+    self.granule_position = pre_skip as u64;
+    self.end_position = pre_skip as u64;
+  }
+
+  fn resample(&mut self, events: &mut Vec<NodeEvent>) -> Result {
+    let num_samples = self.resampler.pull_planar(self.resampled.iter_mut().map(|plane| &mut plane[..]));
+
+    if num_samples > 0 {
+      self.meter.measure(|channel| &self.resampled[channel][..], num_samples, events)?;
+
+      let resampled = &self.resampled;
+      self.pcm
+          .extend((0..num_samples).flat_map(|frame| resampled.iter().map(move |channel| channel[frame] as f32)));
+
+      self.end_position += num_samples as u64;
+    }
+
+    Ok(())
+  }
+
+  fn encode_frames(&mut self) -> Result {
+    let frame_len = FRAME_SIZE * self.resampled.len();
+
+    while self.pcm.len() >= frame_len {
+      let len = unsafe {
+        opus_multistream_encode_float(self.encoder,
+                                      self.pcm.as_ptr(),
+                                      FRAME_SIZE as c_int,
+                                      self.packet.as_mut_ptr(),
+                                      self.packet.len() as i32)
+      };
+
+      if len < 0 {
+        bail!("opus_multistream_encode_float failed: {}", error_string(len));
+      }
+
+      // a final page short of a whole frame tells the decoder to drop the padding
+      self.granule_position = (self.granule_position + FRAME_SIZE as u64).min(self.end_position);
+      self.pages
+          .write_packet(&self.packet[..len as usize], self.granule_position, &mut self.encoded);
+      self.pcm.drain(..frame_len);
+    }
+
+    Ok(())
+  }
+
+  fn send_captured(&mut self) {
+    if self.encoded.is_empty() {
+      return;
+    }
+
+    let _ = self.tx_captured.send(bytes::Bytes::copy_from_slice(&self.encoded));
+    self.encoded.clear();
+  }
+}
+
+fn error_string(error: c_int) -> String {
+  unsafe { CStr::from_ptr(opus_strerror(error)).to_string_lossy().into_owned() }
+}
+
+impl Node for OpusSinkNode {
+  fn get_node_info(&self, _play: PlayHead) -> NodeInfo {
+    self.info.clone()
+  }
+
+  fn loudness_summary(&self) -> Option<LoudnessSummary> {
+    self.meter.summary().ok()
+  }
+
+  fn process(&mut self,
+             _play: PlayHead,
+             _device_buffers: DevicesBuffers,
+             node_buffers: NodeBuffers,
+             _deadline: Instant,
+             events: &mut Vec<NodeEvent>)
+             -> Result {
+    if self.finished {
+      return Ok(());
+    }
+
+    self.resampler.push_planar(node_buffers.inputs().map(|input| &*input));
+    self.resample(events)?;
+    self.encode_frames()?;
+    self.pages.flush(&mut self.encoded);
+    self.send_captured();
+
+    Ok(())
+  }
+
+  /// Encode the tail held back by the resampler, padded to a whole frame, and end the stream
+  fn stop(&mut self, _play: PlayHead) -> Result {
+    if self.finished {
+      return Ok(());
+    }
+
+    self.finished = true;
+    self.resampler.flush();
+
+    while self.resampler.available() > 0 {
+      self.resample(&mut vec![])?;
+      self.encode_frames()?;
+    }
+
+    // the last page needs a packet, so a stream ending on a frame boundary gets a frame of silence
+    let frame_len = FRAME_SIZE * self.resampled.len();
+    self.pcm.resize(frame_len, 0.0);
+    self.encode_frames()?;
+
+    self.pages.finish(&mut self.encoded);
+    self.send_captured();
+
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::f64::consts::TAU;
+
+  use super::*;
+
+  /// Header type, granule position and body of every page in the stream
+  fn split_pages(mut stream: &[u8]) -> Vec<(u8, u64, &[u8])> {
+    let mut pages = vec![];
+
+    while !stream.is_empty() {
+      assert_eq!(&stream[..4], b"OggS");
+
+      let num_segments = stream[26] as usize;
+      let body_start = 27 + num_segments;
+      let body_end = body_start + stream[27..body_start].iter().map(|len| *len as usize).sum::<usize>();
+
+      pages.push((stream[5], u64::from_le_bytes(stream[6..14].try_into().unwrap()), &stream[body_start..body_end]));
+      stream = &stream[body_end..];
+    }
+
+    pages
+  }
+
+  #[test]
+  fn test_stream_ends_on_last_sample() {
+    let mut node = OpusSinkNode::new(2, 48_000, 64_000).expect("Failed to create node");
+    let captured = node.captured();
+
+    let play = PlayHead { buffer_size: 512,
+                          sample_rate: 48_000,
+                          ..Default::default() };
+    let buffers = NodeBuffers::allocate(&node.get_node_info(play), 512);
+
+    // 5 1/3 frames, the last third is padded to a whole frame when the node stops
+    for cycle in 0..10 {
+      for channel in 0..2 {
+        for (i, sample) in buffers.input_plane(channel).iter_mut().enumerate() {
+          *sample = ((cycle * 512 + i) as f64 * 1_000.0 / 48_000.0 * TAU).sin() * 0.5;
+        }
+      }
+
+      node.process(play, DevicesBuffers::default(), buffers.clone(), Instant::now(), &mut vec![])
+          .expect("Failed to process");
+    }
+
+    node.stop(play).expect("Failed to stop");
+
+    let stream = captured.try_iter().flatten().collect::<Vec<_>>();
+    let pages = split_pages(&stream);
+
+    // beginning of stream, then the comment header alone on the second page
+    assert_eq!(pages[0].0, 0x02);
+    assert_eq!(&pages[0].2[..8], b"OpusHead");
+    assert_eq!(&pages[1].2[..8], b"OpusTags");
+
+    let pre_skip = u16::from_le_bytes([pages[0].2[10], pages[0].2[11]]) as u64;
+
+    // only the last page ends the stream, and its granule position trims the padding
+    let (header_type, granule_position, _) = pages[pages.len() - 1];
+    assert_eq!(header_type, 0x04);
+    assert_eq!(granule_position, pre_skip + 10 * 512);
+    assert!(pages[..pages.len() - 1].iter().all(|(header_type, ..)| header_type & 0x04 == 0));
+
+    node.process(play, DevicesBuffers::default(), buffers.clone(), Instant::now(), &mut vec![])
+        .expect("Processing a stopped node is a no-op");
+    assert!(captured.try_recv().is_err());
+  }
+}
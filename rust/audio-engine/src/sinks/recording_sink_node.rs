@@ -0,0 +1,352 @@
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail};
+use hex::ToHex;
+use sha2::digest::FixedOutput;
+
+use api::media::spec::{MediaId, MediaSpec};
+use api::task::graph::RecordingFormat;
+use api::task::player::{NodeEvent, NodeInfo, PlayHead};
+
+use crate::buffer::{DevicesBuffers, NodeBuffers};
+use crate::sinks::flac_writer::FlacWriter;
+use crate::wav::{WavSampleFormat, WavWriter};
+use crate::{Node, Result};
+
+use super::reports;
+
+/// Seconds of audio buffered between the node and the thread writing the file
+const BUFFERED_SECONDS: usize = 4;
+
+/// How long the writer thread sleeps when there is nothing to write
+const WRITER_POLL: Duration = Duration::from_millis(20);
+
+/// Largest number of frames the writer thread writes at once
+const MAX_WRITE_FRAMES: usize = 8192;
+
+const FLAC_BITS_PER_SAMPLE: u32 = 24;
+
+/// Writes its inputs to a new media file
+///
+/// Processing only copies the samples into a ring buffer, a dedicated thread writes them to disk. The file is finalized
+/// and hashed once the node is dropped, and the result is collected with [RecordingHandle::finish].
+pub struct RecordingSinkNode {
+  info:       NodeInfo,
+  tx_samples: rtrb::Producer<f64>,
+  dropped:    Arc<AtomicU64>,
+  /// Frames still to skip at the start, so the recording lines up with the timeline despite the latency before the node
+  skip:       usize,
+}
+
+// the producer is only used from process, which takes the node mutably
+unsafe impl Sync for RecordingSinkNode {}
+
+/// The media written by a [RecordingSinkNode]
+pub struct RecordingHandle {
+  media_id: MediaId,
+  writer:   JoinHandle<Result<RecordedMedia>>,
+}
+
+/// A recording that was finalized
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordedMedia {
+  pub media:      MediaSpec,
+  pub num_frames: u64,
+}
+
+impl RecordingSinkNode {
+  pub fn new(path: PathBuf,
+             media_id: MediaId,
+             num_channels: usize,
+             sample_rate: u32,
+             format: RecordingFormat)
+             -> Result<(Self, RecordingHandle)> {
+    if num_channels == 0 {
+      bail!("RecordingSinkNode needs at least one channel");
+    }
+
+    let writer = match format {
+      | RecordingFormat::Wav => RecordingWriter::Wav(WavWriter::create(&path, num_channels, sample_rate, WavSampleFormat::Float32)?),
+      | RecordingFormat::Flac => RecordingWriter::Flac(FlacWriter::create(&path, num_channels, sample_rate, FLAC_BITS_PER_SAMPLE)?),
+    };
+
+    let (tx_samples, rx_samples) = rtrb::RingBuffer::new(sample_rate.max(1) as usize * BUFFERED_SECONDS * num_channels);
+    let dropped = Arc::new(AtomicU64::new(0));
+
+    let writer = thread::Builder::new().name(format!("recording-{media_id}"))
+                                       .spawn({
+                                         let media_id = media_id.clone();
+                                         let dropped = dropped.clone();
+                                         move || run_writer(writer, rx_samples, num_channels, dropped, path, media_id)
+                                       })?;
+
+    let info = NodeInfo { num_inputs: num_channels,
+                          reports: reports::create(num_channels),
+                          ..Default::default() };
+
+    Ok((Self { info,
+               tx_samples,
+               dropped,
+               skip: 0 },
+        RecordingHandle { media_id, writer }))
+  }
+}
+
+impl RecordingHandle {
+  pub fn media_id(&self) -> &MediaId {
+    &self.media_id
+  }
+
+  /// Wait for the node to be dropped and the file to be finalized
+  pub fn finish(self) -> Result<RecordedMedia> {
+    self.writer
+        .join()
+        .map_err(|_| anyhow!("Recording writer for {} panicked", self.media_id))?
+  }
+}
+
+impl Node for RecordingSinkNode {
+  fn get_node_info(&self, _play: PlayHead) -> NodeInfo {
+    self.info.clone()
+  }
+
+  fn prepare_to_play(&mut self, _play: PlayHead, accumulated_latency: usize) -> Result {
+    self.skip = accumulated_latency;
+
+    Ok(())
+  }
+
+  fn process(&mut self,
+             _play: PlayHead,
+             _devices: DevicesBuffers,
+             node_buffers: NodeBuffers,
+             _deadline: Instant,
+             _events: &mut Vec<NodeEvent>)
+             -> Result {
+    let num_channels = node_buffers.num_inputs;
+    let skip = self.skip.min(node_buffers.buffer_size);
+    let num_frames = node_buffers.buffer_size - skip;
+
+    self.skip -= skip;
+
+    if num_frames == 0 {
+      return Ok(());
+    }
+
+    // the graph keeps playing, the writer fails the recording once it is finished rather than leaving a gap in it
+    let Ok(chunk) = self.tx_samples.write_chunk_uninit(num_frames * num_channels) else {
+      self.dropped.fetch_add(num_frames as u64, Ordering::Relaxed);
+      return Ok(());
+    };
+
+    let buffers = &node_buffers;
+    let frames = (skip..node_buffers.buffer_size).flat_map(|i| (0..num_channels).map(move |channel| buffers.input_plane(channel)[i]));
+
+    chunk.fill_from_iter(frames);
+
+    Ok(())
+  }
+}
+
+enum RecordingWriter {
+  Wav(WavWriter<io::BufWriter<File>>),
+  Flac(FlacWriter),
+}
+
+impl RecordingWriter {
+  fn write_planar(&mut self, planes: &[&[f64]], num_frames: usize) -> Result {
+    match self {
+      | RecordingWriter::Wav(writer) => writer.write_planar(planes, num_frames),
+      | RecordingWriter::Flac(writer) => writer.write_planar(planes, num_frames),
+    }
+  }
+
+  fn num_frames(&self) -> u64 {
+    match self {
+      | RecordingWriter::Wav(writer) => writer.num_frames(),
+      | RecordingWriter::Flac(writer) => writer.num_frames(),
+    }
+  }
+
+  fn finalize(self) -> Result {
+    match self {
+      | RecordingWriter::Wav(writer) => writer.finalize().map(|_| ()),
+      | RecordingWriter::Flac(writer) => writer.finalize(),
+    }
+  }
+}
+
+fn run_writer(writer: RecordingWriter,
+              rx_samples: rtrb::Consumer<f64>,
+              num_channels: usize,
+              dropped: Arc<AtomicU64>,
+              path: PathBuf,
+              media_id: MediaId)
+              -> Result<RecordedMedia> {
+  let result = write_samples(writer, rx_samples, num_channels, &dropped).and_then(|num_frames| {
+                 let sha256 = hash_file(&path)?;
+                 Ok(RecordedMedia { media: MediaSpec { id: media_id, sha256 },
+                                    num_frames })
+               });
+
+  // an unregistered file would never be cleaned up
+  if result.is_err() {
+    let _ = std::fs::remove_file(&path);
+  }
+
+  result
+}
+
+fn write_samples(mut writer: RecordingWriter,
+                 mut rx_samples: rtrb::Consumer<f64>,
+                 num_channels: usize,
+                 dropped: &AtomicU64)
+                 -> Result<u64> {
+  let mut planes = vec![vec![0.0; MAX_WRITE_FRAMES]; num_channels];
+
+  loop {
+    // checked before reading, so samples pushed right before the node was dropped are still written
+    let abandoned = rx_samples.is_abandoned();
+    let num_frames = (rx_samples.slots() / num_channels).min(MAX_WRITE_FRAMES);
+
+    if num_frames == 0 {
+      if abandoned {
+        break;
+      }
+
+      thread::sleep(WRITER_POLL);
+      continue;
+    }
+
+    let chunk = rx_samples.read_chunk(num_frames * num_channels)?;
+    let (first, second) = chunk.as_slices();
+
+    for (index, sample) in first.iter().chain(second.iter()).enumerate() {
+      planes[index % num_channels][index / num_channels] = *sample;
+    }
+
+    chunk.commit_all();
+
+    let planes = planes.iter().map(|plane| &plane[..]).collect::<Vec<_>>();
+    writer.write_planar(&planes, num_frames)?;
+  }
+
+  let num_frames = writer.num_frames();
+  writer.finalize()?;
+
+  match dropped.load(Ordering::Relaxed) {
+    | 0 => Ok(num_frames),
+    | dropped => Err(anyhow!("Recording dropped {dropped} frames because the file was not written fast enough")),
+  }
+}
+
+fn hash_file(path: &Path) -> Result<String> {
+  let mut sha = sha2::Sha256::default();
+  io::copy(&mut File::open(path)?, &mut sha)?;
+
+  Ok(sha.finalize_fixed().encode_hex::<String>())
+}
+
+#[cfg(test)]
+mod test {
+  use crate::sources::flac_reader::FlacReader;
+  use crate::wav::WavReader;
+
+  use super::*;
+
+  fn fill_inputs(buffers: &NodeBuffers, cycle: usize) {
+    let buffer_size = buffers.buffer_size;
+
+    for channel in 0..buffers.num_inputs {
+      for (i, sample) in buffers.input_plane(channel).iter_mut().enumerate() {
+        *sample = (cycle * buffer_size + i) as f64 / 16.0 * if channel == 0 { 1.0 } else { -1.0 };
+      }
+    }
+  }
+
+  fn record(format: RecordingFormat, extension: &str, sample_rate: u32, buffer_size: usize) -> (PathBuf, MediaId, Result<RecordedMedia>) {
+    let media_id = MediaId::new("test");
+    let path = std::env::temp_dir().join(format!("recording-{media_id}.{extension}"));
+
+    let (mut node, recording) =
+      RecordingSinkNode::new(path.clone(), media_id.clone(), 2, sample_rate, format).expect("Failed to create node");
+
+    let play = PlayHead { buffer_size: buffer_size as u32,
+                          sample_rate,
+                          ..Default::default() };
+    let buffers = NodeBuffers::allocate(&node.get_node_info(play), buffer_size);
+
+    node.prepare_to_play(play, 1).expect("Failed to prepare");
+
+    for cycle in 0..2 {
+      fill_inputs(&buffers, cycle);
+
+      node.process(play, DevicesBuffers::default(), buffers.clone(), Instant::now(), &mut vec![])
+          .expect("Failed to process");
+    }
+
+    drop(node);
+
+    (path, media_id, recording.finish())
+  }
+
+  #[test]
+  fn test_record_wav() {
+    let (path, media_id, recorded) = record(RecordingFormat::Wav, "wav", 48_000, 4);
+
+    let recorded = recorded.expect("Failed to finish recording");
+    assert_eq!(recorded.num_frames, 7);
+    assert_eq!(recorded.media.id, media_id);
+    assert_eq!(recorded.media.sha256, hash_file(&path).unwrap());
+
+    // the first frame is the latency of the nodes before the sink
+    let mut reader = WavReader::open(&path).expect("Failed to open recording");
+    let mut planes = vec![vec![0.0; 7]; 2];
+    assert_eq!(reader.read_planar(0, &mut planes, 7).unwrap(), 7);
+    assert_eq!(planes[0][0], 1.0 / 16.0);
+    assert_eq!(planes[1][6], -7.0 / 16.0);
+
+    let _ = std::fs::remove_file(&path);
+  }
+
+  #[test]
+  fn test_record_flac_by_default() {
+    let (path, media_id, recorded) = record(RecordingFormat::default(), "flac", 48_000, 4);
+
+    let recorded = recorded.expect("Failed to finish recording");
+    assert_eq!(recorded.num_frames, 7);
+    assert_eq!(recorded.media.id, media_id);
+    assert_eq!(recorded.media.sha256, hash_file(&path).unwrap());
+
+    let mut reader = FlacReader::open(&path).expect("Failed to open recording");
+    assert_eq!(reader.num_channels(), 2);
+    assert_eq!(reader.sample_rate(), 48_000);
+    assert_eq!(reader.num_frames(), 7);
+
+    let mut planes = vec![vec![0.0; 7]; 2];
+    assert_eq!(reader.read_planar(0, &mut planes, 7).unwrap(), 7);
+
+    // 24 bit samples
+    let tolerance = 1.0 / (1 << 22) as f64;
+    assert!((planes[0][0] - 1.0 / 16.0).abs() < tolerance);
+    assert!((planes[1][6] + 7.0 / 16.0).abs() < tolerance);
+
+    let _ = std::fs::remove_file(&path);
+  }
+
+  #[test]
+  fn test_full_buffer_fails_recording() {
+    // the four seconds buffered at 1 Hz can never hold a buffer of 16 frames
+    let (path, _, recorded) = record(RecordingFormat::Wav, "wav", 1, 16);
+
+    let err = recorded.expect_err("dropped frames must fail the recording");
+    assert!(err.to_string().contains("dropped 31 frames"), "{err}");
+    assert!(!path.exists());
+  }
+}
@@ -5,29 +5,34 @@ use std::time::Instant;
 
 use anyhow::{anyhow, bail};
 use dasp::Sample;
-use ebur128::{EbuR128, Mode};
 use libflac_sys::*;
-use r8brain_rs::PrecisionProfile;
+use r8brain_rs::{MultiChannelResampler, PrecisionProfile};
 
-use api::task::player::{NodeEvent, NodeInfo, PlayHead};
+use api::task::player::{LoudnessSummary, NodeEvent, NodeInfo, PlayHead};
 
 use crate::buffer::{fill_slice, DevicesBuffers, NodeBuffers};
-use crate::events::make_report;
 use crate::{Node, Result};
 
+use super::meter::SinkMeter;
 use super::{parameters, reports};
 
+const MAX_RESAMPLED: usize = 8192;
+
 pub struct StreamingSinkNode {
   info:             NodeInfo,
   shared:           Box<Shared>,
   encoder:          *mut FLAC__StreamEncoder,
   bits_per_sample:  usize,
-  resampler:        Vec<r8brain_rs::ResamplerQueue>,
+  resampler:        MultiChannelResampler,
   input_buffers:    Vec<Vec<i32>>,
-  gain:             [f64; 8],
-  measurements:     EbuR128,
-  measure_position: u64,
-  measure_interval: u64,
+  resampled:        Vec<Vec<f64>>,
+  encoder_planes:   Vec<*const i32>,
+  gain:             Vec<f64>,
+  meter:            SinkMeter,
+  tx_captured:      crossbeam_channel::Sender<bytes::Bytes>,
+  rx_captured:      crossbeam_channel::Receiver<bytes::Bytes>,
+  /// The stream was finished by [Node::stop], nothing more is encoded
+  finished:         bool,
 }
 
 unsafe impl Send for StreamingSinkNode {}
@@ -66,8 +71,8 @@ unsafe extern "C" fn write_callback(encoder: *const FLAC__StreamEncoder,
 
 impl StreamingSinkNode {
   pub fn new(channels: usize, sample_rate: u32, native_sample_rate: u32, bits_per_sample: usize) -> Result<Self> {
-    if channels > 8 {
-      bail!("StreamingSinkNode supports up to 8 channels");
+    if channels == 0 {
+      bail!("StreamingSinkNode needs at least one channel");
     }
 
     if bits_per_sample != 16 && bits_per_sample != 32 {
@@ -110,20 +115,23 @@ impl StreamingSinkNode {
                           reports:     reports::create(channels),
                           parameters:  parameters::create(channels), };
 
-    let input_buffers = (0..channels).map(|_| Vec::new()).collect();
+    // all per-cycle buffers are sized here, so processing does not allocate
+    let input_buffers = (0..channels).map(|_| Vec::with_capacity(MAX_RESAMPLED)).collect();
+    let resampled = vec![vec![0.0; MAX_RESAMPLED]; channels];
+    let encoder_planes = Vec::with_capacity(channels);
 
-    let measure_position = 0;
-    let measure_interval = (sample_rate as f64 * reports::MEASURE_LUFS_FACTOR).floor() as u64;
+    let gain = vec![1.0; channels];
 
-    let gain = [1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0];
+    let resampler = MultiChannelResampler::new(channels,
+                                               native_sample_rate as f64,
+                                               sample_rate as f64,
+                                               MAX_RESAMPLED,
+                                               2.0,
+                                               PrecisionProfile::Bits32);
 
-    let resampler =
-      (0..channels).map(|_| {
-                     r8brain_rs::ResamplerQueue::new(native_sample_rate as f64, sample_rate as f64, 8192, 2.0, PrecisionProfile::Bits32)
-                   })
-                   .collect();
+    let meter = SinkMeter::new(channels, sample_rate, MAX_RESAMPLED)?;
 
-    let measurements = EbuR128::new(channels as u32, sample_rate as u32, Mode::TRUE_PEAK | Mode::I)?;
+    let (tx_captured, rx_captured) = crossbeam_channel::unbounded();
 
     let rv = Self { info,
                     shared,
@@ -131,13 +139,72 @@ impl StreamingSinkNode {
                     input_buffers,
                     bits_per_sample,
                     resampler,
-                    measurements,
-                    measure_position,
-                    measure_interval,
-                    gain };
+                    resampled,
+                    encoder_planes,
+                    meter,
+                    gain,
+                    tx_captured,
+                    rx_captured,
+                    finished: false };
 
     Ok(rv)
   }
+
+  /// Receiver of the encoded stream, one chunk per processed buffer
+  ///
+  /// The first chunk contains the FLAC stream header.
+  pub fn captured(&self) -> crossbeam_channel::Receiver<bytes::Bytes> {
+    self.rx_captured.clone()
+  }
+
+  fn send_captured(&mut self) {
+    let buffer = &mut self.shared.buffer;
+    if buffer.is_empty() {
+      return;
+    }
+
+    let mut captured = bytes::BytesMut::with_capacity(buffer.iter().map(|chunk| chunk.len()).sum());
+    for chunk in buffer.drain(..) {
+      captured.extend_from_slice(&chunk);
+    }
+
+    let _ = self.tx_captured.send(captured.freeze());
+  }
+
+  fn encode_resampled(&mut self, events: &mut Vec<NodeEvent>) -> Result {
+    let num_samples = self.resampler.pull_planar(self.resampled.iter_mut().map(|plane| &mut plane[..]));
+
+    for (buffer, resampled) in self.input_buffers.iter_mut().zip(self.resampled.iter()) {
+      buffer.resize(num_samples, 0);
+      match self.bits_per_sample {
+        | 16 => {
+          fill_slice(&mut buffer[..num_samples],
+                     resampled[..num_samples].iter().map(|x| i16::from_sample(*x) as i32));
+        }
+        | 32 => {
+          fill_slice(&mut buffer[..num_samples], resampled[..num_samples].iter().map(|x| i32::from_sample(*x)));
+        }
+        | _ => {}
+      }
+    }
+
+    if num_samples > 0 {
+      self.meter.measure(|channel| &self.resampled[channel][..], num_samples, events)?;
+
+      self.encoder_planes.clear();
+      self.encoder_planes.extend(self.input_buffers.iter().map(|buf| buf.as_ptr()));
+
+      unsafe {
+        if FLAC__stream_encoder_process(self.encoder, self.encoder_planes.as_ptr(), num_samples as u32) == 0 {
+          bail!("FLAC__stream_encoder_process failed: {}",
+                CStr::from_ptr(FLAC__stream_encoder_get_resolved_state_string(self.encoder)).to_str()
+                                                                                            .unwrap_or_default());
+        }
+      }
+    }
+
+    Ok(())
+  }
 }
 
 impl Node for StreamingSinkNode {
@@ -145,6 +212,10 @@ impl Node for StreamingSinkNode {
     self.info.clone()
   }
 
+  fn loudness_summary(&self) -> Option<LoudnessSummary> {
+    self.meter.summary().ok()
+  }
+
   fn process(&mut self,
              _play: PlayHead,
              _device_buffers: DevicesBuffers,
@@ -152,90 +223,137 @@ impl Node for StreamingSinkNode {
              _deadline: Instant,
              events: &mut Vec<NodeEvent>)
              -> Result {
-    let mut channel0 = [0.0; 8192];
-    let mut channel1 = [0.0; 8192];
-    let mut channel2 = [0.0; 8192];
-    let mut channel3 = [0.0; 8192];
-    let mut channel4 = [0.0; 8192];
-    let mut channel5 = [0.0; 8192];
-    let mut channel6 = [0.0; 8192];
-    let mut channel7 = [0.0; 8192];
-
-    let num_channels = self.info.num_inputs;
-    let mut num_samples = channel0.len();
-
-    for (i, ((resampler, source), buffer)) in self.resampler
-                                                  .iter_mut()
-                                                  .zip(node_buffers.inputs())
-                                                  .zip(self.input_buffers.iter_mut())
-                                                  .enumerate()
-    {
-      let target = match i {
-        | 0 => &mut channel0[..],
-        | 1 => &mut channel1[..],
-        | 2 => &mut channel2[..],
-        | 3 => &mut channel3[..],
-        | 4 => &mut channel4[..],
-        | 5 => &mut channel5[..],
-        | 6 => &mut channel6[..],
-        | 7 => &mut channel7[..],
-        | _ => unreachable!("too many channels"),
-      };
-
-      resampler.push(source);
-      let num_resampled = resampler.pull(&mut target[..]);
-
-      num_samples = num_samples.min(num_resampled);
-      if num_samples == 0 {
-        continue;
+    if self.finished {
+      return Ok(());
+    }
+
+    self.resampler.push_planar(node_buffers.inputs().map(|input| &*input));
+    self.encode_resampled(events)?;
+    self.send_captured();
+
+    Ok(())
+  }
+
+  /// Encode the tail held back by the resampler and finish the FLAC stream
+  fn stop(&mut self, _play: PlayHead) -> Result {
+    if self.finished {
+      return Ok(());
+    }
+
+    self.finished = true;
+    self.resampler.flush();
+
+    while self.resampler.available() > 0 {
+      self.encode_resampled(&mut vec![])?;
+    }
+
+    unsafe {
+      if FLAC__stream_encoder_finish(self.encoder) == 0 {
+        bail!("FLAC__stream_encoder_finish failed");
       }
+    }
 
-      buffer.resize(num_resampled as usize, 0);
-      match self.bits_per_sample {
-        | 16 => {
-          fill_slice(&mut buffer[..num_resampled],
-                     target[..num_resampled].iter().map(|x| i16::from_sample(*x) as i32));
-        }
-        | 32 => {
-          fill_slice(&mut buffer[..num_resampled],
-                     target[..num_resampled].iter().map(|x| i32::from_sample(*x)));
+    self.send_captured();
+
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::f64::consts::TAU;
+
+  use nanoid::nanoid;
+
+  use crate::sources::flac_reader::FlacReader;
+
+  use super::*;
+
+  #[test]
+  fn test_stream_ends_on_stop() {
+    let mut node = StreamingSinkNode::new(2, 48_000, 48_000, 16).expect("Failed to create node");
+    let captured = node.captured();
+
+    let play = PlayHead { buffer_size: 512,
+                          sample_rate: 48_000,
+                          ..Default::default() };
+    let buffers = NodeBuffers::allocate(&node.get_node_info(play), 512);
+
+    // 1.5 FLAC blocks, the half block is only encoded when the stream is finished
+    for cycle in 0..12 {
+      for channel in 0..2 {
+        for (i, sample) in buffers.input_plane(channel).iter_mut().enumerate() {
+          *sample = ((cycle * 512 + i) as f64 * 1_000.0 / 48_000.0 * TAU).sin() * 0.5;
         }
-        | _ => {}
       }
+
+      node.process(play, DevicesBuffers::default(), buffers.clone(), Instant::now(), &mut vec![])
+          .expect("Failed to process");
     }
 
-    if num_samples > 0 {
-      let channels = [&channel0[..num_samples],
-                      &channel1[..num_samples],
-                      &channel2[..num_samples],
-                      &channel3[..num_samples],
-                      &channel4[..num_samples],
-                      &channel5[..num_samples],
-                      &channel6[..num_samples],
-                      &channel7[..num_samples]];
-
-      self.measurements.add_frames_planar_f64(&channels[..num_channels])?;
-      events.extend((0..num_channels).map(|i| (i, self.measurements.true_peak(i as u32).unwrap_or_default()))
-                                     .map(make_report(reports::PEAK_LEVEL, 0)));
-
-      self.measure_position += num_samples as u64;
-      while self.measure_position > self.measure_interval {
-        events.push(make_report(reports::LUFS_LEVEL, 0)((0, self.measurements.loudness_momentary()?)));
-        self.measure_position -= self.measure_interval;
-      }
+    let before_stop = captured.try_iter().map(|chunk| chunk.len()).sum::<usize>();
 
-      let buffers = self.input_buffers.iter().map(|buf| buf.as_ptr()).collect::<Vec<_>>();
-      let buffers = buffers.as_ptr();
+    node.stop(play).expect("Failed to stop");
+    node.stop(play).expect("Stopping again is a no-op");
 
-      unsafe {
-        if FLAC__stream_encoder_process(self.encoder, buffers, num_samples as u32) == 0 {
-          bail!("FLAC__stream_encoder_process failed: {}",
-                CStr::from_ptr(FLAC__stream_encoder_get_resolved_state_string(self.encoder)).to_str()
-                                                                                            .unwrap_or_default());
+    let tail = captured.try_iter().collect::<Vec<_>>();
+    assert!(!tail.is_empty(), "stop emits the last frame");
+    assert!(before_stop > 4);
+
+    node.process(play, DevicesBuffers::default(), buffers.clone(), Instant::now(), &mut vec![])
+        .expect("Failed to process");
+    assert!(captured.try_recv().is_err(), "a finished stream stays finished");
+  }
+
+  #[test]
+  fn test_stream_decodes_with_tail() {
+    let mut node = StreamingSinkNode::new(2, 48_000, 48_000, 16).expect("Failed to create node");
+    let captured = node.captured();
+
+    let play = PlayHead { buffer_size: 512,
+                          sample_rate: 48_000,
+                          ..Default::default() };
+    let buffers = NodeBuffers::allocate(&node.get_node_info(play), 512);
+
+    // 1.5 FLAC blocks, the half block is only encoded when the stream is finished
+    for cycle in 0..12 {
+      for channel in 0..2 {
+        for (i, sample) in buffers.input_plane(channel).iter_mut().enumerate() {
+          *sample = ((cycle * 512 + i) as f64 * 1_000.0 / 48_000.0 * TAU).sin() * 0.5;
         }
       }
+
+      node.process(play, DevicesBuffers::default(), buffers.clone(), Instant::now(), &mut vec![])
+          .expect("Failed to process");
     }
 
-    Ok(())
+    node.stop(play).expect("Failed to stop");
+    node.stop(play).expect("Stopping again is a no-op");
+
+    let stream = captured.try_iter().flatten().collect::<Vec<_>>();
+    assert_eq!(&stream[..4], b"fLaC");
+
+    let path = std::env::temp_dir().join(format!("streaming-sink-{}.flac", nanoid!()));
+    std::fs::write(&path, &stream).expect("Failed to write stream");
+
+    let mut reader = FlacReader::open(&path).expect("Failed to open stream");
+    let mut planes = vec![vec![0.0; 1024]; 2];
+    let mut decoded = vec![];
+
+    loop {
+      let num_read = reader.read_planar(decoded.len() as u64, &mut planes, 1024).expect("Failed to decode");
+      if num_read == 0 {
+        break;
+      }
+
+      decoded.extend_from_slice(&planes[0][..num_read]);
+    }
+
+    let _ = std::fs::remove_file(&path);
+
+    assert_eq!(decoded.len(), 12 * 512);
+
+    let peak = decoded.iter().fold(0.0f64, |peak, sample| peak.max(sample.abs()));
+    assert!((peak - 0.5).abs() < 0.01, "peak {peak}");
   }
 }
@@ -0,0 +1,74 @@
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+use anyhow::bail;
+
+use crate::wav::WavReader;
+use crate::Result;
+
+use super::flac_reader::FlacReader;
+
+/// Random access to the decoded samples of an audio file
+pub trait AudioFileReader: Send + Sync {
+  fn sample_rate(&self) -> u32;
+
+  fn num_channels(&self) -> usize;
+
+  /// Length of the file in frames at the file sample rate, zero if the file does not tell
+  fn num_frames(&self) -> u64;
+
+  /// Read up to `num_frames` frames starting at frame `position` into one plane per channel
+  ///
+  /// Returns the number of frames read, which is zero at the end of the file.
+  fn read_planar(&mut self, position: u64, planes: &mut [Vec<f64>], num_frames: usize) -> Result<usize>;
+}
+
+impl AudioFileReader for WavReader<BufReader<File>> {
+  fn sample_rate(&self) -> u32 {
+    WavReader::sample_rate(self)
+  }
+
+  fn num_channels(&self) -> usize {
+    WavReader::num_channels(self)
+  }
+
+  fn num_frames(&self) -> u64 {
+    WavReader::num_frames(self)
+  }
+
+  fn read_planar(&mut self, position: u64, planes: &mut [Vec<f64>], num_frames: usize) -> Result<usize> {
+    WavReader::read_planar(self, position, planes, num_frames)
+  }
+}
+
+impl AudioFileReader for FlacReader {
+  fn sample_rate(&self) -> u32 {
+    FlacReader::sample_rate(self)
+  }
+
+  fn num_channels(&self) -> usize {
+    FlacReader::num_channels(self)
+  }
+
+  fn num_frames(&self) -> u64 {
+    FlacReader::num_frames(self)
+  }
+
+  fn read_planar(&mut self, position: u64, planes: &mut [Vec<f64>], num_frames: usize) -> Result<usize> {
+    FlacReader::read_planar(self, position, planes, num_frames)
+  }
+}
+
+/// Open a WAV or FLAC file, detecting the format from its header
+pub fn open_audio_file(path: impl AsRef<Path>) -> Result<Box<dyn AudioFileReader>> {
+  let path = path.as_ref();
+  let mut magic = [0u8; 4];
+  File::open(path)?.read_exact(&mut magic)?;
+
+  match &magic {
+    | b"RIFF" => Ok(Box::new(WavReader::open(path)?)),
+    | b"fLaC" => Ok(Box::new(FlacReader::open(path)?)),
+    | _ => bail!("Unsupported audio file format: {}", path.display()),
+  }
+}
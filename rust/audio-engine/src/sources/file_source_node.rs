@@ -0,0 +1,694 @@
+use std::path::Path;
+use std::time::Instant;
+
+use anyhow::bail;
+use r8brain_rs::{MultiChannelResampler, PrecisionProfile};
+
+use api::task::graph::{SourceFade, SourceSpec};
+use api::task::player::PlayHead;
+
+use crate::buffer::{zero_slice, DevicesBuffers, NodeBuffers};
+use crate::crossfade::{fade_gain, Crossfade};
+use crate::events::{db_to_gain_factor, make_report, slice_peak_level_db, slice_rms_level_db};
+use crate::{Node, NodeEvent, NodeInfo, Result};
+
+use super::file_reader::{open_audio_file, AudioFileReader};
+use super::reports;
+
+const READ_SIZE: usize = 1024;
+
+/// Plays back a WAV or FLAC file, resampling it to the engine sample rate when needed
+///
+/// A mono file is played on all outputs, file channels beyond the number of outputs are ignored and outputs beyond the
+/// number of file channels are silent. Past the end of the file the node outputs silence.
+///
+/// Seeks and loop wraps are crossfaded over the crossfade length of the play region. Loops wrap at the exact sample the
+/// play region ends on, even in the middle of a buffer.
+///
+/// The file plays as a clip placed on the timeline, trimmed, faded and leveled as set with [FileSourceNode::with_clip].
+/// Clip boundaries and fades are applied at the exact sample they fall on.
+///
+/// Resampling adds no latency: the resampler lines its first output frame up with the first frame read after a seek, and
+/// the node reads ahead of the play head by the resampler latency to fill the buffer.
+pub struct FileSourceNode {
+  info:            NodeInfo,
+  reader:          Box<dyn AudioFileReader>,
+  resampler:       Option<MultiChannelResampler>,
+  read_buffers:    Vec<Vec<f64>>,
+  channel_buffers: Vec<Vec<f64>>,
+  // next frame to read from the file, at the file sample rate
+  file_position:   u64,
+  // play head position we expect on the next process call, anything else is a seek
+  next_position:   Option<u64>,
+  // media position at the engine sample rate the next read continues from
+  media_position:  Option<u64>,
+  sample_rate:     u32,
+  crossfade:       Crossfade,
+  clip:            Clip,
+}
+
+/// Placement of the file on the timeline, see [SourceSpec]
+#[derive(Debug, Clone, Copy)]
+struct Clip {
+  start_at:  u64,
+  media_in:  u64,
+  media_out: Option<u64>,
+  fade_in:   SourceFade,
+  fade_out:  SourceFade,
+  gain:      f64,
+}
+
+impl Default for Clip {
+  fn default() -> Self {
+    Self { start_at:  0,
+           media_in:  0,
+           media_out: None,
+           fade_in:   SourceFade::default(),
+           fade_out:  SourceFade::default(),
+           gain:      1.0, }
+  }
+}
+
+unsafe impl Send for FileSourceNode {}
+unsafe impl Sync for FileSourceNode {}
+
+impl FileSourceNode {
+  pub fn new(path: impl AsRef<Path>, num_channels: usize) -> Result<Self> {
+    if num_channels == 0 {
+      bail!("File source node needs at least one channel");
+    }
+
+    let reader = open_audio_file(path)?;
+    let file_num_channels = reader.num_channels().min(num_channels);
+
+    let info = NodeInfo { num_outputs: num_channels,
+                          reports: reports::create(num_channels),
+                          ..Default::default() };
+
+    Ok(Self { info,
+              reader,
+              resampler: None,
+              read_buffers: vec![vec![0.0; READ_SIZE]; file_num_channels],
+              channel_buffers: vec![vec![]; file_num_channels],
+              file_position: 0,
+              next_position: None,
+              media_position: None,
+              sample_rate: 0,
+              crossfade: Crossfade::default(),
+              clip: Clip::default() })
+  }
+
+  /// Place the file on the timeline with the trim, fades and gain of the source spec
+  pub fn with_clip(mut self, spec: &SourceSpec) -> Self {
+    self.clip = Clip { start_at:  spec.start_at,
+                       media_in:  spec.media_in,
+                       media_out: spec.media_out,
+                       fade_in:   spec.fade_in,
+                       fade_out:  spec.fade_out,
+                       gain:      db_to_gain_factor(spec.gain_db), };
+    self
+  }
+
+  /// Length of the clip on the timeline, `None` if it plays to the end of a file of unknown length
+  fn clip_length(&self) -> Option<u64> {
+    if let Some(media_out) = self.clip.media_out {
+      return Some(media_out.saturating_sub(self.clip.media_in));
+    }
+
+    let file_num_frames = match (self.reader.num_frames(), self.sample_rate as u64) {
+      | (0, _) => return None,
+      | (num_frames, 0) => num_frames,
+      | (num_frames, sample_rate) => num_frames * sample_rate / self.reader.sample_rate() as u64,
+    };
+
+    Some(file_num_frames.saturating_sub(self.clip.media_in))
+  }
+
+  fn seek(&mut self, position: u64) {
+    let file_sample_rate = self.reader.sample_rate() as u64;
+
+    self.file_position = match self.sample_rate as u64 {
+      | 0 => position,
+      | sample_rate => position * file_sample_rate / sample_rate,
+    };
+
+    if let Some(resampler) = &mut self.resampler {
+      resampler.clear();
+    }
+  }
+
+  fn read_file(&mut self, num_frames: usize) -> Result<usize> {
+    let num_read = self.reader.read_planar(self.file_position, &mut self.read_buffers, num_frames)?;
+    self.file_position += num_read as u64;
+
+    Ok(num_read)
+  }
+
+  /// Fill `num_frames` of the channel buffers from `offset`, continuing where the last fill stopped
+  fn fill(&mut self, offset: usize, num_frames: usize) -> Result {
+    if self.resampler.is_some() {
+      self.fill_resampled(offset, num_frames)
+    } else {
+      self.fill_direct(offset, num_frames)
+    }
+  }
+
+  fn fill_direct(&mut self, offset: usize, num_frames: usize) -> Result {
+    let mut filled = 0;
+
+    while filled < num_frames {
+      let num_read = self.read_file((num_frames - filled).min(READ_SIZE))?;
+      if num_read == 0 {
+        break;
+      }
+
+      for (channel, read) in self.channel_buffers.iter_mut().zip(self.read_buffers.iter()) {
+        channel[offset + filled..offset + filled + num_read].copy_from_slice(&read[..num_read]);
+      }
+
+      filled += num_read;
+    }
+
+    for channel in &mut self.channel_buffers {
+      zero_slice(&mut channel[offset + filled..offset + num_frames]);
+    }
+
+    Ok(())
+  }
+
+  fn fill_resampled(&mut self, offset: usize, num_frames: usize) -> Result {
+    // the resampler withholds its first output until it was fed its input latency, reading on until then compensates it
+    let mut available = self.resampler.as_ref().map_or(num_frames, MultiChannelResampler::available);
+
+    while available < num_frames {
+      let num_read = self.read_file(READ_SIZE)?;
+
+      // past the end of the file, feeding silence flushes the resampler tail and then keeps producing silence
+      for read in &mut self.read_buffers {
+        zero_slice(&mut read[num_read..]);
+      }
+
+      let resampler = self.resampler.as_mut().unwrap();
+      available = resampler.push_planar(self.read_buffers.iter().map(|read| &read[..]));
+    }
+
+    if let Some(resampler) = &mut self.resampler {
+      resampler.pull_planar(self.channel_buffers
+                                .iter_mut()
+                                .map(|channel| &mut channel[offset..offset + num_frames]));
+    }
+
+    Ok(())
+  }
+
+  /// Render `num_frames` of the clip from timeline `position` into the channel buffers from `offset`
+  fn render(&mut self, offset: usize, position: u64, num_frames: usize) -> Result {
+    let clip_length = self.clip_length();
+    let clip_end = clip_length.map(|length| self.clip.start_at + length).unwrap_or(u64::MAX);
+
+    let silent_before = self.clip.start_at.saturating_sub(position).min(num_frames as u64) as usize;
+    let clip_position = position + silent_before as u64;
+    let playing = clip_end.saturating_sub(clip_position).min((num_frames - silent_before) as u64) as usize;
+
+    for channel in &mut self.channel_buffers {
+      zero_slice(&mut channel[offset..offset + silent_before]);
+      zero_slice(&mut channel[offset + silent_before + playing..offset + num_frames]);
+    }
+
+    if playing > 0 {
+      let media_position = clip_position - self.clip.start_at + self.clip.media_in;
+      if self.media_position != Some(media_position) {
+        self.seek(media_position);
+      }
+
+      self.fill(offset + silent_before, playing)?;
+      self.media_position = Some(media_position + playing as u64);
+
+      self.apply_clip_gain(offset + silent_before, clip_position - self.clip.start_at, playing, clip_length);
+    }
+
+    Ok(())
+  }
+
+  /// Apply the fades and the clip gain to `num_frames` from `offset`, starting `clip_position` samples into the clip
+  fn apply_clip_gain(&mut self, offset: usize, clip_position: u64, num_frames: usize, clip_length: Option<u64>) {
+    let Clip { fade_in, fade_out, gain, .. } = self.clip;
+    let fade_out_from = clip_length.map(|length| length.saturating_sub(fade_out.length)).unwrap_or(u64::MAX);
+
+    if gain == 1.0 && clip_position >= fade_in.length && clip_position + num_frames as u64 <= fade_out_from {
+      return;
+    }
+
+    for index in 0..num_frames {
+      let position = clip_position + index as u64;
+      let mut sample_gain = gain * fade_gain(fade_in.curve, position, fade_in.length);
+
+      // the fade out mirrors the fade in, reaching silence on the last sample of the clip
+      if let Some(length) = clip_length {
+        sample_gain *= fade_gain(fade_out.curve, length - position - 1, fade_out.length);
+      }
+
+      for channel in &mut self.channel_buffers {
+        channel[offset + index] *= sample_gain;
+      }
+    }
+  }
+
+  /// Render `num_frames` from `offset` and mix in the crossfade in progress, if any
+  fn render_and_fade(&mut self, offset: usize, position: u64, num_frames: usize) -> Result {
+    self.render(offset, position, num_frames)?;
+    self.crossfade
+        .apply(self.channel_buffers.iter_mut().map(|channel| &mut channel[offset..offset + num_frames]));
+
+    Ok(())
+  }
+
+  /// The stream is about to jump at `offset`, keep what would have followed from timeline `position` to fade it out
+  fn start_crossfade(&mut self, offset: usize, position: u64) -> Result {
+    let length = self.crossfade.length();
+    if length == 0 {
+      return Ok(());
+    }
+
+    self.render(offset, position, length)?;
+    self.crossfade
+        .start(self.channel_buffers.iter_mut().map(|channel| &mut channel[offset..offset + length]));
+
+    Ok(())
+  }
+
+  /// Fit the crossfade and the channel buffers, which hold the tail of a crossfade past the end of the buffer
+  fn resize(&mut self, buffer_size: usize, crossfade: usize) {
+    if self.crossfade.length() != crossfade {
+      self.crossfade = Crossfade::new(self.channel_buffers.len(), crossfade);
+    }
+
+    for channel in &mut self.channel_buffers {
+      if channel.len() < buffer_size + crossfade {
+        channel.resize(buffer_size + crossfade, 0.0);
+      }
+    }
+  }
+
+  fn file_channel(&self, output: usize) -> Option<usize> {
+    match self.channel_buffers.len() {
+      | 1 => Some(0),
+      | num_channels if output < num_channels => Some(output),
+      | _ => None,
+    }
+  }
+}
+
+impl Node for FileSourceNode {
+  fn get_node_info(&self, _play: PlayHead) -> NodeInfo {
+    self.info.clone()
+  }
+
+  fn prepare_to_play(&mut self, play: PlayHead, _accumulated_latency: usize) -> Result {
+    let file_sample_rate = self.reader.sample_rate();
+
+    self.sample_rate = play.sample_rate;
+    self.resampler = if play.sample_rate == 0 || play.sample_rate == file_sample_rate {
+      None
+    } else {
+      Some(MultiChannelResampler::new(self.channel_buffers.len(),
+                                      file_sample_rate as f64,
+                                      play.sample_rate as f64,
+                                      READ_SIZE,
+                                      0.2,
+                                      PrecisionProfile::Bits32))
+    };
+
+    let crossfade = play.play_region.crossfade as usize;
+
+    // the tail of a crossfade is read past the end of the buffer
+    for channel in &mut self.channel_buffers {
+      channel.clear();
+      channel.resize(play.buffer_size as usize + crossfade, 0.0);
+    }
+
+    self.crossfade = Crossfade::new(self.channel_buffers.len(), crossfade);
+    self.next_position = None;
+    self.media_position = None;
+
+    Ok(())
+  }
+
+  fn prepare_to_seek(&mut self, play: PlayHead) -> Result {
+    // the seek itself is crossfaded when the next buffer is processed
+    self.resize(play.buffer_size as usize, play.play_region.crossfade as usize);
+
+    Ok(())
+  }
+
+  fn process(&mut self,
+             play: PlayHead,
+             _device_buffers: DevicesBuffers,
+             node_buffers: NodeBuffers,
+             _deadline: Instant,
+             events: &mut Vec<NodeEvent>)
+             -> Result {
+    let buffer_size = node_buffers.buffer_size;
+    let region = play.play_region;
+
+    // only reallocates if the node is processed with a larger buffer or another crossfade than it was prepared for
+    self.resize(buffer_size, region.crossfade as usize);
+
+    if let Some(next_position) = self.next_position.filter(|next_position| *next_position != play.position) {
+      self.start_crossfade(0, next_position)?;
+    }
+
+    let until_end = region.end.saturating_sub(play.position) as usize;
+
+    if region.looping && region.end > region.start && until_end < buffer_size {
+      self.render_and_fade(0, play.position, until_end)?;
+      self.start_crossfade(until_end, region.end)?;
+      self.render_and_fade(until_end, region.start, buffer_size - until_end)?;
+
+      self.next_position = Some(region.start + (buffer_size - until_end) as u64);
+    } else {
+      self.render_and_fade(0, play.position, buffer_size)?;
+
+      self.next_position = Some(play.position + buffer_size as u64);
+    }
+
+    for (index, output) in node_buffers.outputs().enumerate() {
+      match self.file_channel(index) {
+        | Some(channel) => output.copy_from_slice(&self.channel_buffers[channel][..output.len()]),
+        | None => zero_slice(output),
+      }
+    }
+
+    events.extend(node_buffers.outputs()
+                              .map(|s| slice_peak_level_db(s as &_))
+                              .enumerate()
+                              .map(make_report(reports::PEAK_LEVEL, 0)));
+
+    events.extend(node_buffers.outputs()
+                              .map(|s| slice_rms_level_db(s as &_))
+                              .enumerate()
+                              .map(make_report(reports::RMS_LEVEL, 0)));
+
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::f64::consts::FRAC_PI_2;
+  use std::time::{Duration, Instant};
+
+  use api::media::spec::MediaId;
+  use api::task::graph::FadeCurve;
+  use api::task::player::{NodeEvent, PlayHead};
+
+  use crate::buffer::{DevicesBuffers, NodeBuffers};
+  use crate::wav::{WavSampleFormat, WavWriter};
+  use crate::Node;
+
+  use super::*;
+
+  #[test]
+  fn test_read_and_seek_wav() {
+    let path = std::env::temp_dir().join(format!("file_source_node_{}.wav", std::process::id()));
+    let ramp = (0..1000).map(|i| i as f64 / 1024.0).collect::<Vec<_>>();
+
+    let mut writer = WavWriter::create(&path, 1, 48_000, WavSampleFormat::Float32).expect("create WAV file");
+    writer.write_planar(&[&ramp], ramp.len()).expect("write samples");
+    writer.finalize().expect("finalize WAV file");
+
+    let mut play_head = PlayHead::default();
+    play_head.sample_rate = 48_000;
+    play_head.buffer_size = 256;
+    play_head.play_region.end = 10_000;
+
+    let mut node = FileSourceNode::new(&path, 2).expect("open WAV file");
+    let info = node.get_node_info(play_head);
+    assert_eq!(info.num_outputs, 2);
+
+    node.prepare_to_play(play_head, 0).expect("prepare to play");
+
+    let node_buffers = NodeBuffers::allocate(&info, play_head.buffer_size as usize);
+    let mut events = vec![];
+    let process = |node: &mut FileSourceNode, play_head: PlayHead, events: &mut Vec<NodeEvent>| {
+      node.process(play_head,
+                   DevicesBuffers::default(),
+                   node_buffers.clone(),
+                   Instant::now() + Duration::from_millis(10),
+                   events)
+          .expect("process");
+    };
+
+    process(&mut node, play_head, &mut events);
+    assert_eq!(node_buffers.output_plane(0)[10], ramp[10]);
+    // mono files play on all outputs
+    assert_eq!(node_buffers.output_plane(1)[255], ramp[255]);
+    assert!(events.iter().any(|event| matches!(event, NodeEvent::Report { name, .. } if name == reports::RMS_LEVEL)));
+
+    process(&mut node, play_head.advance_position(), &mut events);
+    assert_eq!(node_buffers.output_plane(0)[0], ramp[256]);
+
+    // jumping back is a seek
+    play_head.position = 100;
+    process(&mut node, play_head, &mut events);
+    assert_eq!(node_buffers.output_plane(0)[0], ramp[100]);
+
+    // past the end of the file is silence
+    play_head.position = 900;
+    process(&mut node, play_head, &mut events);
+    assert_eq!(node_buffers.output_plane(0)[99], ramp[999]);
+    assert!(node_buffers.output_plane(0)[100..].iter().all(|s| *s == 0.0));
+
+    let _ = std::fs::remove_file(path);
+  }
+
+  #[test]
+  fn test_read_and_seek_flac() {
+    let mut play_head = PlayHead::default();
+    play_head.sample_rate = 22_050;
+    play_head.buffer_size = 256;
+    play_head.play_region.end = 100_000;
+
+    // both files hold the same 16 bit mono samples
+    let mut flac = FileSourceNode::new("../../test-files/StarWars3.flac", 1).expect("open FLAC file");
+    let mut wav = FileSourceNode::new("../../test-files/StarWars3.wav", 1).expect("open WAV file");
+    let info = flac.get_node_info(play_head);
+
+    flac.prepare_to_play(play_head, 0).expect("prepare to play");
+    wav.prepare_to_play(play_head, 0).expect("prepare to play");
+
+    let flac_buffers = NodeBuffers::allocate(&info, play_head.buffer_size as usize);
+    let wav_buffers = NodeBuffers::allocate(&info, play_head.buffer_size as usize);
+    let mut render = |play_head: PlayHead| {
+      for (node, node_buffers) in [(&mut flac, &flac_buffers), (&mut wav, &wav_buffers)] {
+        node.process(play_head,
+                     DevicesBuffers::default(),
+                     node_buffers.clone(),
+                     Instant::now() + Duration::from_millis(10),
+                     &mut vec![])
+            .expect("process");
+      }
+
+      (flac_buffers.output_plane(0).to_vec(), wav_buffers.output_plane(0).to_vec())
+    };
+
+    let (decoded, expected) = render(play_head);
+    assert_eq!(decoded, expected);
+
+    play_head = play_head.advance_position();
+    let (decoded, expected) = render(play_head);
+    assert_eq!(decoded, expected);
+
+    // seeking into a later FLAC frame
+    play_head.position = 40_000;
+    let (decoded, expected) = render(play_head);
+    assert!(expected.iter().any(|s| *s != 0.0));
+    assert_eq!(decoded, expected);
+
+    // past the end of the file is silence
+    play_head.position = 66_000;
+    let (decoded, expected) = render(play_head);
+    assert_eq!(decoded, expected);
+    assert!(decoded[150..].iter().all(|s| *s == 0.0));
+  }
+
+  #[test]
+  fn test_resampling_is_aligned() {
+    let path = std::env::temp_dir().join(format!("file_source_node_resampled_{}.wav", std::process::id()));
+    let mut impulse = vec![0.0; 8_820];
+    impulse[4_410] = 1.0;
+
+    let mut writer = WavWriter::create(&path, 1, 44_100, WavSampleFormat::Float32).expect("create WAV file");
+    writer.write_planar(&[&impulse], impulse.len()).expect("write samples");
+    writer.finalize().expect("finalize WAV file");
+
+    let mut play_head = PlayHead::default();
+    play_head.sample_rate = 48_000;
+    play_head.buffer_size = 256;
+    play_head.play_region.end = 20_000;
+
+    let mut node = FileSourceNode::new(&path, 1).expect("open WAV file");
+    let info = node.get_node_info(play_head);
+    assert_eq!(info.latency, 0);
+
+    node.prepare_to_play(play_head, 0).expect("prepare to play");
+
+    let node_buffers = NodeBuffers::allocate(&info, play_head.buffer_size as usize);
+    let mut output = vec![];
+
+    while output.len() < 9_600 {
+      node.process(play_head,
+                   DevicesBuffers::default(),
+                   node_buffers.clone(),
+                   Instant::now() + Duration::from_millis(10),
+                   &mut vec![])
+          .expect("process");
+
+      output.extend_from_slice(node_buffers.output_plane(0));
+      play_head = play_head.advance_position();
+    }
+
+    // 100ms into the file plays 100ms into the timeline
+    let peak = output.iter()
+                     .enumerate()
+                     .max_by(|(_, a), (_, b)| a.abs().total_cmp(&b.abs()))
+                     .map(|(index, _)| index);
+
+    assert!(matches!(peak, Some(4_799..=4_801)), "peak at {peak:?}");
+
+    let _ = std::fs::remove_file(path);
+  }
+
+  #[test]
+  fn test_loop_wrap_crossfade() {
+    let path = std::env::temp_dir().join(format!("file_source_node_loop_{}.wav", std::process::id()));
+    let ramp = (0..1000).map(|i| i as f64 / 1024.0).collect::<Vec<_>>();
+
+    let mut writer = WavWriter::create(&path, 1, 48_000, WavSampleFormat::Float32).expect("create WAV file");
+    writer.write_planar(&[&ramp], ramp.len()).expect("write samples");
+    writer.finalize().expect("finalize WAV file");
+
+    let mut play_head = PlayHead::default();
+    play_head.sample_rate = 48_000;
+    play_head.buffer_size = 256;
+    play_head.play_region.end = 600;
+    play_head.play_region.looping = true;
+    play_head.play_region.crossfade = 16;
+
+    let mut node = FileSourceNode::new(&path, 1).expect("open WAV file");
+    let info = node.get_node_info(play_head);
+    node.prepare_to_play(play_head, 0).expect("prepare to play");
+
+    let node_buffers = NodeBuffers::allocate(&info, play_head.buffer_size as usize);
+    for _ in 0..3 {
+      node.process(play_head,
+                   DevicesBuffers::default(),
+                   node_buffers.clone(),
+                   Instant::now() + Duration::from_millis(10),
+                   &mut vec![])
+          .expect("process");
+
+      play_head = play_head.advance_position();
+    }
+
+    // the last buffer started at 512 and wrapped back to the start of the region 88 samples in
+    let output = node_buffers.output_plane(0);
+    assert_eq!(output[87], ramp[599]);
+    assert!((output[88] - (ramp[600] * (0.5 / 16.0 * FRAC_PI_2).cos() + ramp[0] * (0.5 / 16.0 * FRAC_PI_2).sin())).abs() < 1e-9);
+    assert_eq!(output[88 + 16], ramp[16]);
+    assert_eq!(play_head.position, 168);
+
+    let _ = std::fs::remove_file(path);
+  }
+
+  #[test]
+  fn test_clip_trim_and_fades() {
+    let path = std::env::temp_dir().join(format!("file_source_node_clip_{}.wav", std::process::id()));
+    let ramp = (0..1000).map(|i| i as f64 / 1024.0).collect::<Vec<_>>();
+
+    let mut writer = WavWriter::create(&path, 1, 48_000, WavSampleFormat::Float32).expect("create WAV file");
+    writer.write_planar(&[&ramp], ramp.len()).expect("write samples");
+    writer.finalize().expect("finalize WAV file");
+
+    let mut play_head = PlayHead::default();
+    play_head.sample_rate = 48_000;
+    play_head.buffer_size = 256;
+    play_head.play_region.end = 10_000;
+
+    let fade = SourceFade { length: 10,
+                            curve:  FadeCurve::Linear, };
+
+    let spec = SourceSpec { media_id:     MediaId { app_id:   "app".to_owned(),
+                                                    media_id: "media".to_owned(), },
+                            start_at:     100,
+                            num_channels: 1,
+                            media_in:     200,
+                            media_out:    Some(500),
+                            fade_in:      fade,
+                            fade_out:     fade,
+                            gain_db:      0.0, };
+
+    let mut node = FileSourceNode::new(&path, 1).expect("open WAV file").with_clip(&spec);
+    let info = node.get_node_info(play_head);
+    node.prepare_to_play(play_head, 0).expect("prepare to play");
+
+    let node_buffers = NodeBuffers::allocate(&info, play_head.buffer_size as usize);
+    let process = |node: &mut FileSourceNode, play_head: PlayHead| {
+      node.process(play_head,
+                   DevicesBuffers::default(),
+                   node_buffers.clone(),
+                   Instant::now() + Duration::from_millis(10),
+                   &mut vec![])
+          .expect("process");
+    };
+
+    process(&mut node, play_head);
+    let output = node_buffers.output_plane(0);
+    assert!(output[..101].iter().all(|s| *s == 0.0));
+    assert_eq!(output[105], ramp[205] * 0.5);
+    assert_eq!(output[110], ramp[210]);
+
+    // the clip ends at timeline position 400, fading out over its last ten samples
+    process(&mut node, play_head.advance_position());
+    let output = node_buffers.output_plane(0);
+    assert_eq!(output[395 - 256], ramp[495] * 0.4);
+    assert_eq!(output[399 - 256], 0.0);
+    assert!(output[400 - 256..].iter().all(|s| *s == 0.0));
+
+    let _ = std::fs::remove_file(path);
+  }
+
+  #[test]
+  fn test_many_channels() {
+    let path = std::env::temp_dir().join(format!("file_source_node_many_{}.wav", std::process::id()));
+    let planes = (0..12).map(|channel| vec![channel as f64 / 16.0; 512]).collect::<Vec<_>>();
+
+    let mut writer = WavWriter::create(&path, planes.len(), 48_000, WavSampleFormat::Float32).expect("create WAV file");
+    writer.write_planar(&planes.iter().map(Vec::as_slice).collect::<Vec<_>>(), 512)
+          .expect("write samples");
+    writer.finalize().expect("finalize WAV file");
+
+    let mut play_head = PlayHead::default();
+    play_head.sample_rate = 48_000;
+    play_head.buffer_size = 128;
+    play_head.play_region.end = 512;
+
+    let mut node = FileSourceNode::new(&path, 12).expect("open WAV file");
+    let info = node.get_node_info(play_head);
+    node.prepare_to_play(play_head, 0).expect("prepare to play");
+
+    let node_buffers = NodeBuffers::allocate(&info, play_head.buffer_size as usize);
+    node.process(play_head,
+                 DevicesBuffers::default(),
+                 node_buffers.clone(),
+                 Instant::now() + Duration::from_millis(10),
+                 &mut vec![])
+        .expect("process");
+
+    for (channel, plane) in planes.iter().enumerate() {
+      assert_eq!(node_buffers.output_plane(channel)[127], plane[127]);
+    }
+
+    let _ = std::fs::remove_file(path);
+  }
+}
@@ -0,0 +1,223 @@
+use std::collections::VecDeque;
+use std::ffi::{c_void, CStr, CString};
+use std::path::Path;
+use std::ptr::null_mut;
+
+use anyhow::{anyhow, bail};
+use libflac_sys::*;
+
+use crate::Result;
+
+/// Decodes FLAC files to planar `f64` samples using libFLAC
+pub struct FlacReader {
+  decoder:      *mut FLAC__StreamDecoder,
+  shared:       Box<Shared>,
+  sample_rate:  u32,
+  num_channels: usize,
+  num_frames:   u64,
+  // frame position of the first decoded sample waiting in the shared queues
+  position:     u64,
+}
+
+unsafe impl Send for FlacReader {}
+unsafe impl Sync for FlacReader {}
+
+#[derive(Default)]
+struct Shared {
+  planes: Vec<VecDeque<f64>>,
+  scale:  f64,
+  error:  Option<FLAC__StreamDecoderErrorStatus>,
+}
+
+impl Drop for FlacReader {
+  fn drop(&mut self) {
+    unsafe {
+      if !self.decoder.is_null() {
+        FLAC__stream_decoder_finish(self.decoder);
+        FLAC__stream_decoder_delete(self.decoder);
+        self.decoder = null_mut();
+      }
+    }
+  }
+}
+
+unsafe extern "C" fn write_callback(_decoder: *const FLAC__StreamDecoder,
+                                    frame: *const FLAC__Frame,
+                                    buffer: *const *const FLAC__int32,
+                                    client_data: *mut c_void)
+                                    -> FLAC__StreamDecoderWriteStatus {
+  let shared = &mut *(client_data as *mut Shared);
+  let header = &(*frame).header;
+  let blocksize = header.blocksize as usize;
+
+  if header.channels as usize != shared.planes.len() {
+    return FLAC__STREAM_DECODER_WRITE_STATUS_ABORT;
+  }
+
+  for (channel, plane) in shared.planes.iter_mut().enumerate() {
+    let samples = std::slice::from_raw_parts(*buffer.add(channel), blocksize);
+    plane.extend(samples.iter().map(|sample| *sample as f64 * shared.scale));
+  }
+
+  FLAC__STREAM_DECODER_WRITE_STATUS_CONTINUE
+}
+
+unsafe extern "C" fn error_callback(_decoder: *const FLAC__StreamDecoder, status: FLAC__StreamDecoderErrorStatus, client_data: *mut c_void) {
+  let shared = &mut *(client_data as *mut Shared);
+  shared.error = Some(status);
+}
+
+impl FlacReader {
+  pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+    let path = path.as_ref();
+    let c_path = CString::new(path.to_string_lossy().as_bytes())?;
+
+    let decoder = unsafe { FLAC__stream_decoder_new() };
+    if decoder.is_null() {
+      bail!("FLAC__stream_decoder_new failed");
+    }
+
+    // constructed before init, so the decoder is cleaned up on every error path
+    let mut reader = Self { decoder,
+                            shared: Box::new(Shared::default()),
+                            sample_rate: 0,
+                            num_channels: 0,
+                            num_frames: 0,
+                            position: 0 };
+
+    let init_rv = unsafe {
+      FLAC__stream_decoder_init_file(decoder,
+                                     c_path.as_ptr(),
+                                     Some(write_callback),
+                                     None,
+                                     Some(error_callback),
+                                     reader.shared.as_mut() as *mut Shared as *mut c_void)
+    };
+
+    if init_rv != FLAC__STREAM_DECODER_INIT_STATUS_OK {
+      return Err(anyhow!("FLAC__stream_decoder_init_file failed for {}: {init_rv}", path.display()));
+    }
+
+    unsafe {
+      if FLAC__stream_decoder_process_until_end_of_metadata(decoder) == 0 {
+        bail!("Failed to read FLAC metadata from {}: {}", path.display(), reader.state_string());
+      }
+
+      reader.sample_rate = FLAC__stream_decoder_get_sample_rate(decoder);
+      reader.num_channels = FLAC__stream_decoder_get_channels(decoder) as usize;
+      reader.num_frames = FLAC__stream_decoder_get_total_samples(decoder);
+
+      let bits_per_sample = FLAC__stream_decoder_get_bits_per_sample(decoder);
+      if bits_per_sample == 0 || bits_per_sample > 32 {
+        bail!("Unsupported FLAC bits per sample: {bits_per_sample}");
+      }
+
+      reader.shared.scale = 1.0 / (1u64 << (bits_per_sample - 1)) as f64;
+    }
+
+    if reader.num_channels == 0 || reader.sample_rate == 0 {
+      bail!("Invalid FLAC stream: {} channels at {} Hz", reader.num_channels, reader.sample_rate);
+    }
+
+    reader.shared.planes = (0..reader.num_channels).map(|_| VecDeque::new()).collect();
+
+    Ok(reader)
+  }
+
+  pub fn sample_rate(&self) -> u32 {
+    self.sample_rate
+  }
+
+  pub fn num_channels(&self) -> usize {
+    self.num_channels
+  }
+
+  /// Total number of frames, or zero if the stream does not declare it
+  pub fn num_frames(&self) -> u64 {
+    self.num_frames
+  }
+
+  /// Read up to `num_frames` frames starting at frame `position`, returning the number of frames read
+  ///
+  /// Channels beyond the number of `planes` are skipped. Returns zero at the end of the stream.
+  pub fn read_planar(&mut self, position: u64, planes: &mut [impl AsMut<[f64]>], num_frames: usize) -> Result<usize> {
+    if planes.iter_mut().any(|plane| plane.as_mut().len() < num_frames) {
+      bail!("Not all planes can hold {num_frames} frames");
+    }
+
+    if self.num_frames != 0 && position >= self.num_frames {
+      return Ok(0);
+    }
+
+    if position != self.position {
+      self.seek(position)?;
+    }
+
+    while self.available() < num_frames {
+      if self.is_end_of_stream() {
+        break;
+      }
+
+      if unsafe { FLAC__stream_decoder_process_single(self.decoder) } == 0 {
+        bail!("Failed to decode FLAC frame: {}", self.state_string());
+      }
+
+      if let Some(error) = self.shared.error.take() {
+        bail!("FLAC decoder error: {error}");
+      }
+    }
+
+    let num_read = num_frames.min(self.available());
+
+    for (channel, plane) in self.shared.planes.iter_mut().enumerate() {
+      match planes.get_mut(channel) {
+        | Some(dest) => {
+          for (dest, sample) in dest.as_mut()[..num_read].iter_mut().zip(plane.drain(..num_read)) {
+            *dest = sample;
+          }
+        }
+        | None => {
+          plane.drain(..num_read);
+        }
+      }
+    }
+
+    self.position += num_read as u64;
+
+    Ok(num_read)
+  }
+
+  fn seek(&mut self, position: u64) -> Result {
+    self.shared.planes.iter_mut().for_each(VecDeque::clear);
+    self.position = position;
+
+    // after a seek, libFLAC delivers samples starting exactly at the target
+    if unsafe { FLAC__stream_decoder_seek_absolute(self.decoder, position) } == 0 {
+      let state = unsafe { FLAC__stream_decoder_get_state(self.decoder) };
+      let message = self.state_string();
+
+      if state == FLAC__STREAM_DECODER_SEEK_ERROR {
+        unsafe { FLAC__stream_decoder_flush(self.decoder) };
+      }
+
+      bail!("Failed to seek FLAC stream to {position}: {message}");
+    }
+
+    Ok(())
+  }
+
+  fn available(&self) -> usize {
+    self.shared.planes.first().map(VecDeque::len).unwrap_or_default()
+  }
+
+  fn is_end_of_stream(&self) -> bool {
+    unsafe { FLAC__stream_decoder_get_state(self.decoder) == FLAC__STREAM_DECODER_END_OF_STREAM }
+  }
+
+  fn state_string(&self) -> String {
+    unsafe {
+      CStr::from_ptr(FLAC__stream_decoder_get_resolved_state_string(self.decoder)).to_string_lossy()
+                                                                                  .into_owned()
+    }
+  }
+}
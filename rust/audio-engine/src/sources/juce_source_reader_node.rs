@@ -1,11 +1,12 @@
 use std::time::Instant;
 
 use anyhow::anyhow;
-use r8brain_rs::PrecisionProfile;
+use r8brain_rs::{MultiChannelResampler, PrecisionProfile, PullResampler};
 
 use api::task::player::PlayHead;
 
-use crate::buffer::{cast_sample_ref, fill_slice, DevicesBuffers, NodeBuffers};
+use crate::buffer::{cast_sample_ref, fill_slice, zero_slice, DevicesBuffers, NodeBuffers};
+use crate::crossfade::Crossfade;
 use crate::events::{make_report, slice_peak_level_db};
 use crate::juce::JuceAudioReader;
 use crate::{Node, NodeEvent, NodeInfo, Result};
@@ -15,19 +16,24 @@ use super::reports;
 const BUF_SIZE: usize = 512;
 const PRELOAD_BUFFER_COUNT: usize = 32;
 
+/// Plays back a file through JUCE, for the formats the native readers do not support
+///
+/// Seeks and loop wraps are crossfaded over the crossfade length of the play region, the reader and the resampler are
+/// repositioned without reallocating them.
 pub struct JuceSourceReaderNode {
-  buf0:       [f32; BUF_SIZE],
-  buf1:       [f32; BUF_SIZE],
-  buf2:       [f32; BUF_SIZE],
-  buf3:       [f32; BUF_SIZE],
-  buf4:       [f32; BUF_SIZE],
-  buf5:       [f32; BUF_SIZE],
-  buf6:       [f32; BUF_SIZE],
-  buf7:       [f32; BUF_SIZE],
-  info:       NodeInfo,
-  reader:     JuceAudioReader,
-  resamplers: Option<Vec<r8brain_rs::ResamplerQueue>>,
-  play_head:  PlayHead,
+  // one read buffer per source channel
+  buffers:       Vec<Vec<f32>>,
+  info:          NodeInfo,
+  reader:        JuceAudioReader,
+  resampler:     Option<PullResampler<f32>>,
+  // next frame to read from the file when resampling, at the file sample rate
+  read_position: i64,
+  play_head:     PlayHead,
+  // the play position the next process call continues from, anything else is a seek
+  next_position: u64,
+  crossfade:     Crossfade,
+  // what would have followed a seek, rendered before the reader moves and then faded out
+  tail:          NodeBuffers,
 }
 
 unsafe impl Send for JuceSourceReaderNode {}
@@ -41,71 +47,43 @@ impl JuceSourceReaderNode {
                                reports: reports::create(source_num_channels),
                                ..Default::default() };
 
-    let r8b_resampler = Self::make_resamplers(play_head.sample_rate, source_num_channels, juce_reader.get_sample_rate() as u32);
-
-    Ok(Self { buf0:       [0.0; BUF_SIZE],
-              buf1:       [0.0; BUF_SIZE],
-              buf2:       [0.0; BUF_SIZE],
-              buf3:       [0.0; BUF_SIZE],
-              buf4:       [0.0; BUF_SIZE],
-              buf5:       [0.0; BUF_SIZE],
-              buf6:       [0.0; BUF_SIZE],
-              buf7:       [0.0; BUF_SIZE],
-              info:       node_info,
-              resamplers: r8b_resampler,
-              reader:     juce_reader,
-              play_head:  PlayHead::default(), })
+    let resampler = Self::make_resampler(play_head, source_num_channels, juce_reader.get_sample_rate() as u32);
+
+    Ok(Self { buffers:       vec![vec![0.0; BUF_SIZE]; source_num_channels],
+              info:          node_info,
+              resampler,
+              read_position: 0,
+              reader:        juce_reader,
+              play_head:     PlayHead::default(),
+              next_position: 0,
+              crossfade:     Crossfade::default(),
+              tail:          NodeBuffers::new(vec![], vec![], 0), })
   }
 
-  fn make_resamplers(native_sample_rate: u32, source_num_channels: usize, source_rate: u32) -> Option<Vec<r8brain_rs::ResamplerQueue>> {
+  fn make_resampler(play: PlayHead, source_num_channels: usize, source_rate: u32) -> Option<PullResampler<f32>> {
     assert!(source_num_channels > 0);
-    assert!(native_sample_rate > 0);
+    assert!(play.sample_rate > 0);
 
-    if native_sample_rate == source_rate {
+    if play.sample_rate == source_rate {
       return None;
     }
 
-    Some((0..source_num_channels).map(|_| {
-                                   r8brain_rs::ResamplerQueue::new(source_rate as f64,
-                                                                   native_sample_rate as f64,
-                                                                   8192,
-                                                                   0.2,
-                                                                   PrecisionProfile::Bits32)
-                                 })
-                                 .collect())
-  }
-
-  fn push_to_resamplers(input: &mut [&mut [f32]], num_read: usize, resamplers: &mut Vec<r8brain_rs::ResamplerQueue>) {
-    resamplers.iter_mut().zip(input.iter()).for_each(|(resampler, buffer)| {
-                                             let mut resample_buffer = [0.0; BUF_SIZE];
-                                             fill_slice(&mut resample_buffer[..num_read],
-                                                        buffer[..num_read].into_iter().map(cast_sample_ref()));
+    let resampler =
+      MultiChannelResampler::new(source_num_channels, source_rate as f64, play.sample_rate as f64, 8192, 0.2, PrecisionProfile::Bits32);
 
-                                             resampler.push(&resample_buffer[..num_read]);
-                                           });
+    Some(PullResampler::new(resampler, play.buffer_size as usize, BUF_SIZE))
   }
 
-  fn pull_from_resamplers(node_buffers: &NodeBuffers,
-                          start: usize,
-                          remaining: usize,
-                          resamplers: &mut Vec<r8brain_rs::ResamplerQueue>)
-                          -> usize {
-    let mut resampler_read = 0;
-
-    for (output, resampler) in node_buffers.outputs().zip(resamplers.iter_mut()) {
-      resampler_read = resampler.pull(&mut output[start..start + remaining]);
-    }
-
-    resampler_read
+  /// Position in the file at the file sample rate matching the play head position
+  fn read_position_at(&self, play: PlayHead) -> i64 {
+    (play.position * self.reader.get_sample_rate() as u64 / play.sample_rate as u64) as i64
   }
 
-  fn prepare_to_play_with_resamplers(&mut self) -> Result {
-    let channels = self.reader.get_channel_count() as usize;
-    let mut buffers = crate::buffers8!(self);
-    let resamplers = self.resamplers.as_mut().unwrap();
+  fn prepare_to_play_without_resampler(&mut self) -> Result {
+    let mut play = self.play_head;
 
     for _ in 0..PRELOAD_BUFFER_COUNT {
-      let num_read = self.reader.read_samples(&mut buffers[..channels],
+      let num_read = self.reader.read_samples(&mut self.buffers,
                                               self.play_head.position as i64,
                                               self.play_head.buffer_size as i32);
 
@@ -113,84 +91,109 @@ impl JuceSourceReaderNode {
         break;
       }
 
-      let num_read = num_read as usize;
-
-      Self::push_to_resamplers(&mut buffers[..channels], num_read, resamplers);
-
-      self.play_head = self.play_head.advance_position();
+      play = play.advance_position();
     }
 
     Ok(())
   }
 
-  fn prepare_to_play_no_resamplers(&mut self) -> Result {
-    let channels = self.reader.get_channel_count() as usize;
-    let mut buffers = crate::buffers8!(self);
-    let mut play = self.play_head;
+  /// Fit the crossfade and the buffers its tail is rendered into
+  fn resize_crossfade(&mut self, length: usize) {
+    if self.crossfade.length() == length {
+      return;
+    }
 
-    for _ in 0..PRELOAD_BUFFER_COUNT {
-      let num_read = self.reader.read_samples(&mut buffers[..channels],
-                                              self.play_head.position as i64,
-                                              self.play_head.buffer_size as i32);
+    let num_channels = self.buffers.len();
 
-      if num_read <= 0 {
-        break;
-      }
+    self.crossfade = Crossfade::new(num_channels, length);
+    self.tail = NodeBuffers::allocate(&NodeInfo { num_outputs: num_channels,
+                                                  ..Default::default() },
+                                      length);
+  }
 
-      play = play.advance_position();
+  /// The play head is about to jump, render what would have followed to fade it out
+  fn start_crossfade(&mut self) -> Result {
+    if self.crossfade.length() == 0 {
+      return Ok(());
     }
 
+    let tail = self.tail.clone();
+    self.render(&tail)?;
+    self.crossfade.start(tail.outputs());
+
     Ok(())
   }
 
-  fn process_with_resamplers(&mut self, node_buffers: &NodeBuffers) -> Result {
-    let channels = self.reader.get_channel_count() as usize;
-    let mut buffers = crate::buffers8!(self);
-    let mut total_read = 0;
-    let buffer_size = self.play_head.buffer_size as usize;
-    let resamplers = self.resamplers.as_mut().unwrap();
+  /// Continue reading at the play head, keeping the read buffers and the resampler
+  fn seek(&mut self, play: PlayHead) {
+    self.play_head = play;
 
-    while total_read < buffer_size {
-      let remaining = buffer_size - total_read;
+    if let Some(resampler) = &mut self.resampler {
+      resampler.reset();
+      self.read_position = self.read_position_at(play);
+    }
+  }
 
-      let num_read = self.reader.read_samples(&mut buffers[..channels],
-                                              self.play_head.position as i64,
-                                              self.play_head.buffer_size as i32);
+  /// Fill the outputs of `node_buffers`, continuing where the last call stopped
+  fn render(&mut self, node_buffers: &NodeBuffers) -> Result {
+    if self.resampler.is_some() {
+      self.process_with_resampler(node_buffers)
+    } else {
+      self.process_without_resampler(node_buffers)
+    }
+  }
 
+  fn process_with_resampler(&mut self, node_buffers: &NodeBuffers) -> Result {
+    let resampler = self.resampler.as_mut().unwrap();
+    let reader = &mut self.reader;
+    let read_position = &mut self.read_position;
+    let mut failed = false;
+
+    let mut read = |input: &mut [Vec<f32>]| {
+      let len = input[0].len() as i32;
+      let num_read = reader.read_samples(input, *read_position, len);
       if num_read < 0 {
-        return Err(anyhow!("Error reading samples from file"));
-      } else if num_read == 0 {
-        // we read what we can, but we're done
-        break;
+        failed = true;
+        return 0;
       }
 
-      let num_read = num_read as usize;
+      *read_position += num_read as i64;
+      num_read as usize
+    };
 
-      if resamplers[0].available_for_reading() < remaining {
-        // push to the queue ...
-        Self::push_to_resamplers(&mut buffers[..channels], num_read, resamplers);
+    // the resampler hands out up to a buffer at a time, the tail of a crossfade can be longer
+    let mut num_resampled = 0;
+    while num_resampled < node_buffers.buffer_size {
+      let num_block = resampler.next_block(node_buffers.outputs().map(|output| &mut output[num_resampled..]), &mut read);
+      if num_block == 0 {
+        break;
       }
 
-      // pull from the queue ...
-      let num_resampled = Self::pull_from_resamplers(node_buffers, total_read, remaining, resamplers);
+      num_resampled += num_block;
+    }
 
-      total_read += num_resampled;
+    if failed {
+      return Err(anyhow!("Error reading samples from file"));
+    }
+
+    // once the resampled tail is out, the rest of the buffer is silent
+    for output in node_buffers.outputs() {
+      zero_slice(&mut output[num_resampled..]);
     }
 
     Ok(())
   }
 
-  fn process_without_resamplers(&mut self, node_buffers: &NodeBuffers) -> Result {
-    let channels = self.reader.get_channel_count() as usize;
-    let mut buffers = crate::buffers8!(self);
+  fn process_without_resampler(&mut self, node_buffers: &NodeBuffers) -> Result {
     let mut total_read = 0;
-    let buffer_size = self.play_head.buffer_size as usize;
+    let buffer_size = node_buffers.buffer_size;
+    let read_size = self.buffers.first().map_or(0, Vec::len);
 
     while total_read < buffer_size {
-      let remaining = buffer_size - total_read;
+      let remaining = (buffer_size - total_read).min(read_size);
 
       let num_read = self.reader
-                         .read_samples(&mut buffers[..channels], self.play_head.position as i64, remaining as i32);
+                         .read_samples(&mut self.buffers, self.play_head.position as i64, remaining as i32);
 
       if num_read < 0 {
         return Err(anyhow!("Error reading samples from file"));
@@ -201,7 +204,7 @@ impl JuceSourceReaderNode {
 
       let num_read = num_read as usize;
 
-      for (output, buffer) in node_buffers.outputs().zip(buffers[..channels].iter()) {
+      for (output, buffer) in node_buffers.outputs().zip(self.buffers.iter()) {
         fill_slice(&mut output[total_read..total_read + num_read],
                    buffer[..num_read].into_iter().map(cast_sample_ref()));
       }
@@ -210,6 +213,10 @@ impl JuceSourceReaderNode {
       self.play_head = self.play_head.advance_position_by(num_read);
     }
 
+    for output in node_buffers.outputs() {
+      zero_slice(&mut output[total_read..]);
+    }
+
     Ok(())
   }
 }
@@ -222,28 +229,56 @@ impl Node for JuceSourceReaderNode {
 
   fn prepare_to_play(&mut self, play: PlayHead, _accumulated_latency: usize) -> Result {
     self.play_head = play;
-    self.resamplers = Self::make_resamplers(play.sample_rate, self.info.num_outputs, self.reader.get_sample_rate() as u32);
+    self.next_position = play.position;
 
-    if self.resamplers.is_some() {
-      self.prepare_to_play_with_resamplers()
-    } else {
-      self.prepare_to_play_no_resamplers()
+    // reads are up to one buffer long, so this is the only place the read buffers grow
+    let buffer_size = (play.buffer_size as usize).max(BUF_SIZE);
+    for buffer in &mut self.buffers {
+      buffer.resize(buffer_size, 0.0);
     }
+    self.resampler = Self::make_resampler(play, self.buffers.len(), self.reader.get_sample_rate() as u32);
+
+    // a crossfade in progress does not survive the reset
+    self.crossfade = Crossfade::default();
+    self.resize_crossfade(play.play_region.crossfade as usize);
+
+    match self.resampler {
+      | Some(_) => {
+        // the resampler reads ahead by itself, as much as its latency needs
+        self.read_position = self.read_position_at(play);
+        Ok(())
+      }
+      | None => self.prepare_to_play_without_resampler(),
+    }
+  }
+
+  fn prepare_to_seek(&mut self, play: PlayHead) -> Result {
+    // the seek itself is crossfaded when the next buffer is processed
+    self.resize_crossfade(play.play_region.crossfade as usize);
+
+    Ok(())
   }
 
   fn process(&mut self,
-             _play: PlayHead,
+             play: PlayHead,
              _device_buffers: DevicesBuffers,
              node_buffers: NodeBuffers,
              _deadline: Instant,
              events: &mut Vec<NodeEvent>)
              -> Result {
-    if self.resamplers.is_some() {
-      self.process_with_resamplers(&node_buffers)?;
-    } else {
-      self.process_without_resamplers(&node_buffers)?;
+    // only reallocates if the node was not prepared for the crossfade of the play region
+    self.resize_crossfade(play.play_region.crossfade as usize);
+
+    if play.position != self.next_position {
+      self.start_crossfade()?;
+      self.seek(play);
     }
 
+    self.next_position = play.position + play.buffer_size as u64;
+
+    self.render(&node_buffers)?;
+    self.crossfade.apply(node_buffers.outputs());
+
     events.extend(node_buffers.outputs()
                               .map(|s| slice_peak_level_db(s as &_))
                               .enumerate()
@@ -267,9 +302,7 @@ mod test {
 
   #[test]
   fn test_io_perf() {
-    let mut buffer0 = [0.0; BUF_SIZE];
-
-    let src = JuceAudioReader::new("../../test-files/StarWars3.wav").expect("Failed to open file");
+    let mut src = JuceAudioReader::new("../../test-files/StarWars3.wav").expect("Failed to open file");
 
     let start = Instant::now();
     let length = 10_000 * BUF_SIZE as i64;
@@ -277,10 +310,10 @@ mod test {
     let duration = test_duration();
     let channels = src.get_channel_count() as usize;
     let mut pos = 0;
+    let mut buffers = vec![vec![0.0; BUF_SIZE]; channels];
 
     while start.elapsed() < duration {
-      let mut buffers = [&mut buffer0[..]];
-      if src.read_samples(&mut buffers[..channels], pos, BUF_SIZE as i32) < BUF_SIZE as i32 {
+      if src.read_samples(&mut buffers, pos, BUF_SIZE as i32) < BUF_SIZE as i32 {
         pos = 0;
         continue;
       }
@@ -1,3 +1,7 @@
+pub mod file_reader;
+pub mod file_source_node;
+pub mod flac_reader;
+#[cfg(feature = "juce")]
 pub mod juce_source_reader_node;
 
 pub mod reports {
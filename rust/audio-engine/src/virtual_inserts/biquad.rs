@@ -0,0 +1,133 @@
+use std::f64::consts::PI;
+
+/// Normalized biquad coefficients (a0 = 1), using the formulas from the RBJ Audio EQ Cookbook
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BiquadCoefficients {
+  pub b0: f64,
+  pub b1: f64,
+  pub b2: f64,
+  pub a1: f64,
+  pub a2: f64,
+}
+
+impl Default for BiquadCoefficients {
+  fn default() -> Self {
+    Self::identity()
+  }
+}
+
+impl BiquadCoefficients {
+  pub fn identity() -> Self {
+    Self { b0: 1.0,
+           b1: 0.0,
+           b2: 0.0,
+           a1: 0.0,
+           a2: 0.0, }
+  }
+
+  pub fn peaking(sample_rate: f64, frequency: f64, gain_db: f64, q: f64) -> Self {
+    let Some((a, cos_w0, alpha)) = Self::intermediates(sample_rate, frequency, gain_db, q) else { return Self::identity() };
+
+    Self::normalize(1.0 + alpha * a,
+                    -2.0 * cos_w0,
+                    1.0 - alpha * a,
+                    1.0 + alpha / a,
+                    -2.0 * cos_w0,
+                    1.0 - alpha / a)
+  }
+
+  pub fn low_shelf(sample_rate: f64, frequency: f64, gain_db: f64, q: f64) -> Self {
+    let Some((a, cos_w0, alpha)) = Self::intermediates(sample_rate, frequency, gain_db, q) else { return Self::identity() };
+    let two_sqrt_a_alpha = 2.0 * a.sqrt() * alpha;
+
+    Self::normalize(a * ((a + 1.0) - (a - 1.0) * cos_w0 + two_sqrt_a_alpha),
+                    2.0 * a * ((a - 1.0) - (a + 1.0) * cos_w0),
+                    a * ((a + 1.0) - (a - 1.0) * cos_w0 - two_sqrt_a_alpha),
+                    (a + 1.0) + (a - 1.0) * cos_w0 + two_sqrt_a_alpha,
+                    -2.0 * ((a - 1.0) + (a + 1.0) * cos_w0),
+                    (a + 1.0) + (a - 1.0) * cos_w0 - two_sqrt_a_alpha)
+  }
+
+  pub fn high_shelf(sample_rate: f64, frequency: f64, gain_db: f64, q: f64) -> Self {
+    let Some((a, cos_w0, alpha)) = Self::intermediates(sample_rate, frequency, gain_db, q) else { return Self::identity() };
+    let two_sqrt_a_alpha = 2.0 * a.sqrt() * alpha;
+
+    Self::normalize(a * ((a + 1.0) + (a - 1.0) * cos_w0 + two_sqrt_a_alpha),
+                    -2.0 * a * ((a - 1.0) + (a + 1.0) * cos_w0),
+                    a * ((a + 1.0) + (a - 1.0) * cos_w0 - two_sqrt_a_alpha),
+                    (a + 1.0) - (a - 1.0) * cos_w0 + two_sqrt_a_alpha,
+                    2.0 * ((a - 1.0) - (a + 1.0) * cos_w0),
+                    (a + 1.0) - (a - 1.0) * cos_w0 - two_sqrt_a_alpha)
+  }
+
+  fn intermediates(sample_rate: f64, frequency: f64, gain_db: f64, q: f64) -> Option<(f64, f64, f64)> {
+    if sample_rate <= 0.0 || frequency <= 0.0 || q <= 0.0 {
+      return None;
+    }
+
+    // keep the center frequency safely below nyquist
+    let frequency = frequency.min(sample_rate * 0.49);
+    let a = 10.0_f64.powf(gain_db / 40.0);
+    let w0 = 2.0 * PI * frequency / sample_rate;
+    let alpha = w0.sin() / (2.0 * q);
+
+    Some((a, w0.cos(), alpha))
+  }
+
+  fn normalize(b0: f64, b1: f64, b2: f64, a0: f64, a1: f64, a2: f64) -> Self {
+    Self { b0: b0 / a0,
+           b1: b1 / a0,
+           b2: b2 / a0,
+           a1: a1 / a0,
+           a2: a2 / a0, }
+  }
+}
+
+/// Filter state of a single channel, transposed direct form II
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BiquadState {
+  z1: f64,
+  z2: f64,
+}
+
+impl BiquadState {
+  pub fn process(&mut self, c: &BiquadCoefficients, x: f64) -> f64 {
+    let y = c.b0 * x + self.z1;
+    self.z1 = c.b1 * x - c.a1 * y + self.z2;
+    self.z2 = c.b2 * x - c.a2 * y;
+    y
+  }
+
+  pub fn reset(&mut self) {
+    self.z1 = 0.0;
+    self.z2 = 0.0;
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn dc_gain(c: &BiquadCoefficients) -> f64 {
+    (c.b0 + c.b1 + c.b2) / (1.0 + c.a1 + c.a2)
+  }
+
+  #[test]
+  fn test_shelves_at_dc() {
+    let low = BiquadCoefficients::low_shelf(48_000.0, 200.0, 6.0, 0.707);
+    let high = BiquadCoefficients::high_shelf(48_000.0, 8_000.0, 6.0, 0.707);
+
+    assert!((dc_gain(&low) - 10.0_f64.powf(6.0 / 20.0)).abs() < 1e-6);
+    assert!((dc_gain(&high) - 1.0).abs() < 1e-6);
+  }
+
+  #[test]
+  fn test_flat_peak_is_identity() {
+    let c = BiquadCoefficients::peaking(48_000.0, 1_000.0, 0.0, 1.0);
+    let mut state = BiquadState::default();
+
+    for x in [1.0, 0.5, -0.25, 0.0, 0.75] {
+      assert!((state.process(&c, x) - x).abs() < 1e-9);
+    }
+  }
+}
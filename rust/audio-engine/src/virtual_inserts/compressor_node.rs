@@ -0,0 +1,179 @@
+use std::time::Instant;
+
+use anyhow::bail;
+
+use api::instance::spec::SetParameterCommand;
+use api::task::player::{NodeEvent, PlayHead};
+
+use crate::buffer::{DevicesBuffers, NodeBuffers};
+use crate::events::{db_to_gain_factor, gain_factor_to_db};
+use crate::{Node, NodeInfo, Result};
+
+use super::{reports, time_constant};
+
+mod parameters {
+  use std::collections::HashMap;
+
+  use maplit::hashmap;
+
+  use api::instance::model::{unit_db, unit_ms, ParameterModel};
+
+  use crate::virtual_inserts::bounded;
+
+  pub const THRESHOLD: &'static str = "threshold";
+  pub const RATIO: &'static str = "ratio";
+  pub const ATTACK: &'static str = "attack";
+  pub const RELEASE: &'static str = "release";
+  pub const MAKEUP_GAIN: &'static str = "makeupGain";
+
+  fn threshold() -> ParameterModel {
+    ParameterModel { range: bounded(-60.0, 0.0),
+                     step: Some(0.1),
+                     unit: unit_db(),
+                     channels: 1,
+                     ..Default::default() }
+  }
+
+  fn ratio() -> ParameterModel {
+    ParameterModel { range: bounded(1.0, 20.0),
+                     step: Some(0.1),
+                     channels: 1,
+                     ..Default::default() }
+  }
+
+  fn time(min: f64, max: f64) -> ParameterModel {
+    ParameterModel { range: bounded(min, max),
+                     step: Some(0.1),
+                     unit: unit_ms(),
+                     channels: 1,
+                     ..Default::default() }
+  }
+
+  fn makeup_gain() -> ParameterModel {
+    ParameterModel { range: bounded(0.0, 24.0),
+                     step: Some(0.1),
+                     unit: unit_db(),
+                     channels: 1,
+                     ..Default::default() }
+  }
+
+  pub fn create() -> HashMap<String, ParameterModel> {
+    hashmap! {
+      THRESHOLD.to_owned() => threshold(),
+      RATIO.to_owned() => ratio(),
+      ATTACK.to_owned() => time(0.1, 100.0),
+      RELEASE.to_owned() => time(10.0, 2000.0),
+      MAKEUP_GAIN.to_owned() => makeup_gain(),
+    }
+  }
+}
+
+/// A feed-forward, channel linked peak compressor
+pub struct CompressorNode {
+  info:          NodeInfo,
+  threshold:     f64,
+  ratio:         f64,
+  attack_ms:     f64,
+  release_ms:    f64,
+  makeup_gain:   f64,
+  attack_coeff:  f64,
+  release_coeff: f64,
+  envelope_db:   f64,
+}
+
+impl CompressorNode {
+  pub fn new(num_channels: usize) -> Result<Self> {
+    if num_channels == 0 {
+      bail!("Compressor node needs at least one channel");
+    }
+
+    let info = NodeInfo { num_inputs: num_channels,
+                          num_outputs: num_channels,
+                          parameters: parameters::create(),
+                          reports: reports::create_with_gain_reduction(num_channels),
+                          ..Default::default() };
+
+    Ok(Self { info,
+              threshold: -18.0,
+              ratio: 4.0,
+              attack_ms: 10.0,
+              release_ms: 100.0,
+              makeup_gain: 0.0,
+              attack_coeff: 0.0,
+              release_coeff: 0.0,
+              envelope_db: 0.0 })
+  }
+
+  fn gain_reduction_db(&self, level_db: f64) -> f64 {
+    let over = level_db - self.threshold;
+    if over <= 0.0 || self.ratio <= 1.0 {
+      0.0
+    } else {
+      over * (1.0 / self.ratio - 1.0)
+    }
+  }
+}
+
+impl Node for CompressorNode {
+  fn set_parameter(&mut self, p: &SetParameterCommand) {
+    match &p.parameter[..] {
+      | parameters::THRESHOLD => self.threshold = p.value,
+      | parameters::RATIO => self.ratio = p.value.max(1.0),
+      | parameters::ATTACK => self.attack_ms = p.value,
+      | parameters::RELEASE => self.release_ms = p.value,
+      | parameters::MAKEUP_GAIN => self.makeup_gain = p.value,
+      | _ => {}
+    }
+  }
+
+  fn get_node_info(&self, _play: PlayHead) -> NodeInfo {
+    self.info.clone()
+  }
+
+  fn prepare_to_play(&mut self, play: PlayHead, _accumulated_latency: usize) -> Result {
+    self.attack_coeff = time_constant(self.attack_ms, play.sample_rate);
+    self.release_coeff = time_constant(self.release_ms, play.sample_rate);
+    self.envelope_db = 0.0;
+
+    Ok(())
+  }
+
+  fn process(&mut self,
+             play: PlayHead,
+             _devices: DevicesBuffers,
+             node_buffers: NodeBuffers,
+             _deadline: Instant,
+             events: &mut Vec<NodeEvent>)
+             -> Result {
+    reports::input_peak_levels(&node_buffers, events);
+
+    // time constants may have changed since prepare_to_play
+    self.attack_coeff = time_constant(self.attack_ms, play.sample_rate);
+    self.release_coeff = time_constant(self.release_ms, play.sample_rate);
+
+    let mut max_reduction = 0.0_f64;
+
+    for i in 0..node_buffers.buffer_size {
+      let peak = node_buffers.inputs().map(|s| s[i].abs()).fold(0.0, f64::max);
+      let target = self.gain_reduction_db(gain_factor_to_db(peak).max(-100.0));
+
+      // gain reduction is negative, so attacking means moving down
+      let coeff = if target < self.envelope_db { self.attack_coeff } else { self.release_coeff };
+      self.envelope_db = target + coeff * (self.envelope_db - target);
+      max_reduction = max_reduction.min(self.envelope_db);
+
+      let gain = db_to_gain_factor(self.envelope_db + self.makeup_gain);
+      for channel in 0..node_buffers.num_outputs {
+        node_buffers.output_plane(channel)[i] = node_buffers.input_plane(channel)[i] * gain;
+      }
+    }
+
+    events.push(NodeEvent::Report { name:    reports::GAIN_REDUCTION.to_owned(),
+                                    channel: 0,
+                                    value:   max_reduction, });
+
+    reports::output_peak_levels(&node_buffers, events);
+
+    Ok(())
+  }
+}
@@ -0,0 +1,229 @@
+use std::time::Instant;
+
+use anyhow::bail;
+
+use api::instance::spec::SetParameterCommand;
+use api::task::player::{NodeEvent, PlayHead};
+
+use crate::buffer::{DevicesBuffers, NodeBuffers};
+use crate::{Node, NodeInfo, Result};
+
+use super::biquad::{BiquadCoefficients, BiquadState};
+use super::reports;
+
+mod parameters {
+  use std::collections::HashMap;
+
+  use maplit::hashmap;
+
+  use api::instance::model::{unit_db, unit_hz, ParameterModel};
+
+  use crate::virtual_inserts::bounded;
+
+  pub const LOW_FREQUENCY: &'static str = "lowFrequency";
+  pub const LOW_GAIN: &'static str = "lowGain";
+  pub const LOW_MID_FREQUENCY: &'static str = "lowMidFrequency";
+  pub const LOW_MID_GAIN: &'static str = "lowMidGain";
+  pub const LOW_MID_Q: &'static str = "lowMidQ";
+  pub const HIGH_MID_FREQUENCY: &'static str = "highMidFrequency";
+  pub const HIGH_MID_GAIN: &'static str = "highMidGain";
+  pub const HIGH_MID_Q: &'static str = "highMidQ";
+  pub const HIGH_FREQUENCY: &'static str = "highFrequency";
+  pub const HIGH_GAIN: &'static str = "highGain";
+
+  fn frequency(num_channels: usize) -> ParameterModel {
+    ParameterModel { range: bounded(20.0, 20_000.0),
+                     step: Some(1.0),
+                     unit: unit_hz(),
+                     channels: num_channels,
+                     ..Default::default() }
+  }
+
+  fn gain(num_channels: usize) -> ParameterModel {
+    ParameterModel { range: bounded(-18.0, 18.0),
+                     step: Some(0.1),
+                     unit: unit_db(),
+                     channels: num_channels,
+                     ..Default::default() }
+  }
+
+  fn q(num_channels: usize) -> ParameterModel {
+    ParameterModel { range: bounded(0.1, 10.0),
+                     step: Some(0.01),
+                     channels: num_channels,
+                     ..Default::default() }
+  }
+
+  pub fn create(num_channels: usize) -> HashMap<String, ParameterModel> {
+    hashmap! {
+      LOW_FREQUENCY.to_owned() => frequency(num_channels),
+      LOW_GAIN.to_owned() => gain(num_channels),
+      LOW_MID_FREQUENCY.to_owned() => frequency(num_channels),
+      LOW_MID_GAIN.to_owned() => gain(num_channels),
+      LOW_MID_Q.to_owned() => q(num_channels),
+      HIGH_MID_FREQUENCY.to_owned() => frequency(num_channels),
+      HIGH_MID_GAIN.to_owned() => gain(num_channels),
+      HIGH_MID_Q.to_owned() => q(num_channels),
+      HIGH_FREQUENCY.to_owned() => frequency(num_channels),
+      HIGH_GAIN.to_owned() => gain(num_channels),
+    }
+  }
+}
+
+const NUM_BANDS: usize = 4;
+const SHELF_Q: f64 = 0.707;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BandType {
+  LowShelf,
+  Peaking,
+  HighShelf,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Band {
+  band_type: BandType,
+  frequency: f64,
+  gain:      f64,
+  q:         f64,
+}
+
+impl Band {
+  fn coefficients(&self, sample_rate: u32) -> BiquadCoefficients {
+    let sample_rate = sample_rate as f64;
+
+    match self.band_type {
+      | BandType::LowShelf => BiquadCoefficients::low_shelf(sample_rate, self.frequency, self.gain, self.q),
+      | BandType::Peaking => BiquadCoefficients::peaking(sample_rate, self.frequency, self.gain, self.q),
+      | BandType::HighShelf => BiquadCoefficients::high_shelf(sample_rate, self.frequency, self.gain, self.q),
+    }
+  }
+}
+
+#[derive(Clone, Debug)]
+struct ChannelEq {
+  bands:        [Band; NUM_BANDS],
+  coefficients: [BiquadCoefficients; NUM_BANDS],
+  states:       [BiquadState; NUM_BANDS],
+}
+
+impl Default for ChannelEq {
+  fn default() -> Self {
+    let band = |band_type, frequency, q| Band { band_type,
+                                                frequency,
+                                                gain: 0.0,
+                                                q };
+
+    Self { bands:        [band(BandType::LowShelf, 100.0, SHELF_Q),
+                          band(BandType::Peaking, 500.0, 1.0),
+                          band(BandType::Peaking, 2_500.0, 1.0),
+                          band(BandType::HighShelf, 8_000.0, SHELF_Q)],
+           coefficients: Default::default(),
+           states:       Default::default(), }
+  }
+}
+
+impl ChannelEq {
+  fn update_coefficients(&mut self, sample_rate: u32) {
+    for (coefficients, band) in self.coefficients.iter_mut().zip(self.bands.iter()) {
+      *coefficients = band.coefficients(sample_rate);
+    }
+  }
+
+  fn reset(&mut self) {
+    self.states.iter_mut().for_each(BiquadState::reset);
+  }
+
+  fn process(&mut self, input: &[f64], output: &mut [f64]) {
+    for (x, y) in input.iter().zip(output.iter_mut()) {
+      let mut value = *x;
+      for (state, coefficients) in self.states.iter_mut().zip(self.coefficients.iter()) {
+        value = state.process(coefficients, value);
+      }
+      *y = value;
+    }
+  }
+}
+
+/// A four band equalizer: low shelf, two peaking bands and a high shelf
+pub struct EqNode {
+  info:        NodeInfo,
+  channels:    Vec<ChannelEq>,
+  sample_rate: u32,
+}
+
+impl EqNode {
+  pub fn new(num_channels: usize) -> Result<Self> {
+    if num_channels == 0 {
+      bail!("EQ node needs at least one channel");
+    }
+
+    let info = NodeInfo { num_inputs: num_channels,
+                          num_outputs: num_channels,
+                          parameters: parameters::create(num_channels),
+                          reports: reports::create(num_channels),
+                          ..Default::default() };
+
+    let channels = vec![ChannelEq::default(); num_channels];
+
+    Ok(Self { info,
+              channels,
+              sample_rate: 0 })
+  }
+}
+
+impl Node for EqNode {
+  fn set_parameter(&mut self, p: &SetParameterCommand) {
+    let Some(channel) = self.channels.get_mut(p.channel) else { return; };
+    let bands = &mut channel.bands;
+
+    match &p.parameter[..] {
+      | parameters::LOW_FREQUENCY => bands[0].frequency = p.value,
+      | parameters::LOW_GAIN => bands[0].gain = p.value,
+      | parameters::LOW_MID_FREQUENCY => bands[1].frequency = p.value,
+      | parameters::LOW_MID_GAIN => bands[1].gain = p.value,
+      | parameters::LOW_MID_Q => bands[1].q = p.value,
+      | parameters::HIGH_MID_FREQUENCY => bands[2].frequency = p.value,
+      | parameters::HIGH_MID_GAIN => bands[2].gain = p.value,
+      | parameters::HIGH_MID_Q => bands[2].q = p.value,
+      | parameters::HIGH_FREQUENCY => bands[3].frequency = p.value,
+      | parameters::HIGH_GAIN => bands[3].gain = p.value,
+      | _ => return,
+    }
+
+    channel.update_coefficients(self.sample_rate);
+  }
+
+  fn get_node_info(&self, _play: PlayHead) -> NodeInfo {
+    self.info.clone()
+  }
+
+  fn prepare_to_play(&mut self, play: PlayHead, _accumulated_latency: usize) -> Result {
+    self.sample_rate = play.sample_rate;
+
+    for channel in &mut self.channels {
+      channel.update_coefficients(self.sample_rate);
+      channel.reset();
+    }
+
+    Ok(())
+  }
+
+  fn process(&mut self,
+             _play: PlayHead,
+             _devices: DevicesBuffers,
+             node_buffers: NodeBuffers,
+             _deadline: Instant,
+             events: &mut Vec<NodeEvent>)
+             -> Result {
+    reports::input_peak_levels(&node_buffers, events);
+
+    for (index, channel) in self.channels.iter_mut().enumerate() {
+      channel.process(node_buffers.input_plane(index), node_buffers.output_plane(index));
+    }
+
+    reports::output_peak_levels(&node_buffers, events);
+
+    Ok(())
+  }
+}
@@ -0,0 +1,157 @@
+use std::time::Instant;
+
+use anyhow::bail;
+
+use api::instance::spec::SetParameterCommand;
+use api::task::player::{NodeEvent, ParameterRamp, PlayHead};
+
+use crate::automation::AutomatedValue;
+use crate::buffer::{fill_slice, DevicesBuffers, NodeBuffers};
+use crate::events::db_to_gain_factor;
+use crate::{Node, NodeInfo, Result};
+
+use super::reports;
+
+mod parameters {
+  use std::collections::HashMap;
+
+  use maplit::hashmap;
+
+  use api::instance::model::{unit_db, ParameterModel};
+
+  use crate::virtual_inserts::bounded;
+
+  pub const GAIN: &'static str = "gain";
+
+  fn gain(num_channels: usize) -> ParameterModel {
+    ParameterModel { range: bounded(-48.0, 24.0),
+                     step: Some(0.1),
+                     unit: unit_db(),
+                     channels: num_channels,
+                     ..Default::default() }
+  }
+
+  pub fn create(num_channels: usize) -> HashMap<String, ParameterModel> {
+    hashmap! {
+      GAIN.to_owned() => gain(num_channels),
+    }
+  }
+}
+
+/// A per-channel gain / trim stage
+pub struct GainNode {
+  info:   NodeInfo,
+  /// Linear gain factors, ramps between factors are linear in decibels when exponential
+  gains:  Vec<AutomatedValue>,
+  /// Per-sample gains of a channel that is ramping
+  ramped: Vec<f64>,
+}
+
+impl GainNode {
+  pub fn new(num_channels: usize) -> Result<Self> {
+    if num_channels == 0 {
+      bail!("Gain node needs at least one channel");
+    }
+
+    let info = NodeInfo { num_inputs: num_channels,
+                          num_outputs: num_channels,
+                          parameters: parameters::create(num_channels),
+                          reports: reports::create(num_channels),
+                          ..Default::default() };
+
+    let gains = vec![AutomatedValue::new(1.0); num_channels];
+
+    Ok(Self { info,
+              gains,
+              ramped: vec![] })
+  }
+}
+
+impl Node for GainNode {
+  fn set_parameter(&mut self, p: &SetParameterCommand) {
+    match (&p.parameter[..], p.channel, p.value) {
+      | (parameters::GAIN, ch, value) if ch < self.gains.len() => self.gains[ch].set(db_to_gain_factor(value)),
+      | _ => {}
+    }
+  }
+
+  fn schedule_parameter(&mut self, p: &SetParameterCommand, offset: usize, ramp: ParameterRamp) {
+    match (&p.parameter[..], p.channel, p.value) {
+      | (parameters::GAIN, ch, value) if ch < self.gains.len() => self.gains[ch].schedule(offset, db_to_gain_factor(value), ramp),
+      | _ => {}
+    }
+  }
+
+  fn get_node_info(&self, _play: PlayHead) -> NodeInfo {
+    self.info.clone()
+  }
+
+  fn prepare_to_play(&mut self, play: PlayHead, _accumulated_latency: usize) -> Result {
+    self.ramped.resize(play.buffer_size as usize, 0.0);
+
+    Ok(())
+  }
+
+  fn process(&mut self,
+             _play: PlayHead,
+             _devices: DevicesBuffers,
+             node_buffers: NodeBuffers,
+             _deadline: Instant,
+             events: &mut Vec<NodeEvent>)
+             -> Result {
+    reports::input_peak_levels(&node_buffers, events);
+
+    if self.ramped.len() < node_buffers.buffer_size {
+      self.ramped.resize(node_buffers.buffer_size, 0.0);
+    }
+
+    for (index, gain) in self.gains.iter_mut().enumerate() {
+      let input = node_buffers.input_plane(index);
+      let output = node_buffers.output_plane(index);
+
+      if gain.is_steady() {
+        let gain = gain.value();
+        fill_slice(output, input.iter().map(|x| *x * gain));
+      } else {
+        let ramped = &mut self.ramped[..input.len()];
+        gain.fill(ramped);
+        fill_slice(output, input.iter().zip(ramped.iter()).map(|(x, gain)| *x * *gain));
+      }
+    }
+
+    reports::output_peak_levels(&node_buffers, events);
+
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_scheduled_gain_ramp() {
+    let mut node = GainNode::new(1).expect("Failed to create gain node");
+    let play = PlayHead { buffer_size: 8,
+                          ..Default::default() };
+    let buffers = NodeBuffers::allocate(&node.get_node_info(play), 8);
+
+    fill_slice(buffers.input_plane(0), std::iter::repeat(1.0));
+
+    node.prepare_to_play(play, 0).expect("Failed to prepare");
+    node.schedule_parameter(&SetParameterCommand { parameter: parameters::GAIN.to_owned(),
+                                                   channel:   0,
+                                                   value:     -40.0, },
+                            2,
+                            ParameterRamp::Exponential { length: 4 });
+
+    node.process(play, DevicesBuffers::default(), buffers.clone(), Instant::now(), &mut vec![])
+        .expect("Failed to process");
+
+    // exponential gain ramps move by equal steps in decibels
+    let expected_db = [0.0, 0.0, 0.0, -10.0, -20.0, -30.0, -40.0, -40.0];
+    for (sample, expected_db) in buffers.output_plane(0).iter().zip(expected_db) {
+      assert!((sample - db_to_gain_factor(expected_db)).abs() < 1e-9);
+    }
+  }
+}
@@ -0,0 +1,178 @@
+use std::collections::VecDeque;
+use std::time::Instant;
+
+use anyhow::bail;
+
+use api::instance::spec::SetParameterCommand;
+use api::task::player::{NodeEvent, PlayHead};
+
+use crate::buffer::{DevicesBuffers, NodeBuffers};
+use crate::events::{db_to_gain_factor, gain_factor_to_db};
+use crate::{Node, NodeInfo, Result};
+
+use super::{reports, time_constant};
+
+mod parameters {
+  use std::collections::HashMap;
+
+  use maplit::hashmap;
+
+  use api::instance::model::{unit_db, unit_ms, ParameterModel};
+
+  use crate::virtual_inserts::bounded;
+
+  pub const CEILING: &'static str = "ceiling";
+  pub const RELEASE: &'static str = "release";
+
+  fn ceiling() -> ParameterModel {
+    ParameterModel { range: bounded(-24.0, 0.0),
+                     step: Some(0.1),
+                     unit: unit_db(),
+                     channels: 1,
+                     ..Default::default() }
+  }
+
+  fn release() -> ParameterModel {
+    ParameterModel { range: bounded(1.0, 1000.0),
+                     step: Some(0.1),
+                     unit: unit_ms(),
+                     channels: 1,
+                     ..Default::default() }
+  }
+
+  pub fn create() -> HashMap<String, ParameterModel> {
+    hashmap! {
+      CEILING.to_owned() => ceiling(),
+      RELEASE.to_owned() => release(),
+    }
+  }
+}
+
+const LOOKAHEAD_MS: f64 = 1.5;
+
+fn lookahead_samples(sample_rate: u32) -> usize {
+  (LOOKAHEAD_MS * 0.001 * sample_rate as f64).round() as usize
+}
+
+/// A channel linked lookahead brickwall limiter
+///
+/// The lookahead is reported as the node latency, so it will be compensated like any other insert.
+pub struct LimiterNode {
+  info:          NodeInfo,
+  ceiling:       f64,
+  release_ms:    f64,
+  lookahead:     usize,
+  delay_lines:   Vec<Vec<f64>>,
+  write_pos:     usize,
+  sample_index:  usize,
+  // monotonic queue of (sample index, required gain), for the sliding minimum over the lookahead window
+  required_gain: VecDeque<(usize, f64)>,
+  gain:          f64,
+}
+
+impl LimiterNode {
+  pub fn new(num_channels: usize) -> Result<Self> {
+    if num_channels == 0 {
+      bail!("Limiter node needs at least one channel");
+    }
+
+    let info = NodeInfo { num_inputs: num_channels,
+                          num_outputs: num_channels,
+                          parameters: parameters::create(),
+                          reports: reports::create_with_gain_reduction(num_channels),
+                          ..Default::default() };
+
+    Ok(Self { info,
+              ceiling: -0.3,
+              release_ms: 50.0,
+              lookahead: 0,
+              delay_lines: vec![vec![0.0]; num_channels],
+              write_pos: 0,
+              sample_index: 0,
+              required_gain: VecDeque::new(),
+              gain: 1.0 })
+  }
+}
+
+impl Node for LimiterNode {
+  fn set_parameter(&mut self, p: &SetParameterCommand) {
+    match &p.parameter[..] {
+      | parameters::CEILING => self.ceiling = p.value.min(0.0),
+      | parameters::RELEASE => self.release_ms = p.value,
+      | _ => {}
+    }
+  }
+
+  fn get_node_info(&self, play: PlayHead) -> NodeInfo {
+    NodeInfo { latency: lookahead_samples(play.sample_rate),
+               ..self.info.clone() }
+  }
+
+  fn prepare_to_play(&mut self, play: PlayHead, _accumulated_latency: usize) -> Result {
+    self.lookahead = lookahead_samples(play.sample_rate);
+
+    for delay_line in &mut self.delay_lines {
+      delay_line.clear();
+      delay_line.resize(self.lookahead + 1, 0.0);
+    }
+
+    self.required_gain.clear();
+    self.required_gain.reserve(self.lookahead + 1);
+    self.write_pos = 0;
+    self.sample_index = 0;
+    self.gain = 1.0;
+
+    Ok(())
+  }
+
+  fn process(&mut self,
+             play: PlayHead,
+             _devices: DevicesBuffers,
+             node_buffers: NodeBuffers,
+             _deadline: Instant,
+             events: &mut Vec<NodeEvent>)
+             -> Result {
+    reports::input_peak_levels(&node_buffers, events);
+
+    let ceiling = db_to_gain_factor(self.ceiling);
+    let release_coeff = time_constant(self.release_ms, play.sample_rate);
+    let delay_len = self.lookahead + 1;
+    let mut min_gain = 1.0_f64;
+
+    for i in 0..node_buffers.buffer_size {
+      let peak = node_buffers.inputs().map(|s| s[i].abs()).fold(0.0, f64::max);
+      let required = if peak > ceiling { ceiling / peak } else { 1.0 };
+
+      while matches!(self.required_gain.back(), Some((_, gain)) if *gain >= required) {
+        self.required_gain.pop_back();
+      }
+      self.required_gain.push_back((self.sample_index, required));
+      while matches!(self.required_gain.front(), Some((index, _)) if *index + delay_len <= self.sample_index) {
+        self.required_gain.pop_front();
+      }
+
+      let target = self.required_gain.front().map(|(_, gain)| *gain).unwrap_or(1.0);
+
+      // instant attack, so the delayed sample never exceeds the ceiling
+      self.gain = if target < self.gain { target } else { target + release_coeff * (self.gain - target) };
+      min_gain = min_gain.min(self.gain);
+
+      let read_pos = (self.write_pos + 1) % delay_len;
+      for (channel, delay_line) in self.delay_lines.iter_mut().enumerate() {
+        delay_line[self.write_pos] = node_buffers.input_plane(channel)[i];
+        node_buffers.output_plane(channel)[i] = delay_line[read_pos] * self.gain;
+      }
+
+      self.write_pos = read_pos;
+      self.sample_index += 1;
+    }
+
+    events.push(NodeEvent::Report { name:    reports::GAIN_REDUCTION.to_owned(),
+                                    channel: 0,
+                                    value:   gain_factor_to_db(min_gain).max(-100.0), });
+
+    reports::output_peak_levels(&node_buffers, events);
+
+    Ok(())
+  }
+}
@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+
+use anyhow::anyhow;
+
+use crate::{BoxedNode, Result};
+
+pub mod biquad;
+pub mod compressor_node;
+pub mod eq_node;
+pub mod gain_node;
+pub mod limiter_node;
+
+pub const GAIN_MODEL_ID: &'static str = "audiocloud_gain";
+pub const EQ_MODEL_ID: &'static str = "audiocloud_eq";
+pub const COMPRESSOR_MODEL_ID: &'static str = "audiocloud_compressor";
+pub const LIMITER_MODEL_ID: &'static str = "audiocloud_limiter";
+
+/// Creates a virtual insert node with the given number of channels
+pub type VirtualInsertFactory = fn(usize) -> Result<BoxedNode>;
+
+/// Resolves [VirtualInsertSpec](api::task::graph::VirtualInsertSpec) model ids to node implementations
+#[derive(Clone)]
+pub struct VirtualInsertRegistry {
+  factories: HashMap<String, VirtualInsertFactory>,
+}
+
+impl VirtualInsertRegistry {
+  /// Create an empty registry, without the built-in models
+  pub fn empty() -> Self {
+    Self { factories: HashMap::new() }
+  }
+
+  /// Register (or replace) a model
+  pub fn register(&mut self, model_id: impl ToString, factory: VirtualInsertFactory) {
+    self.factories.insert(model_id.to_string(), factory);
+  }
+
+  /// Create a new node for the model, processing `num_channels` inputs into as many outputs
+  pub fn create(&self, model_id: &str, num_channels: usize) -> Result<BoxedNode> {
+    let factory = self.factories
+                      .get(model_id)
+                      .ok_or_else(|| anyhow!("Unknown virtual insert model {model_id}"))?;
+
+    factory(num_channels)
+  }
+
+  pub fn contains(&self, model_id: &str) -> bool {
+    self.factories.contains_key(model_id)
+  }
+
+  pub fn model_ids(&self) -> impl Iterator<Item = &str> + '_ {
+    self.factories.keys().map(String::as_str)
+  }
+}
+
+impl Default for VirtualInsertRegistry {
+  fn default() -> Self {
+    let mut rv = Self::empty();
+
+    rv.register(GAIN_MODEL_ID, |num_channels| Ok(Box::new(gain_node::GainNode::new(num_channels)?)));
+    rv.register(EQ_MODEL_ID, |num_channels| Ok(Box::new(eq_node::EqNode::new(num_channels)?)));
+    rv.register(COMPRESSOR_MODEL_ID, |num_channels| {
+        Ok(Box::new(compressor_node::CompressorNode::new(num_channels)?))
+      });
+    rv.register(LIMITER_MODEL_ID, |num_channels| Ok(Box::new(limiter_node::LimiterNode::new(num_channels)?)));
+
+    rv
+  }
+}
+
+pub mod reports {
+  use std::collections::HashMap;
+
+  use maplit::hashmap;
+
+  use api::instance::model::{unit_db, ReportModel, ValueRange};
+  use api::task::player::NodeEvent;
+
+  use crate::buffer::NodeBuffers;
+  use crate::events::{make_report, slice_peak_level_db, volume_level_report};
+
+  pub const INPUT_PEAK_LEVEL: &'static str = "inputPeakLevel";
+  pub const OUTPUT_PEAK_LEVEL: &'static str = "outputPeakLevel";
+  pub const GAIN_REDUCTION: &'static str = "gainReduction";
+
+  fn gain_reduction() -> ReportModel {
+    ReportModel { range: ValueRange::Bounded { min:  -40.0,
+                                               max:  0.0,
+                                               step: None, },
+                  unit: unit_db(),
+                  channels: 1,
+                  ..Default::default() }
+  }
+
+  pub fn create(num_channels: usize) -> HashMap<String, ReportModel> {
+    hashmap! {
+      INPUT_PEAK_LEVEL.to_owned() => volume_level_report(num_channels),
+      OUTPUT_PEAK_LEVEL.to_owned() => volume_level_report(num_channels),
+    }
+  }
+
+  pub fn create_with_gain_reduction(num_channels: usize) -> HashMap<String, ReportModel> {
+    let mut rv = create(num_channels);
+    rv.insert(GAIN_REDUCTION.to_owned(), gain_reduction());
+    rv
+  }
+
+  pub fn input_peak_levels(node_buffers: &NodeBuffers, events: &mut Vec<NodeEvent>) {
+    events.extend(node_buffers.inputs()
+                              .map(|s| slice_peak_level_db(s as &_))
+                              .enumerate()
+                              .map(make_report(INPUT_PEAK_LEVEL, 0)));
+  }
+
+  pub fn output_peak_levels(node_buffers: &NodeBuffers, events: &mut Vec<NodeEvent>) {
+    events.extend(node_buffers.outputs()
+                              .map(|s| slice_peak_level_db(s as &_))
+                              .enumerate()
+                              .map(make_report(OUTPUT_PEAK_LEVEL, 0)));
+  }
+}
+
+pub(crate) fn bounded(min: f64, max: f64) -> api::instance::model::ValueRange {
+  api::instance::model::ValueRange::Bounded { min, max, step: None }
+}
+
+pub(crate) fn time_constant(millis: f64, sample_rate: u32) -> f64 {
+  if millis <= 0.0 || sample_rate == 0 {
+    0.0
+  } else {
+    (-1.0 / (millis * 0.001 * sample_rate as f64)).exp()
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_builtin_models() {
+    let registry = VirtualInsertRegistry::default();
+
+    for model_id in [GAIN_MODEL_ID, EQ_MODEL_ID, COMPRESSOR_MODEL_ID, LIMITER_MODEL_ID] {
+      assert!(registry.contains(model_id));
+
+      let node = registry.create(model_id, 2).expect("Failed to create node");
+      let info = node.get_node_info(Default::default());
+
+      assert_eq!(info.num_inputs, 2);
+      assert_eq!(info.num_outputs, 2);
+      assert!(!info.parameters.is_empty());
+      assert!(!info.reports.is_empty());
+    }
+
+    assert!(registry.create("no_such_model", 2).is_err());
+  }
+}
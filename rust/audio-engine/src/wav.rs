@@ -0,0 +1,372 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use anyhow::bail;
+use dasp::sample::{FromSample, ToSample};
+
+use crate::Result;
+
+const WAVE_FORMAT_PCM: u16 = 1;
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+const HEADER_SIZE: u32 = 44;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WavSampleFormat {
+  Pcm16,
+  Pcm24,
+  Pcm32,
+  Float32,
+  Float64,
+}
+
+impl WavSampleFormat {
+  pub fn bits_per_sample(self) -> u16 {
+    match self {
+      | WavSampleFormat::Pcm16 => 16,
+      | WavSampleFormat::Pcm24 => 24,
+      | WavSampleFormat::Pcm32 | WavSampleFormat::Float32 => 32,
+      | WavSampleFormat::Float64 => 64,
+    }
+  }
+
+  fn format_tag(self) -> u16 {
+    match self {
+      | WavSampleFormat::Float32 | WavSampleFormat::Float64 => WAVE_FORMAT_IEEE_FLOAT,
+      | _ => WAVE_FORMAT_PCM,
+    }
+  }
+
+  fn from_format_tag(format_tag: u16, bits_per_sample: u16) -> Result<Self> {
+    Ok(match (format_tag, bits_per_sample) {
+      | (WAVE_FORMAT_PCM, 16) => WavSampleFormat::Pcm16,
+      | (WAVE_FORMAT_PCM, 24) => WavSampleFormat::Pcm24,
+      | (WAVE_FORMAT_PCM, 32) => WavSampleFormat::Pcm32,
+      | (WAVE_FORMAT_IEEE_FLOAT, 32) => WavSampleFormat::Float32,
+      | (WAVE_FORMAT_IEEE_FLOAT, 64) => WavSampleFormat::Float64,
+      | (format_tag, bits_per_sample) => bail!("Unsupported WAV format {format_tag} with {bits_per_sample} bits per sample"),
+    })
+  }
+
+  fn bytes_per_sample(self) -> usize {
+    self.bits_per_sample() as usize / 8
+  }
+
+  fn decode(self, bytes: &[u8]) -> f64 {
+    match self {
+      | WavSampleFormat::Pcm16 => i16::from_le_bytes([bytes[0], bytes[1]]).to_sample_(),
+      | WavSampleFormat::Pcm24 => (i32::from_le_bytes([0, bytes[0], bytes[1], bytes[2]])).to_sample_(),
+      | WavSampleFormat::Pcm32 => i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]).to_sample_(),
+      | WavSampleFormat::Float32 => f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f64,
+      | WavSampleFormat::Float64 => f64::from_le_bytes(bytes[..8].try_into().unwrap()),
+    }
+  }
+}
+
+/// Writes planar `f64` samples to an interleaved RIFF/WAVE stream
+///
+/// The header sizes are patched in [WavWriter::finalize], which must be called for the file to be valid.
+pub struct WavWriter<W: Write + Seek> {
+  writer:       W,
+  num_channels: usize,
+  format:       WavSampleFormat,
+  num_frames:   u64,
+}
+
+impl WavWriter<BufWriter<File>> {
+  pub fn create(path: impl AsRef<Path>, num_channels: usize, sample_rate: u32, format: WavSampleFormat) -> Result<Self> {
+    Self::new(BufWriter::new(File::create(path)?), num_channels, sample_rate, format)
+  }
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+  pub fn new(mut writer: W, num_channels: usize, sample_rate: u32, format: WavSampleFormat) -> Result<Self> {
+    if num_channels == 0 || num_channels > u16::MAX as usize {
+      bail!("Unsupported number of WAV channels: {num_channels}");
+    }
+
+    let bits_per_sample = format.bits_per_sample();
+    let block_align = num_channels as u16 * bits_per_sample / 8;
+
+    writer.write_all(b"RIFF")?;
+    writer.write_all(&0u32.to_le_bytes())?;
+    writer.write_all(b"WAVE")?;
+    writer.write_all(b"fmt ")?;
+    writer.write_all(&16u32.to_le_bytes())?;
+    writer.write_all(&format.format_tag().to_le_bytes())?;
+    writer.write_all(&(num_channels as u16).to_le_bytes())?;
+    writer.write_all(&sample_rate.to_le_bytes())?;
+    writer.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+    writer.write_all(&block_align.to_le_bytes())?;
+    writer.write_all(&bits_per_sample.to_le_bytes())?;
+    writer.write_all(b"data")?;
+    writer.write_all(&0u32.to_le_bytes())?;
+
+    Ok(Self { writer,
+              num_channels,
+              format,
+              num_frames: 0 })
+  }
+
+  pub fn num_frames(&self) -> u64 {
+    self.num_frames
+  }
+
+  /// Write `num_frames` frames, taking one plane per channel
+  pub fn write_planar(&mut self, planes: &[&[f64]], num_frames: usize) -> Result {
+    if planes.len() != self.num_channels {
+      bail!("Expected {} planes, got {}", self.num_channels, planes.len());
+    }
+
+    if planes.iter().any(|plane| plane.len() < num_frames) {
+      bail!("Not all planes contain {num_frames} frames");
+    }
+
+    for i in 0..num_frames {
+      for plane in planes {
+        self.write_sample(plane[i])?;
+      }
+    }
+
+    self.num_frames += num_frames as u64;
+
+    Ok(())
+  }
+
+  fn write_sample(&mut self, sample: f64) -> Result {
+    match self.format {
+      | WavSampleFormat::Pcm16 => self.writer.write_all(&i16::from_sample_(sample).to_le_bytes())?,
+      | WavSampleFormat::Pcm24 => self.writer.write_all(&(i32::from_sample_(sample) >> 8).to_le_bytes()[..3])?,
+      | WavSampleFormat::Pcm32 => self.writer.write_all(&i32::from_sample_(sample).to_le_bytes())?,
+      | WavSampleFormat::Float32 => self.writer.write_all(&(sample as f32).to_le_bytes())?,
+      | WavSampleFormat::Float64 => self.writer.write_all(&sample.to_le_bytes())?,
+    }
+
+    Ok(())
+  }
+
+  /// Patch the chunk sizes in the header and flush the stream
+  pub fn finalize(mut self) -> Result<W> {
+    let data_size = self.num_frames * self.num_channels as u64 * self.format.bytes_per_sample() as u64;
+    if data_size + HEADER_SIZE as u64 > u32::MAX as u64 {
+      bail!("WAV file too large: {data_size} bytes of sample data");
+    }
+
+    let data_size = data_size as u32;
+    let padding = data_size % 2;
+
+    // chunks are word aligned
+    if padding != 0 {
+      self.writer.write_all(&[0u8])?;
+    }
+
+    self.writer.seek(SeekFrom::Start(4))?;
+    self.writer.write_all(&(data_size + padding + HEADER_SIZE - 8).to_le_bytes())?;
+    self.writer.seek(SeekFrom::Start(HEADER_SIZE as u64 - 4))?;
+    self.writer.write_all(&data_size.to_le_bytes())?;
+    self.writer.seek(SeekFrom::End(0))?;
+    self.writer.flush()?;
+
+    Ok(self.writer)
+  }
+}
+
+/// Reads interleaved RIFF/WAVE sample data into planar `f64` buffers
+///
+/// Supports 16, 24 and 32 bit integer PCM and 32 or 64 bit float data, including `WAVE_FORMAT_EXTENSIBLE` headers.
+pub struct WavReader<R: Read + Seek> {
+  reader:       R,
+  sample_rate:  u32,
+  num_channels: usize,
+  format:       WavSampleFormat,
+  data_offset:  u64,
+  num_frames:   u64,
+  position:     u64,
+  scratch:      Vec<u8>,
+}
+
+impl WavReader<BufReader<File>> {
+  pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+    Self::new(BufReader::new(File::open(path)?))
+  }
+}
+
+impl<R: Read + Seek> WavReader<R> {
+  pub fn new(mut reader: R) -> Result<Self> {
+    let mut header = [0u8; 12];
+    reader.read_exact(&mut header)?;
+    if &header[..4] != b"RIFF" || &header[8..] != b"WAVE" {
+      bail!("Not a RIFF/WAVE file");
+    }
+
+    let mut fmt = None;
+
+    loop {
+      let mut chunk_header = [0u8; 8];
+      reader.read_exact(&mut chunk_header)?;
+
+      let chunk_size = u32::from_le_bytes(chunk_header[4..].try_into().unwrap()) as u64;
+
+      match &chunk_header[..4] {
+        | b"fmt " => {
+          if chunk_size < 16 {
+            bail!("WAV fmt chunk too short: {chunk_size} bytes");
+          }
+
+          let mut chunk = vec![0u8; chunk_size as usize];
+          reader.read_exact(&mut chunk)?;
+
+          let u16_at = |offset: usize| u16::from_le_bytes([chunk[offset], chunk[offset + 1]]);
+
+          let mut format_tag = u16_at(0);
+          let num_channels = u16_at(2) as usize;
+          let sample_rate = u32::from_le_bytes(chunk[4..8].try_into().unwrap());
+          let bits_per_sample = u16_at(14);
+
+          // the actual format is in the first two bytes of the sub format GUID
+          if format_tag == WAVE_FORMAT_EXTENSIBLE {
+            if chunk_size < 40 {
+              bail!("WAV extensible fmt chunk too short: {chunk_size} bytes");
+            }
+            format_tag = u16_at(24);
+          }
+
+          if num_channels == 0 || sample_rate == 0 {
+            bail!("Invalid WAV fmt chunk: {num_channels} channels at {sample_rate} Hz");
+          }
+
+          fmt = Some((WavSampleFormat::from_format_tag(format_tag, bits_per_sample)?, num_channels, sample_rate));
+
+          if chunk_size % 2 != 0 {
+            reader.seek(SeekFrom::Current(1))?;
+          }
+        }
+        | b"data" => {
+          let Some((format, num_channels, sample_rate)) = fmt else { bail!("WAV data chunk before fmt chunk"); };
+
+          let data_offset = reader.stream_position()?;
+          let file_len = reader.seek(SeekFrom::End(0))?;
+          // writers that could not patch the header leave the size zeroed or maxed out
+          let data_size = if chunk_size == 0 || chunk_size == u32::MAX as u64 {
+            file_len - data_offset
+          } else {
+            chunk_size.min(file_len - data_offset)
+          };
+
+          let num_frames = data_size / (format.bytes_per_sample() * num_channels) as u64;
+          reader.seek(SeekFrom::Start(data_offset))?;
+
+          return Ok(Self { reader,
+                           sample_rate,
+                           num_channels,
+                           format,
+                           data_offset,
+                           num_frames,
+                           position: 0,
+                           scratch: vec![] });
+        }
+        | _ => {
+          reader.seek(SeekFrom::Current((chunk_size + chunk_size % 2) as i64))?;
+        }
+      }
+    }
+  }
+
+  pub fn sample_rate(&self) -> u32 {
+    self.sample_rate
+  }
+
+  pub fn num_channels(&self) -> usize {
+    self.num_channels
+  }
+
+  pub fn num_frames(&self) -> u64 {
+    self.num_frames
+  }
+
+  pub fn format(&self) -> WavSampleFormat {
+    self.format
+  }
+
+  /// Read up to `num_frames` frames starting at frame `position`, returning the number of frames read
+  ///
+  /// Channels beyond the number of `planes` are skipped. Returns zero at the end of the data.
+  pub fn read_planar(&mut self, position: u64, planes: &mut [impl AsMut<[f64]>], num_frames: usize) -> Result<usize> {
+    if planes.iter_mut().any(|plane| plane.as_mut().len() < num_frames) {
+      bail!("Not all planes can hold {num_frames} frames");
+    }
+
+    let num_frames = (num_frames as u64).min(self.num_frames.saturating_sub(position)) as usize;
+    if num_frames == 0 {
+      return Ok(0);
+    }
+
+    let bytes_per_sample = self.format.bytes_per_sample();
+    let block_align = bytes_per_sample * self.num_channels;
+
+    if position != self.position {
+      self.reader.seek(SeekFrom::Start(self.data_offset + position * block_align as u64))?;
+    }
+
+    self.scratch.resize(num_frames * block_align, 0);
+    self.reader.read_exact(&mut self.scratch)?;
+    self.position = position + num_frames as u64;
+
+    for (channel, plane) in planes.iter_mut().enumerate().take(self.num_channels) {
+      for (i, sample) in plane.as_mut()[..num_frames].iter_mut().enumerate() {
+        let offset = i * block_align + channel * bytes_per_sample;
+        *sample = self.format.decode(&self.scratch[offset..offset + bytes_per_sample]);
+      }
+    }
+
+    Ok(num_frames)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::io::Cursor;
+
+  use super::*;
+
+  #[test]
+  fn test_roundtrip() {
+    let left = (0..1000).map(|i| (i as f64 / 1000.0) - 0.5).collect::<Vec<_>>();
+    let right = left.iter().map(|x| -x).collect::<Vec<_>>();
+
+    for format in [WavSampleFormat::Pcm16,
+                   WavSampleFormat::Pcm24,
+                   WavSampleFormat::Pcm32,
+                   WavSampleFormat::Float32,
+                   WavSampleFormat::Float64]
+    {
+      let mut writer = WavWriter::new(Cursor::new(vec![]), 2, 44_100, format).expect("create writer");
+      writer.write_planar(&[&left, &right], left.len()).expect("write");
+      let mut cursor = writer.finalize().expect("finalize");
+      cursor.set_position(0);
+
+      let mut reader = WavReader::new(cursor).expect("open reader");
+      assert_eq!(reader.sample_rate(), 44_100);
+      assert_eq!(reader.num_channels(), 2);
+      assert_eq!(reader.num_frames(), 1000);
+      assert_eq!(reader.format(), format);
+
+      let tolerance = 1.0 / (1u64 << (format.bits_per_sample().min(24) - 1)) as f64;
+      let (mut l, mut r) = (vec![0.0; 600], vec![0.0; 600]);
+
+      // read out of order to exercise seeking
+      assert_eq!(reader.read_planar(500, &mut [&mut l, &mut r], 600).expect("read"), 500);
+      assert!((l[0] - left[500]).abs() <= tolerance, "{format:?}");
+      assert!((r[499] - right[999]).abs() <= tolerance, "{format:?}");
+
+      assert_eq!(reader.read_planar(0, &mut [&mut l, &mut r], 600).expect("read"), 600);
+      for i in 0..600 {
+        assert!((l[i] - left[i]).abs() <= tolerance, "{format:?}");
+        assert!((r[i] - right[i]).abs() <= tolerance, "{format:?}");
+      }
+
+      assert_eq!(reader.read_planar(1000, &mut [&mut l], 600).expect("read"), 0);
+    }
+  }
+}
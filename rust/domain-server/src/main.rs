@@ -2,20 +2,29 @@ use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::sync::Arc;
 
-use anyhow::{anyhow, bail};
+use anyhow::bail;
 use axum::http::header::{ACCEPT, AUTHORIZATION, CACHE_CONTROL, CONTENT_TYPE, COOKIE, HOST, IF_MATCH, IF_NONE_MATCH, IF_UNMODIFIED_SINCE};
 use axum::http::Method;
 use axum::Router;
+use audio_engine::audio_device::enumeration::default_enumerator;
+use audio_engine::audio_device::AudioDevices;
 use clap::{Args, Parser};
+use domain_service::audio_device::host::AudioDeviceHost;
+use domain_service::audio_device::server::AudioDeviceService;
+use domain_service::instance::attachment::{serve_instance_latency_calibrations, NatsDeviceInstanceResolver};
 use domain_service::instance::driver::scripting::new_scripting_engine;
+use domain_service::media::resolver::MediaRootResolver;
 use domain_service::media::service::MediaService;
 use domain_service::nats::Nats;
 use domain_service::service::{Service, ServiceConfig};
+use domain_service::tasks::server::TasksServer;
+use domain_service::tasks::PlayerResources;
 use domain_service::Result;
 use futures::channel::mpsc;
 use futures::{FutureExt, SinkExt, StreamExt};
 use governor::{Quota, RateLimiter};
 use nonzero_ext::*;
+use tokio::sync::watch;
 use tokio::{select, spawn};
 use tower_http::cors;
 use tower_http::cors::AllowOrigin;
@@ -45,6 +54,9 @@ struct Arguments {
   /// Enable media management services
   #[arg(long, env)]
   pub enable_media:            bool,
+  /// Enables opening the audio devices specified for this host, and publishing the ones it has
+  #[arg(long, env)]
+  pub enable_audio_devices:    bool,
   /// NATS JetStream URL
   #[arg(long, env, default_value = "nats://localhost:4222")]
   pub nats_url:                String,
@@ -98,12 +110,14 @@ enum InternalEvent {
   InstancesFinished,
   TasksFinished,
   MediaFinished,
+  AudioDevicesFinished,
   RestApiFinished,
   ScriptingFinished,
   RestartInstanceDrivers,
   RestartInstances,
   RestartTasks,
   RestartMedia,
+  RestartAudioDevices,
 }
 
 #[tokio::main]
@@ -164,16 +178,22 @@ async fn main() -> Result {
 
   let instances_respawn_limit = Arc::new(RateLimiter::direct(Quota::per_minute(nonzero!(10u32))));
 
+  // the devices opened by the host, published to the players whenever they change
+  let tx_audio_devices = watch::Sender::new(AudioDevices::default());
+  let instance_resolver = NatsDeviceInstanceResolver::new(&service);
+
   let create_tasks = || {
     if args.enable_tasks {
       let mut tx_internal = tx_internal.clone();
-      spawn(async move {
-              error!("Tasks service is not yet implemented");
-              Err::<(), _>(anyhow!("Tasks service is not yet implemented"))
-            }.then(|res| async move {
-               warn!("Tasks service exited: {res:?}");
-               let _ = tx_internal.send(TasksFinished).await;
-             }));
+      info!("Starting tasks service: {}", host_name);
+      let players = PlayerResources { audio_devices: tx_audio_devices.subscribe(),
+                                      media:         MediaRootResolver::new(args.media_root.clone()),
+                                      instances:     instance_resolver.clone(), };
+      let tasks = TasksServer::new(service.clone(), host_name.clone(), players);
+      spawn(tasks.run().then(|res| async move {
+                         warn!("Tasks service exited: {res:?}");
+                         let _ = tx_internal.send(TasksFinished).await;
+                       }));
     }
   };
   let tasks_respawn_limit = Arc::new(RateLimiter::direct(Quota::per_minute(nonzero!(10u32))));
@@ -195,6 +215,30 @@ async fn main() -> Result {
   };
   let media_respawn_limit = Arc::new(RateLimiter::direct(Quota::per_minute(nonzero!(10u32))));
 
+  let create_audio_devices = || {
+    if args.enable_audio_devices {
+      let mut tx_internal = tx_internal.clone();
+      info!("Starting audio device service: {}", host_name);
+      let devices = AudioDeviceService::new(service.clone(), host_name.clone(), default_enumerator());
+      let host = AudioDeviceHost::new(&service, &host_name, tx_audio_devices.clone());
+      let calibrations = serve_instance_latency_calibrations(service.clone(),
+                                                             host_name.clone(),
+                                                             instance_resolver.clone(),
+                                                             tx_audio_devices.subscribe());
+      spawn(async move {
+              select! {
+                res = devices.run() => res,
+                res = host.run() => res,
+                res = calibrations => res,
+              }
+            }.then(|res| async move {
+               warn!("Audio device service exited: {res:?}");
+               let _ = tx_internal.send(AudioDevicesFinished).await;
+             }));
+    }
+  };
+  let audio_devices_respawn_limit = Arc::new(RateLimiter::direct(Quota::per_minute(nonzero!(10u32))));
+
   let create_rest_api = || {
     if args.enable_api {
       let service = service.clone();
@@ -235,6 +279,7 @@ async fn main() -> Result {
   create_instances();
   create_tasks();
   create_media();
+  create_audio_devices();
   create_rest_api();
 
   spawn({
@@ -282,6 +327,14 @@ async fn main() -> Result {
               let _ = tx_internal.send(RestartMedia).await;
             });
           },
+          AudioDevicesFinished => {
+            let mut tx_internal = tx_internal.clone();
+            let audio_devices_respawn_limit = audio_devices_respawn_limit.clone();
+            spawn(async move {
+              let _ = audio_devices_respawn_limit.until_ready().await;
+              let _ = tx_internal.send(RestartAudioDevices).await;
+            });
+          },
           RestApiFinished => {
             error!("FATAL: Rest API stopped, exiting");
             break;
@@ -301,6 +354,9 @@ async fn main() -> Result {
           },
           RestartMedia => {
             create_media();
+          },
+          RestartAudioDevices => {
+            create_audio_devices();
           }
         }
       },
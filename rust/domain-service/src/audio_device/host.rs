@@ -0,0 +1,321 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use futures::StreamExt;
+use governor::clock::QuantaClock;
+use governor::middleware::NoOpMiddleware;
+use governor::state::keyed::DashMapStateStore;
+use governor::{Quota, RateLimiter};
+use nonzero_ext::nonzero;
+use tokio::sync::{mpsc, watch};
+use tokio::task::spawn_blocking;
+use tokio::time::sleep;
+use tokio::{select, spawn};
+use tracing::{info, warn};
+
+use api::audio_device::{AudioDeviceSpec, HostAudioDeviceSpecs};
+use audio_engine::audio_device::{open_device, AudioDevice, AudioDevices};
+
+use crate::nats::WatchStream;
+use crate::service::Service;
+
+use super::Result;
+
+/// Opens the audio devices specified for the host, and keeps them in line with the spec
+///
+/// A device is closed when it is removed from the spec, and closed and opened again when its spec changes, once it
+/// finished closing. Devices that fail to open are retried every second, within a rate limit. Every change to the open
+/// devices or the clock device is published on the watch channel the host was created with.
+pub struct AudioDeviceHost {
+  watch_specs:      WatchStream<String, HostAudioDeviceSpecs>,
+  devices:          HashMap<String, HostedDevice>,
+  clock_device:     Option<String>,
+  audio_devices:    AudioDevices,
+  tx_audio_devices: watch::Sender<AudioDevices>,
+  /// The open devices or the clock device changed since they were last published
+  publish:          bool,
+  tx_internal:      mpsc::Sender<InternalEvent>,
+  rx_internal:      mpsc::Receiver<InternalEvent>,
+  reopen_limiter:   RateLimiter<String, DashMapStateStore<String>, QuantaClock, NoOpMiddleware>,
+}
+
+#[derive(Default)]
+struct HostedDevice {
+  spec:  Option<AudioDeviceSpec>,
+  state: HostedDeviceState,
+}
+
+#[derive(Default, Debug, PartialEq)]
+enum HostedDeviceState {
+  #[default]
+  Closed,
+  /// Opening with the spec, on a blocking thread
+  Opening(AudioDeviceSpec),
+  /// Open with the spec
+  Open(AudioDeviceSpec),
+  /// Terminated, waiting for the driver to let go of the device
+  Closing,
+}
+
+enum InternalEvent {
+  Opened {
+    device_id: String,
+    spec:      AudioDeviceSpec,
+    result:    Result<AudioDevice>,
+  },
+  Closed {
+    device_id: String,
+  },
+}
+
+impl AudioDeviceHost {
+  pub fn new(service: &Service, host: &str, tx_audio_devices: watch::Sender<AudioDevices>) -> Self {
+    Self::with_specs(service.watch_host_audio_device_specs(host), tx_audio_devices)
+  }
+
+  fn with_specs(watch_specs: WatchStream<String, HostAudioDeviceSpecs>, tx_audio_devices: watch::Sender<AudioDevices>) -> Self {
+    let devices = HashMap::new();
+    let clock_device = None;
+    let audio_devices = AudioDevices::default();
+    let (tx_internal, rx_internal) = mpsc::channel(0x100);
+    let reopen_limiter = RateLimiter::new(Quota::per_minute(nonzero!(5u32)).allow_burst(nonzero!(10u32)),
+                                          DashMapStateStore::new(),
+                                          &QuantaClock::default());
+
+    // devices left open by a previous run of the host were closed when it exited
+    tx_audio_devices.send_replace(audio_devices.clone());
+
+    Self { watch_specs,
+           devices,
+           clock_device,
+           audio_devices,
+           tx_audio_devices,
+           publish: false,
+           tx_internal,
+           rx_internal,
+           reopen_limiter }
+  }
+
+  pub async fn run(mut self) -> Result {
+    loop {
+      select! {
+        maybe_update = self.watch_specs.next() => {
+          // the watch ends when NATS goes away, the host is restarted to watch again
+          let Some((_, maybe_new_specs)) = maybe_update else { break; };
+          self.handle_maybe_specs(maybe_new_specs);
+        },
+        Some(event) = self.rx_internal.recv() => {
+          self.handle_internal_event(event);
+        },
+        _ = sleep(Duration::from_secs(1)) => {
+          self.update_devices();
+        },
+      }
+    }
+
+    self.close_devices();
+
+    Ok(())
+  }
+
+  fn handle_maybe_specs(&mut self, maybe_new_specs: Option<HostAudioDeviceSpecs>) {
+    let specs = maybe_new_specs.unwrap_or_default();
+
+    for (device_id, device) in &mut self.devices {
+      device.spec = specs.devices.get(device_id).cloned();
+    }
+
+    for (device_id, spec) in specs.devices {
+      self.devices.entry(device_id).or_default().spec = Some(spec);
+    }
+
+    self.clock_device = specs.clock_device;
+    self.update_devices();
+  }
+
+  fn handle_internal_event(&mut self, event: InternalEvent) {
+    match event {
+      | InternalEvent::Opened { device_id, spec, result } => {
+        let device = self.devices.entry(device_id.clone()).or_default();
+
+        match result {
+          | Ok(audio_device) => {
+            info!(device_id, ?spec, "Opened audio device");
+            self.audio_devices.add_device(device_id, audio_device);
+            self.publish = true;
+            device.state = HostedDeviceState::Open(spec);
+          }
+          | Err(err) => {
+            warn!(device_id, ?err, "Failed to open audio device: {err}");
+            device.state = HostedDeviceState::Closed;
+          }
+        }
+      }
+      | InternalEvent::Closed { device_id } => {
+        info!(device_id, "Closed audio device");
+        self.devices.entry(device_id).or_default().state = HostedDeviceState::Closed;
+      }
+    }
+
+    self.update_devices();
+  }
+
+  fn update_devices(&mut self) {
+    for (device_id, device) in &mut self.devices {
+      if matches!(&device.state, HostedDeviceState::Open(opened) if Some(opened) != device.spec.as_ref()) {
+        info!(device_id, "Closing audio device");
+        device.state = HostedDeviceState::Closing;
+        self.publish = true;
+
+        // drivers can refuse to open a device again while it is still closing
+        if let Some(audio_device) = self.audio_devices.terminate_device(device_id) {
+          let tx_internal = self.tx_internal.clone();
+          let device_id = device_id.clone();
+
+          spawn(async move {
+            audio_device.closed().await;
+            let _ = tx_internal.send(InternalEvent::Closed { device_id }).await;
+          });
+        }
+      }
+
+      let Some(spec) = device.spec.as_ref() else { continue; };
+      if device.state != HostedDeviceState::Closed || self.reopen_limiter.check_key(device_id).is_err() {
+        continue;
+      }
+
+      device.state = HostedDeviceState::Opening(spec.clone());
+
+      // opening blocks on the driver
+      let tx_internal = self.tx_internal.clone();
+      let device_id = device_id.clone();
+      let spec = spec.clone();
+
+      spawn(async move {
+        let opening = (device_id.clone(), spec.clone());
+        let result = spawn_blocking(move || open_device(opening.0, &opening.1)).await
+                                                                               .unwrap_or_else(|err| Err(err.into()));
+
+        let _ = tx_internal.send(InternalEvent::Opened { device_id, spec, result }).await;
+      });
+    }
+
+    self.devices
+        .retain(|_, device| device.spec.is_some() || device.state != HostedDeviceState::Closed);
+
+    if let Some(clock_device) = self.clock_device.as_deref() {
+      if self.audio_devices.clock_device() != Some(clock_device) && self.audio_devices.set_clock_device(clock_device).is_ok() {
+        info!(clock_device, "Audio device clock changed");
+        self.publish = true;
+      }
+    }
+
+    if std::mem::take(&mut self.publish) {
+      self.tx_audio_devices.send_replace(self.audio_devices.clone());
+    }
+  }
+
+  fn close_devices(&mut self) {
+    for (device_id, _) in self.devices.drain() {
+      self.audio_devices.terminate_device(&device_id);
+    }
+
+    self.tx_audio_devices.send_replace(self.audio_devices.clone());
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use tokio::time::timeout;
+
+  use api::audio_device::AudioDeviceDriverSpec;
+
+  use super::*;
+
+  fn simulator(num_outputs: usize) -> AudioDeviceSpec {
+    AudioDeviceSpec { driver: AudioDeviceDriverSpec::Simulator,
+                      sample_rate: 48_000,
+                      buffer_size: 128,
+                      num_inputs: 2,
+                      num_outputs }
+  }
+
+  fn specs(devices: &[(&str, AudioDeviceSpec)], clock_device: Option<&str>) -> HostAudioDeviceSpecs {
+    HostAudioDeviceSpecs { devices:      devices.iter()
+                                                .map(|(device_id, spec)| (device_id.to_string(), spec.clone()))
+                                                .collect(),
+                           clock_device: clock_device.map(str::to_owned), }
+  }
+
+  fn new_host() -> (AudioDeviceHost, watch::Receiver<AudioDevices>) {
+    let (tx_devices, rx_devices) = watch::channel(AudioDevices::default());
+
+    (AudioDeviceHost::with_specs(Box::pin(futures::stream::pending()), tx_devices), rx_devices)
+  }
+
+  /// Handle the events of the host until no device is opening or closing
+  async fn settle(host: &mut AudioDeviceHost) {
+    while host.devices
+              .values()
+              .any(|device| matches!(device.state, HostedDeviceState::Opening(_) | HostedDeviceState::Closing))
+    {
+      let event = timeout(Duration::from_secs(5), host.rx_internal.recv()).await
+                                                                          .expect("device opened or closed in time")
+                                                                          .expect("internal event");
+      host.handle_internal_event(event);
+    }
+  }
+
+  #[tokio::test]
+  async fn test_add_change_and_remove_devices() {
+    let (mut host, mut rx_devices) = new_host();
+
+    host.handle_maybe_specs(Some(specs(&[("a", simulator(2)), ("b", simulator(2))], None)));
+    settle(&mut host).await;
+
+    let devices = rx_devices.borrow_and_update().clone();
+    assert_eq!(devices.get_info("a").unwrap().num_outputs, 2);
+    assert_eq!(devices.get_info("b").unwrap().num_outputs, 2);
+    assert!(devices.clock_device().is_some());
+
+    // a changed device is closed, and only opened again once it finished closing
+    host.handle_maybe_specs(Some(specs(&[("a", simulator(8)), ("b", simulator(2))], None)));
+    assert_eq!(host.devices["a"].state, HostedDeviceState::Closing);
+    assert!(rx_devices.borrow_and_update().get_info("a").is_err());
+
+    settle(&mut host).await;
+    assert_eq!(rx_devices.borrow_and_update().get_info("a").unwrap().num_outputs, 8);
+
+    host.handle_maybe_specs(Some(specs(&[("b", simulator(2))], None)));
+    settle(&mut host).await;
+
+    let devices = rx_devices.borrow_and_update().clone();
+    assert!(!host.devices.contains_key("a"));
+    assert!(devices.get_info("a").is_err());
+    assert_eq!(devices.clock_device(), Some("b"));
+
+    host.handle_maybe_specs(None);
+    settle(&mut host).await;
+
+    assert!(host.devices.is_empty());
+    assert!(rx_devices.borrow_and_update().get_info("b").is_err());
+  }
+
+  #[tokio::test]
+  async fn test_clock_device() {
+    let (mut host, mut rx_devices) = new_host();
+    let devices = [("a", simulator(2)), ("b", simulator(2))];
+
+    host.handle_maybe_specs(Some(specs(&devices, Some("b"))));
+    settle(&mut host).await;
+    assert_eq!(rx_devices.borrow_and_update().clock_device(), Some("b"));
+
+    host.handle_maybe_specs(Some(specs(&devices, Some("a"))));
+    assert!(rx_devices.has_changed().unwrap());
+    assert_eq!(rx_devices.borrow_and_update().clock_device(), Some("a"));
+
+    // the same spec again changes nothing
+    host.handle_maybe_specs(Some(specs(&devices, Some("a"))));
+    assert!(!rx_devices.has_changed().unwrap());
+  }
+}
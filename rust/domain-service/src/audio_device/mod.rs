@@ -0,0 +1,4 @@
+pub mod host;
+pub mod server;
+
+pub type Result<T = ()> = anyhow::Result<T>;
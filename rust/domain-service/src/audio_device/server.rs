@@ -0,0 +1,88 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::anyhow;
+use chrono::Utc;
+use tokio::task::spawn_blocking;
+use tokio::time::{interval, MissedTickBehavior};
+use tracing::{info, warn};
+
+use api::audio_device::{AudioDeviceDescription, AudioDeviceEvent, HostAudioDevices};
+use audio_engine::audio_device::enumeration::{AudioDeviceWatcher, BoxedAudioDeviceEnumerator};
+
+use crate::service::Service;
+
+use super::Result;
+
+/// How often the device names are listed to notice devices being plugged in or out
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How often the device list is stored even if it did not change, it expires from the bucket when the host goes away
+const REFRESH_INTERVAL: Duration = Duration::from_secs(20);
+
+/// Publishes the audio devices of a host, and events when they appear or disappear
+pub struct AudioDeviceService {
+  service: Service,
+  host:    String,
+  watcher: Arc<Mutex<AudioDeviceWatcher>>,
+}
+
+impl AudioDeviceService {
+  pub fn new(service: Service, host: String, enumerator: BoxedAudioDeviceEnumerator) -> Self {
+    let watcher = Arc::new(Mutex::new(AudioDeviceWatcher::new(enumerator)));
+
+    Self { service,
+           host,
+           watcher }
+  }
+
+  pub async fn run(self) -> Result {
+    let mut timer = interval(POLL_INTERVAL);
+    timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let mut stored_at = None::<Instant>;
+
+    loop {
+      timer.tick().await;
+
+      // enumerating blocks on the drivers
+      let watcher = self.watcher.clone();
+      let polled = spawn_blocking(move || poll_devices(&watcher)).await?;
+
+      let (events, devices) = match polled {
+        | Ok(polled) => polled,
+        | Err(err) => {
+          warn!(?err, "Failed to enumerate audio devices: {err}");
+          continue;
+        }
+      };
+
+      for event in events.iter().cloned() {
+        info!(?event, "Audio devices changed");
+
+        if let Err(err) = self.service.publish_audio_device_event(&self.host, event).await {
+          warn!(?err, "Failed to publish audio device event: {err}");
+        }
+      }
+
+      let refresh_due = stored_at.map(|stored_at| stored_at.elapsed() >= REFRESH_INTERVAL).unwrap_or(true);
+
+      if !events.is_empty() || refresh_due {
+        let devices = HostAudioDevices { devices,
+                                         updated_at: Utc::now() };
+
+        match self.service.set_host_audio_devices(&self.host, devices).await {
+          | Ok(_) => stored_at = Some(Instant::now()),
+          | Err(err) => warn!(?err, "Failed to store audio devices: {err}"),
+        }
+      }
+    }
+  }
+}
+
+fn poll_devices(watcher: &Mutex<AudioDeviceWatcher>) -> Result<(Vec<AudioDeviceEvent>, Vec<AudioDeviceDescription>)> {
+  let mut watcher = watcher.lock().map_err(|_| anyhow!("Audio device watcher lock poisoned"))?;
+  let events = watcher.poll()?;
+
+  Ok((events, watcher.devices().to_vec()))
+}
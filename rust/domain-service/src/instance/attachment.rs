@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock, Weak};
+
+use anyhow::anyhow;
+use futures::StreamExt;
+use tokio::sync::watch;
+use tokio::{select, spawn};
+use tracing::{info, warn};
+
+use api::instance::request::CalibrateInstanceLatencyResponse;
+use api::instance::spec::InstanceSpec;
+use api::instance::InstanceLatencyCalibration;
+use audio_engine::audio_device::latency_calibration::{calibrate_device_insert, CalibrationOptions};
+use audio_engine::audio_device::AudioDevices;
+use audio_engine::player::{DeviceInstanceAttachment, DeviceInstanceResolver};
+
+use crate::service::Service;
+
+use super::Result;
+
+type AttachedInstances = RwLock<HashMap<String, AttachedInstance>>;
+
+/// Resolves device instances from the instance specs and latency calibrations in NATS
+///
+/// The buckets are watched in the background, so instances become resolvable shortly after the resolver is created and
+/// new calibrations are used by every insert created after they were stored.
+#[derive(Clone)]
+pub struct NatsDeviceInstanceResolver {
+  instances: Arc<AttachedInstances>,
+}
+
+#[derive(Default)]
+struct AttachedInstance {
+  spec:        Option<InstanceSpec>,
+  calibration: Option<InstanceLatencyCalibration>,
+}
+
+impl NatsDeviceInstanceResolver {
+  pub fn new(service: &Service) -> Self {
+    let instances = Arc::new(AttachedInstances::default());
+
+    spawn(watch_instances(service.clone(), Arc::downgrade(&instances)));
+
+    Self { instances }
+  }
+}
+
+impl DeviceInstanceResolver for NatsDeviceInstanceResolver {
+  fn resolve(&self, instance_id: &str) -> Result<DeviceInstanceAttachment> {
+    let instances = self.instances.read().map_err(|_| anyhow!("Instance attachments lock poisoned"))?;
+    let instance = instances.get(instance_id)
+                            .ok_or_else(|| anyhow!("Instance {instance_id} not found"))?;
+
+    let attachment = instance.spec
+                             .as_ref()
+                             .and_then(|spec| spec.attachment.as_ref())
+                             .ok_or_else(|| anyhow!("Instance {instance_id} is not attached to an audio device"))?;
+
+    // a calibration only holds for the device it was measured on
+    let additional_latency = instance.calibration
+                                     .as_ref()
+                                     .filter(|calibration| calibration.device == attachment.device)
+                                     .map(|calibration| calibration.additional_latency)
+                                     .unwrap_or_default();
+
+    Ok(DeviceInstanceAttachment { device_id: attachment.device.clone(),
+                                  sends: attachment.inputs.iter().map(|channel| *channel as u32).collect(),
+                                  returns: attachment.outputs.iter().map(|channel| *channel as u32).collect(),
+                                  additional_latency })
+  }
+}
+
+async fn watch_instances(service: Service, instances: Weak<AttachedInstances>) {
+  let mut watch_specs = service.watch_all_instance_specs();
+  let mut watch_calibrations = service.watch_all_instance_latency_calibrations();
+
+  loop {
+    select! {
+      Some((instance_id, maybe_spec)) = watch_specs.next() => {
+        let Some(instances) = instances.upgrade() else { break };
+        let Ok(mut instances) = instances.write() else { break };
+
+        instances.entry(instance_id).or_default().spec = maybe_spec;
+      },
+      Some((instance_id, maybe_calibration)) = watch_calibrations.next() => {
+        let Some(instances) = instances.upgrade() else { break };
+        let Ok(mut instances) = instances.write() else { break };
+
+        instances.entry(instance_id).or_default().calibration = maybe_calibration;
+      },
+      else => break
+    }
+  }
+}
+
+/// Measure the round trip latency of an instance attachment and store it, for the resolvers to pick up
+pub async fn calibrate_instance_latency(service: &Service,
+                                        resolver: &NatsDeviceInstanceResolver,
+                                        devices: &AudioDevices,
+                                        instance_id: &str,
+                                        options: &CalibrationOptions)
+                                        -> Result<InstanceLatencyCalibration> {
+  let attachment = resolver.resolve(instance_id)?;
+  let calibration = calibrate_device_insert(devices, &attachment, options).await?;
+
+  info!(instance_id,
+        round_trip = calibration.round_trip,
+        additional_latency = calibration.additional_latency,
+        "Calibrated instance latency: {instance_id}");
+
+  service.set_instance_latency_calibration(instance_id, calibration.clone()).await?;
+
+  Ok(calibration)
+}
+
+/// Serve the latency calibration requests for the instances attached to the devices of a host
+///
+/// Requests are served one at a time, so calibrations sharing a device do not hear each other. Each one runs on the
+/// devices open when it arrived.
+pub async fn serve_instance_latency_calibrations(service: Service,
+                                                 host: String,
+                                                 resolver: NatsDeviceInstanceResolver,
+                                                 audio_devices: watch::Receiver<AudioDevices>)
+                                                 -> Result {
+  let mut requests = service.serve_calibrate_instance_latency_requests(&host);
+  let options = CalibrationOptions::default();
+
+  while let Some((_, request, reply)) = requests.next().await {
+    let devices = audio_devices.borrow().clone();
+
+    let response = match calibrate_instance_latency(&service, &resolver, &devices, &request.instance_id, &options).await {
+      | Ok(calibration) => CalibrateInstanceLatencyResponse::Success(calibration),
+      | Err(err) => {
+        warn!(instance_id = request.instance_id.as_str(),
+              ?err,
+              "Failed to calibrate instance latency: {err}");
+        CalibrateInstanceLatencyResponse::Failed { error: err.to_string() }
+      }
+    };
+
+    let _ = reply.send(response);
+  }
+
+  Ok(())
+}
@@ -1,3 +1,4 @@
+pub mod attachment;
 pub mod driver;
 pub mod service;
 
@@ -1,3 +1,4 @@
+pub mod audio_device;
 pub mod instance;
 pub mod media;
 pub mod nats;
@@ -10,5 +10,6 @@ lazy_static! {
 
 mod download;
 mod probe;
+pub mod resolver;
 pub mod service;
 mod upload;
@@ -0,0 +1,63 @@
+use std::path::PathBuf;
+
+use anyhow::{anyhow, bail};
+
+use api::media::spec::MediaId;
+use audio_engine::player::MediaResolver;
+
+use super::Result;
+
+/// Resolves media to the files the media service keeps under the media root, and creates new media next to them
+#[derive(Clone)]
+pub struct MediaRootResolver {
+  media_root: PathBuf,
+}
+
+impl MediaRootResolver {
+  pub fn new(media_root: PathBuf) -> Self {
+    Self { media_root }
+  }
+}
+
+impl MediaResolver for MediaRootResolver {
+  fn resolve(&self, media_id: &MediaId) -> Result<String> {
+    let path = media_id.to_path(self.media_root.clone());
+    if !path.is_file() {
+      bail!("Media {media_id} not found at {}", path.display());
+    }
+
+    path_to_string(path)
+  }
+
+  fn create(&self, media_id: &MediaId) -> Result<String> {
+    std::fs::create_dir_all(media_id.to_folder_path(self.media_root.clone()))?;
+
+    path_to_string(media_id.to_path(self.media_root.clone()))
+  }
+}
+
+fn path_to_string(path: PathBuf) -> Result<String> {
+  path.into_os_string()
+      .into_string()
+      .map_err(|path| anyhow!("Media path {path:?} is not valid UTF-8"))
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_create_then_resolve() {
+    let root = tempfile::tempdir().expect("media root");
+    let resolver = MediaRootResolver::new(root.path().to_owned());
+    let media_id = MediaId::new("app");
+
+    assert!(resolver.resolve(&media_id).is_err());
+
+    let path = resolver.create(&media_id).expect("create media");
+    assert_eq!(PathBuf::from(&path), media_id.to_path(root.path().to_owned()));
+
+    std::fs::write(&path, b"RIFF").expect("write media");
+    assert_eq!(resolver.resolve(&media_id).expect("resolve media"), path);
+  }
+}
@@ -23,16 +23,17 @@ use tokio_stream::StreamMap;
 use tracing::{debug, trace, warn};
 use wildmatch::WildMatch;
 
+use api::audio_device::{HostAudioDeviceSpecs, HostAudioDevices};
 use api::instance::control::{InstancePlayControl, InstancePowerControl};
 use api::instance::driver::spec::DriverServiceSpec;
 use api::instance::spec::InstanceSpec;
-use api::instance::{InstanceConnectionState, InstancePlayState, InstancePowerState};
+use api::instance::{InstanceConnectionState, InstanceLatencyCalibration, InstancePlayState, InstancePowerState};
 use api::media::spec::{MediaDownloadSpec, MediaId, MediaUploadSpec};
 use api::media::state::{MediaDownloadState, MediaUploadState};
 use api::task::spec::TaskSpec;
 use api::task::DesiredTaskPlayState;
 use api::user::UserSpec;
-use api::{instance, media, task, user, BucketKey, BucketName, Events, Request};
+use api::{audio_device, instance, media, task, user, BucketKey, BucketName, Events, Request};
 
 pub type WatchStream<K, T> = Pin<Box<dyn Stream<Item = (K, Option<T>)> + Send>>;
 
@@ -106,6 +107,7 @@ pub struct Nats {
   pub instance_play_state:       Bucket<String, InstancePlayState>,
   pub instance_connection_state: Bucket<String, InstanceConnectionState>,
   pub instance_spec:             Bucket<String, InstanceSpec>,
+  pub instance_latency:          Bucket<String, InstanceLatencyCalibration>,
   pub instance_power_ctrl:       Bucket<String, InstancePowerControl>,
   pub instance_play_ctrl:        Bucket<String, InstancePlayControl>,
   pub media_download_spec:       Bucket<MediaId, MediaDownloadSpec>,
@@ -116,6 +118,8 @@ pub struct Nats {
   pub task_state:                Bucket<String, ()>,
   pub task_ctrl:                 Bucket<String, DesiredTaskPlayState>,
   pub user_spec:                 Bucket<String, UserSpec>,
+  pub host_audio_devices:        Bucket<String, HostAudioDevices>,
+  pub host_audio_device_spec:    Bucket<String, HostAudioDeviceSpecs>,
 }
 
 impl Nats {
@@ -134,6 +138,7 @@ impl Nats {
               instance_power_state:      Bucket::new(js, &instance::buckets::INSTANCE_POWER_STATE, forever, recreate).await?,
               instance_play_state:       Bucket::new(js, &instance::buckets::INSTANCE_PLAY_STATE, forever, recreate).await?,
               instance_spec:             Bucket::new(js, &instance::buckets::INSTANCE_SPEC, forever, recreate).await?,
+              instance_latency:          Bucket::new(js, &instance::buckets::INSTANCE_LATENCY_CALIBRATION, forever, recreate).await?,
               instance_power_ctrl:       Bucket::new(js, &instance::buckets::INSTANCE_POWER_CONTROL, forever, recreate).await?,
               instance_play_ctrl:        Bucket::new(js, &instance::buckets::INSTANCE_PLAY_CONTROL, forever, recreate).await?,
               media_download_spec:       Bucket::new(js, &media::buckets::DOWNLOAD_SPEC, three_days, recreate).await?,
@@ -143,7 +148,9 @@ impl Nats {
               task_spec:                 Bucket::new(js, &task::buckets::TASK_SPEC, forever, recreate).await?,
               task_ctrl:                 Bucket::new(js, &task::buckets::TASK_CONTROL, forever, recreate).await?,
               task_state:                Bucket::new(js, &task::buckets::TASK_STATE, forever, recreate).await?,
-              user_spec:                 Bucket::new(js, &user::buckets::USER_SPEC, forever, recreate).await?, })
+              user_spec:                 Bucket::new(js, &user::buckets::USER_SPEC, forever, recreate).await?,
+              host_audio_devices:        Bucket::new(js, &audio_device::buckets::HOST_AUDIO_DEVICES, one_minute, recreate).await?,
+              host_audio_device_spec:    Bucket::new(js, &audio_device::buckets::HOST_AUDIO_DEVICE_SPEC, forever, recreate).await?, })
   }
 
   pub fn subscribe_to_events<Evt>(&self, events: Events<Evt>) -> EventStream<Evt>
@@ -26,6 +26,9 @@ pub fn rest_api(router: Router<Service>, service: Service) -> Router<Service> {
   let auth_layer = || middleware::from_fn_with_state(service.clone(), auth);
 
   router.route("/api/v1/instances/:filter/specs", get(list_instances).route_layer(auth_layer()))
+        .route("/api/v1/calibrations/:id", get(get_instance_latency).route_layer(auth_layer()))
+        .route("/api/v1/calibrations/:id",
+               post(calibrate_instance_latency).route_layer(auth_layer()))
         .route("/api/v1/users/login", post(login_user_handler))
         .route("/api/v1/users/whoami", get(whoami_handler).route_layer(auth_layer()))
         .route("/api/v1/users", get(users_summary_handler).route_layer(auth_layer()))
@@ -55,6 +58,20 @@ async fn list_instances(State(service): State<Service>, Path(filter): Path<Strin
          .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
 }
 
+async fn get_instance_latency(State(service): State<Service>, Path(id): Path<String>) -> impl IntoResponse {
+  service.get_instance_latency_calibration(&id)
+         .await
+         .map(Json)
+         .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+}
+
+async fn calibrate_instance_latency(State(service): State<Service>, Path(id): Path<String>) -> impl IntoResponse {
+  service.calibrate_instance_latency(&id)
+         .await
+         .map(Json)
+         .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+}
+
 async fn web_socket(State(service): State<Service>,
                     ws: WebSocketUpgrade,
                     Extension(auth): Extension<Auth>,
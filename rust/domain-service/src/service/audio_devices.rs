@@ -0,0 +1,47 @@
+use std::collections::HashMap;
+
+use api::audio_device::{
+  host_audio_device_events, host_audio_device_specs_key, host_audio_devices_key, AudioDeviceEvent, HostAudioDeviceSpecs, HostAudioDevices,
+};
+
+use crate::nats::{EventStream, WatchStream};
+
+use super::{Result, Service};
+
+impl Service {
+  pub async fn list_host_audio_devices(&self, filter: String) -> Result<HashMap<String, HostAudioDevices>> {
+    Ok(self.nats.host_audio_devices.scan(filter.as_str()).await?)
+  }
+
+  pub fn watch_all_host_audio_devices(&self) -> WatchStream<String, HostAudioDevices> {
+    self.nats.host_audio_devices.watch_all()
+  }
+
+  pub async fn set_host_audio_devices(&self, host: &str, devices: HostAudioDevices) -> Result {
+    self.nats.host_audio_devices.put(host_audio_devices_key(&host), devices).await?;
+
+    Ok(())
+  }
+
+  pub fn subscribe_to_audio_device_events(&self, host: &str) -> EventStream<AudioDeviceEvent> {
+    self.nats.subscribe_to_events(host_audio_device_events(host))
+  }
+
+  pub async fn publish_audio_device_event(&self, host: &str, event: AudioDeviceEvent) -> Result {
+    self.nats.publish_event(host_audio_device_events(host), event).await
+  }
+
+  pub fn watch_host_audio_device_specs(&self, host: &str) -> WatchStream<String, HostAudioDeviceSpecs> {
+    self.nats.host_audio_device_spec.watch(host_audio_device_specs_key(&host))
+  }
+
+  pub async fn get_host_audio_device_specs(&self, host: &str) -> Result<Option<HostAudioDeviceSpecs>> {
+    Ok(self.nats.host_audio_device_spec.get(host_audio_device_specs_key(&host)).await?)
+  }
+
+  pub async fn set_host_audio_device_specs(&self, host: &str, specs: HostAudioDeviceSpecs) -> Result {
+    self.nats.host_audio_device_spec.put(host_audio_device_specs_key(&host), specs).await?;
+
+    Ok(())
+  }
+}
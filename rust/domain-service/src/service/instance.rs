@@ -1,11 +1,16 @@
 use std::collections::HashMap;
 
+use anyhow::anyhow;
+
 use api::instance::control::{instance_play_control_key, instance_power_control_key, InstancePlayControl, InstancePowerControl};
 use api::instance::driver::events::{instance_driver_events, InstanceDriverEvent};
 use api::instance::driver::requests::{set_instance_parameters_request, SetInstanceParameter, SetInstanceParameterResponse};
+use api::instance::request::{calibrate_instance_latency_request, CalibrateInstanceLatencyRequest, CalibrateInstanceLatencyResponse};
 use api::instance::spec::{instance_spec_key, InstanceSpec};
-use api::instance::state::{instance_connection_state_key, instance_play_state_key, instance_power_state_key};
-use api::instance::{InstanceConnectionState, InstancePlayState, InstancePowerState};
+use api::instance::state::{
+  instance_connection_state_key, instance_latency_calibration_key, instance_play_state_key, instance_power_state_key,
+};
+use api::instance::{InstanceConnectionState, InstanceLatencyCalibration, InstancePlayState, InstancePowerState};
 
 use crate::nats::{EventStream, RequestStream, WatchStream};
 
@@ -121,6 +126,46 @@ impl Service {
     Ok(())
   }
 
+  pub fn watch_all_instance_latency_calibrations(&self) -> WatchStream<String, InstanceLatencyCalibration> {
+    self.nats.instance_latency.watch_all()
+  }
+
+  pub async fn get_instance_latency_calibration(&self, instance_id: &str) -> Result<Option<InstanceLatencyCalibration>> {
+    Ok(self.nats
+           .instance_latency
+           .get(instance_latency_calibration_key(&instance_id))
+           .await?)
+  }
+
+  pub async fn set_instance_latency_calibration(&self, instance_id: &str, calibration: InstanceLatencyCalibration) -> Result {
+    self.nats
+        .instance_latency
+        .put(instance_latency_calibration_key(&instance_id), calibration)
+        .await?;
+
+    Ok(())
+  }
+
+  /// Have the host the instance is attached to measure and store its latency calibration
+  pub async fn calibrate_instance_latency(&self, instance_id: &str) -> Result<CalibrateInstanceLatencyResponse> {
+    let spec = self.nats
+                   .instance_spec
+                   .get(instance_spec_key(&instance_id))
+                   .await?
+                   .ok_or_else(|| anyhow!("Instance {instance_id} not found"))?;
+
+    self.nats
+        .request(calibrate_instance_latency_request(&spec.host),
+                 CalibrateInstanceLatencyRequest { instance_id: instance_id.to_owned(), })
+        .await
+  }
+
+  pub fn serve_calibrate_instance_latency_requests(&self,
+                                                   host: &str)
+                                                   -> RequestStream<CalibrateInstanceLatencyRequest, CalibrateInstanceLatencyResponse> {
+    self.nats.serve_requests(calibrate_instance_latency_request(host))
+  }
+
   pub async fn set_instance_parameters(&self,
                                        instance_id: &str,
                                        request: Vec<SetInstanceParameter>)
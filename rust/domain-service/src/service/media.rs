@@ -1,5 +1,5 @@
 use api::media::buckets::{media_download_spec_key, media_upload_spec_key, media_upload_state_key};
-use api::media::spec::{MediaDownloadSpec, MediaId, MediaUploadSpec};
+use api::media::spec::{MediaDownloadSpec, MediaId, MediaSpec, MediaUploadSpec};
 use api::media::state::{media_download_state_key, MediaDownloadState, MediaUploadState};
 use api::BucketKey;
 
@@ -31,6 +31,17 @@ impl Service {
     Ok(())
   }
 
+  /// Register media written locally, such as a recording, as if it had been downloaded
+  pub async fn register_recorded_media(&self, media: MediaSpec) -> Result {
+    let media_id = media.id.clone();
+
+    self.set_media_download_state(&media_id,
+                                  MediaDownloadState { progress: 100.0,
+                                                       done: Some(media),
+                                                       ..Default::default() })
+        .await
+  }
+
   pub fn watch_all_media_upload_specs(&self) -> WatchStream<MediaId, MediaUploadSpec> {
     self.nats.media_upload_spec.watch(BucketKey::all())
   }
@@ -2,6 +2,7 @@ use std::sync::Arc;
 
 use crate::nats::Nats;
 
+pub mod audio_devices;
 pub mod instance;
 pub mod media;
 pub mod users;
@@ -3,7 +3,10 @@ use std::collections::{HashMap, HashSet};
 use anyhow::bail;
 
 use api::auth::Auth;
+use api::task::graph::validate::GraphValidator;
+use api::task::player::GraphPlayerEvent;
 use api::task::spec::TaskSpec;
+use api::task::subjects::task_player_events;
 use api::task::{
   CreateTaskRequest, CreateTaskResponse, DeleteTaskResponse, DesiredTaskPlayState, InstanceAllocationRequest, ModifyTaskGraphRequest,
   ModifyTaskGraphResponse, SetTaskControlRequest, SetTaskControlResponse, SetTaskGraphRequest, SetTaskGraphResponse,
@@ -47,6 +50,11 @@ impl Service {
 
   pub async fn set_task_graph(&self, auth: Auth, id: String, new_graph_spec: SetTaskGraphRequest) -> Result<SetTaskGraphResponse> {
     let Some(mut spec) = self.nats.task_spec.get(BucketKey::new(&id)).await? else { return Ok(SetTaskGraphResponse::NotFound) };
+
+    if let Err(errors) = GraphValidator::default().validate(&new_graph_spec) {
+      return Ok(SetTaskGraphResponse::InvalidGraph { errors });
+    }
+
     spec.graph_spec = new_graph_spec;
 
     self.nats.task_spec.put(BucketKey::new(&id), spec).await?;
@@ -83,9 +91,12 @@ impl Service {
   }
 
   pub async fn modify_task_graph(&self, auth: Auth, id: String, modify: ModifyTaskGraphRequest) -> Result<ModifyTaskGraphResponse> {
-    let Some(spec) = self.nats.task_spec.get(BucketKey::new(&id)).await? else { return Ok(ModifyTaskGraphResponse::NotFound) };
+    let Some(mut spec) = self.nats.task_spec.get(BucketKey::new(&id)).await? else { return Ok(ModifyTaskGraphResponse::NotFound) };
 
-    // TODO: apply modification
+    spec.graph_spec = match GraphValidator::default().validate_modifications(&spec.graph_spec, &modify) {
+      | Ok(graph_spec) => graph_spec,
+      | Err(errors) => return Ok(ModifyTaskGraphResponse::InvalidGraph { errors }),
+    };
 
     self.nats.task_spec.put(BucketKey::new(&id), spec).await?;
 
@@ -174,4 +185,8 @@ impl Service {
 
     Ok(allocated)
   }
+
+  pub async fn publish_task_player_event(&self, task_id: &str, event: GraphPlayerEvent) -> Result {
+    self.nats.publish_event(task_player_events(task_id), event).await
+  }
 }
@@ -1,4 +1,20 @@
+use tokio::sync::watch;
+
+use audio_engine::audio_device::AudioDevices;
+
+use crate::instance::attachment::NatsDeviceInstanceResolver;
+use crate::media::resolver::MediaRootResolver;
+
 pub mod run;
 pub mod server;
 
 pub type Result<T = ()> = anyhow::Result<T>;
+
+/// What the players of the tasks running on this host are created with
+#[derive(Clone)]
+pub struct PlayerResources {
+  /// The devices opened by the host, see [crate::audio_device::host::AudioDeviceHost]
+  pub audio_devices: watch::Receiver<AudioDevices>,
+  pub media:         MediaRootResolver,
+  pub instances:     NatsDeviceInstanceResolver,
+}
@@ -4,10 +4,11 @@ use std::time::Duration;
 use chrono::Utc;
 use futures::channel::mpsc;
 use futures::StreamExt;
-use tokio::select;
+use tokio::sync::mpsc::Receiver;
 use tokio::time::Interval;
+use tokio::{select, spawn};
 use tokio_stream::StreamMap;
-use tracing::{debug, instrument};
+use tracing::{debug, instrument, warn};
 
 use api::instance::control::{InstancePlayControl, InstancePowerControl};
 use api::instance::spec::{instance_spec_key, InstanceSpec};
@@ -16,12 +17,16 @@ use api::instance::{DesiredInstancePlayState, DesiredInstancePowerState, Instanc
 use api::media::spec::MediaId;
 use api::media::state::{media_download_state_key, MediaDownloadState};
 use api::task::buckets::{task_control_key, task_spec_key};
+use api::task::player::{GraphPlaybackError, GraphPlayerEvent};
 use api::task::spec::TaskSpec;
 use api::task::DesiredTaskPlayState;
 use api::BucketKey;
+use audio_engine::audio_device::AudioDeviceInfo;
+use audio_engine::player::GraphPlayerHandle;
 
-use crate::nats::{Nats, WatchStream, WatchStreamMap};
-use crate::tasks::Result;
+use crate::nats::{WatchStream, WatchStreamMap};
+use crate::service::Service;
+use crate::tasks::{PlayerResources, Result};
 
 pub struct RunDomainTask {
   id: String,
@@ -39,16 +44,19 @@ pub struct RunDomainTask {
   instances: HashMap<String, TaskInstance>,
   media: HashMap<MediaId, TaskMedia>,
   desired_play_state: DesiredTaskPlayState,
-  player: Option<()>,
-  nats: Nats,
+  player: Option<GraphPlayerHandle>,
+  /// The clock device the player was created with
+  player_clock: Option<AudioDeviceInfo>,
+  players: PlayerResources,
+  service: Service,
 }
 
 enum ExternalTask {}
 
 impl RunDomainTask {
-  pub fn new(id: String, spec: TaskSpec, nats: Nats) -> RunDomainTask {
-    let watch_spec = nats.task_spec.watch(task_spec_key(&id));
-    let watch_control = nats.task_ctrl.watch(task_control_key(&id));
+  pub fn new(id: String, spec: TaskSpec, service: Service, players: PlayerResources) -> RunDomainTask {
+    let watch_spec = service.nats.task_spec.watch(task_spec_key(&id));
+    let watch_control = service.nats.task_ctrl.watch(task_control_key(&id));
     let watch_instance_specs = StreamMap::new();
     let watch_instance_power_states = StreamMap::new();
     let watch_instance_play_states = StreamMap::new();
@@ -59,6 +67,7 @@ impl RunDomainTask {
     let media = HashMap::new();
 
     let player = None;
+    let player_clock = None;
     let desired_play_state = DesiredTaskPlayState::Idle;
 
     let (tx_external, rx_external) = mpsc::channel(0xff);
@@ -68,8 +77,10 @@ impl RunDomainTask {
                         spec,
                         timer,
                         player,
+                        player_clock,
+                        players,
                         media,
-                        nats,
+                        service,
                         instances,
                         watch_spec,
                         watch_control,
@@ -118,6 +129,9 @@ impl RunDomainTask {
         Some((_, maybe_new_control)) = self.watch_control.next() => {
           self.set_desired_play_state(maybe_new_control).await;
         },
+        Ok(()) = self.players.audio_devices.changed() => {
+          self.audio_devices_changed().await;
+        },
         Some(external_task) = self.rx_external.next() => {
           self.external_task_completed(external_task);
         },
@@ -129,7 +143,7 @@ impl RunDomainTask {
 
     debug!("Task finished, cleaning up");
 
-    self.cleanup();
+    self.cleanup().await;
 
     Ok(())
   }
@@ -152,6 +166,14 @@ impl RunDomainTask {
 
   async fn set_desired_play_state(&mut self, new_control: Option<DesiredTaskPlayState>) {
     self.desired_play_state = new_control.unwrap_or_default();
+
+    // a running player moves on to the new play request, or is stopped below
+    if let (Some(player), DesiredTaskPlayState::Play(request)) = (self.player.as_mut(), &self.desired_play_state) {
+      if let Err(err) = player.set_play(request.clone()).await {
+        warn!(?err, "Failed to change the play request: {err}");
+      }
+    }
+
     self.update_instance_power_play_state().await;
   }
 
@@ -169,17 +191,18 @@ impl RunDomainTask {
     }
 
     for instance_id in self.spec.instances.values() {
-      self.watch_instance_specs
-          .insert(instance_id.clone(), self.nats.instance_spec.watch(instance_spec_key(&instance_id)));
+      self.watch_instance_specs.insert(instance_id.clone(),
+                                       self.service.nats.instance_spec.watch(instance_spec_key(&instance_id)));
 
       self.watch_instance_power_states.insert(instance_id.clone(),
-                                              self.nats.instance_power_state.watch(instance_power_state_key(&instance_id)));
+                                              self.service.nats.instance_power_state.watch(instance_power_state_key(&instance_id)));
 
       self.watch_instance_play_states.insert(instance_id.clone(),
-                                             self.nats.instance_play_state.watch(instance_play_state_key(&instance_id)));
+                                             self.service.nats.instance_play_state.watch(instance_play_state_key(&instance_id)));
 
       self.watch_instance_connection_state.insert(instance_id.clone(),
-                                                  self.nats
+                                                  self.service
+                                                      .nats
                                                       .instance_connection_state
                                                       .watch(instance_connection_state_key(&instance_id)));
     }
@@ -203,7 +226,10 @@ impl RunDomainTask {
       }
 
       self.watch_download_states.insert(source.media_id.clone(),
-                                        self.nats.media_download_state.watch(media_download_state_key(&source.media_id)));
+                                        self.service
+                                            .nats
+                                            .media_download_state
+                                            .watch(media_download_state_key(&source.media_id)));
     }
   }
 
@@ -256,7 +282,8 @@ impl RunDomainTask {
         let control = InstancePowerControl { desired: desired.clone(),
                                              until:   self.spec.to, };
 
-        let _ = self.nats
+        let _ = self.service
+                    .nats
                     .instance_power_ctrl
                     .put(BucketKey::new(instance_id), control.clone())
                     .await;
@@ -281,7 +308,11 @@ impl RunDomainTask {
         let control = InstancePlayControl { desired: desired.clone(),
                                             until:   self.spec.to, };
 
-        let _ = self.nats.instance_play_ctrl.put(BucketKey::new(instance_id), control.clone()).await;
+        let _ = self.service
+                    .nats
+                    .instance_play_ctrl
+                    .put(BucketKey::new(instance_id), control.clone())
+                    .await;
 
         instance.play_control = Some(control);
       }
@@ -304,18 +335,83 @@ impl RunDomainTask {
 
     if should_play && is_ready {
       if !is_playing {
-        self.start_player();
+        self.start_player().await;
       }
     } else {
       if is_playing {
-        self.stop_player();
+        self.stop_player().await;
       }
     }
   }
 
-  fn start_player(&mut self) {}
+  async fn start_player(&mut self) {
+    let DesiredTaskPlayState::Play(request) = &self.desired_play_state else { return };
+
+    let devices = self.players.audio_devices.borrow_and_update().clone();
+    self.player_clock = devices.clock_device().and_then(|device_id| devices.get_info(device_id).ok());
+
+    let player = GraphPlayerHandle::new(devices,
+                                        Box::new(self.players.media.clone()),
+                                        Box::new(self.players.instances.clone()),
+                                        self.spec.graph_spec.clone());
+
+    let mut player = match player {
+      | Ok(player) => player,
+      | Err(err) => {
+        warn!(?err, "Failed to create player: {err}");
+        return;
+      }
+    };
+
+    // recordings finish after the player stops, so their events outlive the handle
+    if let Some(rx_events) = player.take_events() {
+      spawn(forward_player_events(self.service.clone(), self.id.clone(), rx_events));
+    }
+
+    // the encoded output of the streaming sinks waits for NATS rather than being dropped
+    if let Some(rx_captured) = player.take_captured() {
+      spawn(forward_player_events(self.service.clone(), self.id.clone(), rx_captured));
+    }
+
+    if let Err(err) = player.set_play(request.clone()).await {
+      warn!(?err, "Failed to start playing: {err}");
+      return;
+    }
+
+    self.player = Some(player);
+  }
+
+  async fn stop_player(&mut self) {
+    let Some(mut player) = self.player.take() else { return };
+
+    // dropping the handle afterwards ends the player
+    if let Err(err) = player.stop().await {
+      warn!(?err, "Failed to stop playing: {err}");
+    }
+  }
+
+  async fn audio_devices_changed(&mut self) {
+    let devices = self.players.audio_devices.borrow_and_update().clone();
+    if self.player.is_none() {
+      return;
+    }
 
-  fn stop_player(&mut self) {}
+    // the player runs at the sample rate and buffer size of the clock device it was created with, if there was one
+    let format = |info: &AudioDeviceInfo| (info.sample_rate, info.buffer_size);
+    let clock = devices.clock_device().and_then(|device_id| devices.get_info(device_id).ok());
+
+    if matches!(&clock, Some(clock) if self.player_clock.as_ref().map(format) != Some(format(clock))) {
+      debug!(?clock, "Clock device format changed, restarting the player");
+      self.stop_player().await;
+      self.start_player().await;
+      return;
+    }
+
+    let Some(player) = self.player.as_ref() else { return };
+    if let Err(err) = player.set_audio_devices(devices).await {
+      warn!(?err, "Failed to update the player audio devices: {err}");
+    }
+  }
 
   fn get_missing_or_unready_instances(&self) -> (HashSet<String>, HashSet<String>) {
     let mut missing_instances = HashSet::new();
@@ -395,10 +491,10 @@ impl RunDomainTask {
     (missing_media, downloading_media)
   }
 
-  fn cleanup(&mut self) {
+  async fn cleanup(&mut self) {
     let is_playing = self.player.is_some();
     if is_playing {
-      self.stop_player();
+      self.stop_player().await;
     }
   }
 
@@ -421,3 +517,26 @@ struct TaskInstance {
 struct TaskMedia {
   state: Option<MediaDownloadState>,
 }
+
+/// Publish the events of the player, registering the media of the recordings it finished on the way, until the player
+/// and its recordings are done
+async fn forward_player_events(service: Service, task_id: String, mut rx_events: Receiver<GraphPlayerEvent>) {
+  while let Some(event) = rx_events.recv().await {
+    match &event {
+      | GraphPlayerEvent::GraphRecordingFinished { sink_id, media, .. } => {
+        let media_id = media.id.clone();
+        if let Err(err) = service.register_recorded_media(media.clone()).await {
+          warn!(sink_id, %media_id, ?err, "Failed to register recorded media: {err}");
+        }
+      }
+      | GraphPlayerEvent::Error(GraphPlaybackError::RecordingFailed { sink, error }) => {
+        warn!(sink, %error, "Recording failed");
+      }
+      | _ => {}
+    }
+
+    if let Err(err) = service.publish_task_player_event(&task_id, event).await {
+      warn!(%task_id, ?err, "Failed to publish player event: {err}");
+    }
+  }
+}
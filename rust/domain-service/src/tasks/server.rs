@@ -11,9 +11,10 @@ use api::task::spec::TaskSpec;
 use api::task::subjects::set_task_graph_req;
 use api::task::{SetTaskGraphRequest, SetTaskGraphResponse};
 
-use crate::nats::{Nats, RequestStream, WatchStream};
+use crate::nats::{RequestStream, WatchStream};
+use crate::service::Service;
 use crate::tasks::run::RunDomainTask;
-use crate::tasks::Result;
+use crate::tasks::{PlayerResources, Result};
 
 pub struct TasksServer {
   host_id:        String,
@@ -21,15 +22,16 @@ pub struct TasksServer {
   watch_specs:    WatchStream<String, TaskSpec>,
   tasks:          HashMap<String, Task>,
   timer:          Interval,
-  nats:           Nats,
+  players:        PlayerResources,
+  service:        Service,
 }
 
 impl TasksServer {
-  pub fn new(nats: Nats, host_id: String) -> Self {
-    let watch_specs = nats.task_spec.watch_all();
+  pub fn new(service: Service, host_id: String, players: PlayerResources) -> Self {
+    let watch_specs = service.nats.task_spec.watch_all();
     let timer = tokio::time::interval(Duration::from_secs(1));
 
-    let set_task_graph = nats.serve_requests(set_task_graph_req());
+    let set_task_graph = service.nats.serve_requests(set_task_graph_req());
 
     let tasks = HashMap::new();
 
@@ -38,7 +40,8 @@ impl TasksServer {
            watch_specs,
            tasks,
            timer,
-           nats }
+           players,
+           service }
   }
 
   pub async fn run(mut self) -> Result {
@@ -80,7 +83,7 @@ impl TasksServer {
       }
 
       if task.handle.as_ref().map(|task| task.is_finished()).unwrap_or(true) {
-        let mut domain_task = RunDomainTask::new(task_id.clone(), spec.clone(), self.nats.clone());
+        let mut domain_task = RunDomainTask::new(task_id.clone(), spec.clone(), self.service.clone(), self.players.clone());
         task.handle = Some(spawn(async move { domain_task.run().await }));
       }
     }
@@ -4,6 +4,10 @@ use std::os::raw::{c_double, c_int};
 use std::ptr::null_mut;
 use std::slice;
 
+pub use multichannel::{MultiChannelResampler, PullResampler, Sample};
+
+mod multichannel;
+
 const R8B_RESAMPLER_16_BIT: c_int = 0;
 const R8B_RESAMPLER_16_BIT_IR: c_int = 1;
 const R8B_RESAMPLER_24_BIT: c_int = 2;
@@ -41,6 +45,7 @@ unsafe impl Send for Resampler {}
 /// - [`PrecisionProfile::Bits16ForImpulseResponses`]
 /// - [`PrecisionProfile::Bits24`]
 /// - [`PrecisionProfile::Bits32`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PrecisionProfile {
   /// 16-bit
   Bits16,
@@ -133,6 +138,20 @@ impl Resampler {
   ///
   /// See [`Self::new()`]
   pub fn process(&mut self, input: &[f64], output: &mut [f64]) -> usize {
+    let resampled = self.process_borrowed(input);
+    let out_len = resampled.len();
+
+    // make sure we have room
+    assert!(out_len <= output.len(), "output buffer is too small");
+
+    // copy samples
+    output[0..out_len].copy_from_slice(resampled);
+
+    out_len
+  }
+
+  /// Process the samples and return the output, which lives in a buffer owned by r8brain until the next call
+  fn process_borrowed(&mut self, input: &[f64]) -> &[f64] {
     // make sure the user obeys [`Self::max_input_len`]
     assert!(input.len() <= self.max_input_len, "input buffer is too large");
 
@@ -143,14 +162,12 @@ impl Resampler {
       // process the samples and wait for out_len to be populated
       let out_len = r8b_process(self.ptr, input.as_ptr(), input.len() as c_int, &mut ptr) as usize;
 
-      // make sure we have room
-      assert!(out_len <= output.len(), "output buffer is too small");
-
-      // copy samples
-      let slice = slice::from_raw_parts(ptr, out_len);
-      output[0..out_len].copy_from_slice(slice);
+      // r8b does not have to set the pointer when there is no output
+      if out_len == 0 {
+        return &[];
+      }
 
-      out_len
+      slice::from_raw_parts(ptr, out_len)
     }
   }
 
@@ -0,0 +1,345 @@
+use std::collections::VecDeque;
+
+use crate::{PrecisionProfile, Resampler};
+
+/// Sample types the multichannel resamplers accept and produce. Resampling itself always runs in `f64`.
+pub trait Sample: Copy {
+  fn to_f64(self) -> f64;
+
+  fn from_f64(value: f64) -> Self;
+}
+
+impl Sample for f32 {
+  fn to_f64(self) -> f64 {
+    self as f64
+  }
+
+  fn from_f64(value: f64) -> Self {
+    value as f32
+  }
+}
+
+impl Sample for f64 {
+  fn to_f64(self) -> f64 {
+    self
+  }
+
+  fn from_f64(value: f64) -> Self {
+    value
+  }
+}
+
+/// Resamples several channels in lockstep, taking and producing planar or interleaved `f32` or `f64` frames
+///
+/// Resampled frames are queued until pulled, so input and output can be any length. All channels always have the same
+/// number of frames queued.
+pub struct MultiChannelResampler {
+  channels:      Vec<Resampler>,
+  queues:        Vec<VecDeque<f64>>,
+  /// conversion buffer for one chunk of one channel
+  scratch:       Vec<f64>,
+  src_rate:      f64,
+  dst_rate:      f64,
+  /// frames pushed since the last flush or clear
+  num_pushed:    u64,
+  /// frames produced by the resamplers since the last flush or clear
+  num_produced:  u64,
+  input_latency: usize,
+}
+
+impl MultiChannelResampler {
+  /// Create a new multichannel resampler
+  ///
+  /// # Arguments
+  ///
+  /// * `num_channels`: number of channels, at least one
+  /// * `src_rate`: source sample rate (48000.0) or ratio (1.0)
+  /// * `dst_rate`: destination sample rate (96000.0) or ratio (2.0)
+  /// * `max_input_len`: largest chunk submitted to the r8brain resamplers, longer input is split
+  /// * `req_trans_band`: transition band in percent, usually 2.0
+  /// * `profile`: precision profile, see [PrecisionProfile] for options
+  ///
+  /// returns: MultiChannelResampler
+  ///
+  /// # Examples
+  ///
+  /// ```
+  /// use r8brain_rs::{MultiChannelResampler, PrecisionProfile};
+  ///
+  /// let mut resampler = MultiChannelResampler::new(2, 48000.0, 96000.0, 4096, 2.0, PrecisionProfile::Bits24);
+  ///
+  /// let left = [0.1f32; 64];
+  /// let right = [0.2f32; 64];
+  /// resampler.push_planar([&left[..], &right[..]]);
+  ///
+  /// let mut interleaved = [0.0f32; 256];
+  /// let num_frames = resampler.pull_interleaved(&mut interleaved);
+  /// let resampled = &interleaved[..num_frames * 2];
+  /// ```
+  pub fn new(num_channels: usize,
+             src_rate: f64,
+             dst_rate: f64,
+             max_input_len: usize,
+             req_trans_band: f64,
+             profile: PrecisionProfile)
+             -> Self {
+    assert!(num_channels > 0, "at least one channel is required");
+
+    let channels = (0..num_channels).map(|_| Resampler::new(src_rate, dst_rate, max_input_len, req_trans_band, profile))
+                                    .collect::<Vec<_>>();
+
+    let input_latency = channels[0].input_len_for_output_pos(0);
+
+    Self { channels,
+           queues: vec![VecDeque::new(); num_channels],
+           scratch: vec![0.0; max_input_len],
+           src_rate,
+           dst_rate,
+           num_pushed: 0,
+           num_produced: 0,
+           input_latency }
+  }
+
+  /// Create a new multichannel resampler with the same parameters as [Resampler::default]
+  pub fn with_default_profile(num_channels: usize, src_rate: f64, dst_rate: f64) -> Self {
+    Self::new(num_channels, src_rate, dst_rate, 8192, 2.0, PrecisionProfile::Bits32)
+  }
+
+  pub fn num_channels(&self) -> usize {
+    self.channels.len()
+  }
+
+  /// Number of input frames that have to be pushed before the first output frame is produced
+  ///
+  /// r8brain compensates for its filter delay, so the first output frame lines up with the first input frame. The
+  /// latency shows up as output that is withheld until this many input frames arrived.
+  pub fn input_latency(&self) -> usize {
+    self.input_latency
+  }
+
+  /// [Self::input_latency] at the destination sample rate, rounded up
+  pub fn output_latency(&self) -> usize {
+    self.frames_at_dst_rate(self.input_latency as u64) as usize
+  }
+
+  /// Number of frames queued for pulling
+  pub fn available(&self) -> usize {
+    self.queues[0].len()
+  }
+
+  /// Resample one slice per channel, all of the same length, and return the number of frames available for pulling
+  pub fn push_planar<'a, S: Sample + 'a>(&mut self, input: impl IntoIterator<Item = &'a [S]>) -> usize {
+    let mut num_frames = 0;
+    let mut num_produced = 0;
+
+    for ((resampler, queue), plane) in self.channels.iter_mut().zip(self.queues.iter_mut()).zip(input) {
+      num_frames = plane.len();
+      num_produced = 0;
+
+      for chunk in plane.chunks(self.scratch.len()) {
+        let scratch = &mut self.scratch[..chunk.len()];
+        for (converted, sample) in scratch.iter_mut().zip(chunk) {
+          *converted = sample.to_f64();
+        }
+
+        let resampled = resampler.process_borrowed(scratch);
+        num_produced += resampled.len();
+        queue.extend(resampled);
+      }
+    }
+
+    self.num_pushed += num_frames as u64;
+    self.num_produced += num_produced as u64;
+
+    self.available()
+  }
+
+  /// Resample interleaved frames and return the number of frames available for pulling
+  pub fn push_interleaved<S: Sample>(&mut self, input: &[S]) -> usize {
+    let num_channels = self.num_channels();
+    assert_eq!(input.len() % num_channels, 0, "input is not a whole number of frames");
+
+    let max_chunk = self.scratch.len();
+    let num_frames = input.len() / num_channels;
+    let mut num_produced = 0;
+
+    for (channel, (resampler, queue)) in self.channels.iter_mut().zip(self.queues.iter_mut()).enumerate() {
+      num_produced = 0;
+
+      for start in (0..num_frames).step_by(max_chunk) {
+        let scratch = &mut self.scratch[..max_chunk.min(num_frames - start)];
+        for (index, converted) in scratch.iter_mut().enumerate() {
+          *converted = input[(start + index) * num_channels + channel].to_f64();
+        }
+
+        let resampled = resampler.process_borrowed(scratch);
+        num_produced += resampled.len();
+        queue.extend(resampled);
+      }
+    }
+
+    self.num_pushed += num_frames as u64;
+    self.num_produced += num_produced as u64;
+
+    self.available()
+  }
+
+  /// Pull as many frames as available into one slice per channel, all of the same length, and return the number pulled
+  pub fn pull_planar<'a, S: Sample + 'a>(&mut self, output: impl IntoIterator<Item = &'a mut [S]>) -> usize {
+    let mut num_pulled = 0;
+
+    for (queue, plane) in self.queues.iter_mut().zip(output) {
+      num_pulled = plane.len().min(queue.len());
+
+      for (target, sample) in plane.iter_mut().zip(queue.drain(..num_pulled)) {
+        *target = S::from_f64(sample);
+      }
+    }
+
+    num_pulled
+  }
+
+  /// Pull as many frames as available into an interleaved slice and return the number of frames pulled
+  pub fn pull_interleaved<S: Sample>(&mut self, output: &mut [S]) -> usize {
+    let num_channels = self.num_channels();
+    let num_pulled = (output.len() / num_channels).min(self.available());
+
+    for (channel, queue) in self.queues.iter_mut().enumerate() {
+      for (index, sample) in queue.drain(..num_pulled).enumerate() {
+        output[index * num_channels + channel] = S::from_f64(sample);
+      }
+    }
+
+    num_pulled
+  }
+
+  /// Queue the tail of everything pushed so far and return the number of frames available for pulling
+  ///
+  /// Exactly as many frames are queued as the pushed input lasts at the destination rate, no tail is lost and no
+  /// trailing silence is added. The resamplers start over afterwards, as if just created.
+  pub fn flush(&mut self) -> usize {
+    let expected = self.frames_at_dst_rate(self.num_pushed);
+    let zeroes = vec![0.0; self.scratch.len()];
+
+    while self.num_produced < expected {
+      let mut num_produced = 0;
+      for (resampler, queue) in self.channels.iter_mut().zip(self.queues.iter_mut()) {
+        let resampled = resampler.process_borrowed(&zeroes);
+        num_produced = resampled.len();
+        queue.extend(resampled);
+      }
+
+      self.num_produced += num_produced as u64;
+    }
+
+    // the last chunk of silence usually produces more than the tail
+    let excess = (self.num_produced - expected) as usize;
+    for queue in &mut self.queues {
+      queue.truncate(queue.len().saturating_sub(excess));
+    }
+
+    self.reset_resamplers();
+
+    self.available()
+  }
+
+  /// Reset the resamplers and drop the queued frames, return the number of frames dropped
+  pub fn clear(&mut self) -> usize {
+    let dropped = self.available();
+
+    self.reset_resamplers();
+    for queue in &mut self.queues {
+      queue.clear();
+    }
+
+    dropped
+  }
+
+  fn reset_resamplers(&mut self) {
+    for resampler in &mut self.channels {
+      resampler.clear();
+    }
+
+    self.num_pushed = 0;
+    self.num_produced = 0;
+  }
+
+  fn frames_at_dst_rate(&self, num_frames: u64) -> u64 {
+    // the tolerance keeps exact ratios from rounding up a frame
+    ((num_frames as f64 * self.dst_rate / self.src_rate) - 1e-9).ceil().max(0.0) as u64
+  }
+}
+
+/// Pulls fixed size blocks of resampled frames, reading input only when the queued frames do not fill a block
+///
+/// Once the input runs out, the resampler is flushed and the remaining frames are returned in a last, shorter block.
+pub struct PullResampler<I: Sample> {
+  resampler:  MultiChannelResampler,
+  input:      Vec<Vec<I>>,
+  block_size: usize,
+  finished:   bool,
+}
+
+impl<I: Sample + Default> PullResampler<I> {
+  /// Create an adapter producing `block_size` frames per block, reading up to `read_size` input frames at a time
+  pub fn new(resampler: MultiChannelResampler, block_size: usize, read_size: usize) -> Self {
+    assert!(read_size > 0, "the read size must be positive");
+
+    let input = vec![vec![I::default(); read_size]; resampler.num_channels()];
+
+    Self { resampler,
+           input,
+           block_size,
+           finished: false }
+  }
+}
+
+impl<I: Sample> PullResampler<I> {
+  pub fn block_size(&self) -> usize {
+    self.block_size
+  }
+
+  /// Access the wrapped resampler
+  pub fn resampler(&self) -> &MultiChannelResampler {
+    &self.resampler
+  }
+
+  /// True once the input ran out and every resampled frame was pulled
+  pub fn is_finished(&self) -> bool {
+    self.finished && self.resampler.available() == 0
+  }
+
+  /// Write the next block to one slice per channel and return the number of frames written
+  ///
+  /// `read` fills the planes it is given with input frames and returns how many it wrote, returning zero when the input
+  /// ran out. Blocks are [Self::block_size] frames long, or as long as the output slices if they are shorter, except for
+  /// the last block after the input ran out.
+  pub fn next_block<'a, O: Sample + 'a>(&mut self,
+                                        output: impl IntoIterator<Item = &'a mut [O]>,
+                                        mut read: impl FnMut(&mut [Vec<I>]) -> usize)
+                                        -> usize {
+    while !self.finished && self.resampler.available() < self.block_size {
+      match read(&mut self.input) {
+        | 0 => {
+          self.resampler.flush();
+          self.finished = true;
+        }
+        | num_read => {
+          self.resampler.push_planar(self.input.iter().map(|plane| &plane[..num_read]));
+        }
+      }
+    }
+
+    let block_size = self.block_size;
+    self.resampler.pull_planar(output.into_iter().map(|plane| {
+                                                  let len = plane.len().min(block_size);
+                                                  &mut plane[..len]
+                                                }))
+  }
+
+  /// Drop everything queued and start reading input again, for example after a seek
+  pub fn reset(&mut self) {
+    self.resampler.clear();
+    self.finished = false;
+  }
+}
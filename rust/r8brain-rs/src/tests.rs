@@ -69,6 +69,97 @@ fn test_resampler_input_len() {
     );
 }
 
+fn sine(num_frames: usize, frequency: f64) -> Vec<f64> {
+    (0..num_frames)
+        .map(|i| (i as f64 * frequency * std::f64::consts::TAU).sin() * 0.5)
+        .collect()
+}
+
+#[test]
+fn test_multichannel_flush_length() {
+    for (src_rate, dst_rate) in [(48000.0, 44100.0), (44100.0, 48000.0), (96000.0, 48000.0)] {
+        let mut resampler = MultiChannelResampler::new(2, src_rate, dst_rate, 256, 2.0, PrecisionProfile::Bits24);
+        let left = sine(10_000, 0.01);
+        let right = sine(10_000, 0.02);
+
+        let mut output = vec![0.0f32; 2 * 20_000];
+        resampler.push_planar([&left[..], &right[..]]);
+        let mut num_frames = resampler.pull_interleaved(&mut output);
+
+        resampler.flush();
+        num_frames += resampler.pull_interleaved(&mut output[num_frames * 2..]);
+
+        // every input frame is accounted for, the tail of the sine included
+        let expected = (10_000.0 * dst_rate / src_rate).ceil() as usize;
+        assert_eq!(num_frames, expected);
+        assert!(output[(expected - 10) * 2..expected * 2].iter().any(|sample| sample.abs() > 0.01));
+        assert_eq!(resampler.available(), 0);
+    }
+}
+
+#[test]
+fn test_multichannel_latency() {
+    let mut resampler = MultiChannelResampler::new(1, 48000.0, 44100.0, 128, 2.0, PrecisionProfile::Bits24);
+    let latency = resampler.input_latency();
+
+    assert!(latency > 0);
+    assert!(resampler.output_latency() < latency);
+
+    let input = sine(latency - 1, 0.01);
+    assert_eq!(resampler.push_planar([&input[..]]), 0);
+
+    let input = sine(256, 0.01);
+    assert!(resampler.push_planar([&input[..]]) > 0);
+}
+
+#[test]
+fn test_multichannel_planar_matches_interleaved() {
+    let mut planar = MultiChannelResampler::new(2, 44100.0, 48000.0, 128, 2.0, PrecisionProfile::Bits24);
+    let mut interleaved = MultiChannelResampler::new(2, 44100.0, 48000.0, 128, 2.0, PrecisionProfile::Bits24);
+
+    let left = sine(5_000, 0.01);
+    let right = sine(5_000, 0.03);
+    let frames = left.iter().zip(right.iter()).flat_map(|(l, r)| [*l as f32, *r as f32]).collect::<Vec<_>>();
+
+    planar.push_planar([&left[..], &right[..]]);
+    interleaved.push_interleaved(&frames);
+    planar.flush();
+    interleaved.flush();
+
+    let mut planes = vec![vec![0.0f64; 6_000]; 2];
+    let mut output = vec![0.0f64; 12_000];
+    let num_frames = planar.pull_planar(planes.iter_mut().map(|plane| &mut plane[..]));
+
+    assert_eq!(interleaved.pull_interleaved(&mut output), num_frames);
+
+    for frame in 0..num_frames {
+        // the interleaved input went through f32
+        assert!((planes[0][frame] - output[frame * 2]).abs() < 1e-6);
+        assert!((planes[1][frame] - output[frame * 2 + 1]).abs() < 1e-6);
+    }
+}
+
+#[test]
+fn test_pull_resampler_blocks() {
+    let resampler = MultiChannelResampler::new(2, 44100.0, 48000.0, 512, 2.0, PrecisionProfile::Bits24);
+    let mut pull = PullResampler::<f32>::new(resampler, 480, 441);
+    let mut remaining = 44100;
+
+    let mut planes = vec![vec![0.0f64; 480]; 2];
+    let mut blocks = vec![];
+
+    while !pull.is_finished() {
+        blocks.push(pull.next_block(planes.iter_mut().map(|plane| &mut plane[..]), |input| {
+            let num_read = input[0].len().min(remaining);
+            remaining -= num_read;
+            num_read
+        }));
+    }
+
+    assert_eq!(blocks.iter().sum::<usize>(), 48000);
+    assert!(blocks.iter().all(|block| *block == 480));
+}
+
 #[cfg(bench)]
 mod benchmarks {
 
@@ -8,6 +8,59 @@ export type Array_of_SetTaskSetting = z.infer<
   ReturnType<typeof Array_of_SetTaskSetting>
 >;
 
+export const AudioDeviceDescription = memoizeOne(() =>
+  z.object({
+    bufferSizes: z.array(z.number().int()),
+    deviceType: z.string(),
+    name: z.string(),
+    numInputs: z.number().int(),
+    numOutputs: z.number().int(),
+    sampleRates: z.array(z.number().int()),
+  })
+);
+export type AudioDeviceDescription = z.infer<
+  ReturnType<typeof AudioDeviceDescription>
+>;
+
+export const AudioDeviceDriverSpec = memoizeOne(() =>
+  z.discriminatedUnion("type", [
+    z.object({
+      deviceType: z.string(),
+      name: z.string(),
+      type: z.literal("juce"),
+    }),
+    z.object({ type: z.literal("simulator") }),
+  ])
+);
+export type AudioDeviceDriverSpec = z.infer<
+  ReturnType<typeof AudioDeviceDriverSpec>
+>;
+
+export const AudioDeviceEvent = memoizeOne(() =>
+  z.discriminatedUnion("type", [
+    z.object({
+      device: z.lazy(AudioDeviceDescription),
+      type: z.literal("appeared"),
+    }),
+    z.object({
+      device: z.lazy(AudioDeviceDescription),
+      type: z.literal("disappeared"),
+    }),
+  ])
+);
+export type AudioDeviceEvent = z.infer<ReturnType<typeof AudioDeviceEvent>>;
+
+export const AudioDeviceSpec = memoizeOne(() =>
+  z.object({
+    bufferSize: z.number().int(),
+    driver: z.lazy(AudioDeviceDriverSpec),
+    numInputs: z.number().int(),
+    numOutputs: z.number().int(),
+    sampleRate: z.number().int(),
+  })
+);
+export type AudioDeviceSpec = z.infer<ReturnType<typeof AudioDeviceSpec>>;
+
 export const AudioGraphSpec = memoizeOne(() =>
   z.object({
     busses: z.record(z.lazy(BusSpec)),
@@ -30,9 +83,13 @@ export const BinaryPosition = memoizeOne(() =>
 );
 export type BinaryPosition = z.infer<ReturnType<typeof BinaryPosition>>;
 
+export const BusMode = memoizeOne(() => z.enum(["summing", "matrix"]));
+export type BusMode = z.infer<ReturnType<typeof BusMode>>;
+
 export const BusSpec = memoizeOne(() =>
   z.object({
     inputs: z.array(z.array(z.lazy(OutputId))),
+    mode: z.lazy(BusMode),
     numOutputs: z.number().int(),
   })
 );
@@ -43,6 +100,17 @@ export const Clamp = memoizeOne(() =>
 );
 export type Clamp = z.infer<ReturnType<typeof Clamp>>;
 
+export const ClockDomainStats = memoizeOne(() =>
+  z.object({
+    deviceId: z.string(),
+    driftPpm: z.number(),
+    latency: z.number().int(),
+    overruns: z.number().int(),
+    underruns: z.number().int(),
+  })
+);
+export type ClockDomainStats = z.infer<ReturnType<typeof ClockDomainStats>>;
+
 export const CreateTaskRequest = memoizeOne(() =>
   z.object({
     app: z.string(),
@@ -95,9 +163,11 @@ export const DesiredTaskPlayState = memoizeOne(() =>
   z.discriminatedUnion("type", [
     z.object({ type: z.literal("idle") }),
     z.object({
+      crossfade: z.number().int(),
       end: z.number().int(),
       looping: z.boolean(),
       playId: z.number().int(),
+      recordings: z.record(z.lazy(RecordingSpec)),
       sinks: z.record(z.lazy(SinkSpec)),
       start: z.number().int(),
       startFrom: z.number().int(),
@@ -122,6 +192,53 @@ export const DriverServiceSpec = memoizeOne(() =>
 );
 export type DriverServiceSpec = z.infer<ReturnType<typeof DriverServiceSpec>>;
 
+export const FadeCurve = memoizeOne(() =>
+  z.enum(["linear", "equalPower", "sCurve", "exponential"])
+);
+export type FadeCurve = z.infer<ReturnType<typeof FadeCurve>>;
+
+export const GraphModificationError = memoizeOne(() =>
+  z.discriminatedUnion("type", [
+    z.object({
+      component: z.lazy(NodeId),
+      input: z.number().int(),
+      output: z.lazy(OutputId),
+      type: z.literal("inputSourceNotFound"),
+    }),
+    z.object({
+      component: z.lazy(NodeId),
+      input: z.number().int(),
+      output: z.lazy(OutputId),
+      type: z.literal("loopDetected"),
+    }),
+    z.object({ component: z.lazy(NodeId), type: z.literal("componentNotFound") }),
+    z.object({
+      component: z.lazy(NodeId),
+      input: z.number().int(),
+      num_inputs: z.number().int(),
+      type: z.literal("inputChannelOutOfRange"),
+    }),
+    z.object({
+      component: z.lazy(NodeId),
+      input: z.number().int(),
+      num_outputs: z.number().int(),
+      output: z.lazy(OutputId),
+      type: z.literal("outputChannelOutOfRange"),
+    }),
+    z.object({
+      sink_id: z.number().int(),
+      type: z.literal("sinkWithoutInputs"),
+    }),
+    z.object({
+      source_id: z.number().int(),
+      type: z.literal("emptySourceClip"),
+    }),
+  ])
+);
+export type GraphModificationError = z.infer<
+  ReturnType<typeof GraphModificationError>
+>;
+
 export const GraphPlaybackError = memoizeOne(() =>
   z.discriminatedUnion("type", [
     z.object({
@@ -133,6 +250,11 @@ export const GraphPlaybackError = memoizeOne(() =>
       play_id: z.number().int(),
       type: z.literal("graphAlreadyPlaying"),
     }),
+    z.object({
+      error: z.string(),
+      sink: z.number().int(),
+      type: z.literal("recordingFailed"),
+    }),
   ])
 );
 export type GraphPlaybackError = z.infer<ReturnType<typeof GraphPlaybackError>>;
@@ -160,6 +282,10 @@ export const GraphPlayerEvent = memoizeOne(() =>
       }),
       type: z.literal("graphNodesPrepared"),
     }),
+    z.object({
+      details: z.object({ latency: z.number().int() }),
+      type: z.literal("graphLatencyChanged"),
+    }),
     z.object({
       details: z.object({
         data: z.array(z.number().int()),
@@ -177,10 +303,68 @@ export const GraphPlayerEvent = memoizeOne(() =>
       }),
       type: z.literal("nodeEvents"),
     }),
+    z.object({
+      details: z.object({
+        play_id: z.number().int(),
+        summaries: z.array(z.tuple([z.lazy(NodeId), z.lazy(LoudnessSummary)])),
+      }),
+      type: z.literal("graphLoudnessSummary"),
+    }),
+    z.object({
+      details: z.object({
+        late_nodes: z.array(z.lazy(NodeId)),
+        play_head: z.lazy(PlayHead),
+        play_id: z.number().int(),
+        stats: z.lazy(XRunStats),
+      }),
+      type: z.literal("graphXRun"),
+    }),
+    z.object({
+      details: z.object({
+        play_id: z.number().int(),
+        stats: z.lazy(ProcessingStats),
+      }),
+      type: z.literal("graphProcessingStats"),
+    }),
+    z.object({
+      details: z.object({
+        media: z.lazy(MediaSpec),
+        num_frames: z.number().int(),
+        play_id: z.number().int(),
+        sink_id: z.number().int(),
+      }),
+      type: z.literal("graphRecordingFinished"),
+    }),
+    z.object({
+      details: z.object({
+        clock_device: z.string(),
+        domains: z.array(z.lazy(ClockDomainStats)),
+        play_id: z.number().int(),
+      }),
+      type: z.literal("graphClockDomains"),
+    }),
   ])
 );
 export type GraphPlayerEvent = z.infer<ReturnType<typeof GraphPlayerEvent>>;
 
+export const HostAudioDeviceSpecs = memoizeOne(() =>
+  z.object({
+    clockDevice: z.union([z.string(), z.null()]),
+    devices: z.record(z.lazy(AudioDeviceSpec)),
+  })
+);
+export type HostAudioDeviceSpecs = z.infer<
+  ReturnType<typeof HostAudioDeviceSpecs>
+>;
+
+export const HostAudioDevices = memoizeOne(() =>
+  z.object({
+    devices: z.array(z.lazy(AudioDeviceDescription)),
+    updatedAt: z.coerce.date(),
+  })
+);
+export type HostAudioDevices = z.infer<ReturnType<typeof HostAudioDevices>>;
+
 export const HttpDriverParameter = memoizeOne(() =>
   z.object({
     body: z.union([z.string(), z.null()]),
@@ -322,6 +506,20 @@ export const InstanceFeature = memoizeOne(() =>
 );
 export type InstanceFeature = z.infer<ReturnType<typeof InstanceFeature>>;
 
+export const InstanceLatencyCalibration = memoizeOne(() =>
+  z.object({
+    additionalLatency: z.number().int(),
+    device: z.string(),
+    measuredAt: z.string(),
+    returns: z.array(z.union([z.number().int(), z.null()])),
+    roundTrip: z.number().int(),
+    sampleRate: z.number().int(),
+  })
+);
+export type InstanceLatencyCalibration = z.infer<
+  ReturnType<typeof InstanceLatencyCalibration>
+>;
+
 export const InstanceMediaSpec = memoizeOne(() =>
   z.object({
     durationMs: z.number().int(),
@@ -412,6 +610,11 @@ export const InstanceSpec = memoizeOne(() =>
 );
 export type InstanceSpec = z.infer<ReturnType<typeof InstanceSpec>>;
 
+export const JuceAudioDeviceSpec = memoizeOne(() =>
+  z.object({ deviceType: z.string(), name: z.string() })
+);
+export type JuceAudioDeviceSpec = z.infer<ReturnType<typeof JuceAudioDeviceSpec>>;
+
 export const LoginUserRequest = memoizeOne(() =>
   z.object({ id: z.string(), password: z.string() })
 );
@@ -422,6 +625,16 @@ export const LoginUserResponse = memoizeOne(() =>
 );
 export type LoginUserResponse = z.infer<ReturnType<typeof LoginUserResponse>>;
 
+export const LoudnessSummary = memoizeOne(() =>
+  z.object({
+    integrated: z.number(),
+    maxShortTerm: z.number(),
+    maxTruePeak: z.number(),
+    range: z.number(),
+  })
+);
+export type LoudnessSummary = z.infer<ReturnType<typeof LoudnessSummary>>;
+
 export const Map_of_InstanceAllocationRequest = memoizeOne(() =>
   z.record(z.lazy(InstanceAllocationRequest))
 );
@@ -486,6 +699,7 @@ export const NodeId = memoizeOne(() =>
     z.object({ id: z.number().int(), type: z.literal("bus") }),
     z.object({ id: z.number().int(), type: z.literal("deviceSink") }),
     z.object({ id: z.number().int(), type: z.literal("streamingSink") }),
+    z.object({ id: z.number().int(), type: z.literal("recordingSink") }),
   ])
 );
 export type NodeId = z.infer<ReturnType<typeof NodeId>>;
@@ -501,6 +715,19 @@ export const NodeInfo = memoizeOne(() =>
 );
 export type NodeInfo = z.infer<ReturnType<typeof NodeInfo>>;
 
+export const NodeProcessingStats = memoizeOne(() =>
+  z.object({
+    avgLoad: z.number(),
+    avgUs: z.number(),
+    maxLoad: z.number(),
+    maxUs: z.number(),
+    minUs: z.number(),
+  })
+);
+export type NodeProcessingStats = z.infer<
+  ReturnType<typeof NodeProcessingStats>
+>;
+
 export const OscParameterConfig = memoizeOne(() =>
   z.object({
     address: z.string(),
@@ -535,6 +762,15 @@ export const OutputId = memoizeOne(() =>
 );
 export type OutputId = z.infer<ReturnType<typeof OutputId>>;
 
+export const ParameterEvent = memoizeOne(() =>
+  z.object({
+    change: z.lazy(SetParameterCommand),
+    position: z.number().int(),
+    ramp: z.lazy(ParameterRamp),
+  })
+);
+export type ParameterEvent = z.infer<ReturnType<typeof ParameterEvent>>;
+
 export const ParameterModel = memoizeOne(() =>
   z.object({
     channels: z.number().int(),
@@ -546,6 +782,15 @@ export const ParameterModel = memoizeOne(() =>
 );
 export type ParameterModel = z.infer<ReturnType<typeof ParameterModel>>;
 
+export const ParameterRamp = memoizeOne(() =>
+  z.discriminatedUnion("type", [
+    z.object({ type: z.literal("step") }),
+    z.object({ length: z.number().int(), type: z.literal("linear") }),
+    z.object({ length: z.number().int(), type: z.literal("exponential") }),
+  ])
+);
+export type ParameterRamp = z.infer<ReturnType<typeof ParameterRamp>>;
+
 export const PlayHead = memoizeOne(() =>
   z.object({
     bufferSize: z.number().int(),
@@ -560,6 +805,7 @@ export type PlayHead = z.infer<ReturnType<typeof PlayHead>>;
 
 export const PlayRegion = memoizeOne(() =>
   z.object({
+    crossfade: z.number().int(),
     end: z.number().int(),
     looping: z.boolean(),
     start: z.number().int(),
@@ -580,6 +826,28 @@ export type PlayStateReportTrigger = z.infer<
   ReturnType<typeof PlayStateReportTrigger>
 >;
 
+export const ProcessingStats = memoizeOne(() =>
+  z.object({
+    budgetUs: z.number(),
+    cycles: z.number().int(),
+    nodes: z.array(z.tuple([z.lazy(NodeId), z.lazy(NodeProcessingStats)])),
+  })
+);
+export type ProcessingStats = z.infer<ReturnType<typeof ProcessingStats>>;
+
+export const RecordingFormat = memoizeOne(() => z.enum(["wav", "flac"]));
+export type RecordingFormat = z.infer<ReturnType<typeof RecordingFormat>>;
+
+export const RecordingSpec = memoizeOne(() =>
+  z.object({
+    appId: z.string(),
+    format: z.lazy(RecordingFormat),
+    inputs: z.array(z.array(z.lazy(OutputId))),
+    sampleRate: z.number().int(),
+  })
+);
+export type RecordingSpec = z.infer<ReturnType<typeof RecordingSpec>>;
+
 export const RegisterOrUpdateInstanceRequest = memoizeOne(() =>
   z.object({
     driverConfig: z.lazy(InstanceDriverConfig),
@@ -835,6 +1103,10 @@ export const SetTaskGraphResponse = memoizeOne(() =>
   z.discriminatedUnion("type", [
     z.object({ type: z.literal("success") }),
     z.object({ type: z.literal("notFound") }),
+    z.object({
+      errors: z.array(z.lazy(GraphModificationError)),
+      type: z.literal("invalidGraph"),
+    }),
   ])
 );
 export type SetTaskGraphResponse = z.infer<
@@ -889,17 +1161,36 @@ export type SetTaskTimeResponse = z.infer<
   ReturnType<typeof SetTaskTimeResponse>
 >;
 
+export const SinkCodec = memoizeOne(() =>
+  z.discriminatedUnion("type", [
+    z.object({ type: z.literal("flac") }),
+    z.object({ bitrate: z.number().int(), type: z.literal("opus") }),
+  ])
+);
+export type SinkCodec = z.infer<ReturnType<typeof SinkCodec>>;
+
 export const SinkSpec = memoizeOne(() =>
   z.object({
+    codec: z.lazy(SinkCodec),
     inputs: z.array(z.array(z.lazy(OutputId))),
     sampleRate: z.number().int(),
   })
 );
 export type SinkSpec = z.infer<ReturnType<typeof SinkSpec>>;
 
+export const SourceFade = memoizeOne(() =>
+  z.object({ curve: z.lazy(FadeCurve), length: z.number().int() })
+);
+export type SourceFade = z.infer<ReturnType<typeof SourceFade>>;
+
 export const SourceSpec = memoizeOne(() =>
   z.object({
+    fadeIn: z.lazy(SourceFade),
+    fadeOut: z.lazy(SourceFade),
+    gainDb: z.number(),
     mediaId: z.lazy(MediaId),
+    mediaIn: z.number().int(),
+    mediaOut: z.union([z.number().int(), z.null()]),
     numChannels: z.number().int(),
     startAt: z.number().int(),
   })
@@ -1020,3 +1311,12 @@ export const VirtualInsertSpec = memoizeOne(() =>
   z.object({ inputs: z.array(z.array(z.lazy(OutputId))), modelId: z.string() })
 );
 export type VirtualInsertSpec = z.infer<ReturnType<typeof VirtualInsertSpec>>;
+
+export const XRunStats = memoizeOne(() =>
+  z.object({
+    cycles: z.number().int(),
+    missedCycles: z.number().int(),
+    nodes: z.array(z.tuple([z.lazy(NodeId), z.number().int()])),
+  })
+);
+export type XRunStats = z.infer<ReturnType<typeof XRunStats>>;